@@ -196,8 +196,11 @@ async fn main() {
                     Event::EnrAdded { enr, replaced: _ } => info!("Enr added {}", enr),
                     Event::NodeInserted { node_id, replaced: _ } => info!("Node inserted {}", node_id),
                     Event::SessionEstablished(enr, _) => info!("Session established {}", enr),
+                    Event::SessionRoamed { node_id, to, .. } => info!("Session with {} moved to {}", node_id, to),
                     Event::SocketUpdated(addr) => info!("Socket updated {}", addr),
+                    Event::ListenSocketsUpdated(listen_config) => info!("Listening on {:?}", listen_config),
                     Event::TalkRequest(_) => info!("Talk request received"),
+                    Event::NatStatusUpdated(status) => info!("NAT status updated: {:?}", status),
                 };
             }
         }
@@ -0,0 +1,564 @@
+//! A local admin interface to a running [`Discv5`] server.
+//!
+//! The [`AdminServer`] listens on a Unix socket or a loopback TCP address and speaks JSON-RPC 2.0,
+//! one request or response per line. Its methods are a thin layer over the [`Discv5`] methods of
+//! the same name:
+//!
+//! | Method | Params | Result |
+//! |---|---|---|
+//! | `local_enr` | | ENR |
+//! | `table_entries` | | `[{node_id, enr, state, direction}]` |
+//! | `sessions` | | `[{node_id, socket_addr, established}]` |
+//! | `metrics` | | object of the [`crate::metrics::Metrics`] fields |
+//! | `active_queries` | | `[{id, target, elapsed_ms}]` |
+//! | `find_node` | `{target}` | `[ENR]` |
+//! | `send_ping` | `{enr}` | `{enr_seq, ip, port}` |
+//! | `talk_req` | `{enr, protocol, request}` | hex response |
+//! | `find_node_designated_peer` | `{enr, distances}` | `[ENR]` |
+//! | `ban_node`, `ban_ip` | `{node_id \| ip, duration_ms?, reason?}` | `null` |
+//! | `ban_node_remove`, `permit_node`, `permit_node_remove` | `{node_id}` | `null` |
+//! | `ban_ip_remove`, `permit_ip`, `permit_ip_remove` | `{ip}` | `null` |
+//! | `bans` | | `[{node_id \| ip, expires_in_ms, origin, reason}]` |
+//!
+//! ENRs are in their base64 text form, node ids and binary data are hex and IPs may be CIDR
+//! ranges. `find_node_designated_peer` with the distance `0` asks a node for its current ENR,
+//! given a possibly outdated one.
+//!
+//! ### Example
+//! ```ignore
+//! let discv5 = Arc::new(discv5);
+//! let server = AdminServer::bind(AdminListen::Tcp("127.0.0.1:9100".parse()?)).await?;
+//! tokio::spawn(server.run(discv5.clone()));
+//! ```
+//!
+//! ```text
+//! $ echo '{"jsonrpc":"2.0","id":1,"method":"local_enr"}' | nc 127.0.0.1 9100
+//! {"id":1,"jsonrpc":"2.0","result":"enr:-IS4Q..."}
+//! ```
+use crate::{
+    kbucket::{ConnectionDirection, ConnectionState},
+    permit_ban::{Ban, BanTarget, IpNetwork},
+    Discv5, Enr, ProtocolIdentity,
+};
+use enr::NodeId;
+use serde_json::{json, Map, Value};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+use tracing::{debug, warn};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+/// JSON-RPC error code for a request that is not valid JSON.
+const PARSE_ERROR: i64 = -32700;
+/// JSON-RPC error code for JSON that is not a valid request.
+const INVALID_REQUEST: i64 = -32600;
+/// JSON-RPC error code for an unknown method.
+const METHOD_NOT_FOUND: i64 = -32601;
+/// JSON-RPC error code for invalid method parameters.
+const INVALID_PARAMS: i64 = -32602;
+/// JSON-RPC error code for a request to the network that failed.
+const REQUEST_FAILED: i64 = -32000;
+
+/// Where the [`AdminServer`] listens.
+#[derive(Debug, Clone)]
+pub enum AdminListen {
+    /// A TCP address, which must be a loopback address.
+    Tcp(SocketAddr),
+    /// The path of a Unix socket. Access is controlled by the permissions of the path.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// A JSON-RPC server to inspect and manage a running [`Discv5`] server. See the
+/// [module](crate::admin) documentation for its methods.
+pub struct AdminServer {
+    listener: Listener,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl AdminServer {
+    /// Binds the listening socket. TCP addresses must be loopback addresses, as the interface is
+    /// not authenticated.
+    pub async fn bind(listen: AdminListen) -> io::Result<Self> {
+        let listener = match listen {
+            AdminListen::Tcp(addr) => {
+                if !addr.ip().is_loopback() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "The admin interface can only listen on a loopback address",
+                    ));
+                }
+                Listener::Tcp(TcpListener::bind(addr).await?)
+            }
+            #[cfg(unix)]
+            AdminListen::Unix(path) => Listener::Unix(UnixListener::bind(path)?),
+        };
+        Ok(AdminServer { listener })
+    }
+
+    /// The TCP address the server listens on, if it listens on TCP.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    /// Serves connections until accepting one fails. Each connection is served on its own task,
+    /// spawned on the executor of the server, and its requests are answered in order.
+    pub async fn run<P>(self, discv5: Arc<Discv5<P>>) -> io::Result<()>
+    where
+        P: ProtocolIdentity + Send + Sync + 'static,
+    {
+        let executor = discv5.executor();
+        loop {
+            let discv5 = discv5.clone();
+            match &self.listener {
+                Listener::Tcp(listener) => {
+                    let (stream, addr) = listener.accept().await?;
+                    debug!("Admin connection from {}", addr);
+                    executor.spawn(Box::pin(serve_connection(stream, discv5)));
+                }
+                #[cfg(unix)]
+                Listener::Unix(listener) => {
+                    let (stream, _) = listener.accept().await?;
+                    debug!("Admin connection on the Unix socket");
+                    executor.spawn(Box::pin(serve_connection(stream, discv5)));
+                }
+            }
+        }
+    }
+}
+
+/// Answers the requests of a connection until it is closed.
+async fn serve_connection<S, P>(stream: S, discv5: Arc<Discv5<P>>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    P: ProtocolIdentity,
+{
+    let (read, mut write) = tokio::io::split(stream);
+    let mut lines = BufReader::new(read).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(e) => {
+                debug!("Admin connection failed: {}", e);
+                return;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut response = handle_request(&discv5, &line).await.to_string();
+        response.push('\n');
+        if let Err(e) = write.write_all(response.as_bytes()).await {
+            warn!("Failed to answer an admin request: {}", e);
+            return;
+        }
+    }
+}
+
+/// An error to answer a request with.
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn invalid_params(message: impl Into<String>) -> Self {
+        RpcError {
+            code: INVALID_PARAMS,
+            message: message.into(),
+        }
+    }
+
+    fn request_failed(error: impl std::fmt::Display) -> Self {
+        RpcError {
+            code: REQUEST_FAILED,
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Answers a single JSON-RPC request.
+async fn handle_request<P: ProtocolIdentity>(discv5: &Discv5<P>, request: &str) -> Value {
+    let request: Value = match serde_json::from_str(request) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = RpcError {
+                code: PARSE_ERROR,
+                message: e.to_string(),
+            };
+            return response(Value::Null, Err(error));
+        }
+    };
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method,
+        None => {
+            let error = RpcError {
+                code: INVALID_REQUEST,
+                message: "Missing method".into(),
+            };
+            return response(id, Err(error));
+        }
+    };
+    let empty = Map::new();
+    let params = match request.get("params") {
+        Some(Value::Object(params)) => params,
+        None | Some(Value::Null) => &empty,
+        Some(_) => {
+            let error = RpcError::invalid_params("Params must be an object");
+            return response(id, Err(error));
+        }
+    };
+    response(id, call(discv5, method, params).await)
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(error) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {"code": error.code, "message": error.message},
+        }),
+    }
+}
+
+/// Calls the [`Discv5`] method a request names.
+async fn call<P: ProtocolIdentity>(
+    discv5: &Discv5<P>,
+    method: &str,
+    params: &Map<String, Value>,
+) -> Result<Value, RpcError> {
+    let result = match method {
+        "local_enr" => json!(discv5.local_enr().to_base64()),
+        "table_entries" => discv5
+            .table_entries()
+            .into_iter()
+            .map(|(node_id, enr, status)| {
+                json!({
+                    "node_id": node_id_to_hex(&node_id),
+                    "enr": enr.to_base64(),
+                    "state": match status.state {
+                        ConnectionState::Connected => "connected",
+                        ConnectionState::Disconnected => "disconnected",
+                    },
+                    "direction": match status.direction {
+                        ConnectionDirection::Incoming => "incoming",
+                        ConnectionDirection::Outgoing => "outgoing",
+                    },
+                })
+            })
+            .collect(),
+        "sessions" => discv5
+            .sessions()
+            .await
+            .map_err(RpcError::request_failed)?
+            .into_iter()
+            .map(|session| {
+                json!({
+                    "node_id": node_id_to_hex(&session.node_address.node_id),
+                    "socket_addr": session.node_address.socket_addr.to_string(),
+                    "established": session.established,
+                })
+            })
+            .collect(),
+        "metrics" => {
+            let metrics = discv5.metrics();
+            json!({
+                "active_sessions": metrics.active_sessions,
+                "unsolicited_requests_per_second": metrics.unsolicited_requests_per_second,
+                "bytes_sent": metrics.bytes_sent,
+                "bytes_recv": metrics.bytes_recv,
+                "ipv4_subnet_rejections": metrics.ipv4_subnet_rejections,
+                "ipv6_subnet_rejections": metrics.ipv6_subnet_rejections,
+                "outbound_packets_delayed": metrics.outbound_packets_delayed,
+                "outbound_packets_dropped": metrics.outbound_packets_dropped,
+                "outbound_queued_packets": metrics.outbound_queued_packets,
+            })
+        }
+        "active_queries" => discv5
+            .active_queries()
+            .await
+            .map_err(RpcError::request_failed)?
+            .into_iter()
+            .map(|query| {
+                json!({
+                    "id": query.id,
+                    "target": node_id_to_hex(&query.target),
+                    "elapsed_ms": query.elapsed.map(|elapsed| elapsed.as_millis() as u64),
+                })
+            })
+            .collect(),
+        "find_node" => {
+            let target = node_id_param(params, "target")?;
+            let enrs = discv5
+                .find_node(target)
+                .await
+                .map_err(RpcError::request_failed)?;
+            enrs.iter().map(|enr| json!(enr.to_base64())).collect()
+        }
+        "send_ping" => {
+            let pong = discv5
+                .send_ping(enr_param(params, "enr")?)
+                .await
+                .map_err(RpcError::request_failed)?;
+            json!({"enr_seq": pong.enr_seq, "ip": pong.ip.to_string(), "port": pong.port})
+        }
+        "talk_req" => {
+            let enr = enr_param(params, "enr")?;
+            let protocol = hex_param(params, "protocol")?;
+            let request = hex_param(params, "request")?;
+            let response = discv5
+                .talk_req(enr, protocol, request)
+                .await
+                .map_err(RpcError::request_failed)?;
+            json!(hex::encode(response))
+        }
+        "find_node_designated_peer" => {
+            let enr = enr_param(params, "enr")?;
+            let distances = distances_param(params)?;
+            let enrs = discv5
+                .find_node_designated_peer(enr, distances)
+                .await
+                .map_err(RpcError::request_failed)?;
+            enrs.iter().map(|enr| json!(enr.to_base64())).collect()
+        }
+        "ban_node" => {
+            discv5.ban_node_with_reason(
+                &node_id_param(params, "node_id")?,
+                duration_param(params)?,
+                reason_param(params)?,
+            );
+            Value::Null
+        }
+        "ban_node_remove" => {
+            discv5.ban_node_remove(&node_id_param(params, "node_id")?);
+            Value::Null
+        }
+        "permit_node" => {
+            discv5.permit_node(&node_id_param(params, "node_id")?);
+            Value::Null
+        }
+        "permit_node_remove" => {
+            discv5.permit_node_remove(&node_id_param(params, "node_id")?);
+            Value::Null
+        }
+        "ban_ip" => {
+            discv5.ban_ip_with_reason(
+                ip_param(params)?,
+                duration_param(params)?,
+                reason_param(params)?,
+            );
+            Value::Null
+        }
+        "ban_ip_remove" => {
+            discv5.ban_ip_remove(ip_param(params)?);
+            Value::Null
+        }
+        "permit_ip" => {
+            discv5.permit_ip(ip_param(params)?);
+            Value::Null
+        }
+        "permit_ip_remove" => {
+            discv5.permit_ip_remove(ip_param(params)?);
+            Value::Null
+        }
+        "bans" => bans_to_json(discv5.bans()),
+        _ => {
+            return Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("Unknown method {}", method),
+            })
+        }
+    };
+    Ok(result)
+}
+
+fn bans_to_json(bans: Vec<(BanTarget, Ban)>) -> Value {
+    let now = Instant::now();
+    bans.into_iter()
+        .map(|(target, ban)| {
+            let mut entry = match target {
+                BanTarget::Node(node_id) => json!({"node_id": node_id_to_hex(&node_id)}),
+                BanTarget::Ip(network) => json!({"ip": network.to_string()}),
+            };
+            let expires_in = ban
+                .expires
+                .map(|expires| expires.saturating_duration_since(now).as_millis() as u64);
+            entry["expires_in_ms"] = json!(expires_in);
+            entry["origin"] = json!(ban.origin.to_string());
+            entry["reason"] = json!(ban.reason);
+            entry
+        })
+        .collect()
+}
+
+fn node_id_to_hex(node_id: &NodeId) -> String {
+    format!("0x{}", hex::encode(node_id.raw()))
+}
+
+fn str_param<'a>(params: &'a Map<String, Value>, name: &str) -> Result<&'a str, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::invalid_params(format!("Missing string param {}", name)))
+}
+
+fn node_id_param(params: &Map<String, Value>, name: &str) -> Result<NodeId, RpcError> {
+    let mut raw = [0u8; 32];
+    hex::decode_to_slice(str_param(params, name)?.trim_start_matches("0x"), &mut raw)
+        .map_err(|_| RpcError::invalid_params(format!("Invalid node id {}", name)))?;
+    Ok(NodeId::new(&raw))
+}
+
+fn enr_param(params: &Map<String, Value>, name: &str) -> Result<Enr, RpcError> {
+    str_param(params, name)?
+        .parse()
+        .map_err(|e| RpcError::invalid_params(format!("Invalid ENR {}: {}", name, e)))
+}
+
+fn hex_param(params: &Map<String, Value>, name: &str) -> Result<Vec<u8>, RpcError> {
+    hex::decode(str_param(params, name)?.trim_start_matches("0x"))
+        .map_err(|_| RpcError::invalid_params(format!("Invalid hex {}", name)))
+}
+
+fn distances_param(params: &Map<String, Value>) -> Result<Vec<u64>, RpcError> {
+    params
+        .get("distances")
+        .and_then(Value::as_array)
+        .and_then(|distances| distances.iter().map(Value::as_u64).collect())
+        .ok_or_else(|| RpcError::invalid_params("Missing array of integers param distances"))
+}
+
+fn ip_param(params: &Map<String, Value>) -> Result<IpNetwork, RpcError> {
+    str_param(params, "ip")?
+        .parse()
+        .map_err(|e| RpcError::invalid_params(format!("Invalid ip: {}", e)))
+}
+
+fn duration_param(params: &Map<String, Value>) -> Result<Option<Duration>, RpcError> {
+    match params.get("duration_ms") {
+        None | Some(Value::Null) => Ok(None),
+        Some(duration) => duration
+            .as_u64()
+            .map(|duration| Some(Duration::from_millis(duration)))
+            .ok_or_else(|| RpcError::invalid_params("Invalid duration_ms")),
+    }
+}
+
+fn reason_param(params: &Map<String, Value>) -> Result<Option<String>, RpcError> {
+    match params.get("reason") {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(reason)) => Ok(Some(reason.clone())),
+        Some(_) => Err(RpcError::invalid_params("Invalid reason")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{permit_ban::BanOrigin, ConfigBuilder, ListenConfig, PermitBanList};
+    use enr::CombinedKey;
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpStream;
+
+    async fn request(discv5: &Discv5, request: Value) -> Value {
+        handle_request(discv5, &request.to_string()).await
+    }
+
+    #[tokio::test]
+    async fn admin_requests() {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder().ip4(Ipv4Addr::LOCALHOST).build(&key).unwrap();
+        let config =
+            ConfigBuilder::new(ListenConfig::from_ip(Ipv4Addr::LOCALHOST.into(), 0)).build();
+        let mut discv5: Discv5 = Discv5::new(enr.clone(), key, config).unwrap();
+        discv5.start().await.unwrap();
+
+        let response = request(
+            &discv5,
+            json!({"jsonrpc": "2.0", "id": 1, "method": "local_enr"}),
+        );
+        assert_eq!(response.await["result"], json!(enr.to_base64()));
+
+        let response = request(
+            &discv5,
+            json!({"jsonrpc": "2.0", "id": 2, "method": "sessions"}),
+        );
+        assert_eq!(response.await["result"], json!([]));
+
+        let invalid = json!({"jsonrpc": "2.0", "id": 5, "method": "ban_node", "params": {}});
+        let response = request(&discv5, invalid).await;
+        assert_eq!(response["error"]["code"], json!(INVALID_PARAMS));
+        assert_eq!(response["id"], json!(5));
+
+        let params = json!({"enr": discv5.local_enr().to_base64(), "distances": [0, "1"]});
+        let invalid = json!({"jsonrpc": "2.0", "id": 8, "method": "find_node_designated_peer", "params": params});
+        let response = request(&discv5, invalid).await;
+        assert_eq!(response["error"]["code"], json!(INVALID_PARAMS));
+
+        let unknown = json!({"jsonrpc": "2.0", "id": 6, "method": "unknown"});
+        let response = request(&discv5, unknown).await;
+        assert_eq!(response["error"]["code"], json!(METHOD_NOT_FOUND));
+
+        let response = handle_request(&discv5, "{").await;
+        assert_eq!(response["error"]["code"], json!(PARSE_ERROR));
+
+        // Requests are also answered over a connection to the server.
+        let server = AdminServer::bind(AdminListen::Tcp("127.0.0.1:0".parse().unwrap()))
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let discv5 = Arc::new(discv5);
+        discv5.executor().spawn(Box::pin(async move {
+            let _ = server.run(discv5).await;
+        }));
+        let (read, mut write) = TcpStream::connect(addr).await.unwrap().into_split();
+        write
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":7,\"method\":\"local_enr\"}\n")
+            .await
+            .unwrap();
+        let line = BufReader::new(read).lines().next_line().await.unwrap();
+        let response: Value = serde_json::from_str(&line.unwrap()).unwrap();
+        assert_eq!(response["result"], json!(enr.to_base64()));
+    }
+
+    #[test]
+    fn bans_are_listed() {
+        // The global ban list is shared with the other tests, so a local one is listed.
+        let mut list = PermitBanList::default();
+        let network: IpNetwork = "10.20.0.0/16".parse().unwrap();
+        list.ban_ips
+            .insert(network, Ban::by_user(None, Some("abusive range".into())));
+        let bans = list
+            .bans()
+            .map(|(target, ban)| (target, ban.clone()))
+            .collect();
+        let bans = bans_to_json(bans);
+        assert_eq!(bans[0]["ip"], json!("10.20.0.0/16"));
+        assert_eq!(bans[0]["reason"], json!("abusive range"));
+        assert_eq!(bans[0]["origin"], json!(BanOrigin::User.to_string()));
+        assert_eq!(bans[0]["expires_in_ms"], Value::Null);
+    }
+}
@@ -0,0 +1,161 @@
+//! The `enr` subcommands, which work offline.
+use crate::{node_id_hex, parse_secret_key};
+use clap::Subcommand;
+use discv5::{
+    enr::{CombinedKey, EnrPublicKey},
+    Enr,
+};
+use serde_json::{json, Map, Value};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// The keys whose values are decoded into their own fields.
+const KNOWN_KEYS: [&[u8]; 9] = [
+    b"id",
+    b"secp256k1",
+    b"ed25519",
+    b"ip",
+    b"ip6",
+    b"udp",
+    b"udp6",
+    b"tcp",
+    b"tcp6",
+];
+
+#[derive(Subcommand)]
+pub enum EnrCommand {
+    /// Prints the fields of an ENR.
+    Decode {
+        /// The ENR, in its base64 text form.
+        enr: Enr,
+    },
+    /// Builds an ENR, signed with `--secret-key` or with a new key which is printed.
+    Build {
+        /// The sequence number.
+        #[clap(long, default_value_t = 1)]
+        seq: u64,
+        #[clap(long)]
+        ip4: Option<Ipv4Addr>,
+        #[clap(long)]
+        udp4: Option<u16>,
+        #[clap(long)]
+        tcp4: Option<u16>,
+        #[clap(long)]
+        ip6: Option<Ipv6Addr>,
+        #[clap(long)]
+        udp6: Option<u16>,
+        #[clap(long)]
+        tcp6: Option<u16>,
+    },
+}
+
+impl EnrCommand {
+    pub fn run(self, secret_key: Option<&str>) -> Result<Value, String> {
+        match self {
+            EnrCommand::Decode { enr } => Ok(decode(&enr)),
+            EnrCommand::Build {
+                seq,
+                ip4,
+                udp4,
+                tcp4,
+                ip6,
+                udp6,
+                tcp6,
+            } => {
+                let (key, generated) = match secret_key {
+                    Some(secret_key) => (parse_secret_key(secret_key)?, false),
+                    None => (CombinedKey::generate_secp256k1(), true),
+                };
+                let mut builder = Enr::builder();
+                builder.seq(seq);
+                if let Some(ip4) = ip4 {
+                    builder.ip4(ip4);
+                }
+                if let Some(udp4) = udp4 {
+                    builder.udp4(udp4);
+                }
+                if let Some(tcp4) = tcp4 {
+                    builder.tcp4(tcp4);
+                }
+                if let Some(ip6) = ip6 {
+                    builder.ip6(ip6);
+                }
+                if let Some(udp6) = udp6 {
+                    builder.udp6(udp6);
+                }
+                if let Some(tcp6) = tcp6 {
+                    builder.tcp6(tcp6);
+                }
+                let enr = builder
+                    .build(&key)
+                    .map_err(|e| format!("Failed to build the ENR: {}", e))?;
+
+                let mut built = json!({
+                    "enr": enr.to_base64(),
+                    "node_id": node_id_hex(&enr.node_id()),
+                });
+                if generated {
+                    built["secret_key"] = json!(hex::encode(key.encode()));
+                }
+                Ok(built)
+            }
+        }
+    }
+}
+
+/// The fields of an ENR. Values of unknown keys are given as hex-encoded RLP.
+fn decode(enr: &Enr) -> Value {
+    let other: Map<String, Value> = enr
+        .iter()
+        .filter(|(key, _)| !KNOWN_KEYS.contains(&key.as_slice()))
+        .map(|(key, value)| {
+            let key = String::from_utf8_lossy(key).into_owned();
+            (key, json!(hex::encode(value)))
+        })
+        .collect();
+    json!({
+        "enr": enr.to_base64(),
+        "seq": enr.seq(),
+        "node_id": node_id_hex(&enr.node_id()),
+        "public_key": hex::encode(enr.public_key().encode()),
+        "signature_valid": enr.verify(),
+        "id": enr.id(),
+        "ip4": enr.ip4(),
+        "udp4": enr.udp4(),
+        "tcp4": enr.tcp4(),
+        "ip6": enr.ip6(),
+        "udp6": enr.udp6(),
+        "tcp6": enr.tcp6(),
+        "other": other,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_and_decode() {
+        let secret_key = "b71c71a67e1177ad4e901695e1b4b9ee17ae16c6668d313eac2f96dbcda3f291";
+        let build = EnrCommand::Build {
+            seq: 3,
+            ip4: Some(Ipv4Addr::LOCALHOST),
+            udp4: Some(9000),
+            tcp4: None,
+            ip6: None,
+            udp6: None,
+            tcp6: None,
+        };
+        let built = build.run(Some(secret_key)).unwrap();
+        assert!(built.get("secret_key").is_none());
+
+        let enr: Enr = built["enr"].as_str().unwrap().parse().unwrap();
+        let decoded = EnrCommand::Decode { enr }.run(None).unwrap();
+        assert_eq!(decoded["seq"], json!(3));
+        assert_eq!(decoded["ip4"], json!("127.0.0.1"));
+        assert_eq!(decoded["udp4"], json!(9000));
+        assert_eq!(decoded["tcp4"], Value::Null);
+        assert_eq!(decoded["node_id"], built["node_id"]);
+        assert_eq!(decoded["signature_valid"], json!(true));
+        assert_eq!(decoded["other"], json!({}));
+    }
+}
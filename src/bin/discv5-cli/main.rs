@@ -0,0 +1,507 @@
+//! A command-line tool to interact with Discovery v5 networks.
+//!
+//! Build it with the `cli` feature:
+//! ```text
+//! cargo run --features cli --bin discv5-cli -- --help
+//! ```
+//!
+//! Network commands start a local node that only uses the public [`Discv5`] API, print their
+//! results as text or, with `--json`, as JSON and exit. `bootnode` serves until interrupted.
+
+mod enr_cmd;
+
+use clap::{Args, Parser, Subcommand};
+use discv5::{
+    enr::{CombinedKey, NodeId},
+    ConfigBuilder, Discv5, Enr, Event, ListenConfig,
+};
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashSet, VecDeque},
+    net::{IpAddr, Ipv4Addr},
+    process::ExitCode,
+};
+use tracing::info;
+
+#[derive(Parser)]
+#[clap(
+    name = "discv5-cli",
+    version,
+    about = "Interact with Discovery v5 networks"
+)]
+struct Cli {
+    /// Print results as JSON instead of text.
+    #[clap(long, global = true)]
+    json: bool,
+    #[clap(flatten)]
+    node: NodeArgs,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+/// The local node that network commands run.
+#[derive(Args)]
+struct NodeArgs {
+    /// The address to listen on.
+    #[clap(long, global = true, default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    listen_address: IpAddr,
+    /// The UDP port to listen on. By default a free port is picked.
+    #[clap(long, global = true, default_value_t = 0)]
+    port: u16,
+    /// The hex-encoded secp256k1 secret key of the local node. By default a new key is generated.
+    #[clap(long, global = true)]
+    secret_key: Option<String>,
+    /// An IP to advertise in the local ENR, so that other nodes can contact the local node.
+    #[clap(long, global = true)]
+    enr_ip: Option<IpAddr>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Sends a PING to a node.
+    Ping {
+        /// The ENR of the node.
+        enr: Enr,
+    },
+    /// Asks a node for the nodes in its routing table at the given distances.
+    Findnode {
+        /// The ENR of the node.
+        enr: Enr,
+        /// The log2 distances from the node to ask for, 0 for the ENR of the node itself.
+        #[clap(long, value_delimiter = ',', default_value = "256")]
+        distances: Vec<u64>,
+    },
+    /// Sends a TALKREQ to a node and prints the hex-encoded response.
+    Talk {
+        /// The ENR of the node.
+        enr: Enr,
+        /// The protocol of the request.
+        protocol: String,
+        /// The hex-encoded request.
+        #[clap(default_value = "")]
+        request: String,
+    },
+    /// Searches the network for the nodes closest to a target.
+    Lookup {
+        /// The hex-encoded node id to search for. By default a random one.
+        target: Option<String>,
+        /// The ENRs of the nodes to start from.
+        #[clap(long = "bootnode", required = true)]
+        bootnodes: Vec<Enr>,
+    },
+    /// Visits every node that can be reached from the bootnodes, printing each as it is found.
+    Crawl {
+        /// The ENRs of the nodes to start from.
+        #[clap(long = "bootnode", required = true)]
+        bootnodes: Vec<Enr>,
+        /// The log2 distances to ask each node for.
+        #[clap(long, value_delimiter = ',', default_value = "256,255,254")]
+        distances: Vec<u64>,
+        /// The number of nodes asked at once.
+        #[clap(long, default_value_t = 16)]
+        concurrency: usize,
+        /// Stop after visiting this many nodes.
+        #[clap(long)]
+        max_nodes: Option<usize>,
+    },
+    /// Decodes and builds ENRs.
+    Enr {
+        #[clap(subcommand)]
+        command: enr_cmd::EnrCommand,
+    },
+    /// Serves as a bootnode until interrupted.
+    Bootnode {
+        /// The ENRs of other nodes to join the network through.
+        #[clap(long = "bootnode")]
+        bootnodes: Vec<Enr>,
+    },
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let filter_layer = tracing_subscriber::EnvFilter::try_from_default_env()
+        .or_else(|_| tracing_subscriber::EnvFilter::try_new("warn"))
+        .unwrap();
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter_layer)
+        .with_writer(std::io::stderr)
+        .try_init();
+
+    let cli = Cli::parse();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), String> {
+    let output = Output { json: cli.json };
+    match cli.command {
+        Command::Ping { enr } => {
+            let discv5 = start_node(&cli.node, &[]).await?;
+            let pong = discv5.send_ping(enr).await.map_err(|e| e.to_string())?;
+            output.print(
+                json!({"enr_seq": pong.enr_seq, "ip": pong.ip, "port": pong.port}),
+                format!(
+                    "PONG: ENR sequence number {}, our address as seen by the node {}:{}",
+                    pong.enr_seq, pong.ip, pong.port
+                ),
+            );
+        }
+        Command::Findnode { enr, distances } => {
+            let discv5 = start_node(&cli.node, &[]).await?;
+            let enrs = discv5
+                .find_node_designated_peer(enr, distances)
+                .await
+                .map_err(|e| e.to_string())?;
+            output.print_enrs(&enrs);
+        }
+        Command::Talk {
+            enr,
+            protocol,
+            request,
+        } => {
+            let request = hex::decode(request).map_err(|e| format!("Invalid request: {}", e))?;
+            let discv5 = start_node(&cli.node, &[]).await?;
+            let response = discv5
+                .talk_req(enr, protocol.into_bytes(), request)
+                .await
+                .map_err(|e| e.to_string())?;
+            let response = hex::encode(response);
+            output.print(json!(response), response.clone());
+        }
+        Command::Lookup { target, bootnodes } => {
+            let target = match target {
+                Some(target) => parse_node_id(&target)?,
+                None => NodeId::random(),
+            };
+            let discv5 = start_node(&cli.node, &bootnodes).await?;
+            info!("Searching for the nodes closest to {}", target);
+            let enrs = discv5.find_node(target).await.map_err(|e| e.to_string())?;
+            output.print_enrs(&enrs);
+        }
+        Command::Crawl {
+            bootnodes,
+            distances,
+            concurrency,
+            max_nodes,
+        } => {
+            let discv5 = start_node(&cli.node, &[]).await?;
+            crawl(
+                &discv5,
+                bootnodes,
+                distances,
+                concurrency.max(1),
+                max_nodes,
+                &output,
+            )
+            .await;
+        }
+        Command::Enr { command } => {
+            output.print_value(command.run(cli.node.secret_key.as_deref())?)
+        }
+        Command::Bootnode { bootnodes } => {
+            let mut discv5 = start_node(&cli.node, &bootnodes).await?;
+            let enr = discv5.local_enr();
+            output.print(
+                json!({"enr": enr.to_base64(), "node_id": node_id_hex(&enr.node_id())}),
+                format!(
+                    "Serving as a bootnode\nENR: {}\nNode id: {}",
+                    enr.to_base64(),
+                    node_id_hex(&enr.node_id())
+                ),
+            );
+            serve(&discv5, &output).await?;
+            discv5.shutdown(true).await;
+        }
+    }
+    Ok(())
+}
+
+/// Creates and starts the local node, adding the given nodes to its routing table.
+async fn start_node(args: &NodeArgs, bootnodes: &[Enr]) -> Result<Discv5, String> {
+    let key = match &args.secret_key {
+        Some(secret_key) => parse_secret_key(secret_key)?,
+        None => CombinedKey::generate_secp256k1(),
+    };
+    let listen_config = ListenConfig::from_ip(args.listen_address, args.port);
+    let mut builder = Enr::builder();
+    if let Some(ip) = args.enr_ip {
+        if args.port == 0 {
+            return Err("Advertising an IP requires a --port".into());
+        }
+        builder.ip(ip);
+        match ip {
+            IpAddr::V4(_) => builder.udp4(args.port),
+            IpAddr::V6(_) => builder.udp6(args.port),
+        };
+    }
+    let enr = builder
+        .build(&key)
+        .map_err(|e| format!("Failed to build the local ENR: {}", e))?;
+    let config = ConfigBuilder::new(listen_config).build();
+    let mut discv5: Discv5 = Discv5::new(enr, key, config)?;
+    for enr in bootnodes {
+        discv5
+            .add_enr(enr.clone())
+            .map_err(|e| format!("Failed to add bootnode {}: {}", enr.node_id(), e))?;
+    }
+    discv5.start().await.map_err(|e| format!("{:?}", e))?;
+    Ok(discv5)
+}
+
+/// Asks every reachable node for the nodes it knows, printing each node once. Returns the nodes
+/// that responded, in the order they did.
+async fn crawl(
+    discv5: &Discv5,
+    bootnodes: Vec<Enr>,
+    distances: Vec<u64>,
+    concurrency: usize,
+    max_nodes: Option<usize>,
+    output: &Output,
+) -> Vec<Enr> {
+    // The local node is not asked about itself.
+    let mut seen: HashSet<NodeId> = std::iter::once(discv5.local_enr().node_id())
+        .chain(bootnodes.iter().map(Enr::node_id))
+        .collect();
+    let mut to_visit: VecDeque<Enr> = bootnodes.into();
+    let mut in_progress = FuturesUnordered::new();
+    let mut responded = Vec::new();
+    let (mut visited, mut failed) = (0, 0);
+
+    loop {
+        while in_progress.len() < concurrency
+            && max_nodes.is_none_or(|max| visited + in_progress.len() < max)
+        {
+            let enr = match to_visit.pop_front() {
+                Some(enr) => enr,
+                None => break,
+            };
+            let request = discv5.find_node_designated_peer(enr.clone(), distances.clone());
+            in_progress.push(async move { (enr, request.await) });
+        }
+        let (enr, result) = match in_progress.next().await {
+            Some(response) => response,
+            None => break,
+        };
+        visited += 1;
+        match result {
+            Ok(enrs) => {
+                output.print(enr_json(&enr), enr.to_base64());
+                for enr in enrs {
+                    if seen.insert(enr.node_id()) {
+                        to_visit.push_back(enr);
+                    }
+                }
+                responded.push(enr);
+            }
+            Err(e) => {
+                failed += 1;
+                info!("Failed to crawl {}: {}", enr.node_id(), e);
+            }
+        }
+    }
+
+    let summary = json!({"visited": visited, "responded": visited - failed, "seen": seen.len()});
+    if output.json {
+        eprintln!("{}", summary);
+    } else {
+        eprintln!(
+            "Visited {} nodes, {} responded, {} seen",
+            visited,
+            visited - failed,
+            seen.len()
+        );
+    }
+    responded
+}
+
+/// Prints the events of the local node until interrupted.
+async fn serve(discv5: &Discv5, output: &Output) -> Result<(), String> {
+    let mut events = discv5
+        .event_stream()
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+            Some(event) = events.recv() => {
+                let (event, text) = match event {
+                    Event::SessionEstablished(enr, addr) => (
+                        json!({"event": "session_established", "node_id": node_id_hex(&enr.node_id()), "addr": addr}),
+                        format!("Session established with {} at {}", node_id_hex(&enr.node_id()), addr),
+                    ),
+                    Event::SessionRoamed { node_id, from, to } => (
+                        json!({"event": "session_roamed", "node_id": node_id_hex(&node_id), "from": from, "to": to}),
+                        format!("Session with {} moved from {} to {}", node_id_hex(&node_id), from, to),
+                    ),
+                    Event::NodeInserted { node_id, .. } => (
+                        json!({"event": "node_inserted", "node_id": node_id_hex(&node_id)}),
+                        format!("Node inserted {}", node_id_hex(&node_id)),
+                    ),
+                    Event::SocketUpdated(addr) => (
+                        json!({"event": "socket_updated", "addr": addr}),
+                        format!("Local socket updated to {}", addr),
+                    ),
+                    _ => continue,
+                };
+                output.print(event, text);
+            }
+        }
+    }
+}
+
+fn parse_secret_key(secret_key: &str) -> Result<CombinedKey, String> {
+    let mut bytes = hex::decode(secret_key.trim_start_matches("0x"))
+        .map_err(|e| format!("Invalid secret key: {}", e))?;
+    CombinedKey::secp256k1_from_bytes(&mut bytes).map_err(|e| format!("Invalid secret key: {}", e))
+}
+
+fn node_id_hex(node_id: &NodeId) -> String {
+    format!("0x{}", hex::encode(node_id.raw()))
+}
+
+fn parse_node_id(hex_id: &str) -> Result<NodeId, String> {
+    let mut raw = [0u8; 32];
+    hex::decode_to_slice(hex_id.trim_start_matches("0x"), &mut raw)
+        .map_err(|e| format!("Invalid node id: {}", e))?;
+    Ok(NodeId::new(&raw))
+}
+
+fn enr_json(enr: &Enr) -> Value {
+    json!({
+        "enr": enr.to_base64(),
+        "node_id": node_id_hex(&enr.node_id()),
+        "udp4": enr.udp4_socket(),
+        "udp6": enr.udp6_socket(),
+    })
+}
+
+/// Prints results as text or as JSON.
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn print(&self, json: Value, text: String) {
+        if self.json {
+            println!("{}", json);
+        } else {
+            println!("{}", text);
+        }
+    }
+
+    fn print_value(&self, value: Value) {
+        if self.json {
+            println!("{}", value);
+        } else {
+            println!("{}", text(&value));
+        }
+    }
+
+    fn print_enrs(&self, enrs: &[Enr]) {
+        let json = enrs.iter().map(enr_json).collect();
+        let text = match enrs.len() {
+            0 => "No nodes found".to_string(),
+            _ => enrs
+                .iter()
+                .map(|enr| format!("{} {}", node_id_hex(&enr.node_id()), enr.to_base64()))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        self.print(json, text);
+    }
+}
+
+/// Formats a JSON object as `key: value` lines.
+fn text(value: &Value) -> String {
+    match value {
+        Value::Object(fields) => fields
+            .iter()
+            .filter(|(_, value)| {
+                !value.is_null() && value.as_object().is_none_or(|fields| !fields.is_empty())
+            })
+            .map(|(key, value)| match value {
+                Value::String(value) => format!("{}: {}", key, value),
+                Value::Object(_) => format!("{}:\n  {}", key, text(value).replace('\n', "\n  ")),
+                value => format!("{}: {}", key, value),
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_args(port: u16) -> NodeArgs {
+        NodeArgs {
+            listen_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+            secret_key: None,
+            enr_ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_against_a_bootnode() {
+        let bootnode = start_node(&node_args(9710), &[]).await.unwrap();
+        let client = start_node(&node_args(9711), &[]).await.unwrap();
+        let bootnode_enr = bootnode.local_enr();
+
+        let pong = client.send_ping(bootnode_enr.clone()).await.unwrap();
+        assert_eq!(pong.port, 9711);
+
+        let enrs = client
+            .find_node_designated_peer(bootnode_enr.clone(), vec![0])
+            .await
+            .unwrap();
+        assert_eq!(enrs, vec![bootnode_enr.clone()]);
+
+        let output = Output { json: true };
+        // The bootnode knows a peer, which only knows the bootnode and the crawling client.
+        let peer = start_node(&node_args(9712), &[]).await.unwrap();
+        peer.send_ping(bootnode_enr.clone()).await.unwrap();
+        let distances = (1..=256).collect();
+        let visited = crawl(
+            &client,
+            vec![bootnode_enr.clone()],
+            distances,
+            4,
+            None,
+            &output,
+        )
+        .await;
+        assert_eq!(visited, vec![bootnode_enr.clone(), peer.local_enr()]);
+
+        // Subcommands are run as parsed from the command line.
+        let enr = bootnode_enr.to_base64();
+        let cli = Cli::try_parse_from([
+            "discv5-cli",
+            "--json",
+            "--listen-address",
+            "127.0.0.1",
+            "ping",
+            &enr,
+        ])
+        .unwrap();
+        assert!(matches!(&cli.command, Command::Ping { enr } if *enr == bootnode_enr));
+        run(cli).await.unwrap();
+
+        let cli = Cli::try_parse_from(["discv5-cli", "crawl"]);
+        assert!(cli.is_err(), "crawl requires a bootnode");
+    }
+
+    #[test]
+    fn formats_text() {
+        let value = json!({"seq": 1, "ip4": "127.0.0.1", "tcp4": null, "other": {"eth": "c0"}});
+        assert_eq!(text(&value), "ip4: 127.0.0.1\nother:\n  eth: c0\nseq: 1");
+    }
+}
@@ -1,9 +1,24 @@
 //! A set of configuration parameters to tune the discovery protocol.
 use crate::{
-    kbucket::MAX_NODES_PER_BUCKET, socket::ListenConfig, Enr, Executor, PermitBanList, RateLimiter,
-    RateLimiterBuilder,
+    kbucket::{Filter, IpLimits, MAX_NODES_PER_BUCKET},
+    permit_ban::PermitBanStore,
+    service::max_nodes_response_limit,
+    socket::{ListenConfig, OutboundRateLimiter},
+    AdaptiveTimeoutConfig, Enr, Executor, PermitBanList, PortMappingConfig, RateLimiter,
+    RateLimiterBuilder, RekeyConfig, RevalidationConfig,
 };
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
+
+#[cfg(feature = "serde")]
+mod file;
+#[cfg(feature = "serde")]
+pub use file::{
+    AdaptiveTimeoutFile, ConfigFile, OutboundRateLimitFile, QuotaFile, RateLimitFile,
+    RevalidationFile, SessionRekeyFile, SubnetLimitFile,
+};
+
+/// A closure used to decide whether to insert nodes into the local routing table.
+pub type TableFilter = Arc<dyn Fn(&Enr) -> bool + Send + Sync>;
 
 /// Configuration parameters that define the performance of the discovery network.
 #[derive(Clone)]
@@ -14,10 +29,21 @@ pub struct Config {
     /// The request timeout for each UDP request. Default: 1 seconds.
     pub request_timeout: Duration,
 
+    /// If set, each request times out after a retransmission timeout derived from the round-trip
+    /// times measured to the peer, which doubles with every retry. `request_timeout` then only
+    /// applies to peers without measurements. Default: None.
+    pub adaptive_request_timeout: Option<AdaptiveTimeoutConfig>,
+
     /// The interval over which votes are remembered when determining our external IP. A lower
     /// interval will respond faster to IP changes. Default is 30 seconds.
     pub vote_duration: Duration,
 
+    /// How long our external socket must be known, or peers must disagree on it, without a node we
+    /// have not contacted establishing a session before we are considered unreachable from the
+    /// outside. Nodes are only expected to contact us once they have learned of us, so this
+    /// should be well above the time our ENR takes to spread. Default: 10 minutes.
+    pub inbound_observation_window: Duration,
+
     /// The timeout after which a `QueryPeer` in an ongoing query is marked unresponsive.
     /// Unresponsive peers don't count towards the parallelism limits for a query.
     /// Hence, we may potentially end up making more requests to good peers. Default: 2 seconds.
@@ -35,12 +61,22 @@ pub struct Config {
     /// The maximum number of established sessions to maintain. Default: 1000.
     pub session_cache_capacity: usize,
 
+    /// After how many messages or how long the keys of a session are renewed through a new
+    /// handshake. See [`RekeyConfig`] for the defaults.
+    pub session_rekey: RekeyConfig,
+
     /// Updates the local ENR IP and port based on PONG responses from peers. Default: true.
     pub enr_update: bool,
 
-    /// The maximum number of nodes we return to a find nodes request. The default is 16.
+    /// The maximum number of nodes we return to a find nodes request. This cannot be larger than
+    /// the number of nodes that fit in the NODES responses a peer with the same `bucket_size`
+    /// accepts. The default is 16.
     pub max_nodes_response: usize,
 
+    /// The maximum number of nodes in a bucket of the routing table, i.e. the `k` parameter of
+    /// Kademlia. This is also the number of results of a query. Default: 16.
+    pub bucket_size: usize,
+
     /// The minimum number of peer's who agree on an external IP port before updating the
     /// local ENR. Default: 10.
     pub enr_peer_update_min: usize,
@@ -48,31 +84,69 @@ pub struct Config {
     /// The number of peers to request in parallel in a single query. Default: 3.
     pub query_parallelism: usize,
 
-    /// Limits the number of IP addresses from the same
-    /// /24 subnet in the kbuckets table. This is to mitigate eclipse attacks. Default: false.
+    /// Among the peers of a query at the same log2-distance to the target, contact those with the
+    /// lowest measured round-trip time first. Default: false.
+    pub latency_aware_queries: bool,
+
+    /// Limits the number of IP addresses from the same subnet in the kbuckets table, as defined
+    /// by `ip_limits`. This is to mitigate eclipse attacks. Default: false.
     pub ip_limit: bool,
 
+    /// The subnet prefix lengths and the number of nodes permitted per subnet in the table and in
+    /// each bucket, for IPv4 and IPv6. Only applied if `ip_limit` is set. Default: 10 per table
+    /// and 2 per bucket in each IPv4 /24 and IPv6 /56 subnet.
+    pub ip_limits: IpLimits,
+
     /// Sets a maximum limit to the number of  incoming nodes (nodes that have dialed us) to exist per-bucket. This cannot be larger
-    /// than the bucket size (16). By default this is disabled (set to the maximum bucket size, 16).
+    /// than the bucket size. By default this is disabled (set to the default bucket size, 16).
     pub incoming_bucket_limit: usize,
 
     /// A filter used to decide whether to insert nodes into our local routing table. Nodes can be
     /// excluded if they do not pass this filter. The default is to accept all nodes.
-    pub table_filter: fn(&Enr) -> bool,
+    pub table_filter: TableFilter,
+
+    /// A custom [`Filter`] applied to the whole routing table when adding or updating a node. This
+    /// is given the ENR to be inserted and all ENRs currently in the table. If `ip_limit` is set,
+    /// both filters must pass. Default: None.
+    pub kbucket_table_filter: Option<Box<dyn Filter<Enr>>>,
 
-    /// The time between pings to ensure connectivity amongst connected nodes. Default: 300
-    /// seconds.
+    /// A custom [`Filter`] applied to a single bucket when adding or updating a node. This is
+    /// given the ENR to be inserted and all ENRs currently in the bucket. If `ip_limit` is set,
+    /// both filters must pass. Default: None.
+    pub kbucket_bucket_filter: Option<Box<dyn Filter<Enr>>>,
+
+    /// The time between pings to ensure connectivity amongst connected nodes, once they have
+    /// been responsive for [`RevalidationConfig::stable_after`]. Default: 300 seconds.
     pub ping_interval: Duration,
 
+    /// How often other routing table entries are pinged, and when unresponsive entries are
+    /// evicted. See [`RevalidationConfig`] for the defaults.
+    pub revalidation: RevalidationConfig,
+
+    /// The number of connected peers pinged per second after the local ENR changes, so that they
+    /// learn of the new record. Peers in the closest buckets are pinged first. Default: 20.
+    pub enr_propagation_rate: usize,
+
     /// Reports all discovered ENR's when traversing the DHT to the event stream. Default true.
     pub report_discovered_peers: bool,
 
+    /// Runs lookups without serving the network. The local ENR advertises no UDP socket, so that
+    /// peers don't add the node to their routing tables, FINDNODE and TALK requests are answered
+    /// with empty responses and incoming sessions are not added to the local routing table. The
+    /// UDP socket of the ENR is never updated and port mapping is disabled. Default: false.
+    pub client_only: bool,
+
     /// A set of configuration parameters for setting inbound request rate limits. See
     /// [`RateLimiterBuilder`] for options. This is only functional if the packet filter is
     /// enabled via the `enable_packet_filter` option. See the `Default` implementation for
     /// default values. If set to None, inbound requests are not filtered.
     pub filter_rate_limiter: Option<RateLimiter>,
 
+    /// Limits the rate of outbound packets. Packets that exceed the budgets are queued by
+    /// priority until they fit. See [`crate::OutboundRateLimiterBuilder`] for options. Default is
+    /// `None`, i.e. outbound packets are not limited.
+    pub outbound_rate_limiter: Option<OutboundRateLimiter>,
+
     /// The maximum number of node-ids allowed per IP address before the IP address gets banned.
     /// Having this set to None, disables this feature. Default value is 10. This is only
     /// applicable if the `enable_packet_filter` option is set.
@@ -87,6 +161,10 @@ pub struct Config {
     /// `crate::PermitBanList`.
     pub permit_ban_list: PermitBanList,
 
+    /// If set, the permit/ban list is saved to this store whenever it changes, so that bans
+    /// survive restarts. Default: None.
+    pub permit_ban_store: Option<Arc<dyn PermitBanStore>>,
+
     /// Set the default duration for which nodes are banned for. This timeouts are checked every 5 minutes,
     /// so the precision will be to the nearest 5 minutes. If set to `None`, bans from the filter
     /// will last indefinitely. Default is 1 hour.
@@ -98,6 +176,11 @@ pub struct Config {
 
     /// Configuration for the sockets to listen on.
     pub listen_config: ListenConfig,
+
+    /// If set, a UDP port mapping for the IPv4 listening socket is requested from the gateway of
+    /// the local network and the mapped socket is advertised in the local ENR. See
+    /// `crate::PortMappingConfig`. Default is `None`.
+    pub port_mapping: Option<PortMappingConfig>,
 }
 
 #[derive(Debug)]
@@ -121,28 +204,42 @@ impl ConfigBuilder {
         let config = Config {
             enable_packet_filter: false,
             request_timeout: Duration::from_secs(1),
+            adaptive_request_timeout: None,
             vote_duration: Duration::from_secs(30),
+            inbound_observation_window: Duration::from_secs(600),
             query_peer_timeout: Duration::from_secs(2),
             query_timeout: Duration::from_secs(60),
             request_retries: 1,
             session_timeout: Duration::from_secs(86400),
             session_cache_capacity: 1000,
+            session_rekey: RekeyConfig::default(),
             enr_update: true,
             max_nodes_response: 16,
+            bucket_size: MAX_NODES_PER_BUCKET,
             enr_peer_update_min: 10,
             query_parallelism: 3,
+            latency_aware_queries: false,
             ip_limit: false,
+            ip_limits: IpLimits::default(),
             incoming_bucket_limit: MAX_NODES_PER_BUCKET,
-            table_filter: |_| true,
+            table_filter: Arc::new(|_| true),
+            kbucket_table_filter: None,
+            kbucket_bucket_filter: None,
             ping_interval: Duration::from_secs(300),
+            revalidation: RevalidationConfig::default(),
+            enr_propagation_rate: 20,
             report_discovered_peers: true,
+            client_only: false,
             filter_rate_limiter,
+            outbound_rate_limiter: None,
             filter_max_nodes_per_ip: Some(10),
             filter_max_bans_per_ip: Some(5),
             permit_ban_list: PermitBanList::default(),
+            permit_ban_store: None,
             ban_duration: Some(Duration::from_secs(3600)), // 1 hour
             executor: None,
             listen_config,
+            port_mapping: None,
         };
 
         ConfigBuilder { config }
@@ -160,6 +257,14 @@ impl ConfigBuilder {
         self
     }
 
+    /// Derives the timeout of each request from the round-trip times measured to the peer, within
+    /// the given bounds, doubling it with every retry. The `request_timeout` then only applies to
+    /// peers without measurements.
+    pub fn adaptive_request_timeout(&mut self, bounds: AdaptiveTimeoutConfig) -> &mut Self {
+        self.config.adaptive_request_timeout = Some(bounds);
+        self
+    }
+
     /// The interval over which votes are remembered when determining our external IP. A lower
     /// interval will respond faster to IP changes. Default is 30 seconds.
     pub fn vote_duration(&mut self, vote_duration: Duration) -> &mut Self {
@@ -167,6 +272,14 @@ impl ConfigBuilder {
         self
     }
 
+    /// How long our external socket must be known, or peers must disagree on it, without an
+    /// unsolicited session before we are considered unreachable from the outside. Default: 10
+    /// minutes.
+    pub fn inbound_observation_window(&mut self, window: Duration) -> &mut Self {
+        self.config.inbound_observation_window = window;
+        self
+    }
+
     /// The timeout after which a `QueryPeer` in an ongoing query is marked unresponsive.
     /// Unresponsive peers don't count towards the parallelism limits for a query.
     /// Hence, we may potentially end up making more requests to good peers.
@@ -199,6 +312,12 @@ impl ConfigBuilder {
         self
     }
 
+    /// After how many messages or how long the keys of a session are renewed.
+    pub fn session_rekey(&mut self, rekey: RekeyConfig) -> &mut Self {
+        self.config.session_rekey = rekey;
+        self
+    }
+
     /// Disables the auto-update of the local ENR IP and port based on PONG responses from peers.
     pub fn disable_enr_update(&mut self) -> &mut Self {
         self.config.enr_update = false;
@@ -211,6 +330,20 @@ impl ConfigBuilder {
         self
     }
 
+    /// The maximum number of nodes in a bucket of the routing table, i.e. the `k` parameter of
+    /// Kademlia. This also sets the number of results of a query and the number of NODES
+    /// responses accepted for a request. `incoming_bucket_limit` and `max_nodes_response` are
+    /// lowered to the largest values the bucket size permits, if they were larger.
+    pub fn bucket_size(&mut self, bucket_size: usize) -> &mut Self {
+        self.config.bucket_size = bucket_size;
+        self.config.incoming_bucket_limit = self.config.incoming_bucket_limit.min(bucket_size);
+        self.config.max_nodes_response = self
+            .config
+            .max_nodes_response
+            .min(max_nodes_response_limit(bucket_size));
+        self
+    }
+
     /// The minimum number of peer's who agree on an external IP port before updating the
     /// local ENR.
     pub fn enr_peer_update_min(&mut self, min: usize) -> &mut Self {
@@ -227,45 +360,108 @@ impl ConfigBuilder {
         self
     }
 
-    /// Limits the number of IP addresses from the same
-    /// /24 subnet in the kbuckets table. This is to mitigate eclipse attacks.
+    /// Among the peers of a query at the same log2-distance to the target, contact those with the
+    /// lowest measured round-trip time first.
+    pub fn latency_aware_queries(&mut self) -> &mut Self {
+        self.config.latency_aware_queries = true;
+        self
+    }
+
+    /// Limits the number of IP addresses from the same subnet in the kbuckets table. This is to
+    /// mitigate eclipse attacks.
     pub fn ip_limit(&mut self) -> &mut Self {
         self.config.ip_limit = true;
         self
     }
 
+    /// Limits the number of IP addresses from the same subnet in the kbuckets table with custom
+    /// prefix lengths and limits.
+    pub fn ip_limits(&mut self, ip_limits: IpLimits) -> &mut Self {
+        self.config.ip_limit = true;
+        self.config.ip_limits = ip_limits;
+        self
+    }
+
     /// Sets a maximum limit to the number of  incoming nodes (nodes that have dialed us) to exist per-bucket. This cannot be larger
-    /// than the bucket size (16). By default, half of every bucket (8 positions) is the largest number of nodes that we accept that dial us.
+    /// than the bucket size. By default, half of every bucket (8 positions) is the largest number of nodes that we accept that dial us.
     pub fn incoming_bucket_limit(&mut self, limit: usize) -> &mut Self {
         self.config.incoming_bucket_limit = limit;
         self
     }
 
     /// A filter used to decide whether to insert nodes into our local routing table. Nodes can be
-    /// excluded if they do not pass this filter.
-    pub fn table_filter(&mut self, filter: fn(&Enr) -> bool) -> &mut Self {
-        self.config.table_filter = filter;
+    /// excluded if they do not pass this filter. The closure may capture state, such as an
+    /// allow-list.
+    pub fn table_filter<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: Fn(&Enr) -> bool + Send + Sync + 'static,
+    {
+        self.config.table_filter = Arc::new(filter);
         self
     }
 
-    /// The time between pings to ensure connectivity amongst connected nodes.
+    /// A custom [`Filter`] applied to the whole routing table. The filter is given the ENR to be
+    /// inserted and the ENRs of all nodes currently in the table.
+    pub fn kbucket_table_filter(&mut self, filter: Box<dyn Filter<Enr>>) -> &mut Self {
+        self.config.kbucket_table_filter = Some(filter);
+        self
+    }
+
+    /// A custom [`Filter`] applied to each bucket. The filter is given the ENR to be inserted and
+    /// the ENRs of all nodes currently in the bucket.
+    pub fn kbucket_bucket_filter(&mut self, filter: Box<dyn Filter<Enr>>) -> &mut Self {
+        self.config.kbucket_bucket_filter = Some(filter);
+        self
+    }
+
+    /// The time between pings to ensure connectivity amongst connected nodes, once they have
+    /// been responsive for [`RevalidationConfig::stable_after`].
     pub fn ping_interval(&mut self, interval: Duration) -> &mut Self {
         self.config.ping_interval = interval;
         self
     }
 
+    /// How often the other routing table entries are pinged and when unresponsive entries are
+    /// evicted.
+    pub fn revalidation(&mut self, revalidation: RevalidationConfig) -> &mut Self {
+        self.config.revalidation = revalidation;
+        self
+    }
+
+    /// The number of connected peers pinged per second after the local ENR changes. Must be
+    /// non-zero.
+    pub fn enr_propagation_rate(&mut self, peers_per_second: usize) -> &mut Self {
+        self.config.enr_propagation_rate = peers_per_second;
+        self
+    }
+
     /// Disables reporting of discovered peers through the event stream.
     pub fn disable_report_discovered_peers(&mut self) -> &mut Self {
         self.config.report_discovered_peers = false;
         self
     }
 
+    /// Runs lookups without serving the network. See [`Config::client_only`].
+    pub fn client_only(&mut self) -> &mut Self {
+        self.config.client_only = true;
+        self
+    }
+
     /// A rate limiter for limiting inbound requests.
     pub fn filter_rate_limiter(&mut self, rate_limiter: Option<RateLimiter>) -> &mut Self {
         self.config.filter_rate_limiter = rate_limiter;
         self
     }
 
+    /// A rate limiter for limiting outbound packets.
+    pub fn outbound_rate_limiter(
+        &mut self,
+        rate_limiter: Option<OutboundRateLimiter>,
+    ) -> &mut Self {
+        self.config.outbound_rate_limiter = rate_limiter;
+        self
+    }
+
     /// If the filter is enabled, sets the maximum number of nodes per IP before banning
     /// the IP.
     pub fn filter_max_nodes_per_ip(&mut self, max_nodes_per_ip: Option<usize>) -> &mut Self {
@@ -281,12 +477,18 @@ impl ConfigBuilder {
     }
 
     /// A set of lists that permit or ban IP's or NodeIds from the server. See
-    /// `crate::PermitBanList`.
+    /// `crate::PermitBanList`. A saved list can be read with `crate::PermitBanList::load`.
     pub fn permit_ban_list(&mut self, list: PermitBanList) -> &mut Self {
         self.config.permit_ban_list = list;
         self
     }
 
+    /// Autosaves the permit/ban list to a store, such as a `crate::permit_ban::FileStore`.
+    pub fn permit_ban_store(&mut self, store: Arc<dyn PermitBanStore>) -> &mut Self {
+        self.config.permit_ban_store = Some(store);
+        self
+    }
+
     /// Set the default duration for which nodes are banned for. This timeouts are checked every 5 minutes,
     /// so the precision will be to the nearest 5 minutes. If set to `None`, bans from the filter
     /// will last indefinitely. Default is 1 hour.
@@ -302,40 +504,156 @@ impl ConfigBuilder {
         self
     }
 
+    /// Requests a UDP port mapping for the IPv4 listening socket from the gateway of the local
+    /// network and advertises the mapped socket in the local ENR.
+    pub fn port_mapping(&mut self, port_mapping: PortMappingConfig) -> &mut Self {
+        self.config.port_mapping = Some(port_mapping);
+        self
+    }
+
     pub fn build(&mut self) -> Config {
         // If an executor is not provided, assume a current tokio runtime is running.
         if self.config.executor.is_none() {
             self.config.executor = Some(Box::<crate::executor::TokioExecutor>::default());
         };
 
-        assert!(self.config.incoming_bucket_limit <= MAX_NODES_PER_BUCKET);
+        assert!(self.config.bucket_size > 0);
+        assert!(self.config.inbound_observation_window > Duration::ZERO);
+        assert!(self.config.enr_propagation_rate > 0);
+        assert!(self.config.incoming_bucket_limit <= self.config.bucket_size);
+        assert!(
+            self.config.max_nodes_response <= max_nodes_response_limit(self.config.bucket_size)
+        );
+        assert!(self.config.ip_limits.ipv4.prefix_len <= 32);
+        assert!(self.config.ip_limits.ipv6.prefix_len <= 128);
+        if let Some(bounds) = self.config.adaptive_request_timeout {
+            assert!(bounds.min_timeout <= bounds.max_timeout);
+        }
+        assert!(self.config.revalidation.validate().is_ok());
+        assert!(self.config.session_rekey.validate().is_ok());
 
         self.config.clone()
     }
 }
 
+/// A set of configuration parameters that can be changed on a running [`crate::Discv5`] service
+/// via [`crate::Discv5::reconfigure`]. Parameters set to `None` are left unchanged.
+///
+/// Changes only apply to requests, queries and bans started after the update has been applied.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigUpdate {
+    /// The request timeout for each UDP request.
+    pub request_timeout: Option<Duration>,
+    /// The number of retries for each UDP request.
+    pub request_retries: Option<u8>,
+    /// The timeout after which a `QueryPeer` in an ongoing query is marked unresponsive.
+    pub query_peer_timeout: Option<Duration>,
+    /// The timeout for an entire query.
+    pub query_timeout: Option<Duration>,
+    /// The number of peers to request in parallel in a single query.
+    pub query_parallelism: Option<usize>,
+    /// The time between pings to ensure connectivity amongst connected nodes.
+    pub ping_interval: Option<Duration>,
+    /// The rate limiter for inbound requests. Setting this to `Some(None)` removes the rate
+    /// limits.
+    pub filter_rate_limiter: Option<Option<RateLimiter>>,
+    /// The duration of bans enacted by the service and the packet filter. Setting this to
+    /// `Some(None)` makes new bans last indefinitely.
+    pub ban_duration: Option<Option<Duration>>,
+}
+
+impl ConfigUpdate {
+    /// Checks that the new parameters are usable by a running service.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let zero_duration = [
+            self.request_timeout,
+            self.query_peer_timeout,
+            self.query_timeout,
+            self.ping_interval,
+        ]
+        .contains(&Some(Duration::ZERO));
+        if zero_duration {
+            return Err("Timeouts and intervals must be non-zero");
+        }
+        if self.query_parallelism == Some(0) {
+            return Err("Query parallelism must be non-zero");
+        }
+        Ok(())
+    }
+
+    /// Applies the update to a [`Config`].
+    pub(crate) fn apply(&self, config: &mut Config) {
+        if let Some(request_timeout) = self.request_timeout {
+            config.request_timeout = request_timeout;
+        }
+        if let Some(request_retries) = self.request_retries {
+            config.request_retries = request_retries;
+        }
+        if let Some(query_peer_timeout) = self.query_peer_timeout {
+            config.query_peer_timeout = query_peer_timeout;
+        }
+        if let Some(query_timeout) = self.query_timeout {
+            config.query_timeout = query_timeout;
+        }
+        if let Some(query_parallelism) = self.query_parallelism {
+            config.query_parallelism = query_parallelism;
+        }
+        if let Some(ping_interval) = self.ping_interval {
+            config.ping_interval = ping_interval;
+        }
+        if let Some(rate_limiter) = &self.filter_rate_limiter {
+            config.filter_rate_limiter = rate_limiter.clone();
+        }
+        if let Some(ban_duration) = self.ban_duration {
+            config.ban_duration = ban_duration;
+        }
+    }
+}
+
 impl std::fmt::Debug for Config {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Config")
             .field("filter_enabled", &self.enable_packet_filter)
             .field("request_timeout", &self.request_timeout)
+            .field("adaptive_request_timeout", &self.adaptive_request_timeout)
             .field("vote_duration", &self.vote_duration)
+            .field(
+                "inbound_observation_window",
+                &self.inbound_observation_window,
+            )
             .field("query_timeout", &self.query_timeout)
             .field("query_peer_timeout", &self.query_peer_timeout)
             .field("request_retries", &self.request_retries)
             .field("session_timeout", &self.session_timeout)
             .field("session_cache_capacity", &self.session_cache_capacity)
+            .field("session_rekey", &self.session_rekey)
             .field("enr_update", &self.enr_update)
+            .field("bucket_size", &self.bucket_size)
             .field("query_parallelism", &self.query_parallelism)
+            .field("latency_aware_queries", &self.latency_aware_queries)
             .field("report_discovered_peers", &self.report_discovered_peers)
+            .field("client_only", &self.client_only)
             .field("ip_limit", &self.ip_limit)
+            .field(
+                "outbound_rate_limiter",
+                &self.outbound_rate_limiter.is_some(),
+            )
             .field("filter_max_nodes_per_ip", &self.filter_max_nodes_per_ip)
             .field("filter_max_bans_per_ip", &self.filter_max_bans_per_ip)
-            .field("ip_limit", &self.ip_limit)
+            .field("ip_limits", &self.ip_limits)
             .field("incoming_bucket_limit", &self.incoming_bucket_limit)
+            .field("kbucket_table_filter", &self.kbucket_table_filter.is_some())
+            .field(
+                "kbucket_bucket_filter",
+                &self.kbucket_bucket_filter.is_some(),
+            )
             .field("ping_interval", &self.ping_interval)
+            .field("revalidation", &self.revalidation)
+            .field("enr_propagation_rate", &self.enr_propagation_rate)
+            .field("permit_ban_store", &self.permit_ban_store.is_some())
             .field("ban_duration", &self.ban_duration)
             .field("listen_config", &self.listen_config)
+            .field("port_mapping", &self.port_mapping)
             .finish()
     }
 }
@@ -0,0 +1,739 @@
+//! A serializable representation of the [`Config`], suitable for loading from configuration
+//! files.
+//!
+//! Durations are given in milliseconds. Any parameter that is omitted takes its default value
+//! from the [`ConfigBuilder`].
+use super::{Config, ConfigBuilder};
+use crate::{
+    kbucket::{IpLimits, SubnetLimit, MAX_NODES_PER_BUCKET},
+    service::max_nodes_response_limit,
+    AdaptiveTimeoutConfig, ListenConfig, OutboundRateLimiter, OutboundRateLimiterBuilder,
+    RateLimiterBuilder, RekeyConfig, RevalidationConfig,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    time::Duration,
+};
+
+/// A serializable set of configuration parameters which can be built into a [`Config`].
+///
+/// Parameters that cannot be serialized, such as the executor and the table filters, must be set
+/// on the resulting [`Config`] directly.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// The sockets to listen on. Any number of IPv4 and IPv6 sockets can be given. Default:
+    /// `0.0.0.0:9000`.
+    pub listen_addresses: Vec<SocketAddr>,
+    /// The listen addresses advertised in the local ENR, at most one per IP version. Only used
+    /// when more than one socket of an IP version is listened on. Default: the first listen
+    /// address of each IP version.
+    pub enr_addresses: Vec<SocketAddr>,
+    /// Whether to enable the incoming packet filter.
+    pub enable_packet_filter: Option<bool>,
+    /// The request timeout for each UDP request.
+    pub request_timeout_ms: Option<u64>,
+    /// Adapts the request timeout to the measured round-trip time of each node, within the
+    /// given bounds.
+    pub adaptive_request_timeout: Option<AdaptiveTimeoutFile>,
+    /// The interval over which votes are remembered when determining our external IP.
+    pub vote_duration_ms: Option<u64>,
+    /// How long our external socket must be known without an unsolicited session before we are
+    /// considered unreachable from the outside.
+    pub inbound_observation_window_ms: Option<u64>,
+    /// The timeout after which a peer in an ongoing query is marked unresponsive.
+    pub query_peer_timeout_ms: Option<u64>,
+    /// The timeout for an entire query.
+    pub query_timeout_ms: Option<u64>,
+    /// The number of retries for each UDP request.
+    pub request_retries: Option<u8>,
+    /// The session timeout for each node.
+    pub session_timeout_ms: Option<u64>,
+    /// The maximum number of established sessions to maintain.
+    pub session_cache_capacity: Option<usize>,
+    /// When the keys of a session are renewed through a new handshake.
+    pub session_rekey: Option<SessionRekeyFile>,
+    /// Updates the local ENR IP and port based on PONG responses from peers.
+    pub enr_update: Option<bool>,
+    /// The maximum number of nodes we return to a find nodes request.
+    pub max_nodes_response: Option<usize>,
+    /// The maximum number of nodes in a bucket of the routing table.
+    pub bucket_size: Option<usize>,
+    /// The minimum number of peers who agree on an external IP port before updating the local
+    /// ENR. This must be at least 2.
+    pub enr_peer_update_min: Option<usize>,
+    /// The number of peers to request in parallel in a single query.
+    pub query_parallelism: Option<usize>,
+    /// Prefers peers with a lower round-trip time when iterating queries.
+    pub latency_aware_queries: Option<bool>,
+    /// Limits the number of IP addresses from the same subnet in the kbuckets table.
+    pub ip_limit: Option<bool>,
+    /// The IPv4 subnet limits. Setting either subnet limit enables `ip_limit`. If omitted, the
+    /// default limits are used.
+    pub ipv4_subnet_limit: Option<SubnetLimitFile>,
+    /// The IPv6 subnet limits.
+    pub ipv6_subnet_limit: Option<SubnetLimitFile>,
+    /// The maximum number of incoming nodes per bucket.
+    pub incoming_bucket_limit: Option<usize>,
+    /// The time between pings to ensure connectivity amongst connected nodes.
+    pub ping_interval_ms: Option<u64>,
+    /// The number of connected peers pinged per second after the local ENR changes.
+    pub enr_propagation_rate: Option<usize>,
+    /// How often other routing table entries are pinged and when they are evicted.
+    pub revalidation: Option<RevalidationFile>,
+    /// Reports all discovered ENRs when traversing the DHT to the event stream.
+    pub report_discovered_peers: Option<bool>,
+    /// Runs lookups without serving the network.
+    pub client_only: Option<bool>,
+    /// The rate limits for inbound requests. If omitted, the default rate limits are used.
+    pub rate_limit: Option<RateLimitFile>,
+    /// The budgets for outbound packets. If omitted, outbound packets are not limited.
+    pub outbound_rate_limit: Option<OutboundRateLimitFile>,
+    /// The maximum number of node-ids allowed per IP address before the IP address gets banned.
+    pub filter_max_nodes_per_ip: Option<usize>,
+    /// The maximum number of nodes that can be banned by a single IP before that IP gets banned.
+    pub filter_max_bans_per_ip: Option<usize>,
+    /// The duration of bans enacted by the service. A value of 0 makes bans last indefinitely.
+    pub ban_duration_ms: Option<u64>,
+}
+
+/// The serializable rate limits for inbound requests. See [`RateLimiterBuilder`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitFile {
+    /// The quota for all inbound requests.
+    pub total: QuotaFile,
+    /// The quota for inbound requests per node id.
+    #[serde(default)]
+    pub node: Option<QuotaFile>,
+    /// The quota for inbound requests per IP.
+    #[serde(default)]
+    pub ip: Option<QuotaFile>,
+    /// The quota for inbound requests per subnet.
+    #[serde(default)]
+    pub subnet: Option<QuotaFile>,
+    /// The prefix length of the IPv4 subnets. Default: 24.
+    #[serde(default)]
+    pub ipv4_subnet_prefix: Option<u8>,
+    /// The prefix length of the IPv6 subnets. Default: 48.
+    #[serde(default)]
+    pub ipv6_subnet_prefix: Option<u8>,
+    /// The quota for handshakes per IP.
+    #[serde(default)]
+    pub handshake: Option<QuotaFile>,
+    /// The quota for requested FINDNODE distances per node id.
+    #[serde(default)]
+    pub findnode: Option<QuotaFile>,
+    /// The quota for PINGs per node id.
+    #[serde(default)]
+    pub ping: Option<QuotaFile>,
+    /// The quota for TALKREQs of any protocol per node id.
+    #[serde(default)]
+    pub talk: Option<QuotaFile>,
+    /// The quotas for TALKREQs per node id, by protocol.
+    #[serde(default)]
+    pub talk_protocols: BTreeMap<String, QuotaFile>,
+}
+
+/// The serializable budgets for outbound packets. See [`OutboundRateLimiterBuilder`]. At least one
+/// budget must be given.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundRateLimitFile {
+    /// The budget in packets for all outbound packets.
+    pub total_packets: Option<QuotaFile>,
+    /// The budget in bytes for all outbound packets.
+    pub total_bytes: Option<QuotaFile>,
+    /// The budget in packets per destination node id.
+    pub node_packets: Option<QuotaFile>,
+    /// The budget in bytes per destination node id.
+    pub node_bytes: Option<QuotaFile>,
+    /// The budget in packets per destination IP.
+    pub ip_packets: Option<QuotaFile>,
+    /// The budget in bytes per destination IP.
+    pub ip_bytes: Option<QuotaFile>,
+}
+
+/// Allows `max_tokens` requests every `every_ms` milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaFile {
+    /// The maximum size of a burst of requests.
+    pub max_tokens: u64,
+    /// The period over which all tokens are replenished, in milliseconds.
+    pub every_ms: u64,
+}
+
+/// The number of nodes permitted per subnet. See [`SubnetLimit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubnetLimitFile {
+    /// The length of the prefix that defines a subnet.
+    pub prefix_len: u8,
+    /// The number of nodes permitted in the same subnet per table.
+    pub max_per_table: usize,
+    /// The number of nodes permitted in the same subnet per bucket.
+    pub max_per_bucket: usize,
+}
+
+/// The bounds of the adaptive request timeouts. See [`AdaptiveTimeoutConfig`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdaptiveTimeoutFile {
+    /// The shortest timeout applied to a request, in milliseconds.
+    pub min_timeout_ms: u64,
+    /// The longest timeout applied to a request, in milliseconds.
+    pub max_timeout_ms: u64,
+}
+
+/// The liveness checks of routing table entries. See [`RevalidationConfig`]. Any parameter that is
+/// omitted takes its default value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RevalidationFile {
+    /// The time between pings of a connected entry that is not yet stable, in milliseconds.
+    pub fresh_interval_ms: Option<u64>,
+    /// How long an entry must be responsive to become stable, in milliseconds.
+    pub stable_after_ms: Option<u64>,
+    /// The time between pings of a disconnected entry, in milliseconds.
+    pub disconnected_interval_ms: Option<u64>,
+    /// The number of requests in a row that an entry may fail before it is evicted.
+    pub max_failures: Option<u32>,
+    /// The percentage by which each interval is randomly shortened or lengthened.
+    pub jitter_percent: Option<u8>,
+}
+
+impl RevalidationFile {
+    fn build(&self) -> Result<RevalidationConfig, &'static str> {
+        let mut revalidation = RevalidationConfig::default();
+        if let Some(interval) = non_zero_duration(self.fresh_interval_ms)? {
+            revalidation.fresh_interval = interval;
+        }
+        if let Some(stable_after_ms) = self.stable_after_ms {
+            revalidation.stable_after = Duration::from_millis(stable_after_ms);
+        }
+        if let Some(interval) = non_zero_duration(self.disconnected_interval_ms)? {
+            revalidation.disconnected_interval = interval;
+        }
+        if let Some(max_failures) = self.max_failures {
+            revalidation.max_failures = max_failures;
+        }
+        if let Some(jitter_percent) = self.jitter_percent {
+            revalidation.jitter_percent = jitter_percent;
+        }
+        revalidation.validate()?;
+        Ok(revalidation)
+    }
+}
+
+/// When the keys of a session are renewed. See [`RekeyConfig`]. Any parameter that is omitted
+/// takes its default value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionRekeyFile {
+    /// The number of messages that may be sent under the same keys.
+    pub max_messages: Option<u32>,
+    /// How long the same keys may be used, in milliseconds.
+    pub max_age_ms: Option<u64>,
+}
+
+impl SessionRekeyFile {
+    fn build(&self) -> Result<RekeyConfig, &'static str> {
+        let mut rekey = RekeyConfig::default();
+        if let Some(max_messages) = self.max_messages {
+            rekey.max_messages = max_messages;
+        }
+        if let Some(max_age_ms) = self.max_age_ms {
+            rekey.max_age = Duration::from_millis(max_age_ms);
+        }
+        rekey.validate()?;
+        Ok(rekey)
+    }
+}
+
+impl From<SubnetLimitFile> for SubnetLimit {
+    fn from(file: SubnetLimitFile) -> Self {
+        SubnetLimit {
+            prefix_len: file.prefix_len,
+            max_per_table: file.max_per_table,
+            max_per_bucket: file.max_per_bucket,
+        }
+    }
+}
+
+impl ConfigFile {
+    /// Validates the parameters and builds a [`Config`].
+    pub fn build(&self) -> Result<Config, &'static str> {
+        let mut builder = ConfigBuilder::new(self.listen_config()?);
+
+        if self.enable_packet_filter == Some(true) {
+            builder.enable_packet_filter();
+        }
+        if let Some(timeout) = non_zero_duration(self.request_timeout_ms)? {
+            builder.request_timeout(timeout);
+        }
+        if let Some(duration) = non_zero_duration(self.vote_duration_ms)? {
+            builder.vote_duration(duration);
+        }
+        if let Some(window) = non_zero_duration(self.inbound_observation_window_ms)? {
+            builder.inbound_observation_window(window);
+        }
+        if let Some(timeout) = non_zero_duration(self.query_peer_timeout_ms)? {
+            builder.query_peer_timeout(timeout);
+        }
+        if let Some(timeout) = non_zero_duration(self.query_timeout_ms)? {
+            builder.query_timeout(timeout);
+        }
+        if let Some(retries) = self.request_retries {
+            builder.request_retries(retries);
+        }
+        if let Some(timeout) = non_zero_duration(self.session_timeout_ms)? {
+            builder.session_timeout(timeout);
+        }
+        if let Some(capacity) = self.session_cache_capacity {
+            if capacity == 0 {
+                return Err("session_cache_capacity must be non-zero");
+            }
+            builder.session_cache_capacity(capacity);
+        }
+        if let Some(rekey) = &self.session_rekey {
+            builder.session_rekey(rekey.build()?);
+        }
+        if self.enr_update == Some(false) {
+            builder.disable_enr_update();
+        }
+        let bucket_size = self.bucket_size.unwrap_or(MAX_NODES_PER_BUCKET);
+        if bucket_size == 0 {
+            return Err("bucket_size must be non-zero");
+        }
+        builder.bucket_size(bucket_size);
+        if let Some(max) = self.max_nodes_response {
+            if max == 0 {
+                return Err("max_nodes_response must be non-zero");
+            }
+            if max > max_nodes_response_limit(bucket_size) {
+                return Err(
+                    "max_nodes_response exceeds what the NODES responses for the bucket size hold",
+                );
+            }
+            builder.max_nodes_response(max);
+        }
+        if let Some(min) = self.enr_peer_update_min {
+            if min < 2 {
+                return Err("enr_peer_update_min must be at least 2");
+            }
+            builder.enr_peer_update_min(min);
+        }
+        if let Some(parallelism) = self.query_parallelism {
+            if parallelism == 0 {
+                return Err("query_parallelism must be non-zero");
+            }
+            builder.query_parallelism(parallelism);
+        }
+        if self.ip_limit == Some(true) {
+            builder.ip_limit();
+        }
+        if self.ipv4_subnet_limit.is_some() || self.ipv6_subnet_limit.is_some() {
+            let mut ip_limits = IpLimits::default();
+            if let Some(limit) = self.ipv4_subnet_limit {
+                if limit.prefix_len > 32 {
+                    return Err("The IPv4 subnet prefix length cannot be larger than 32");
+                }
+                ip_limits.ipv4 = limit.into();
+            }
+            if let Some(limit) = self.ipv6_subnet_limit {
+                if limit.prefix_len > 128 {
+                    return Err("The IPv6 subnet prefix length cannot be larger than 128");
+                }
+                ip_limits.ipv6 = limit.into();
+            }
+            builder.ip_limits(ip_limits);
+        }
+        if let Some(limit) = self.incoming_bucket_limit {
+            if limit > bucket_size {
+                return Err("incoming_bucket_limit cannot be larger than the bucket size");
+            }
+            builder.incoming_bucket_limit(limit);
+        }
+        if let Some(interval) = non_zero_duration(self.ping_interval_ms)? {
+            builder.ping_interval(interval);
+        }
+        if let Some(rate) = self.enr_propagation_rate {
+            if rate == 0 {
+                return Err("enr_propagation_rate must be non-zero");
+            }
+            builder.enr_propagation_rate(rate);
+        }
+        if let Some(revalidation) = &self.revalidation {
+            builder.revalidation(revalidation.build()?);
+        }
+        if self.report_discovered_peers == Some(false) {
+            builder.disable_report_discovered_peers();
+        }
+        if self.client_only == Some(true) {
+            builder.client_only();
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            builder.filter_rate_limiter(Some(rate_limit.build()?));
+        }
+        if let Some(outbound_rate_limit) = &self.outbound_rate_limit {
+            builder.outbound_rate_limiter(Some(outbound_rate_limit.build()?));
+        }
+        if let Some(bounds) = self.adaptive_request_timeout {
+            if bounds.min_timeout_ms > bounds.max_timeout_ms {
+                return Err("min_timeout_ms cannot be larger than max_timeout_ms");
+            }
+            builder.adaptive_request_timeout(AdaptiveTimeoutConfig {
+                min_timeout: Duration::from_millis(bounds.min_timeout_ms),
+                max_timeout: Duration::from_millis(bounds.max_timeout_ms),
+            });
+        }
+        if self.latency_aware_queries == Some(true) {
+            builder.latency_aware_queries();
+        }
+        if let Some(max) = self.filter_max_nodes_per_ip {
+            builder.filter_max_nodes_per_ip(Some(max));
+        }
+        if let Some(max) = self.filter_max_bans_per_ip {
+            builder.filter_max_bans_per_ip(Some(max));
+        }
+        if let Some(ban_duration) = self.ban_duration_ms {
+            let ban_duration = (ban_duration != 0).then(|| Duration::from_millis(ban_duration));
+            builder.ban_duration(ban_duration);
+        }
+
+        Ok(builder.build())
+    }
+
+    /// Builds the [`ListenConfig`] from the listen addresses.
+    fn listen_config(&self) -> Result<ListenConfig, &'static str> {
+        let mut ipv4: Vec<SocketAddrV4> = Vec::new();
+        let mut enr_ipv4: Option<SocketAddrV4> = None;
+        let mut enr_ipv6: Option<SocketAddrV6> = None;
+        for addr in &self.enr_addresses {
+            if !self.listen_addresses.contains(addr) {
+                return Err("ENR addresses must be listen addresses");
+            }
+            let duplicate = match addr {
+                SocketAddr::V4(addr) => enr_ipv4.replace(*addr).is_some(),
+                SocketAddr::V6(addr) => enr_ipv6.replace(*addr).is_some(),
+            };
+            if duplicate {
+                return Err("At most one ENR address can be given per IP version");
+            }
+        }
+        let mut ipv6: Vec<SocketAddrV6> = Vec::new();
+        for (index, addr) in self.listen_addresses.iter().enumerate() {
+            if self.listen_addresses[..index].contains(addr) {
+                return Err("Listen addresses must be unique");
+            }
+            match addr {
+                SocketAddr::V4(addr) => ipv4.push(*addr),
+                SocketAddr::V6(addr) => ipv6.push(*addr),
+            }
+        }
+        match (ipv4.as_slice(), ipv6.as_slice()) {
+            ([], []) => Ok(ListenConfig::default()),
+            ([] | [_], [] | [_]) => Ok(ListenConfig::from_two_sockets(
+                ipv4.first().copied(),
+                ipv6.first().copied(),
+            )),
+            _ => Ok(ListenConfig::Multi {
+                sockets: self.listen_addresses.clone(),
+                enr_ipv4,
+                enr_ipv6,
+            }),
+        }
+    }
+}
+
+impl RateLimitFile {
+    /// Builds the [`crate::RateLimiter`] from the given quotas.
+    pub fn build(&self) -> Result<crate::RateLimiter, &'static str> {
+        let mut builder =
+            RateLimiterBuilder::new().total_n_every(self.total.max_tokens, self.total.every()?);
+        if let Some(node) = &self.node {
+            builder = builder.node_n_every(node.max_tokens, node.every()?);
+        }
+        if let Some(ip) = &self.ip {
+            builder = builder.ip_n_every(ip.max_tokens, ip.every()?);
+        }
+        if let Some(subnet) = &self.subnet {
+            builder = builder.subnet_n_every(subnet.max_tokens, subnet.every()?);
+        }
+        if let Some(prefix_len) = self.ipv4_subnet_prefix {
+            builder = builder.ipv4_subnet_prefix(prefix_len);
+        }
+        if let Some(prefix_len) = self.ipv6_subnet_prefix {
+            builder = builder.ipv6_subnet_prefix(prefix_len);
+        }
+        if let Some(handshake) = &self.handshake {
+            builder = builder.handshake_n_every(handshake.max_tokens, handshake.every()?);
+        }
+        if let Some(findnode) = &self.findnode {
+            builder = builder.findnode_n_every(findnode.max_tokens, findnode.every()?);
+        }
+        if let Some(ping) = &self.ping {
+            builder = builder.ping_n_every(ping.max_tokens, ping.every()?);
+        }
+        if let Some(talk) = &self.talk {
+            builder = builder.talk_n_every(talk.max_tokens, talk.every()?);
+        }
+        for (protocol, quota) in &self.talk_protocols {
+            builder = builder.talk_protocol_n_every(
+                protocol.as_bytes(),
+                quota.max_tokens,
+                quota.every()?,
+            );
+        }
+        builder.build()
+    }
+}
+
+impl OutboundRateLimitFile {
+    /// Builds the [`OutboundRateLimiter`] from the given budgets.
+    pub fn build(&self) -> Result<OutboundRateLimiter, &'static str> {
+        let mut builder = OutboundRateLimiterBuilder::new();
+        if let Some(quota) = &self.total_packets {
+            builder = builder.total_packets_n_every(quota.max_tokens, quota.every()?);
+        }
+        if let Some(quota) = &self.total_bytes {
+            builder = builder.total_bytes_n_every(quota.max_tokens, quota.every()?);
+        }
+        if let Some(quota) = &self.node_packets {
+            builder = builder.node_packets_n_every(quota.max_tokens, quota.every()?);
+        }
+        if let Some(quota) = &self.node_bytes {
+            builder = builder.node_bytes_n_every(quota.max_tokens, quota.every()?);
+        }
+        if let Some(quota) = &self.ip_packets {
+            builder = builder.ip_packets_n_every(quota.max_tokens, quota.every()?);
+        }
+        if let Some(quota) = &self.ip_bytes {
+            builder = builder.ip_bytes_n_every(quota.max_tokens, quota.every()?);
+        }
+        builder.build()
+    }
+}
+
+impl QuotaFile {
+    fn every(&self) -> Result<Duration, &'static str> {
+        non_zero_duration(Some(self.every_ms)).map(|every| every.expect("Duration is given"))
+    }
+}
+
+/// Converts a number of milliseconds into a [`Duration`], rejecting zero durations.
+fn non_zero_duration(millis: Option<u64>) -> Result<Option<Duration>, &'static str> {
+    match millis {
+        Some(0) => Err("Durations must be non-zero"),
+        millis => Ok(millis.map(Duration::from_millis)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file_builds_config() {
+        let file: ConfigFile = serde_json::from_str(
+            r#"{
+                "listen_addresses": ["127.0.0.1:9001", "[::1]:9002"],
+                "request_timeout_ms": 2500,
+                "query_parallelism": 5,
+                "bucket_size": 8,
+                "enr_propagation_rate": 50,
+                "revalidation": { "fresh_interval_ms": 10000, "max_failures": 5 },
+                "ban_duration_ms": 0,
+                "adaptive_request_timeout": { "min_timeout_ms": 100, "max_timeout_ms": 4000 },
+                "latency_aware_queries": true,
+                "client_only": true,
+                "session_rekey": { "max_messages": 5000 },
+                "rate_limit": {
+                    "total": { "max_tokens": 20, "every_ms": 1000 },
+                    "subnet": { "max_tokens": 10, "every_ms": 1000 },
+                    "findnode": { "max_tokens": 12, "every_ms": 1000 },
+                    "talk_protocols": { "eth": { "max_tokens": 5, "every_ms": 1000 } }
+                },
+                "outbound_rate_limit": {
+                    "total_bytes": { "max_tokens": 65536, "every_ms": 1000 },
+                    "node_packets": { "max_tokens": 10, "every_ms": 1000 }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let config = file.build().unwrap();
+        assert_eq!(config.request_timeout, Duration::from_millis(2500));
+        assert_eq!(config.query_parallelism, 5);
+        assert_eq!(config.bucket_size, 8);
+        assert_eq!(config.incoming_bucket_limit, 8);
+        assert_eq!(config.enr_propagation_rate, 50);
+        assert_eq!(
+            config.revalidation,
+            RevalidationConfig {
+                fresh_interval: Duration::from_secs(10),
+                max_failures: 5,
+                ..Default::default()
+            }
+        );
+        assert_eq!(config.ban_duration, None);
+        assert!(config.filter_rate_limiter.is_some());
+        assert!(config.outbound_rate_limiter.is_some());
+        assert_eq!(
+            config.adaptive_request_timeout,
+            Some(AdaptiveTimeoutConfig {
+                min_timeout: Duration::from_millis(100),
+                max_timeout: Duration::from_secs(4),
+            })
+        );
+        assert!(config.latency_aware_queries);
+        assert!(config.client_only);
+        assert_eq!(
+            config.session_rekey,
+            RekeyConfig {
+                max_messages: 5000,
+                ..Default::default()
+            }
+        );
+        assert!(matches!(
+            config.listen_config,
+            ListenConfig::DualStack {
+                ipv4_port: 9001,
+                ipv6_port: 9002,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn config_file_builds_multi_listen_config() {
+        let listen_addresses: Vec<SocketAddr> = vec![
+            "127.0.0.1:9000".parse().unwrap(),
+            "127.0.0.2:9000".parse().unwrap(),
+            "[::1]:9000".parse().unwrap(),
+        ];
+        let file = ConfigFile {
+            listen_addresses: listen_addresses.clone(),
+            ..Default::default()
+        };
+        let config = file.build().unwrap();
+        assert_eq!(
+            config.listen_config,
+            ListenConfig::Multi {
+                sockets: listen_addresses.clone(),
+                enr_ipv4: None,
+                enr_ipv6: None,
+            }
+        );
+        assert_eq!(config.listen_config.ipv4(), "127.0.0.1:9000".parse().ok());
+
+        let file = ConfigFile {
+            listen_addresses: listen_addresses.clone(),
+            enr_addresses: vec![listen_addresses[1]],
+            ..Default::default()
+        };
+        let config = file.build().unwrap();
+        assert_eq!(config.listen_config.ipv4(), "127.0.0.2:9000".parse().ok());
+        assert_eq!(config.listen_config.ipv6(), "[::1]:9000".parse().ok());
+
+        // Advertised addresses must be listened on, and one per IP version.
+        let file = ConfigFile {
+            listen_addresses: listen_addresses.clone(),
+            enr_addresses: vec!["127.0.0.3:9000".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(file.build().is_err());
+        let file = ConfigFile {
+            listen_addresses: listen_addresses.clone(),
+            enr_addresses: listen_addresses[..2].to_vec(),
+            ..Default::default()
+        };
+        assert!(file.build().is_err());
+    }
+
+    #[test]
+    fn config_file_rejects_invalid_values() {
+        let file = ConfigFile {
+            enr_peer_update_min: Some(1),
+            ..Default::default()
+        };
+        assert!(file.build().is_err());
+
+        let file = ConfigFile {
+            enr_propagation_rate: Some(0),
+            ..Default::default()
+        };
+        assert!(file.build().is_err());
+
+        let file = ConfigFile {
+            bucket_size: Some(4),
+            incoming_bucket_limit: Some(5),
+            ..Default::default()
+        };
+        assert!(file.build().is_err());
+
+        let file = ConfigFile {
+            bucket_size: Some(4),
+            max_nodes_response: Some(100),
+            ..Default::default()
+        };
+        assert!(file.build().is_err());
+
+        let file = ConfigFile {
+            revalidation: Some(RevalidationFile {
+                jitter_percent: Some(100),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(file.build().is_err());
+
+        let file = ConfigFile {
+            session_rekey: Some(SessionRekeyFile {
+                max_age_ms: Some(0),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(file.build().is_err());
+
+        let file = ConfigFile {
+            listen_addresses: vec![
+                "127.0.0.1:9000".parse().unwrap(),
+                "127.0.0.1:9000".parse().unwrap(),
+            ],
+            ..Default::default()
+        };
+        assert!(file.build().is_err());
+
+        let file = ConfigFile {
+            ipv6_subnet_limit: Some(SubnetLimitFile {
+                prefix_len: 129,
+                max_per_table: 10,
+                max_per_bucket: 2,
+            }),
+            ..Default::default()
+        };
+        assert!(file.build().is_err());
+
+        let file = ConfigFile {
+            outbound_rate_limit: Some(OutboundRateLimitFile::default()),
+            ..Default::default()
+        };
+        assert!(file.build().is_err());
+
+        let file = ConfigFile {
+            adaptive_request_timeout: Some(AdaptiveTimeoutFile {
+                min_timeout_ms: 500,
+                max_timeout_ms: 100,
+            }),
+            ..Default::default()
+        };
+        assert!(file.build().is_err());
+
+        assert!(serde_json::from_str::<ConfigFile>(r#"{ "unknown": 1 }"#).is_err());
+    }
+}
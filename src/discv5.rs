@@ -14,14 +14,16 @@
 
 use crate::{
     error::{Error, QueryError, RequestError},
+    handler::SessionInfo,
     kbucket::{
         self, ConnectionDirection, ConnectionState, FailureReason, InsertResult, KBucketsTable,
-        NodeStatus, UpdateResult,
+        NodeStats, NodeStatus, TableDiff, TableSnapshot, UpdateResult,
     },
     node_info::NodeContact,
     packet::ProtocolIdentity,
-    service::{QueryKind, Service, ServiceRequest, TalkRequest},
-    Config, DefaultProtocolId, Enr, IpMode,
+    permit_ban::{Ban, BanTarget, IpNetwork, PermitBanList},
+    service::{ActiveQuery, NatStatus, QueryKind, Service, ServiceRequest, TalkRequest},
+    Config, ConfigUpdate, DefaultProtocolId, Enr, IpMode, ListenConfig, TableFilter,
 };
 use enr::{CombinedKey, EnrError, EnrKey, NodeId};
 use parking_lot::RwLock;
@@ -69,10 +71,23 @@ pub enum Event {
     },
     /// A new session has been established with a node.
     SessionEstablished(Enr, SocketAddr),
+    /// The IP or port of a node has changed and its session has moved to the new address, which
+    /// answered a PING sent under the session.
+    SessionRoamed {
+        node_id: NodeId,
+        from: SocketAddr,
+        to: SocketAddr,
+    },
     /// Our local ENR IP address has been updated.
     SocketUpdated(SocketAddr),
+    /// The service is now listening on the sockets of this [`ListenConfig`]. See
+    /// [`Discv5::rebind`].
+    ListenSocketsUpdated(ListenConfig),
     /// A node has initiated a talk request.
     TalkRequest(TalkRequest),
+    /// The NAT classification or inbound reachability of the local node has changed. See
+    /// [`Discv5::nat_status`].
+    NatStatusUpdated(NatStatus),
 }
 
 /// The main Discv5 Service struct. This provides the user-level API for performing queries and
@@ -85,9 +100,16 @@ where
     /// The channel to make requests from the main service.
     service_channel: Option<mpsc::Sender<ServiceRequest>>,
     /// The exit channel to shutdown the underlying service.
-    service_exit: Option<oneshot::Sender<()>>,
+    service_exit: Option<oneshot::Sender<bool>>,
+    /// Resolves once the underlying service and all of its tasks have exited.
+    service_exited: Option<oneshot::Receiver<()>>,
     /// The routing table of the discv5 service.
     kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+    /// The filter deciding which ENRs may enter the routing table, shared with the service so
+    /// that it can be replaced at runtime.
+    table_filter: Arc<RwLock<TableFilter>>,
+    /// The reachability of the local node, updated by the service.
+    nat_status: Arc<RwLock<NatStatus>>,
     /// The local ENR of the server.
     local_enr: Arc<RwLock<Enr>>,
     /// The key associated with the local ENR, required for updating the local ENR.
@@ -100,7 +122,7 @@ where
 
 impl<P: ProtocolIdentity> Discv5<P> {
     pub fn new(
-        local_enr: Enr,
+        mut local_enr: Enr,
         enr_key: CombinedKey,
         mut config: Config,
     ) -> Result<Self, &'static str> {
@@ -109,28 +131,45 @@ impl<P: ProtocolIdentity> Discv5<P> {
             return Err("Provided keypair does not match the provided ENR");
         }
 
+        // Peers only add contactable nodes to their routing tables.
+        if config.client_only && (local_enr.udp4().is_some() || local_enr.udp6().is_some()) {
+            local_enr
+                .remove_insert(
+                    [b"udp".as_slice(), b"udp6".as_slice()].iter(),
+                    std::iter::empty::<(&[u8], &[u8])>(),
+                    &enr_key,
+                )
+                .map_err(|_| "Failed to remove the UDP sockets from the ENR")?;
+        }
+
         // If an executor is not provided, assume a current tokio runtime is running. If not panic.
         if config.executor.is_none() {
             config.executor = Some(Box::<crate::executor::TokioExecutor>::default());
         };
 
-        // NOTE: Currently we don't expose custom filter support in the configuration. Users can
-        // optionally use the IP filter via the ip_limit configuration parameter. In the future, we
-        // may expose this functionality to the users if there is demand for it.
-        let (table_filter, bucket_filter) = if config.ip_limit {
+        // Custom filters from the configuration are combined with the IP filters, if the
+        // ip_limit configuration parameter is set.
+        let (ip_table_filter, ip_bucket_filter) = if config.ip_limit {
             (
-                Some(Box::new(kbucket::IpTableFilter) as Box<dyn kbucket::Filter<Enr>>),
-                Some(Box::new(kbucket::IpBucketFilter) as Box<dyn kbucket::Filter<Enr>>),
+                Some(Box::new(kbucket::IpTableFilter::new(config.ip_limits))
+                    as Box<dyn kbucket::Filter<Enr>>),
+                Some(Box::new(kbucket::IpBucketFilter::new(config.ip_limits))
+                    as Box<dyn kbucket::Filter<Enr>>),
             )
         } else {
             (None, None)
         };
+        let table_filter =
+            kbucket::AllFilter::join(ip_table_filter, config.kbucket_table_filter.clone());
+        let bucket_filter =
+            kbucket::AllFilter::join(ip_bucket_filter, config.kbucket_bucket_filter.clone());
 
         let local_enr = Arc::new(RwLock::new(local_enr));
         let enr_key = Arc::new(RwLock::new(enr_key));
         let kbuckets = Arc::new(RwLock::new(KBucketsTable::new(
             local_enr.read().node_id().into(),
             Duration::from_secs(60),
+            config.bucket_size,
             config.incoming_bucket_limit,
             table_filter,
             bucket_filter,
@@ -140,12 +179,16 @@ impl<P: ProtocolIdentity> Discv5<P> {
         *PERMIT_BAN_LIST.write() = config.permit_ban_list.clone();
 
         let ip_mode = IpMode::new_from_listen_config(&config.listen_config);
+        let table_filter = Arc::new(RwLock::new(config.table_filter.clone()));
 
         Ok(Discv5 {
             config,
             service_channel: None,
             service_exit: None,
+            service_exited: None,
             kbuckets,
+            table_filter,
+            nat_status: Default::default(),
             local_enr,
             enr_key,
             ip_mode,
@@ -161,30 +204,87 @@ impl<P: ProtocolIdentity> Discv5<P> {
         }
 
         // create the main service
-        let (service_exit, service_channel) = Service::spawn::<P>(
+        let (service_exit, service_exited, service_channel) = Service::spawn::<P>(
             self.local_enr.clone(),
             self.enr_key.clone(),
             self.kbuckets.clone(),
+            self.table_filter.clone(),
+            self.nat_status.clone(),
             self.config.clone(),
         )
         .await?;
         self.service_exit = Some(service_exit);
+        self.service_exited = Some(service_exited);
         self.service_channel = Some(service_channel);
         Ok(())
     }
 
-    /// Terminates the service.
-    pub fn shutdown(&mut self) {
+    /// Terminates the service and waits for all of its tasks to exit.
+    ///
+    /// Outstanding requests and queries fail with [`RequestError::ServiceShutdown`] and
+    /// [`QueryError::ServiceShutdown`] respectively. If `send_final_responses` is set, responses
+    /// that were queued before the shutdown, such as answers to [`TalkRequest`]s, are sent before
+    /// the sockets are closed. Once this returns, the listening sockets are released and the
+    /// service can be started again with [`Discv5::start`]. The routing table is kept, with all
+    /// nodes marked as disconnected.
+    pub async fn shutdown(&mut self, send_final_responses: bool) {
+        self.service_channel = None;
         if let Some(exit) = self.service_exit.take() {
-            if exit.send(()).is_err() {
+            if exit.send(send_final_responses).is_err() {
                 debug!("Discv5 service already shutdown");
             }
-            self.service_channel = None;
+            if let Some(exited) = self.service_exited.take() {
+                let _ = exited.await;
+            }
         } else {
             debug!("Service is already shutdown");
         }
     }
 
+    /// Applies changes to the configuration without restarting the service. Only the parameters
+    /// in [`ConfigUpdate`] can be changed at runtime; these are kept if the service is restarted.
+    /// If the service is not running, the changes are applied when it starts.
+    pub fn reconfigure(
+        &mut self,
+        update: ConfigUpdate,
+    ) -> impl Future<Output = Result<(), Error>> + 'static {
+        let result = update.validate().map_err(Error::Custom).map(|_| {
+            update.apply(&mut self.config);
+            self.service_channel.clone()
+        });
+
+        async move {
+            if let Some(channel) = result? {
+                channel
+                    .send(ServiceRequest::Reconfigure(update))
+                    .await
+                    .map_err(|_| Error::ServiceChannelClosed)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Moves the running service to a new set of listening sockets. Sockets whose address is
+    /// unchanged are kept, sessions and queries continue over the new sockets and the UDP sockets
+    /// of the local ENR are updated. On success, an [`Event::ListenSocketsUpdated`] is emitted.
+    /// If binding fails, the previous sockets remain in use.
+    pub async fn rebind(&mut self, listen_config: ListenConfig) -> Result<(), Error> {
+        let channel = self.clone_channel()?;
+        let (callback_send, callback_recv) = oneshot::channel();
+
+        channel
+            .send(ServiceRequest::Rebind(listen_config.clone(), callback_send))
+            .await
+            .map_err(|_| Error::ServiceChannelClosed)?;
+        callback_recv
+            .await
+            .map_err(|_| Error::ServiceChannelClosed)??;
+
+        self.ip_mode = IpMode::new_from_listen_config(&listen_config);
+        self.config.listen_config = listen_config;
+        Ok(())
+    }
+
     /// Adds a known ENR of a peer participating in Service to the
     /// routing table.
     ///
@@ -199,7 +299,7 @@ impl<P: ProtocolIdentity> Discv5<P> {
             return Err("ENR has no compatible UDP socket to connect to");
         }
 
-        if !(self.config.table_filter)(&enr) {
+        if !(self.table_filter.read())(&enr) {
             warn!("ENR attempted to be added which is banned by the configuration table filter.");
             return Err("ENR banned by table filter");
         }
@@ -228,6 +328,39 @@ impl<P: ProtocolIdentity> Discv5<P> {
         }
     }
 
+    /// Replaces the filter used to decide whether to insert nodes into the routing table. Nodes
+    /// already in the table are not re-checked against the new filter.
+    pub fn set_table_filter<F>(&self, filter: F)
+    where
+        F: Fn(&Enr) -> bool + Send + Sync + 'static,
+    {
+        *self.table_filter.write() = Arc::new(filter);
+    }
+
+    /// Replaces the custom [`kbucket::Filter`] applied to the whole routing table. If the
+    /// `ip_limit` configuration parameter is set, the IP filter remains applied.
+    pub fn set_kbucket_table_filter(&self, filter: Option<Box<dyn kbucket::Filter<Enr>>>) {
+        let ip_filter = self.config.ip_limit.then(|| {
+            Box::new(kbucket::IpTableFilter::new(self.config.ip_limits))
+                as Box<dyn kbucket::Filter<Enr>>
+        });
+        self.kbuckets
+            .write()
+            .set_table_filter(kbucket::AllFilter::join(ip_filter, filter));
+    }
+
+    /// Replaces the custom [`kbucket::Filter`] applied to each bucket. If the `ip_limit`
+    /// configuration parameter is set, the IP filter remains applied.
+    pub fn set_kbucket_bucket_filter(&self, filter: Option<Box<dyn kbucket::Filter<Enr>>>) {
+        let ip_filter = self.config.ip_limit.then(|| {
+            Box::new(kbucket::IpBucketFilter::new(self.config.ip_limits))
+                as Box<dyn kbucket::Filter<Enr>>
+        });
+        self.kbuckets
+            .write()
+            .set_bucket_filter(kbucket::AllFilter::join(ip_filter, filter));
+    }
+
     /// Removes a `node_id` from the routing table.
     ///
     /// This allows applications, for whatever reason, to remove nodes from the local routing
@@ -277,6 +410,13 @@ impl<P: ProtocolIdentity> Discv5<P> {
         )
     }
 
+    /// Returns the NAT classification and inbound reachability of the local node, as derived from
+    /// the sockets peers report in PONG responses and the sessions other nodes initiate. Changes
+    /// are reported with [`Event::NatStatusUpdated`].
+    pub fn nat_status(&self) -> NatStatus {
+        *self.nat_status.read()
+    }
+
     /// Returns the number of connected peers that exist in the routing table.
     pub fn connected_peers(&self) -> usize {
         self.kbuckets
@@ -348,16 +488,76 @@ impl<P: ProtocolIdentity> Discv5<P> {
         }
     }
 
+    /// Lists the sessions held with peers.
+    pub fn sessions(
+        &self,
+    ) -> impl Future<Output = Result<Vec<SessionInfo>, RequestError>> + 'static {
+        let (callback_send, mut callback_recv) = mpsc::channel(1);
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| RequestError::ServiceNotStarted)?;
+
+            let event = ServiceRequest::Sessions(callback_send);
+
+            // send the request
+            channel
+                .send(event)
+                .await
+                .map_err(|_| RequestError::ChannelFailed("Service channel closed".into()))?;
+            // await the response
+            callback_recv
+                .recv()
+                .await
+                .ok_or_else(|| RequestError::ChannelFailed("Handler channel closed".into()))
+        }
+    }
+
+    /// Lists the queries in progress.
+    pub fn active_queries(
+        &self,
+    ) -> impl Future<Output = Result<Vec<ActiveQuery>, RequestError>> + 'static {
+        let (callback_send, callback_recv) = oneshot::channel();
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel.map_err(|_| RequestError::ServiceNotStarted)?;
+
+            let event = ServiceRequest::ActiveQueries(callback_send);
+
+            // send the request
+            channel
+                .send(event)
+                .await
+                .map_err(|_| RequestError::ChannelFailed("Service channel closed".into()))?;
+            // await the response
+            callback_recv
+                .await
+                .map_err(|e| RequestError::ChannelFailed(e.to_string()))
+        }
+    }
+
     /// Bans a node from the server. This will remove the node from the routing table if it exists
     /// and block all incoming packets from the node until the timeout specified. Setting the
     /// timeout to `None` creates a permanent ban.
     pub fn ban_node(&self, node_id: &NodeId, duration_of_ban: Option<Duration>) {
+        self.ban_node_with_reason(node_id, duration_of_ban, None);
+    }
+
+    /// Bans a node as [`Discv5::ban_node`], recording why it was banned. The reason is listed by
+    /// [`Discv5::bans`].
+    pub fn ban_node_with_reason(
+        &self,
+        node_id: &NodeId,
+        duration_of_ban: Option<Duration>,
+        reason: Option<String>,
+    ) {
         let time_to_unban = duration_of_ban.map(|v| Instant::now() + v);
         self.remove_node(node_id);
         PERMIT_BAN_LIST
             .write()
             .ban_nodes
-            .insert(*node_id, time_to_unban);
+            .insert(*node_id, Ban::by_user(time_to_unban, reason));
     }
 
     /// Removes a banned node from the banned list.
@@ -375,73 +575,104 @@ impl<P: ProtocolIdentity> Discv5<P> {
         PERMIT_BAN_LIST.write().permit_nodes.remove(node_id);
     }
 
-    /// Bans an IP from the server.  This will block all incoming packets from the IP.
-    pub fn ban_ip(&self, ip: std::net::IpAddr, duration_of_ban: Option<Duration>) {
+    /// Bans an IP, or a range of IPs, from the server. This will block all incoming packets from
+    /// the range.
+    pub fn ban_ip(&self, ip: impl Into<IpNetwork>, duration_of_ban: Option<Duration>) {
+        self.ban_ip_with_reason(ip, duration_of_ban, None);
+    }
+
+    /// Bans an IP range as [`Discv5::ban_ip`], recording why it was banned. The reason is listed
+    /// by [`Discv5::bans`].
+    pub fn ban_ip_with_reason(
+        &self,
+        ip: impl Into<IpNetwork>,
+        duration_of_ban: Option<Duration>,
+        reason: Option<String>,
+    ) {
         let time_to_unban = duration_of_ban.map(|v| Instant::now() + v);
-        PERMIT_BAN_LIST.write().ban_ips.insert(ip, time_to_unban);
+        PERMIT_BAN_LIST
+            .write()
+            .ban_ips
+            .insert(ip.into(), Ban::by_user(time_to_unban, reason));
+    }
+
+    /// Removes a banned IP range from the banned list. The range must match a banned range
+    /// exactly; ranges that overlap it stay banned.
+    pub fn ban_ip_remove(&self, ip: impl Into<IpNetwork>) {
+        PERMIT_BAN_LIST.write().ban_ips.remove(&ip.into());
+    }
+
+    /// Permits an IP, or a range of IPs, allowing all packets from the range to bypass the packet
+    /// filter.
+    pub fn permit_ip(&self, ip: impl Into<IpNetwork>) {
+        PERMIT_BAN_LIST.write().permit_ips.add(ip.into());
+    }
+
+    /// Removes an IP range from the permit list.
+    pub fn permit_ip_remove(&self, ip: impl Into<IpNetwork>) {
+        PERMIT_BAN_LIST.write().permit_ips.remove(&ip.into());
     }
 
-    /// Removes a banned IP from the banned list.
-    pub fn ban_ip_remove(&self, ip: &std::net::IpAddr) {
-        PERMIT_BAN_LIST.write().ban_ips.remove(ip);
+    /// The executor the tasks of the server are spawned on.
+    #[cfg(feature = "admin")]
+    pub(crate) fn executor(&self) -> Box<dyn crate::Executor + Send + Sync> {
+        self.config.executor.clone().expect("Executor must exist")
     }
 
-    /// Permits an IP, allowing the all packets from the IP to bypass the packet filter.
-    pub fn permit_ip(&self, ip: std::net::IpAddr) {
-        PERMIT_BAN_LIST.write().permit_ips.insert(ip);
+    /// A snapshot of the permit/ban list, which can be saved with [`PermitBanList::export`].
+    pub fn permit_ban_list(&self) -> PermitBanList {
+        PERMIT_BAN_LIST.read().clone()
     }
 
-    /// Removes an IP from the permit list.
-    pub fn permit_ip_remove(&self, ip: &std::net::IpAddr) {
-        PERMIT_BAN_LIST.write().permit_ips.remove(ip);
+    /// Lists the nodes and IP ranges that are currently banned, with when, why and by whom.
+    pub fn bans(&self) -> Vec<(BanTarget, Ban)> {
+        PERMIT_BAN_LIST
+            .read()
+            .bans()
+            .map(|(target, ban)| (target, ban.clone()))
+            .collect()
     }
 
-    /// Updates the local ENR TCP/UDP socket.
+    /// Updates the local ENR TCP/UDP socket. Connected peers are informed of the change.
+    /// Client-only nodes never advertise a UDP socket, see [`Config::client_only`].
     pub fn update_local_enr_socket(&self, socket_addr: SocketAddr, is_tcp: bool) -> bool {
-        let mut local_enr = self.local_enr.write();
-        match (is_tcp, socket_addr) {
-            (false, SocketAddr::V4(specific_socket_addr)) => {
-                if Some(specific_socket_addr) != local_enr.udp4_socket() {
-                    return local_enr
-                        .set_udp_socket(socket_addr, &self.enr_key.read())
-                        .is_ok();
-                }
-            }
-            (true, SocketAddr::V4(specific_socket_addr)) => {
-                if Some(specific_socket_addr) != local_enr.tcp4_socket() {
-                    return local_enr
-                        .set_tcp_socket(socket_addr, &self.enr_key.read())
-                        .is_ok();
-                }
-            }
-            (false, SocketAddr::V6(specific_socket_addr)) => {
-                if Some(specific_socket_addr) != local_enr.udp6_socket() {
-                    return local_enr
-                        .set_udp_socket(socket_addr, &self.enr_key.read())
-                        .is_ok();
-                }
-            }
-            (true, SocketAddr::V6(specific_socket_addr)) => {
-                if Some(specific_socket_addr) != local_enr.tcp6_socket() {
-                    return local_enr
-                        .set_tcp_socket(socket_addr, &self.enr_key.read())
-                        .is_ok();
-                }
-            }
+        if self.config.client_only && !is_tcp {
+            return false;
         }
-        false
+        let updated = update_enr_socket(&self.local_enr, &self.enr_key, socket_addr, is_tcp);
+        if updated {
+            self.local_enr_updated();
+        }
+        updated
     }
 
-    /// Allows application layer to insert an arbitrary field into the local ENR.
+    /// Allows application layer to insert an arbitrary field into the local ENR. Connected peers
+    /// are informed of the change.
     pub fn enr_insert<T: rlp::Encodable>(
         &self,
         key: &str,
         value: &T,
     ) -> Result<Option<Vec<u8>>, EnrError> {
-        self.local_enr
+        let previous = self
+            .local_enr
             .write()
             .insert(key, value, &self.enr_key.read())
-            .map(|v| v.map(|v| v.to_vec()))
+            .map(|v| v.map(|v| v.to_vec()))?;
+        self.local_enr_updated();
+        Ok(previous)
+    }
+
+    /// Asks the service, if running, to spread the changed local ENR to connected peers. If the
+    /// service channel is full, the service notices the change once it handles the queued
+    /// requests.
+    fn local_enr_updated(&self) {
+        if let Some(channel) = &self.service_channel {
+            if let Err(mpsc::error::TrySendError::Full(_)) =
+                channel.try_send(ServiceRequest::LocalEnrUpdated)
+            {
+                debug!("Service channel full, the local ENR update is already pending");
+            }
+        }
     }
 
     /// Returns an iterator over all ENR node IDs of nodes currently contained in the routing table.
@@ -477,6 +708,60 @@ impl<P: ProtocolIdentity> Discv5<P> {
             .collect()
     }
 
+    /// Returns the request statistics of a node in the routing table.
+    pub fn node_stats(&self, node_id: &NodeId) -> Option<NodeStats> {
+        let key = kbucket::Key::from(*node_id);
+        self.kbuckets.read().node_stats(&key).cloned()
+    }
+
+    /// Returns the reputation score of a node in the routing table, see [`NodeStats::score`].
+    pub fn node_score(&self, node_id: &NodeId) -> Option<f64> {
+        let key = kbucket::Key::from(*node_id);
+        self.kbuckets.read().node_stats(&key).map(NodeStats::score)
+    }
+
+    /// Returns the reputation scores of all the nodes in the routing table.
+    pub fn table_entries_score(&self) -> Vec<(NodeId, f64)> {
+        self.kbuckets
+            .write()
+            .iter()
+            .map(|entry| (*entry.node.key.preimage(), entry.node.stats.score()))
+            .collect()
+    }
+
+    /// Returns a structured view of the routing table, which can be serialized with the `serde`
+    /// feature.
+    pub fn table_snapshot(&self) -> TableSnapshot {
+        self.kbuckets.read().snapshot()
+    }
+
+    /// Subscribes to the changes of the routing table. The first batch lists every entry and
+    /// pending node of the table as added, and later batches hold the changes since the previous
+    /// one. The changes are found by polling snapshots of the table every second, so a change
+    /// that is undone within one poll is never reported.
+    ///
+    /// A subscriber which lets too many batches queue up is disconnected, which closes its
+    /// receiver. It can subscribe again to start over from the current table.
+    pub fn table_diffs(
+        &self,
+    ) -> impl Future<Output = Result<mpsc::Receiver<Vec<TableDiff>>, Error>> + 'static {
+        let channel = self.clone_channel();
+
+        async move {
+            let channel = channel?;
+
+            let (callback_send, callback_recv) = oneshot::channel();
+
+            let event = ServiceRequest::TableDiffs(callback_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| Error::ServiceChannelClosed)?;
+
+            callback_recv.await.map_err(|_| Error::ServiceChannelClosed)
+        }
+    }
+
     /// Takes a closure parameterized by type `Arc<RwLock<KBucketsTable<NodeId, Enr>>>` as
     /// parameter. Caution: caller is responsible of dropping a lock taken on the kbuckets. For
     /// example, a read lock can be taken on the kbuckets to optimistically view the current keys
@@ -650,7 +935,47 @@ impl<P: ProtocolIdentity> Discv5<P> {
 
             callback_recv
                 .await
-                .map_err(|e| QueryError::ChannelFailed(e.to_string()))
+                .map_err(|e| QueryError::ChannelFailed(e.to_string()))?
+        }
+    }
+
+    /// Resolves a node to its latest ENR.
+    ///
+    /// The lookup starts from the routing table and iterates towards the node until the node
+    /// itself answers a `FIND_NODE` at distance 0. The ENR with the highest sequence number seen
+    /// is returned, which is at least as recent as the one the node returned for itself. If no
+    /// fresher ENR is found, the ENR in the routing table is returned. If the node is not in the
+    /// routing table and cannot be found, [`QueryError::NodeNotFound`] is returned.
+    pub fn resolve(
+        &self,
+        node_id: NodeId,
+    ) -> impl Future<Output = Result<Enr, QueryError>> + 'static {
+        let channel = self.clone_channel();
+        let known_enr = self.find_enr(&node_id);
+
+        async move {
+            let channel = channel.map_err(|_| QueryError::ServiceNotStarted)?;
+            let (callback_send, callback_recv) = oneshot::channel();
+
+            let query_kind = QueryKind::Resolve {
+                target_node: node_id,
+            };
+
+            let event = ServiceRequest::StartQuery(query_kind, callback_send);
+            channel
+                .send(event)
+                .await
+                .map_err(|_| QueryError::ChannelFailed("Service channel closed".into()))?;
+
+            let resolved = callback_recv
+                .await
+                .map_err(|e| QueryError::ChannelFailed(e.to_string()))??
+                .pop();
+            match (resolved, known_enr) {
+                (Some(resolved), Some(known)) if known.seq() > resolved.seq() => Ok(known),
+                (Some(resolved), _) => Ok(resolved),
+                (None, known) => known.ok_or(QueryError::NodeNotFound),
+            }
         }
     }
 
@@ -697,7 +1022,7 @@ impl<P: ProtocolIdentity> Discv5<P> {
 
             callback_recv
                 .await
-                .map_err(|e| QueryError::ChannelFailed(e.to_string()))
+                .map_err(|e| QueryError::ChannelFailed(e.to_string()))?
         }
     }
 
@@ -733,7 +1058,51 @@ impl<P: ProtocolIdentity> Discv5<P> {
 }
 
 impl<P: ProtocolIdentity> Drop for Discv5<P> {
+    // signal the service to exit, without waiting for it
     fn drop(&mut self) {
-        self.shutdown();
+        if let Some(exit) = self.service_exit.take() {
+            let _ = exit.send(false);
+        }
+    }
+}
+
+/// Updates the TCP/UDP socket of the `local_enr`. Returns whether the ENR changed.
+pub(crate) fn update_enr_socket(
+    local_enr: &RwLock<Enr>,
+    enr_key: &RwLock<CombinedKey>,
+    socket_addr: SocketAddr,
+    is_tcp: bool,
+) -> bool {
+    let mut local_enr = local_enr.write();
+    match (is_tcp, socket_addr) {
+        (false, SocketAddr::V4(specific_socket_addr)) => {
+            if Some(specific_socket_addr) != local_enr.udp4_socket() {
+                return local_enr
+                    .set_udp_socket(socket_addr, &enr_key.read())
+                    .is_ok();
+            }
+        }
+        (true, SocketAddr::V4(specific_socket_addr)) => {
+            if Some(specific_socket_addr) != local_enr.tcp4_socket() {
+                return local_enr
+                    .set_tcp_socket(socket_addr, &enr_key.read())
+                    .is_ok();
+            }
+        }
+        (false, SocketAddr::V6(specific_socket_addr)) => {
+            if Some(specific_socket_addr) != local_enr.udp6_socket() {
+                return local_enr
+                    .set_udp_socket(socket_addr, &enr_key.read())
+                    .is_ok();
+            }
+        }
+        (true, SocketAddr::V6(specific_socket_addr)) => {
+            if Some(specific_socket_addr) != local_enr.tcp6_socket() {
+                return local_enr
+                    .set_tcp_socket(socket_addr, &enr_key.read())
+                    .is_ok();
+            }
+        }
     }
+    false
 }
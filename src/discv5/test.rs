@@ -5,7 +5,7 @@ use enr::{k256, CombinedKey, Enr, EnrKey, NodeId};
 use rand_core::{RngCore, SeedableRng};
 use std::{
     collections::HashMap,
-    net::{Ipv4Addr, Ipv6Addr},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4},
 };
 
 fn init() {
@@ -659,6 +659,74 @@ async fn test_findnode_query_with_target() {
         .any(|enr| enr.node_id() == target_node_id));
 }
 
+/// Resolve a node that is only known to a peer, by an older ENR than its current one.
+#[tokio::test]
+async fn test_resolve() {
+    init();
+    let mut nodes = build_nodes(3, 10050).await;
+    let target = nodes.pop().unwrap();
+    let peer = nodes.pop().unwrap();
+    let resolver = nodes.pop().unwrap();
+
+    peer.add_enr(target.local_enr()).unwrap();
+    resolver.add_enr(peer.local_enr()).unwrap();
+    target.enr_insert("test", &b"updated".to_vec()).unwrap();
+
+    let resolved = resolver
+        .resolve(target.local_enr().node_id())
+        .await
+        .unwrap();
+    assert_eq!(resolved, target.local_enr());
+
+    let unknown = resolver.resolve(NodeId::random()).await;
+    assert!(matches!(unknown, Err(QueryError::NodeNotFound)));
+}
+
+/// A node in the routing table that cannot be reached resolves to its ENR in the table.
+#[tokio::test]
+async fn test_resolve_falls_back_to_the_table() {
+    init();
+    let mut nodes = build_nodes(2, 10070).await;
+    let mut target = nodes.pop().unwrap();
+    let resolver = nodes.pop().unwrap();
+
+    let known_enr = target.local_enr();
+    resolver.add_enr(known_enr.clone()).unwrap();
+    target.shutdown(false).await;
+
+    let resolved = resolver.resolve(known_enr.node_id()).await.unwrap();
+    assert_eq!(resolved, known_enr);
+}
+
+#[tokio::test]
+async fn test_client_only_lookup() {
+    init();
+    let servers = build_nodes(2, 10060).await;
+    let ip = Ipv4Addr::LOCALHOST;
+    let enr_key = CombinedKey::generate_secp256k1();
+    let config = ConfigBuilder::new(ListenConfig::Ipv4 { ip, port: 10062 })
+        .client_only()
+        .build();
+    let enr = Enr::builder().ip4(ip).udp4(10062).build(&enr_key).unwrap();
+    let mut client: Discv5 = Discv5::new(enr, enr_key, config).unwrap();
+    client.start().await.unwrap();
+    assert!(client.local_enr().udp4().is_none());
+    assert!(!client.update_local_enr_socket("127.0.0.1:10063".parse().unwrap(), false));
+
+    servers[0].add_enr(servers[1].local_enr()).unwrap();
+    client.add_enr(servers[0].local_enr()).unwrap();
+    // Looking up the second server makes the first return the bucket that holds it.
+    let found = client
+        .find_node(servers[1].local_enr().node_id())
+        .await
+        .unwrap();
+    assert!(found.contains(&servers[1].local_enr()));
+
+    // The servers can't contact the client, so they don't add it to their tables.
+    let client_id = client.local_enr().node_id();
+    assert!(!servers[0].table_entries_id().contains(&client_id));
+}
+
 #[tokio::test]
 async fn test_predicate_search() {
     init();
@@ -816,3 +884,322 @@ async fn test_bucket_limits() {
     // Number of entries should be equal to `bucket_limit`.
     assert_eq!(discv5.kbuckets.read().iter_ref().count(), bucket_limit);
 }
+
+/// A table filter holding an allow-list of IPs, which can't be expressed as a fn pointer.
+#[derive(Clone)]
+struct AllowListFilter(std::sync::Arc<Vec<Ipv4Addr>>);
+
+impl kbucket::Filter<Enr<CombinedKey>> for AllowListFilter {
+    fn filter(
+        &self,
+        value_to_be_inserted: &Enr<CombinedKey>,
+        _other_vals: &mut dyn Iterator<Item = &Enr<CombinedKey>>,
+    ) -> bool {
+        value_to_be_inserted
+            .ip4()
+            .is_some_and(|ip| self.0.contains(&ip))
+    }
+}
+
+// Custom stateful filters can be given in the config and replaced at runtime.
+#[tokio::test]
+async fn test_custom_table_filters() {
+    let mut keypairs = generate_deterministic_keypair(5, 9487);
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+    let enr_key: CombinedKey = keypairs.remove(0);
+    let enr = Enr::builder().ip4(ip).udp4(9060).build(&enr_key).unwrap();
+    let enrs: Vec<Enr<CombinedKey>> = keypairs
+        .iter()
+        .enumerate()
+        .map(|(i, key)| {
+            Enr::builder()
+                .ip4(Ipv4Addr::new(192, 168, 1, i as u8 + 1))
+                .udp4(9060 + i as u16 + 1)
+                .build(key)
+                .unwrap()
+        })
+        .collect();
+
+    let allowed = vec![enrs[0].ip4().unwrap(), enrs[1].ip4().unwrap()];
+    let denied = enrs[2].node_id();
+    let config = ConfigBuilder::new(ListenConfig::from_ip(ip.into(), 9060))
+        .kbucket_table_filter(Box::new(AllowListFilter(std::sync::Arc::new(allowed))))
+        .table_filter(move |enr| enr.node_id() != denied)
+        .build();
+    let discv5: Discv5 = Discv5::new(enr, enr_key, config).unwrap();
+
+    assert!(discv5.add_enr(enrs[0].clone()).is_ok());
+    assert!(discv5.add_enr(enrs[1].clone()).is_ok());
+    assert!(discv5.add_enr(enrs[2].clone()).is_err());
+    assert!(discv5.add_enr(enrs[3].clone()).is_err());
+    assert_eq!(discv5.kbuckets.read().iter_ref().count(), 2);
+
+    // Replace both filters at runtime.
+    discv5.set_kbucket_table_filter(None);
+    discv5.set_table_filter(|_| true);
+    assert!(discv5.add_enr(enrs[2].clone()).is_ok());
+    assert!(discv5.add_enr(enrs[3].clone()).is_ok());
+    assert_eq!(discv5.kbuckets.read().iter_ref().count(), 4);
+}
+
+// Timeouts changed via `reconfigure` apply to requests made by the running service.
+#[tokio::test]
+async fn test_reconfigure_request_timeout() {
+    init();
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+    let listen_config = ListenConfig::Ipv4 { ip, port: 9070 };
+    let config = ConfigBuilder::new(listen_config)
+        .request_timeout(std::time::Duration::from_secs(10))
+        .build();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder().ip4(ip).udp4(9070).build(&enr_key).unwrap();
+    let mut discv5: Discv5 = Discv5::new(enr, enr_key, config).unwrap();
+    discv5.start().await.unwrap();
+
+    // A node which never responds.
+    let silent_key = CombinedKey::generate_secp256k1();
+    let silent_enr = Enr::builder()
+        .ip4(ip)
+        .udp4(9071)
+        .build(&silent_key)
+        .unwrap();
+
+    let invalid_update = ConfigUpdate {
+        query_parallelism: Some(0),
+        ..Default::default()
+    };
+    assert!(discv5.reconfigure(invalid_update).await.is_err());
+
+    let update = ConfigUpdate {
+        request_timeout: Some(std::time::Duration::from_millis(100)),
+        request_retries: Some(0),
+        ..Default::default()
+    };
+    discv5.reconfigure(update).await.unwrap();
+    assert_eq!(
+        discv5.config.request_timeout,
+        std::time::Duration::from_millis(100)
+    );
+
+    let result = tokio::time::timeout(
+        std::time::Duration::from_secs(2),
+        discv5.send_ping(silent_enr),
+    )
+    .await
+    .expect("The ping should time out with the new request timeout");
+    assert!(matches!(result, Err(RequestError::Timeout)));
+}
+
+// A running service can move to a new port and keep communicating with its peers.
+#[tokio::test]
+async fn test_rebind() {
+    init();
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+
+    let mut nodes = Vec::new();
+    for port in [9080, 9082] {
+        let enr_key = CombinedKey::generate_secp256k1();
+        let config = ConfigBuilder::new(ListenConfig::Ipv4 { ip, port }).build();
+        let enr = Enr::builder().ip4(ip).udp4(port).build(&enr_key).unwrap();
+        let mut discv5: Discv5 = Discv5::new(enr, enr_key, config).unwrap();
+        discv5.start().await.unwrap();
+        nodes.push(discv5);
+    }
+    let mut remote = nodes.pop().unwrap();
+    let mut node = nodes.pop().unwrap();
+
+    node.send_ping(remote.local_enr()).await.unwrap();
+    let mut events = node.event_stream().await.unwrap();
+
+    node.rebind(ListenConfig::Ipv4 { ip, port: 9081 })
+        .await
+        .unwrap();
+    assert_eq!(node.local_enr().udp4(), Some(9081));
+
+    let mut rebound = false;
+    while let Ok(Some(event)) =
+        tokio::time::timeout(std::time::Duration::from_millis(100), events.recv()).await
+    {
+        if let Event::ListenSocketsUpdated(listen_config) = event {
+            assert_eq!(listen_config, ListenConfig::Ipv4 { ip, port: 9081 });
+            rebound = true;
+        }
+    }
+    assert!(rebound);
+
+    // The remote learns of the new socket from the ENR sent in the handshake.
+    node.send_ping(remote.local_enr()).await.unwrap();
+    remote.send_ping(node.local_enr()).await.unwrap();
+
+    // Binding an address that is in use fails and leaves the current sockets in place.
+    assert!(node
+        .rebind(ListenConfig::Ipv4 { ip, port: 9082 })
+        .await
+        .is_err());
+    node.send_ping(remote.local_enr()).await.unwrap();
+    remote.shutdown(false).await;
+}
+
+// A node listening on several sockets answers each request from the socket it arrived on.
+#[tokio::test]
+async fn test_multi_socket_listening() {
+    init();
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+
+    let multi_key = CombinedKey::generate_secp256k1();
+    let listen_config = ListenConfig::Multi {
+        sockets: vec![(ip, 9090).into(), (ip, 9091).into()],
+        enr_ipv4: None,
+        enr_ipv6: None,
+    };
+    let config = ConfigBuilder::new(listen_config).build();
+    let enr = Enr::builder().ip4(ip).udp4(9090).build(&multi_key).unwrap();
+    // The same node, as advertised on its second socket.
+    let second_enr = Enr::builder().ip4(ip).udp4(9091).build(&multi_key).unwrap();
+    let mut multi: Discv5 = Discv5::new(enr, multi_key, config).unwrap();
+    multi.start().await.unwrap();
+
+    let mut nodes = Vec::new();
+    for port in [9092, 9093] {
+        let enr_key = CombinedKey::generate_secp256k1();
+        let config = ConfigBuilder::new(ListenConfig::Ipv4 { ip, port }).build();
+        let enr = Enr::builder().ip4(ip).udp4(port).build(&enr_key).unwrap();
+        let mut discv5: Discv5 = Discv5::new(enr, enr_key, config).unwrap();
+        discv5.start().await.unwrap();
+        nodes.push(discv5);
+    }
+
+    // Responses from any other socket would not match the request and time out.
+    nodes[0].send_ping(multi.local_enr()).await.unwrap();
+    nodes[1].send_ping(second_enr).await.unwrap();
+
+    // Requests from the multi-homed node reuse the socket each peer knows it by.
+    multi.send_ping(nodes[0].local_enr()).await.unwrap();
+    multi.send_ping(nodes[1].local_enr()).await.unwrap();
+    assert_eq!(multi.connected_peers(), 2);
+
+    // The ENR advertises the chosen socket rather than the first one.
+    let listen_config = ListenConfig::Multi {
+        sockets: vec![(ip, 9094).into(), (ip, 9095).into()],
+        enr_ipv4: Some(SocketAddrV4::new(ip, 9095)),
+        enr_ipv6: None,
+    };
+    multi.rebind(listen_config).await.unwrap();
+    assert_eq!(multi.local_enr().udp4(), Some(9095));
+
+    // A socket that is not listened on cannot be advertised.
+    let listen_config = ListenConfig::Multi {
+        sockets: vec![(ip, 9096).into(), (ip, 9097).into()],
+        enr_ipv4: Some(SocketAddrV4::new(ip, 9098)),
+        enr_ipv6: None,
+    };
+    assert!(multi.rebind(listen_config).await.is_err());
+    assert_eq!(multi.local_enr().udp4(), Some(9095));
+}
+
+// A shutdown fails outstanding requests, can send final responses and releases the sockets, so
+// that the service can be started again with its routing table intact.
+#[tokio::test]
+async fn test_shutdown_and_restart() {
+    init();
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+
+    let mut nodes = Vec::new();
+    for port in [9100, 9101] {
+        let enr_key = CombinedKey::generate_secp256k1();
+        let config = ConfigBuilder::new(ListenConfig::Ipv4 { ip, port }).build();
+        let enr = Enr::builder().ip4(ip).udp4(port).build(&enr_key).unwrap();
+        let mut discv5: Discv5 = Discv5::new(enr, enr_key, config).unwrap();
+        discv5.start().await.unwrap();
+        nodes.push(discv5);
+    }
+    let mut remote = nodes.pop().unwrap();
+    let mut node = nodes.pop().unwrap();
+    node.add_enr(remote.local_enr()).unwrap();
+    node.send_ping(remote.local_enr()).await.unwrap();
+
+    // The remote answers a TALK request while shutting down.
+    let mut events = remote.event_stream().await.unwrap();
+    let talk = tokio::spawn(node.talk_req(remote.local_enr(), b"p".to_vec(), b"req".to_vec()));
+    let talk_request = loop {
+        if let Some(Event::TalkRequest(talk_request)) = events.recv().await {
+            break talk_request;
+        }
+    };
+    talk_request.respond(b"resp".to_vec()).unwrap();
+    remote.shutdown(true).await;
+    assert_eq!(talk.await.unwrap().unwrap(), b"resp".to_vec());
+
+    // A request to a node which never responds fails once the service shuts down.
+    let silent_key = CombinedKey::generate_secp256k1();
+    let silent_enr = Enr::builder()
+        .ip4(ip)
+        .udp4(9102)
+        .build(&silent_key)
+        .unwrap();
+    let ping = tokio::spawn(node.send_ping(silent_enr));
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    node.shutdown(false).await;
+    assert_eq!(
+        ping.await.unwrap().unwrap_err(),
+        RequestError::ServiceShutdown
+    );
+    assert_eq!(
+        node.send_ping(remote.local_enr()).await.unwrap_err(),
+        RequestError::ServiceNotStarted
+    );
+
+    // Both services restart on the same sockets and the routing table is kept.
+    remote.start().await.unwrap();
+    node.start().await.unwrap();
+    assert_eq!(node.table_entries_id(), vec![remote.local_enr().node_id()]);
+    assert_eq!(node.connected_peers(), 0);
+    node.send_ping(remote.local_enr()).await.unwrap();
+    assert_eq!(node.connected_peers(), 1);
+}
+
+#[tokio::test]
+async fn test_port_mapping() {
+    init();
+    let ip: Ipv4Addr = "127.0.0.1".parse().unwrap();
+    let port = 9110;
+    let mut gateway = crate::port_mapping::mock::NatPmpGateway::spawn(true).await;
+
+    let enr_key = CombinedKey::generate_secp256k1();
+    let config = ConfigBuilder::new(ListenConfig::Ipv4 { ip, port })
+        .port_mapping(PortMappingConfig {
+            gateway: Some(gateway.addr),
+            ..Default::default()
+        })
+        .build();
+    let enr = Enr::builder().ip4(ip).udp4(port).build(&enr_key).unwrap();
+    let mut discv5: Discv5 = Discv5::new(enr, enr_key, config).unwrap();
+    discv5.start().await.unwrap();
+
+    // The mapped socket is advertised in the ENR.
+    let mapped = std::net::SocketAddrV4::new(crate::port_mapping::mock::EXTERNAL_IP, port);
+    tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while discv5.local_enr().udp4_socket() != Some(mapped) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("The mapped socket is advertised");
+    assert_eq!(
+        gateway.requests.recv().await,
+        Some(crate::port_mapping::mock::Request::Pcp {
+            internal_port: port,
+            lifetime: 3600
+        })
+    );
+
+    // The mapping is deleted on shutdown.
+    discv5.shutdown(false).await;
+    assert_eq!(
+        gateway.requests.try_recv().ok(),
+        Some(crate::port_mapping::mock::Request::Pcp {
+            internal_port: port,
+            lifetime: 0
+        })
+    );
+}
@@ -111,6 +111,8 @@ pub enum RequestError {
     InvalidMultiaddr(&'static str),
     /// Failure generating random numbers during request.
     EntropyFailure(&'static str),
+    /// The discovery service was shut down before the request completed.
+    ServiceShutdown,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -125,6 +127,10 @@ pub enum QueryError {
     EncryptionFailed(String),
     /// The multiaddr provided was invalid.
     InvalidMultiaddr(String),
+    /// The discovery service was shut down before the query completed.
+    ServiceShutdown,
+    /// The node being resolved was not found or did not respond.
+    NodeNotFound,
 }
 
 impl fmt::Display for Error {
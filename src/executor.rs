@@ -4,6 +4,12 @@ use std::{future::Future, pin::Pin};
 pub trait Executor: ExecutorClone {
     /// Run the given future in the background until it ends.
     fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>);
+
+    /// Run the given function, which may block, on a thread where blocking is acceptable. By
+    /// default, the blocking thread pool of the current tokio runtime is used.
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        tokio::task::spawn_blocking(task);
+    }
 }
 
 pub trait ExecutorClone {
@@ -11,6 +11,8 @@ pub(super) struct ActiveRequests {
     // requests with active requests sent.
     /// A mapping of all active raw requests message nonces to their NodeAddress.
     active_requests_nonce_mapping: HashMapDelay<MessageNonce, NodeAddress>,
+    /// The timeout applied to newly inserted requests.
+    request_timeout: Duration,
 }
 
 impl ActiveRequests {
@@ -18,18 +20,27 @@ impl ActiveRequests {
         ActiveRequests {
             active_requests_mapping: HashMap::new(),
             active_requests_nonce_mapping: HashMapDelay::new(request_timeout),
+            request_timeout,
         }
     }
 
-    /// Insert a new request into the active requests mapping.
+    /// Sets the timeout for requests inserted from now on. Requests already awaiting a response
+    /// keep their timeout.
+    pub fn set_request_timeout(&mut self, request_timeout: Duration) {
+        self.request_timeout = request_timeout;
+    }
+
+    /// Insert a new request into the active requests mapping. The request times out after its own
+    /// timeout, if set, or the default request timeout.
     pub fn insert(&mut self, node_address: NodeAddress, request_call: RequestCall) {
         let nonce = *request_call.packet().message_nonce();
+        let timeout = request_call.timeout().unwrap_or(self.request_timeout);
         self.active_requests_mapping
             .entry(node_address.clone())
             .or_default()
             .push(request_call);
         self.active_requests_nonce_mapping
-            .insert(nonce, node_address);
+            .insert_at(nonce, node_address, timeout);
     }
 
     /// Update the underlying packet for the request via message nonce.
@@ -43,10 +54,8 @@ impl ActiveRequests {
                 return;
             };
 
-        self.active_requests_nonce_mapping
-            .insert(new_packet.header.message_nonce, node_address.clone());
-
-        match self.active_requests_mapping.entry(node_address) {
+        let new_nonce = new_packet.header.message_nonce;
+        match self.active_requests_mapping.entry(node_address.clone()) {
             Entry::Occupied(mut requests) => {
                 let maybe_request_call = requests
                     .get_mut()
@@ -54,6 +63,9 @@ impl ActiveRequests {
                     .find(|req| req.packet().message_nonce() == &old_nonce);
 
                 if let Some(request_call) = maybe_request_call {
+                    let timeout = request_call.timeout().unwrap_or(self.request_timeout);
+                    self.active_requests_nonce_mapping
+                        .insert_at(new_nonce, node_address, timeout);
                     request_call.update_packet(new_packet);
                 } else {
                     debug_unreachable!("expected to find request call in active_requests_mapping");
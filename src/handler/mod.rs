@@ -11,7 +11,9 @@
 //!
 //! An ongoing established connection is abstractly represented by a `Session`. A node that provides an ENR with an
 //! IP address/port that doesn't match the source, is considered invalid. A node that doesn't know
-//! their external contactable addresses should set their ENR IP field to `None`.
+//! their external contactable addresses should set their ENR IP field to `None`. Once established,
+//! a session follows a node to a new IP address or port that answers a PING sent under the
+//! session, see [`HandlerOut::SessionRoamed`].
 //!
 //! The Handler also routinely checks the timeouts for banned nodes and removes them from the
 //! banned list once their ban expires.
@@ -19,21 +21,24 @@
 //! # Usage
 //!
 //! Interacting with a handler is done via channels. A Handler is spawned using the [`Handler::spawn`]
-//! function. This returns an exit channel, a sending and receiving channel respectively. If the
-//! exit channel is dropped or fired, the handler task gets shutdown.
+//! function. This returns an exit channel, a channel that resolves once the handler has exited, a
+//! sending and receiving channel respectively. If the exit channel is dropped or fired, the
+//! handler task gets shutdown.
 //!
 //! Requests from the application layer can be made via the receive channel using a [`HandlerIn`].
 //! Responses from the application layer can be made via the receive channel using a [`HandlerIn`].
 //! Messages from a node on the network come by [`Socket`] and get the form of a [`HandlerOut`]
 //! and can be forwarded to the application layer via the send channel.
 use crate::{
-    config::Config,
+    config::{Config, ConfigUpdate},
     discv5::PERMIT_BAN_LIST,
     error::{Error, RequestError},
+    ipmode::IpMode,
     packet::{ChallengeData, IdNonce, MessageNonce, Packet, PacketKind, ProtocolIdentity},
+    permit_ban::Autosave,
     rpc::{Message, Request, RequestBody, RequestId, Response, ResponseBody},
     socket,
-    socket::{FilterConfig, Socket},
+    socket::{FilterConfig, FilterUpdate, LimitKind, RateLimiter, SendPriority, Socket},
     Enr,
 };
 use delay_map::HashMapDelay;
@@ -58,9 +63,14 @@ use tracing::{debug, error, trace, warn};
 mod active_requests;
 mod crypto;
 mod request_call;
+mod roaming;
+mod rtt;
 mod session;
 mod tests;
 
+pub use rtt::AdaptiveTimeoutConfig;
+pub use session::RekeyConfig;
+
 pub use crate::node_info::{NodeAddress, NodeContact};
 
 use crate::metrics::METRICS;
@@ -68,6 +78,8 @@ use crate::metrics::METRICS;
 use crate::{lru_time_cache::LruTimeCache, socket::ListenConfig};
 use active_requests::ActiveRequests;
 use request_call::RequestCall;
+use roaming::{PathValidation, PathValidations};
+use rtt::RttEstimator;
 use session::Session;
 
 // The time interval to check banned peer timeouts and unban peers when the timeout has elapsed (in
@@ -81,7 +93,7 @@ const ONE_TIME_SESSION_TIMEOUT: u64 = 30;
 const ONE_TIME_SESSION_CACHE_CAPACITY: usize = 100;
 
 /// Messages sent from the application layer to `Handler`.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 #[allow(clippy::large_enum_variant)]
 pub enum HandlerIn {
     /// A Request to send to a `NodeContact` has been received from the application layer. A
@@ -110,6 +122,27 @@ pub enum HandlerIn {
     /// The `WhoAreYouRef` is sent out in the `HandlerOut::WhoAreYou` event and should
     /// be returned here to submit the application's response.
     WhoAreYou(WhoAreYouRef, Option<Enr>),
+
+    /// Changes the request timeout, request retries and packet filter parameters of the running
+    /// handler. Other parameters of the update are ignored.
+    Reconfigure(ConfigUpdate),
+
+    /// Replaces the UDP sockets with sockets bound according to the new [`ListenConfig`]. The
+    /// result is reported via `HandlerOut::Rebound` or `HandlerOut::RebindFailed`.
+    Rebind(ListenConfig),
+
+    /// Lists the sessions the handler holds with peers. The list is sent once on the channel.
+    Sessions(mpsc::Sender<Vec<SessionInfo>>),
+}
+
+/// A session held with a peer, see [`crate::Discv5::sessions`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    /// The address of the peer.
+    pub node_address: NodeAddress,
+    /// Whether the session is established. A session is not established while the ENR of a peer
+    /// contacted without one is awaited.
+    pub established: bool,
 }
 
 /// Messages sent between a node on the network and `Handler`.
@@ -119,14 +152,16 @@ pub enum HandlerOut {
     ///
     /// A session is only considered established once we have received a signed ENR from the
     /// node and either the observed `SocketAddr` matches the one declared in the ENR or the
-    /// ENR declares no `SocketAddr`.
+    /// ENR declares no `SocketAddr`. Handshakes that renew the keys of a session are not
+    /// reported.
     Established(Enr, SocketAddr, ConnectionDirection),
 
     /// A Request has been received from a node on the network.
     Request(NodeAddress, Box<Request>),
 
-    /// A Response has been received from a node on the network.
-    Response(NodeAddress, Box<Response>),
+    /// A Response has been received from a node on the network, with the round-trip time of the
+    /// request if the response is the first to a request that was sent only once.
+    Response(NodeAddress, Box<Response>, Option<Duration>),
 
     /// An unknown source has requested information from us. Return the reference with the known
     /// ENR of this node (if known). See the `HandlerIn::WhoAreYou` variant.
@@ -136,10 +171,25 @@ pub enum HandlerOut {
     ///
     /// This returns the request ID and an error indicating why the request failed.
     RequestFailed(RequestId, RequestError),
+
+    /// The UDP sockets have been replaced by sockets bound according to this [`ListenConfig`].
+    Rebound(ListenConfig),
+
+    /// Binding the sockets of a `HandlerIn::Rebind` failed. The previous sockets remain in use.
+    RebindFailed(ListenConfig, std::io::ErrorKind, String),
+
+    /// The session with a node has moved to the new address of the node, which answered a PING
+    /// sent there under the session. The previous `SocketAddr` of the node is given.
+    SessionRoamed(NodeAddress, SocketAddr),
 }
 
 /// How we connected to the node.
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum ConnectionDirection {
     /// The node contacted us.
     Incoming,
@@ -190,6 +240,16 @@ impl From<&HandlerReqId> for RequestId {
 pub struct Handler {
     /// Configuration for the discv5 service.
     request_retries: u8,
+    /// The timeout for requests and challenges sent to peers.
+    request_timeout: Duration,
+    /// If set, request timeouts are derived from the round-trip times measured to each peer.
+    adaptive_timeout: Option<AdaptiveTimeoutConfig>,
+    /// The round-trip time estimates of peers, kept for as long as sessions.
+    rtt_estimates: LruTimeCache<NodeAddress, RttEstimator>,
+    /// Charges inbound requests by their type and cost, if the packet filter is enabled.
+    request_limiter: Option<RateLimiter>,
+    /// Saves the permit/ban list when it changes, if a store is configured.
+    permit_ban_autosave: Option<Autosave>,
     /// The local node id to save unnecessary read locks on the ENR. The NodeID should not change
     /// during the operation of the server.
     node_id: NodeId,
@@ -207,8 +267,15 @@ pub struct Handler {
     active_challenges: HashMapDelay<NodeAddress, Challenge>,
     /// Established sessions with peers.
     sessions: LruTimeCache<NodeAddress, Session>,
+    /// The address of the latest session of each node, to find the session of a node that has
+    /// moved. Entries whose session has since been removed are pruned periodically.
+    session_addresses: HashMap<NodeId, NodeAddress>,
+    /// When the keys of sessions are renewed.
+    session_rekey: RekeyConfig,
     /// Established sessions with peers for a specific request, stored just one per node.
     one_time_sessions: LruTimeCache<NodeAddress, (RequestId, Session)>,
+    /// New addresses of peers with a session, awaiting validation before the session moves.
+    path_validations: PathValidations,
     /// The channel to receive messages from the application layer.
     service_recv: mpsc::UnboundedReceiver<HandlerIn>,
     /// The channel to send messages to the application layer.
@@ -217,12 +284,16 @@ pub struct Handler {
     listen_sockets: SmallVec<[SocketAddr; 2]>,
     /// The discovery v5 UDP socket tasks.
     socket: Socket,
-    /// Exit channel to shutdown the handler.
-    exit: oneshot::Receiver<()>,
+    /// The configuration the current socket tasks were created with.
+    socket_config: socket::SocketConfig,
+    /// Exit channel to shutdown the handler. If `true` is sent, queued responses are sent before
+    /// the handler exits.
+    exit: oneshot::Receiver<bool>,
 }
 
 type HandlerReturn = (
-    oneshot::Sender<()>,
+    oneshot::Sender<bool>,
+    oneshot::Receiver<()>,
     mpsc::UnboundedSender<HandlerIn>,
     mpsc::Receiver<HandlerOut>,
 );
@@ -235,6 +306,7 @@ impl Handler {
         config: Config,
     ) -> Result<HandlerReturn, std::io::Error> {
         let (exit_sender, exit) = oneshot::channel();
+        let (exited_sender, exited) = oneshot::channel();
         // create the channels to send/receive messages from the application
         let (handler_send, service_recv) = mpsc::unbounded_channel();
         let (service_send, handler_recv) = mpsc::channel(50);
@@ -255,20 +327,7 @@ impl Handler {
             max_bans_per_ip: config.filter_max_bans_per_ip,
         };
 
-        let mut listen_sockets = SmallVec::default();
-        match config.listen_config {
-            ListenConfig::Ipv4 { ip, port } => listen_sockets.push((ip, port).into()),
-            ListenConfig::Ipv6 { ip, port } => listen_sockets.push((ip, port).into()),
-            ListenConfig::DualStack {
-                ipv4,
-                ipv4_port,
-                ipv6,
-                ipv6_port,
-            } => {
-                listen_sockets.push((ipv4, ipv4_port).into());
-                listen_sockets.push((ipv6, ipv6_port).into());
-            }
-        };
+        let listen_sockets = config.listen_config.sockets().into_iter().collect();
 
         let socket_config = socket::SocketConfig {
             executor: config.executor.clone().expect("Executor must exist"),
@@ -277,10 +336,12 @@ impl Handler {
             local_node_id: node_id,
             expected_responses: filter_expected_responses.clone(),
             ban_duration: config.ban_duration,
+            outbound_rate_limiter: config.outbound_rate_limiter.clone(),
+            request_timeout: config.request_timeout,
         };
 
         // Attempt to bind to the socket before spinning up the send/recv tasks.
-        let socket = Socket::new::<P>(socket_config).await?;
+        let socket = Socket::new::<P>(socket_config.clone()).await?;
 
         config
             .executor
@@ -289,6 +350,18 @@ impl Handler {
             .spawn(Box::pin(async move {
                 let mut handler = Handler {
                     request_retries: config.request_retries,
+                    request_timeout: config.request_timeout,
+                    adaptive_timeout: config.adaptive_request_timeout,
+                    rtt_estimates: LruTimeCache::new(
+                        config.session_timeout,
+                        Some(config.session_cache_capacity),
+                    ),
+                    request_limiter: config
+                        .enable_packet_filter
+                        .then(|| config.filter_rate_limiter.clone())
+                        .flatten()
+                        .filter(RateLimiter::limits_requests),
+                    permit_ban_autosave: config.permit_ban_store.clone().map(Autosave::new),
                     node_id,
                     enr,
                     key,
@@ -299,22 +372,29 @@ impl Handler {
                         config.session_timeout,
                         Some(config.session_cache_capacity),
                     ),
+                    session_rekey: config.session_rekey,
+                    session_addresses: HashMap::new(),
                     one_time_sessions: LruTimeCache::new(
                         Duration::from_secs(ONE_TIME_SESSION_TIMEOUT),
                         Some(ONE_TIME_SESSION_CACHE_CAPACITY),
                     ),
+                    path_validations: PathValidations::new(config.request_timeout),
                     active_challenges: HashMapDelay::new(config.request_timeout),
                     service_recv,
                     service_send,
                     listen_sockets,
                     socket,
+                    socket_config,
                     exit,
                 };
                 debug!("Handler Starting");
                 handler.start::<P>().await;
+                // Release the sockets before reporting that the handler has exited.
+                drop(handler);
+                let _ = exited_sender.send(());
             }));
 
-        Ok((exit_sender, handler_send, handler_recv))
+        Ok((exit_sender, exited, handler_send, handler_recv))
     }
 
     /// The main execution loop for the handler.
@@ -336,6 +416,19 @@ impl Handler {
                         }
                         HandlerIn::Response(dst, response) => self.send_response::<P>(dst, *response).await,
                         HandlerIn::WhoAreYou(wru_ref, enr) => self.send_challenge::<P>(wru_ref, enr).await,
+                        HandlerIn::Reconfigure(update) => self.reconfigure(update),
+                        HandlerIn::Rebind(listen_config) => self.rebind::<P>(listen_config).await,
+                        HandlerIn::Sessions(callback) => {
+                            let sessions = self
+                                .sessions
+                                .iter()
+                                .map(|(node_address, session)| SessionInfo {
+                                    node_address: node_address.clone(),
+                                    established: session.awaiting_enr.is_none(),
+                                })
+                                .collect();
+                            let _ = callback.try_send(sessions);
+                        }
                     }
                 }
                 Some(inbound_packet) = self.socket.recv.recv() => {
@@ -349,14 +442,54 @@ impl Handler {
                     // challenge. We process them here
                     self.send_pending_requests::<P>(&node_address).await;
                 }
-                _ = banned_nodes_check.tick() => self.unban_nodes_check(), // Unban nodes that are past the timeout
-                _ = &mut self.exit => {
+                Some(Ok((_, validation))) = self.path_validations.next() => {
+                    // The node did not answer at the new address. The session stays where it is.
+                    debug!("Path validation timed out. {}", validation.new_address);
+                    self.remove_expected_response(validation.new_address.socket_addr);
+                }
+                _ = banned_nodes_check.tick() => {
+                    self.unban_nodes_check(); // Unban nodes that are past the timeout
+                    if let Some(autosave) = self.permit_ban_autosave.as_ref() {
+                        let list = PERMIT_BAN_LIST.read().clone();
+                        autosave.save(list, self.socket_config.executor.as_ref());
+                    }
+                    if let Some(request_limiter) = self.request_limiter.as_mut() {
+                        request_limiter.prune();
+                    }
+                    let sessions = &self.sessions;
+                    self.session_addresses
+                        .retain(|_, node_address| sessions.peek(node_address).is_some());
+                }
+                send_final_responses = &mut self.exit => {
+                    self.shutdown::<P>(send_final_responses.unwrap_or(false)).await;
                     return;
                 }
             }
         }
     }
 
+    /// Stops accepting requests from the service and shuts down the socket tasks. If
+    /// `send_final_responses` is set, responses that were queued before the shutdown are sent
+    /// first.
+    async fn shutdown<P: ProtocolIdentity>(&mut self, send_final_responses: bool) {
+        self.service_recv.close();
+        if send_final_responses {
+            while let Ok(handler_request) = self.service_recv.try_recv() {
+                if let HandlerIn::Response(dst, response) = handler_request {
+                    self.send_response::<P>(dst, *response).await;
+                }
+            }
+        }
+        self.socket.shutdown(send_final_responses).await;
+        if let Some(autosave) = self.permit_ban_autosave.as_ref() {
+            let list = PERMIT_BAN_LIST.read().clone();
+            if let Some(saved) = autosave.save(list, self.socket_config.executor.as_ref()) {
+                let _ = saved.await;
+            }
+        }
+        debug!("Handler shutdown");
+    }
+
     /// Processes an inbound decoded packet.
     async fn process_inbound_packet<P: ProtocolIdentity>(
         &mut self,
@@ -385,6 +518,7 @@ impl Handler {
                 let node_address = NodeAddress {
                     socket_addr: inbound_packet.src_address,
                     node_id: src_id,
+                    local_socket: Some(inbound_packet.local_socket),
                 };
                 self.handle_auth_message::<P>(
                     node_address,
@@ -401,8 +535,9 @@ impl Handler {
                 let node_address = NodeAddress {
                     socket_addr: inbound_packet.src_address,
                     node_id: src_id,
+                    local_socket: Some(inbound_packet.local_socket),
                 };
-                self.handle_message(
+                self.handle_message::<P>(
                     node_address,
                     message_nonce,
                     &inbound_packet.message,
@@ -433,6 +568,70 @@ impl Handler {
             .or_default() += 1;
     }
 
+    /// Applies the handler-level parameters of a [`ConfigUpdate`].
+    fn reconfigure(&mut self, update: ConfigUpdate) {
+        if let Some(request_timeout) = update.request_timeout {
+            self.request_timeout = request_timeout;
+            self.active_requests.set_request_timeout(request_timeout);
+            // Applies to the send task once the sockets are rebound.
+            self.socket_config.request_timeout = request_timeout;
+        }
+        if let Some(request_retries) = update.request_retries {
+            self.request_retries = request_retries;
+        }
+        if let Some(rate_limiter) = &update.filter_rate_limiter {
+            self.socket_config.filter_config.rate_limiter = rate_limiter.clone();
+            if self.socket_config.filter_config.enabled {
+                self.request_limiter = rate_limiter.clone().filter(RateLimiter::limits_requests);
+            }
+        }
+        if let Some(ban_duration) = update.ban_duration {
+            self.socket_config.ban_duration = ban_duration;
+        }
+        if update.filter_rate_limiter.is_some() || update.ban_duration.is_some() {
+            let filter_update = FilterUpdate {
+                rate_limiter: update.filter_rate_limiter,
+                ban_duration: update.ban_duration,
+            };
+            if self.socket.filter_update.send(filter_update).is_err() {
+                warn!("Failed to update the packet filter, the socket has closed");
+            }
+        }
+    }
+
+    /// Replaces the socket tasks with tasks listening according to the new [`ListenConfig`].
+    /// Sessions are kept unless the peer is no longer reachable over the new sockets.
+    async fn rebind<P: ProtocolIdentity>(&mut self, listen_config: ListenConfig) {
+        let mut socket_config = self.socket_config.clone();
+        socket_config.listen_config = listen_config.clone();
+
+        let event = match self.socket.rebind::<P>(socket_config.clone()).await {
+            Ok(socket) => {
+                // Dropping the old socket shuts down its send/recv tasks.
+                self.socket = socket;
+                self.socket_config = socket_config;
+                self.listen_sockets = listen_config.sockets().into_iter().collect();
+
+                let ip_mode = IpMode::new_from_listen_config(&listen_config);
+                self.sessions
+                    .retain(|node_address, _| ip_mode.is_reachable(&node_address.socket_addr));
+                self.one_time_sessions
+                    .retain(|node_address, _| ip_mode.is_reachable(&node_address.socket_addr));
+
+                debug!(?listen_config, "Handler sockets rebound");
+                HandlerOut::Rebound(listen_config)
+            }
+            Err(e) => {
+                warn!(?listen_config, error = %e, "Failed to rebind the handler sockets");
+                HandlerOut::RebindFailed(listen_config, e.kind(), e.to_string())
+            }
+        };
+
+        if let Err(e) = self.service_send.send(event).await {
+            warn!("Failed to inform of the rebind result {}", e);
+        }
+    }
+
     /// A request has timed out.
     async fn handle_request_timeout(
         &mut self,
@@ -453,9 +652,15 @@ impl Handler {
                 request_call.body(),
                 node_address
             );
-            self.send(node_address.clone(), request_call.packet().clone())
-                .await;
+            let priority = request_call.priority();
+            self.send(
+                node_address.clone(),
+                request_call.packet().clone(),
+                priority,
+            )
+            .await;
             request_call.increment_retries();
+            self.set_request_timeout(&node_address, &mut request_call);
             self.active_requests.insert(node_address, request_call);
         }
     }
@@ -491,8 +696,36 @@ impl Handler {
             return Ok(());
         }
 
+        // A session that can't encrypt any more messages, or whose keys are due for renewal, is
+        // replaced by a new handshake. The session is kept until the handshake completes, so that
+        // responses in flight can still be decrypted.
+        let renews_session = match self.sessions.get(&node_address) {
+            Some(session) if session.is_exhausted() => {
+                debug!(
+                    "Session keys exhausted, starting a new session. {}",
+                    node_address
+                );
+                true
+            }
+            Some(session)
+                if session.awaiting_enr.is_none()
+                    && session.is_due_for_rekey(&self.session_rekey) =>
+            {
+                debug!(
+                    "Session keys due for renewal, starting a new session. {}",
+                    node_address
+                );
+                true
+            }
+            _ => false,
+        };
+
         let (packet, initiating_session) = {
-            if let Some(session) = self.sessions.get_mut(&node_address) {
+            if let Some(session) = self
+                .sessions
+                .get_mut(&node_address)
+                .filter(|_| !renews_session)
+            {
                 // Encrypt the message and send
                 let request = match &request_id {
                     HandlerReqId::Internal(id) | HandlerReqId::External(id) => Request {
@@ -516,16 +749,19 @@ impl Handler {
             }
         };
 
-        let call = RequestCall::new(
+        let mut call = RequestCall::new(
             contact,
             packet.clone(),
             request_id,
             request,
             initiating_session,
         );
+        call.set_renews_session(renews_session);
+        self.set_request_timeout(&node_address, &mut call);
         // let the filter know we are expecting a response
         self.add_expected_response(node_address.socket_addr);
-        self.send(node_address.clone(), packet).await;
+        self.send(node_address.clone(), packet, call.priority())
+            .await;
 
         self.active_requests.insert(node_address, call);
         Ok(())
@@ -553,7 +789,7 @@ impl Handler {
         };
 
         match packet {
-            Ok(packet) => self.send(node_address, packet).await,
+            Ok(packet) => self.send(node_address, packet, SendPriority::High).await,
             Err(e) => warn!("Could not encrypt response: {:?}", e),
         }
     }
@@ -591,13 +827,15 @@ impl Handler {
             .expect("Must be the correct challenge size");
         debug!("Sending WHOAREYOU to {}", node_address);
         self.add_expected_response(node_address.socket_addr);
-        self.send(node_address.clone(), packet).await;
-        self.active_challenges.insert(
+        self.send(node_address.clone(), packet, SendPriority::High)
+            .await;
+        self.active_challenges.insert_at(
             node_address,
             Challenge {
                 data: challenge_data,
                 remote_enr,
             },
+            self.request_timeout,
         );
     }
 
@@ -643,6 +881,7 @@ impl Handler {
             "Received a WHOAREYOU packet response. Source: {}",
             request_call.contact()
         );
+        self.record_rtt(&request_call.contact().node_address(), &request_call);
 
         // We do not allow multiple WHOAREYOU packets for a single challenge request. If we have
         // already sent a WHOAREYOU ourselves, we drop sessions who send us a WHOAREYOU in
@@ -699,6 +938,9 @@ impl Handler {
         // must not panic.
         let node_address = request_call.contact().node_address();
         let auth_message_nonce = auth_packet.header.message_nonce;
+        let renews_session =
+            request_call.renews_session() || self.is_renewing_session(&node_address);
+        request_call.set_renews_session(false);
         match request_call.contact().enr() {
             Some(enr) => {
                 // NOTE: Here we decide if the session is outgoing or ingoing. The condition for an
@@ -720,20 +962,24 @@ impl Handler {
                 request_call.update_packet(auth_packet.clone());
                 request_call.set_handshake_sent();
                 request_call.set_initiating_session(false);
+                self.set_request_timeout(&node_address, &mut request_call);
                 // Reinsert the request_call
                 self.insert_active_request(request_call);
                 // Send the actual packet to the send task.
-                self.send(node_address.clone(), auth_packet).await;
+                self.send(node_address.clone(), auth_packet, SendPriority::High)
+                    .await;
 
                 // Notify the application that the session has been established
-                self.service_send
-                    .send(HandlerOut::Established(
-                        enr,
-                        node_address.socket_addr,
-                        connection_direction,
-                    ))
-                    .await
-                    .unwrap_or_else(|e| warn!("Error with sending channel: {}", e));
+                if !renews_session {
+                    self.service_send
+                        .send(HandlerOut::Established(
+                            enr,
+                            node_address.socket_addr,
+                            connection_direction,
+                        ))
+                        .await
+                        .unwrap_or_else(|e| warn!("Error with sending channel: {}", e));
+                }
             }
             None => {
                 // Don't know the ENR. Establish the session, but request an ENR also
@@ -747,9 +993,11 @@ impl Handler {
                 );
                 request_call.update_packet(auth_packet.clone());
                 request_call.set_handshake_sent();
+                self.set_request_timeout(&node_address, &mut request_call);
                 // Reinsert the request_call
                 self.insert_active_request(request_call);
-                self.send(node_address.clone(), auth_packet).await;
+                self.send(node_address.clone(), auth_packet, SendPriority::High)
+                    .await;
 
                 let id = RequestId::random();
                 let request = RequestBody::FindNode { distances: vec![0] };
@@ -766,6 +1014,14 @@ impl Handler {
             .await;
     }
 
+    /// Whether a handshake with the node renews the keys of the session held at the address,
+    /// rather than establishing a new session.
+    fn is_renewing_session(&self, node_address: &NodeAddress) -> bool {
+        self.sessions
+            .peek(node_address)
+            .is_some_and(|session| session.awaiting_enr.is_none())
+    }
+
     /// Verifies a Node ENR to it's observed address. If it fails, any associated session is also
     /// considered failed. If it succeeds, we notify the application.
     fn verify_enr(&self, enr: &Enr, node_address: &NodeAddress) -> bool {
@@ -822,7 +1078,9 @@ impl Handler {
                         // Notify the application
                         // The session established here are from WHOAREYOU packets that we sent.
                         // This occurs when a node established a connection with us.
-                        if let Err(e) = self
+                        if self.is_renewing_session(&node_address) {
+                            trace!("Session keys renewed. {}", node_address);
+                        } else if let Err(e) = self
                             .service_send
                             .send(HandlerOut::Established(
                                 enr,
@@ -838,7 +1096,7 @@ impl Handler {
                         // the message nonce on to `new_session`.
                         self.new_session::<P>(node_address.clone(), session, None)
                             .await;
-                        self.handle_message(
+                        self.handle_message::<P>(
                             node_address.clone(),
                             message_nonce,
                             message,
@@ -871,7 +1129,9 @@ impl Handler {
                             },
                             _ => None,
                         };
-                        if let Some(request) = maybe_ping_request {
+                        if let Some(request) = maybe_ping_request
+                            .filter(|request| self.request_permitted(&node_address, request))
+                        {
                             debug!(
                                 "Responding to a PING request using a one-time session. node_address: {}",
                                 node_address
@@ -895,7 +1155,8 @@ impl Handler {
                         node_address
                     );
                     // insert back the challenge
-                    self.active_challenges.insert(node_address, challenge);
+                    self.active_challenges
+                        .insert_at(node_address, challenge, self.request_timeout);
                 }
                 Err(e) => {
                     warn!(
@@ -987,7 +1248,11 @@ impl Handler {
                 if let Ok(new_packet) =
                     session.encrypt_message::<P>(self.node_id, &request_call.encode())
                 {
-                    packets.push((*request_call.packet().message_nonce(), new_packet));
+                    packets.push((
+                        *request_call.packet().message_nonce(),
+                        new_packet,
+                        request_call.priority(),
+                    ));
                 } else {
                     error!(
                         "Failed to re-encrypt packet while replaying active request with id: {:?}",
@@ -1003,16 +1268,16 @@ impl Handler {
             return;
         };
 
-        for (old_nonce, new_packet) in packets {
+        for (old_nonce, new_packet, priority) in packets {
             self.active_requests
                 .update_packet(old_nonce, new_packet.clone());
-            self.send(node_address.clone(), new_packet).await;
+            self.send(node_address.clone(), new_packet, priority).await;
         }
     }
 
     /// Handle a standard message that does not contain an authentication header.
     #[allow(clippy::single_match)]
-    async fn handle_message(
+    async fn handle_message<P: ProtocolIdentity>(
         &mut self,
         node_address: NodeAddress,
         message_nonce: MessageNonce,
@@ -1033,16 +1298,15 @@ impl Handler {
                 },
                 Err(e) => {
                     // We have a session, but the message could not be decrypted. It is likely the node
-                    // sending this message has dropped their session. In this case, this message is a
-                    // Random packet and we should reply with a WHOAREYOU.
-                    // This means we need to drop the current session and re-establish.
+                    // sending this message is renewing the session keys or has dropped their session.
+                    // In this case, this message is a Random packet and we should reply with a
+                    // WHOAREYOU. The current session is kept until the new handshake replaces it,
+                    // at which point the active requests are replayed under the new keys.
                     trace!("Decryption failed. Error {}", e);
                     debug!(
                         "Message from node: {} is not encrypted with known session keys.",
                         node_address
                     );
-                    self.fail_session(&node_address, RequestError::InvalidRemotePacket, true)
-                        .await;
                     // If we haven't already sent a WhoAreYou,
                     // spawn a WHOAREYOU event to check for highest known ENR
                     if self.active_challenges.get(&node_address).is_none() {
@@ -1062,10 +1326,31 @@ impl Handler {
             };
 
             trace!("Received message from: {}", node_address);
+            let rekey_due =
+                session.awaiting_enr.is_none() && session.is_due_for_rekey(&self.session_rekey);
 
             // Remove any associated request from pending_request
             match message {
+                Message::Request(request) if rekey_due => {
+                    // Challenging the request makes the peer send it again in a handshake under
+                    // new keys. Its other requests are replayed under the new keys once the
+                    // handshake completes.
+                    if self.active_challenges.get(&node_address).is_none() {
+                        debug!("Session keys due for renewal. Challenging {}", node_address);
+                        let whoareyou_ref = WhoAreYouRef(node_address, message_nonce);
+                        self.send_challenge::<P>(whoareyou_ref, None).await;
+                    } else {
+                        trace!(
+                            "Dropped request {} awaiting new session keys. {}",
+                            request.id,
+                            node_address
+                        );
+                    }
+                }
                 Message::Request(request) => {
+                    if !self.request_permitted(&node_address, &request) {
+                        return;
+                    }
                     // report the request to the application
                     if let Err(e) = self
                         .service_send
@@ -1117,6 +1402,11 @@ impl Handler {
                     self.handle_response(node_address, response).await;
                 }
             }
+        } else if self
+            .handle_roaming_message::<P>(&node_address, message_nonce, message, authenticated_data)
+            .await
+        {
+            // The message is from a node with a session at another address.
         } else {
             // no session exists
             trace!("Received a message without a session. {}", node_address);
@@ -1136,6 +1426,134 @@ impl Handler {
         }
     }
 
+    /// Handles a message from a node at an address without a session. If the node holds a session
+    /// at another address which authenticates the message, the message is dropped and the new
+    /// address is validated, or the session moves there if the message is the PONG of the
+    /// validation. Returns false if no session of the node authenticates the message.
+    async fn handle_roaming_message<P: ProtocolIdentity>(
+        &mut self,
+        node_address: &NodeAddress,
+        message_nonce: MessageNonce,
+        message: &[u8],
+        authenticated_data: &[u8],
+    ) -> bool {
+        let node_id = node_address.node_id;
+        let old_address = match self.path_validations.get(&node_id) {
+            Some(validation) => validation.old_address.clone(),
+            None => {
+                let old_address = match self.session_addresses.get(&node_id) {
+                    Some(old_address) => old_address.clone(),
+                    None => return false,
+                };
+                // Sessions still awaiting the ENR of the node have not verified its identity.
+                match self.sessions.peek(&old_address) {
+                    Some(session) if session.awaiting_enr.is_none() => old_address,
+                    Some(_) => return false,
+                    None => {
+                        self.session_addresses.remove(&node_id);
+                        return false;
+                    }
+                }
+            }
+        };
+        let enr_seq = self.enr.read().seq();
+        let session = match self.sessions.get_mut(&old_address) {
+            Some(session) => session,
+            None => return false,
+        };
+        let message = match session
+            .decrypt_message(message_nonce, message, authenticated_data)
+            .ok()
+            .and_then(|m| Message::decode(&m).ok())
+        {
+            Some(message) => message,
+            None => return false,
+        };
+
+        if let Some(validation) = self.path_validations.get(&node_id) {
+            let is_pong = validation.new_address == *node_address
+                && matches!(&message, Message::Response(response)
+                    if response.id == validation.request_id
+                        && matches!(response.body, ResponseBody::Pong { .. }));
+            if is_pong {
+                let validation = self
+                    .path_validations
+                    .remove(&node_id)
+                    .expect("validation must exist");
+                self.migrate_session(validation).await;
+            } else {
+                trace!("Dropped message during path validation. {}", node_address);
+            }
+            return true;
+        }
+
+        if !self.path_validations.may_start(&node_id) {
+            debug!("Dropped message from unvalidated address. {}", node_address);
+            return true;
+        }
+        let request_id = RequestId::random();
+        let ping = Request {
+            id: request_id.clone(),
+            body: RequestBody::Ping { enr_seq },
+        };
+        let packet = match session.encrypt_message::<P>(self.node_id, &ping.encode()) {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Failed to encrypt path validation PING {:?}", e);
+                return true;
+            }
+        };
+        debug!(
+            "Validating new address {} of node, previously at {}",
+            node_address.socket_addr, old_address.socket_addr
+        );
+        self.path_validations.start(PathValidation {
+            old_address,
+            new_address: node_address.clone(),
+            request_id,
+        });
+        self.add_expected_response(node_address.socket_addr);
+        self.send(node_address.clone(), packet, SendPriority::Normal)
+            .await;
+        true
+    }
+
+    /// Moves the session of a node to its validated new address. Requests in flight to the old
+    /// address are left to time out.
+    async fn migrate_session(&mut self, validation: PathValidation) {
+        let PathValidation {
+            old_address,
+            new_address,
+            ..
+        } = validation;
+        self.remove_expected_response(new_address.socket_addr);
+        let session = match self.sessions.remove(&old_address) {
+            Some(session) => session,
+            // The session expired during the validation.
+            None => return,
+        };
+        self.sessions.insert(new_address.clone(), session);
+        self.session_addresses
+            .insert(new_address.node_id, new_address.clone());
+        if let Some(estimator) = self.rtt_estimates.remove(&old_address) {
+            self.rtt_estimates.insert(new_address.clone(), estimator);
+        }
+        debug!(
+            "Session moved from {} to {}",
+            old_address.socket_addr, new_address
+        );
+        if let Err(e) = self
+            .service_send
+            .send(HandlerOut::SessionRoamed(
+                new_address,
+                old_address.socket_addr,
+            ))
+            .await
+        {
+            warn!("Failed to inform of the roamed session {}", e)
+        }
+    }
+
     /// Handles a response to a request. Re-inserts the request call if the response is a multiple
     /// Nodes response.
     async fn handle_response(&mut self, node_address: NodeAddress, response: Response) {
@@ -1144,7 +1562,15 @@ impl Handler {
             .active_requests
             .remove_request(&node_address, &response.id)
         {
-            // The response matches a request
+            // The response matches a request. Only the first of multiple responses measures the
+            // round-trip time.
+            let rtt = match request_call.remaining_responses_mut() {
+                None => {
+                    self.record_rtt(&node_address, &request_call);
+                    request_call.rtt()
+                }
+                Some(_) => None,
+            };
             // Check to see if this is a Nodes response, in which case we may require to wait for
             // extra responses
             if let ResponseBody::Nodes { total, .. } = response.body {
@@ -1159,7 +1585,7 @@ impl Handler {
                                 .insert(node_address.clone(), request_call);
                             if let Err(e) = self
                                 .service_send
-                                .send(HandlerOut::Response(node_address, Box::new(response)))
+                                .send(HandlerOut::Response(node_address, Box::new(response), rtt))
                                 .await
                             {
                                 warn!("Failed to inform of response {}", e)
@@ -1174,7 +1600,7 @@ impl Handler {
                             .insert(node_address.clone(), request_call);
                         if let Err(e) = self
                             .service_send
-                            .send(HandlerOut::Response(node_address, Box::new(response)))
+                            .send(HandlerOut::Response(node_address, Box::new(response), rtt))
                             .await
                         {
                             warn!("Failed to inform of response {}", e)
@@ -1193,6 +1619,7 @@ impl Handler {
                 .send(HandlerOut::Response(
                     node_address.clone(),
                     Box::new(response),
+                    rtt,
                 ))
                 .await
             {
@@ -1205,6 +1632,65 @@ impl Handler {
         }
     }
 
+    /// Adds the round-trip time of a request to the estimate of the node, if timeouts are adaptive
+    /// and the response can be attributed to a single transmission.
+    fn record_rtt(&mut self, node_address: &NodeAddress, request_call: &RequestCall) {
+        if self.adaptive_timeout.is_none() {
+            return;
+        }
+        if let Some(sample) = request_call.rtt() {
+            match self.rtt_estimates.get_mut(node_address) {
+                Some(estimator) => estimator.update(sample),
+                None => self
+                    .rtt_estimates
+                    .insert(node_address.clone(), RttEstimator::new(sample)),
+            }
+        }
+    }
+
+    /// Sets the timeout of the next transmission of a request, if timeouts are adaptive.
+    fn set_request_timeout(&mut self, node_address: &NodeAddress, request_call: &mut RequestCall) {
+        if let Some(config) = self.adaptive_timeout {
+            let timeout = config.timeout(
+                self.rtt_estimates.get(node_address),
+                self.request_timeout,
+                request_call.retries(),
+            );
+            request_call.set_timeout(Some(timeout));
+        }
+    }
+
+    /// Charges an inbound request against the quotas of its type. Requests that exceed a quota
+    /// are dropped without a response.
+    fn request_permitted(&mut self, node_address: &NodeAddress, request: &Request) -> bool {
+        let request_limiter = match self.request_limiter.as_mut() {
+            Some(request_limiter) => request_limiter,
+            None => return true,
+        };
+        {
+            let permit_ban_list = PERMIT_BAN_LIST.read();
+            if permit_ban_list.permit_nodes.contains(&node_address.node_id)
+                || permit_ban_list.is_ip_permitted(&node_address.socket_addr.ip())
+            {
+                return true;
+            }
+        }
+        let node_id = node_address.node_id;
+        let limit_kind = match &request.body {
+            RequestBody::Ping { .. } => LimitKind::Ping(node_id),
+            RequestBody::FindNode { distances } => LimitKind::FindNode(node_id, distances.len()),
+            RequestBody::Talk { protocol, .. } => LimitKind::Talk(node_id, protocol.clone()),
+        };
+        if request_limiter.allows(&limit_kind).is_err() {
+            debug!(
+                "Dropped request exceeding its quota. Request: {} Node: {}",
+                request.body, node_address
+            );
+            return false;
+        }
+        true
+    }
+
     /// Inserts a request and associated auth_tag mapping.
     fn insert_active_request(&mut self, request_call: RequestCall) {
         let node_address = request_call.contact().node_address();
@@ -1230,8 +1716,12 @@ impl Handler {
             // the request that was used to re-establish the session handshake.
             self.replay_active_requests::<P>(&node_address, message_nonce)
                 .await;
+            // Requests may have been queued while the session was being renewed.
+            self.send_pending_requests::<P>(&node_address).await;
         } else {
             self.sessions.insert(node_address.clone(), session);
+            self.session_addresses
+                .insert(node_address.node_id, node_address.clone());
             METRICS
                 .active_sessions
                 .store(self.sessions.len(), Ordering::Relaxed);
@@ -1345,10 +1835,11 @@ impl Handler {
     }
 
     /// Sends a packet to the send handler to be encoded and sent.
-    async fn send(&mut self, node_address: NodeAddress, packet: Packet) {
+    async fn send(&mut self, node_address: NodeAddress, packet: Packet, priority: SendPriority) {
         let outbound_packet = socket::OutboundPacket {
             node_address,
             packet,
+            priority,
         };
         if let Err(e) = self.socket.send.send(outbound_packet).await {
             warn!("Failed to send outbound packet {}", e)
@@ -1357,26 +1848,20 @@ impl Handler {
 
     /// Check if any banned nodes have served their time and unban them.
     fn unban_nodes_check(&self) {
-        PERMIT_BAN_LIST
-            .write()
-            .ban_ips
-            .retain(|_, time| time.is_none() || Some(Instant::now()) < *time);
-        PERMIT_BAN_LIST
-            .write()
-            .ban_nodes
-            .retain(|_, time| time.is_none() || Some(Instant::now()) < *time);
+        PERMIT_BAN_LIST.write().remove_expired(Instant::now());
     }
 
     /// Returns whether a session with this node does not exist and a request that initiates
     /// a session has been sent.
     fn is_awaiting_session_to_be_established(&mut self, node_address: &NodeAddress) -> bool {
-        if self.sessions.get(node_address).is_some() {
-            // session exists
-            return false;
-        }
-
+        let session_exists = self.sessions.get(node_address).is_some();
         if let Some(requests) = self.active_requests.get(node_address) {
-            requests.iter().any(|req| req.initiating_session())
+            if session_exists {
+                // The session is kept while its keys are being renewed.
+                requests.iter().any(|req| req.renews_session())
+            } else {
+                requests.iter().any(|req| req.initiating_session())
+            }
         } else {
             false
         }
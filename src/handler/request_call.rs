@@ -2,9 +2,11 @@ pub use crate::node_info::NodeContact;
 use crate::{
     packet::Packet,
     rpc::{Request, RequestBody},
+    socket::SendPriority,
 };
 
 use super::HandlerReqId;
+use std::time::{Duration, Instant};
 
 /// A request to a node that we are waiting for a response.
 #[derive(Debug)]
@@ -26,6 +28,16 @@ pub(super) struct RequestCall {
     /// Signifies if we are initiating the session with a random packet. This is only used to
     /// determine the connection direction of the session.
     initiating_session: bool,
+    /// Signifies the handshake of this call renews the keys of the session with the node, so the
+    /// session it establishes is not reported as new.
+    renews_session: bool,
+    /// When the current packet was last sent.
+    sent_at: Instant,
+    /// Whether the current packet has been sent more than once, in which case a response can't
+    /// be attributed to a single transmission.
+    retransmitted: bool,
+    /// The timeout of the current transmission, if it differs from the default request timeout.
+    timeout: Option<Duration>,
 }
 
 impl RequestCall {
@@ -45,6 +57,10 @@ impl RequestCall {
             retries: 1,
             remaining_responses: None,
             initiating_session,
+            renews_session: false,
+            sent_at: Instant::now(),
+            retransmitted: false,
+            timeout: None,
         }
     }
 
@@ -63,6 +79,15 @@ impl RequestCall {
         &self.request
     }
 
+    /// The priority of the request's packets under the outbound rate limits. TALKREQs are sent
+    /// last, as applications may send them in bulk.
+    pub fn priority(&self) -> SendPriority {
+        match self.request {
+            RequestBody::Talk { .. } => SendPriority::Low,
+            _ => SendPriority::Normal,
+        }
+    }
+
     /// Returns the packet associated with this call.
     pub fn packet(&self) -> &Packet {
         &self.packet
@@ -88,6 +113,23 @@ impl RequestCall {
     /// Increments the number of retries for this call
     pub fn increment_retries(&mut self) {
         self.retries += 1;
+        self.sent_at = Instant::now();
+        self.retransmitted = true;
+    }
+
+    /// The round-trip time of the current packet, unless it has been retransmitted.
+    pub fn rtt(&self) -> Option<Duration> {
+        (!self.retransmitted).then(|| self.sent_at.elapsed())
+    }
+
+    /// The timeout of the current transmission, if it differs from the default request timeout.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Sets the timeout of the current transmission.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
     }
 
     /// Returns whether the handshake has been sent for this call or not.
@@ -110,9 +152,21 @@ impl RequestCall {
         self.initiating_session
     }
 
-    /// Updates the underlying packet for the call.
+    /// Indicates the handshake of this call renews the keys of a session.
+    pub fn set_renews_session(&mut self, state: bool) {
+        self.renews_session = state;
+    }
+
+    /// Returns whether the handshake of this call renews the keys of a session.
+    pub fn renews_session(&self) -> bool {
+        self.renews_session
+    }
+
+    /// Updates the underlying packet for the call, which is about to be sent.
     pub fn update_packet(&mut self, packet: Packet) {
         self.packet = packet;
+        self.sent_at = Instant::now();
+        self.retransmitted = false;
     }
 
     /// Gets a mutable reference to the remaining repsonses.
@@ -0,0 +1,83 @@
+//! Moves sessions to the new address of a peer whose IP or port has changed.
+//!
+//! A message from a known node id at an unknown address is decrypted with the session held at the
+//! node's previous address. If it is authentic, the message is dropped and a PING encrypted under
+//! the session is sent to the new address. The session moves to the new address once the PONG
+//! arrives from it.
+//!
+//! A replayed or spoofed packet could otherwise direct our traffic at any host, so each validation
+//! sends a single PING, the address of a node is validated at most once per [`ROAM_COOLDOWN`] and
+//! at most [`MAX_PENDING_VALIDATIONS`] validations run at once.
+use super::*;
+
+/// The minimum time between two validations of a new address of the same node.
+pub(super) const ROAM_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// The maximum number of addresses being validated at once.
+pub(super) const MAX_PENDING_VALIDATIONS: usize = 16;
+
+/// The maximum number of nodes whose cooldown is tracked.
+const RECENT_VALIDATIONS_CAPACITY: usize = 1000;
+
+/// A new address of a node awaiting the PONG that proves the node can be reached there.
+#[derive(Debug, Clone)]
+pub(super) struct PathValidation {
+    /// The address the session is held at.
+    pub old_address: NodeAddress,
+    /// The address the node has been seen at.
+    pub new_address: NodeAddress,
+    /// The id of the PING sent to the new address.
+    pub request_id: RequestId,
+}
+
+pub(super) struct PathValidations {
+    /// The validations awaiting a PONG, which expire after the request timeout.
+    pending: HashMapDelay<NodeId, PathValidation>,
+    /// The nodes whose addresses have been validated within the cooldown.
+    recent: LruTimeCache<NodeId, ()>,
+}
+
+impl PathValidations {
+    pub fn new(request_timeout: Duration) -> Self {
+        PathValidations {
+            pending: HashMapDelay::new(request_timeout),
+            recent: LruTimeCache::new(ROAM_COOLDOWN, Some(RECENT_VALIDATIONS_CAPACITY)),
+        }
+    }
+
+    /// Whether a new address of the node may be validated now.
+    pub fn may_start(&self, node_id: &NodeId) -> bool {
+        !self.pending.contains_key(node_id)
+            && self.recent.peek(node_id).is_none()
+            && self.pending.len() < MAX_PENDING_VALIDATIONS
+    }
+
+    /// Starts the cooldown of the node and awaits the PONG of the validation.
+    pub fn start(&mut self, validation: PathValidation) {
+        let node_id = validation.new_address.node_id;
+        self.recent.insert(node_id, ());
+        self.pending.insert(node_id, validation);
+    }
+
+    /// The validation of a new address of the node, if one is pending.
+    pub fn get(&self, node_id: &NodeId) -> Option<&PathValidation> {
+        self.pending.get(node_id)
+    }
+
+    pub fn remove(&mut self, node_id: &NodeId) -> Option<PathValidation> {
+        self.pending.remove(node_id)
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+impl Stream for PathValidations {
+    /// The validations that timed out.
+    type Item = Result<(NodeId, PathValidation), String>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.pending.poll_next_unpin(cx)
+    }
+}
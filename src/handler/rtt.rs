@@ -0,0 +1,118 @@
+//! Round-trip time estimation and adaptive request timeouts.
+//!
+//! The estimation follows the retransmission timer of TCP (RFC 6298): the timeout is the
+//! smoothed round-trip time plus four times its variation, and it doubles with every
+//! retransmission of the same request.
+use std::time::Duration;
+
+/// Bounds the adaptive request timeouts. See [`crate::ConfigBuilder::adaptive_request_timeout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveTimeoutConfig {
+    /// The shortest timeout applied to a request. Default: 200 milliseconds.
+    pub min_timeout: Duration,
+    /// The longest timeout applied to a request, including retransmissions. Default: 10
+    /// seconds.
+    pub max_timeout: Duration,
+}
+
+impl Default for AdaptiveTimeoutConfig {
+    fn default() -> Self {
+        AdaptiveTimeoutConfig {
+            min_timeout: Duration::from_millis(200),
+            max_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl AdaptiveTimeoutConfig {
+    /// The timeout of a request to a node with the given round-trip time estimate, after the
+    /// request has been sent `attempts` times. Without an estimate, `initial_timeout` is used.
+    pub(crate) fn timeout(
+        &self,
+        estimator: Option<&RttEstimator>,
+        initial_timeout: Duration,
+        attempts: u8,
+    ) -> Duration {
+        let base = estimator.map_or(initial_timeout, RttEstimator::timeout);
+        let backoff = 1u32 << attempts.saturating_sub(1).min(16);
+        base.saturating_mul(backoff)
+            .clamp(self.min_timeout, self.max_timeout.max(self.min_timeout))
+    }
+}
+
+/// A round-trip time estimate of a single node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RttEstimator {
+    /// The smoothed round-trip time.
+    srtt: Duration,
+    /// The round-trip time variation.
+    rttvar: Duration,
+}
+
+impl RttEstimator {
+    /// Creates an estimate from a first round-trip time sample.
+    pub(crate) fn new(sample: Duration) -> Self {
+        RttEstimator {
+            srtt: sample,
+            rttvar: sample / 2,
+        }
+    }
+
+    /// Adds a round-trip time sample to the estimate.
+    pub(crate) fn update(&mut self, sample: Duration) {
+        let deviation = self.srtt.abs_diff(sample);
+        self.rttvar = (self.rttvar * 3 + deviation) / 4;
+        self.srtt = (self.srtt * 7 + sample) / 8;
+    }
+
+    /// The retransmission timeout before backoff.
+    pub(crate) fn timeout(&self) -> Duration {
+        self.srtt + self.rttvar * 4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_converges() {
+        let mut estimator = RttEstimator::new(Duration::from_millis(100));
+        assert_eq!(estimator.timeout(), Duration::from_millis(300));
+        for _ in 0..50 {
+            estimator.update(Duration::from_millis(40));
+        }
+        assert!(estimator.srtt < Duration::from_millis(45));
+        assert!(estimator.timeout() < Duration::from_millis(60));
+    }
+
+    #[test]
+    fn timeout_backs_off_within_bounds() {
+        let config = AdaptiveTimeoutConfig {
+            min_timeout: Duration::from_millis(100),
+            max_timeout: Duration::from_secs(1),
+        };
+        let estimator = RttEstimator::new(Duration::from_millis(100));
+        let initial = Duration::from_secs(5);
+
+        assert_eq!(
+            config.timeout(Some(&estimator), initial, 1),
+            Duration::from_millis(300)
+        );
+        assert_eq!(
+            config.timeout(Some(&estimator), initial, 2),
+            Duration::from_millis(600)
+        );
+        assert_eq!(
+            config.timeout(Some(&estimator), initial, 3),
+            Duration::from_secs(1)
+        );
+        // Without an estimate, the initial timeout applies, within the bounds.
+        assert_eq!(config.timeout(None, initial, 1), Duration::from_secs(1));
+        let fast = RttEstimator::new(Duration::from_millis(1));
+        assert_eq!(
+            config.timeout(Some(&fast), initial, 1),
+            Duration::from_millis(100)
+        );
+    }
+}
@@ -6,14 +6,71 @@ use crate::{
     },
 };
 use enr::{CombinedKey, NodeId};
+use std::time::Instant;
 use zeroize::Zeroize;
 
+/// How long the keys replaced by a handshake are kept to decrypt packets that were in flight.
+const OLD_KEYS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// When the keys of a session are renewed through a fresh handshake. See
+/// [`crate::ConfigBuilder::session_rekey`].
+///
+/// The node that receives a request on a session which is due answers with a WHOAREYOU, so that
+/// the peer sends the request again in a handshake under new keys. Regardless of this setting, a
+/// session whose message counter is exhausted is never used to encrypt again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyConfig {
+    /// The number of messages that may be sent under the same keys. Default: 1,000,000.
+    pub max_messages: u32,
+    /// How long the same keys may be used. Default: 1 hour.
+    pub max_age: Duration,
+}
+
+impl Default for RekeyConfig {
+    fn default() -> Self {
+        RekeyConfig {
+            max_messages: 1_000_000,
+            max_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl RekeyConfig {
+    /// Checks that `max_messages` and `max_age` are non-zero.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.max_messages == 0 {
+            return Err("max_messages must be non-zero");
+        }
+        if self.max_age.is_zero() {
+            return Err("max_age must be non-zero");
+        }
+        Ok(())
+    }
+}
+
 #[derive(Zeroize, PartialEq)]
 pub(crate) struct Keys {
     /// The encryption key.
     encryption_key: [u8; 16],
     /// The decryption key.
     decryption_key: [u8; 16],
+    /// Number of messages encrypted with these keys. Used to ensure the nonce used in message
+    /// encryption is always unique.
+    counter: u32,
+    /// When the keys were derived.
+    #[zeroize(skip)]
+    created: Instant,
+}
+
+impl Keys {
+    fn new(encryption_key: [u8; 16], decryption_key: [u8; 16]) -> Self {
+        Keys {
+            encryption_key,
+            decryption_key,
+            counter: 0,
+            created: Instant::now(),
+        }
+    }
 }
 
 /// A Session containing the encryption/decryption keys. These are kept individually for a given
@@ -21,21 +78,19 @@ pub(crate) struct Keys {
 pub(crate) struct Session {
     /// The current keys used to encrypt/decrypt messages.
     keys: Keys,
-    /// If a new handshake is being established, the older keys are maintained as race
-    /// conditions in the handshake can give different views of which keys are canon.
-    /// The key that worked to decrypt our last message (or are freshly established) exist in
-    /// `keys` and previous keys are optionally stored in `old_keys`. We attempt to decrypt
-    /// messages with `keys` before optionally trying `old_keys`.
-    old_keys: Option<Keys>,
+    /// If a new handshake is being established, the older keys are maintained to decrypt packets
+    /// that were in flight during the handshake. The freshly established keys exist in `keys`
+    /// and previous keys are optionally stored in `old_keys`. We attempt to decrypt messages
+    /// with `keys` before optionally trying `old_keys`, which never become canonical again, as
+    /// they may be due for renewal. The old keys are dropped `OLD_KEYS_TIMEOUT` after they were
+    /// replaced.
+    old_keys: Option<(Keys, Instant)>,
     /// If we contacted this node without an ENR, i.e. via a multiaddr, during the session
     /// establishment we request the nodes ENR. Once the ENR is received and verified, this session
     /// becomes established.
     ///
     /// This field holds the request_id associated with the ENR request.
     pub awaiting_enr: Option<RequestId>,
-    /// Number of messages sent. Used to ensure the nonce used in message encryption is always
-    /// unique.
-    counter: u32,
 }
 
 impl Session {
@@ -44,14 +99,14 @@ impl Session {
             keys,
             old_keys: None,
             awaiting_enr: None,
-            counter: 0,
         }
     }
 
     /// A new session has been established. Update this session based on the new session.
     pub fn update(&mut self, new_session: Session) {
         // Optimistically assume the new keys are canonical.
-        self.old_keys = Some(std::mem::replace(&mut self.keys, new_session.keys));
+        let old_keys = std::mem::replace(&mut self.keys, new_session.keys);
+        self.old_keys = Some((old_keys, Instant::now() + OLD_KEYS_TIMEOUT));
         self.awaiting_enr = new_session.awaiting_enr;
     }
 
@@ -62,13 +117,16 @@ impl Session {
         src_id: NodeId,
         message: &[u8],
     ) -> Result<Packet, Error> {
-        self.counter += 1;
+        // Never wrap the counter, which would repeat nonces under the same keys.
+        self.keys.counter = self.keys.counter.checked_add(1).ok_or(Error::Custom(
+            "The message counter of the session is exhausted",
+        ))?;
 
         // If the message nonce length is ever set below 4 bytes this will explode. The packet
         // size constants shouldn't be modified.
         let random_nonce: [u8; MESSAGE_NONCE_LENGTH - 4] = rand::random();
         let mut message_nonce: MessageNonce = [0u8; MESSAGE_NONCE_LENGTH];
-        message_nonce[..4].copy_from_slice(&self.counter.to_be_bytes());
+        message_nonce[..4].copy_from_slice(&self.keys.counter.to_be_bytes());
         message_nonce[4..].copy_from_slice(&random_nonce);
 
         // the authenticated data is the IV concatenated with the packet header
@@ -96,9 +154,8 @@ impl Session {
         })
     }
 
-    /// Decrypts an encrypted message. If a Session is already established, the original decryption
-    /// keys are tried first, upon failure, the new keys are attempted. If the new keys succeed,
-    /// the session keys are updated along with the Session state.
+    /// Decrypts an encrypted message. The current decryption keys are tried first, upon failure,
+    /// the keys replaced by the latest handshake are attempted if they have not expired.
     pub(crate) fn decrypt_message(
         &mut self,
         message_nonce: MessageNonce,
@@ -115,18 +172,26 @@ impl Session {
         }
 
         // If these keys did not work, try old_keys
-        if let Some(old_keys) = self.old_keys.take() {
-            let result =
-                crypto::decrypt_message(&old_keys.decryption_key, message_nonce, message, aad);
-            if result.is_ok() {
-                // rotate the keys
-                self.old_keys = Some(std::mem::replace(&mut self.keys, old_keys));
+        if let Some((old_keys, expiry)) = self.old_keys.as_ref() {
+            if *expiry <= Instant::now() {
+                self.old_keys = None;
+                return result_canon;
             }
-            return result;
+            return crypto::decrypt_message(&old_keys.decryption_key, message_nonce, message, aad);
         }
         result_canon
     }
 
+    /// Whether the keys of the session are due to be renewed by a new handshake.
+    pub(crate) fn is_due_for_rekey(&self, config: &RekeyConfig) -> bool {
+        self.keys.counter >= config.max_messages || self.keys.created.elapsed() >= config.max_age
+    }
+
+    /// Whether no more messages can be encrypted under the current keys.
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.keys.counter == u32::MAX
+    }
+
     /* Session Helper Functions */
 
     /// Generates session keys from an authentication header. If the IP of the ENR does not match the
@@ -189,10 +254,7 @@ impl Session {
             ephem_pubkey,
         )?;
 
-        let keys = Keys {
-            encryption_key,
-            decryption_key,
-        };
+        let keys = Keys::new(encryption_key, decryption_key);
 
         // Takes ownership of the provided ENRs - Slightly annoying code duplication, but avoids
         // cloning ENRs
@@ -225,10 +287,7 @@ impl Session {
         let (encryption_key, decryption_key, ephem_pubkey) =
             crypto::generate_session_keys(local_node_id, remote_contact, challenge_data)?;
 
-        let keys = Keys {
-            encryption_key,
-            decryption_key,
-        };
+        let keys = Keys::new(encryption_key, decryption_key);
 
         // construct the nonce signature
         let sig = crypto::sign_nonce(
@@ -268,8 +327,69 @@ impl Session {
 
 #[cfg(test)]
 pub(crate) fn build_dummy_session() -> Session {
-    Session::new(Keys {
-        encryption_key: [0; 16],
-        decryption_key: [0; 16],
-    })
+    Session::new(Keys::new([0; 16], [0; 16]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::DefaultProtocolId;
+
+    fn encrypt(session: &mut Session) -> Option<Packet> {
+        session
+            .encrypt_message::<DefaultProtocolId>(NodeId::random(), b"message")
+            .ok()
+    }
+
+    #[test]
+    fn exhausted_keys_are_not_reused() {
+        let mut session = build_dummy_session();
+        session.keys.counter = u32::MAX - 1;
+        assert!(encrypt(&mut session).is_some());
+        assert!(session.is_exhausted());
+        assert!(encrypt(&mut session).is_none());
+
+        // New keys start a new count.
+        session.update(build_dummy_session());
+        assert!(!session.is_exhausted());
+        assert!(encrypt(&mut session).is_some());
+    }
+
+    #[test]
+    fn keys_are_due_by_count_or_age() {
+        let config = RekeyConfig {
+            max_messages: 2,
+            max_age: Duration::from_secs(60),
+        };
+        let mut session = build_dummy_session();
+        assert!(!session.is_due_for_rekey(&config));
+        encrypt(&mut session).unwrap();
+        encrypt(&mut session).unwrap();
+        assert!(session.is_due_for_rekey(&config));
+
+        let mut session = build_dummy_session();
+        session.keys.created -= config.max_age;
+        assert!(session.is_due_for_rekey(&config));
+    }
+
+    #[test]
+    fn old_keys_expire() {
+        let mut sender = build_dummy_session();
+        let mut receiver = build_dummy_session();
+        receiver.update(Session::new(Keys::new([1; 16], [1; 16])));
+
+        let packet = encrypt(&mut sender).unwrap();
+        let aad = packet.authenticated_data::<DefaultProtocolId>();
+        let nonce = packet.header.message_nonce;
+        assert!(receiver
+            .decrypt_message(nonce, &packet.message, &aad)
+            .is_ok());
+        // The old keys decrypted the message, but the new keys remain canonical.
+        assert!(receiver.keys.decryption_key == [1; 16]);
+
+        receiver.old_keys.as_mut().unwrap().1 = Instant::now();
+        assert!(receiver
+            .decrypt_message(nonce, &packet.message, &aad)
+            .is_err());
+    }
 }
@@ -34,7 +34,7 @@ async fn build_handler<P: ProtocolIdentity>(
     key: CombinedKey,
     config: Config,
 ) -> (
-    oneshot::Sender<()>,
+    oneshot::Sender<bool>,
     mpsc::UnboundedSender<HandlerIn>,
     mpsc::Receiver<HandlerOut>,
     Handler,
@@ -44,33 +44,40 @@ async fn build_handler<P: ProtocolIdentity>(
     let node_id = enr.node_id();
     let filter_expected_responses = Arc::new(RwLock::new(HashMap::new()));
 
-    let socket = {
-        let socket_config = {
-            let filter_config = FilterConfig {
-                enabled: config.enable_packet_filter,
-                rate_limiter: config.filter_rate_limiter.clone(),
-                max_nodes_per_ip: config.filter_max_nodes_per_ip,
-                max_bans_per_ip: config.filter_max_bans_per_ip,
-            };
-
-            socket::SocketConfig {
-                executor: config.executor.clone().expect("Executor must exist"),
-                filter_config,
-                listen_config: config.listen_config.clone(),
-                local_node_id: node_id,
-                expected_responses: filter_expected_responses.clone(),
-                ban_duration: config.ban_duration,
-            }
+    let socket_config = {
+        let filter_config = FilterConfig {
+            enabled: config.enable_packet_filter,
+            rate_limiter: config.filter_rate_limiter.clone(),
+            max_nodes_per_ip: config.filter_max_nodes_per_ip,
+            max_bans_per_ip: config.filter_max_bans_per_ip,
         };
 
-        Socket::new::<P>(socket_config).await.unwrap()
+        socket::SocketConfig {
+            executor: config.executor.clone().expect("Executor must exist"),
+            filter_config,
+            listen_config: config.listen_config.clone(),
+            local_node_id: node_id,
+            expected_responses: filter_expected_responses.clone(),
+            ban_duration: config.ban_duration,
+            outbound_rate_limiter: config.outbound_rate_limiter.clone(),
+            request_timeout: config.request_timeout,
+        }
     };
+    let socket = Socket::new::<P>(socket_config.clone()).await.unwrap();
     let (handler_send, service_recv) = mpsc::unbounded_channel();
     let (service_send, handler_recv) = mpsc::channel(50);
     let (exit_sender, exit) = oneshot::channel();
 
     let handler = Handler {
         request_retries: config.request_retries,
+        request_timeout: config.request_timeout,
+        adaptive_timeout: config.adaptive_request_timeout,
+        rtt_estimates: LruTimeCache::new(
+            config.session_timeout,
+            Some(config.session_cache_capacity),
+        ),
+        request_limiter: None,
+        permit_ban_autosave: None,
         node_id,
         enr: Arc::new(RwLock::new(enr)),
         key: Arc::new(RwLock::new(key)),
@@ -78,15 +85,19 @@ async fn build_handler<P: ProtocolIdentity>(
         pending_requests: HashMap::new(),
         filter_expected_responses,
         sessions: LruTimeCache::new(config.session_timeout, Some(config.session_cache_capacity)),
+        session_addresses: HashMap::new(),
+        session_rekey: config.session_rekey,
         one_time_sessions: LruTimeCache::new(
             Duration::from_secs(ONE_TIME_SESSION_TIMEOUT),
             Some(ONE_TIME_SESSION_CACHE_CAPACITY),
         ),
+        path_validations: PathValidations::new(config.request_timeout),
         active_challenges: HashMapDelay::new(config.request_timeout),
         service_recv,
         service_send,
         listen_sockets,
         socket,
+        socket_config,
         exit,
     };
     (exit_sender, handler_send, handler_recv, handler)
@@ -128,7 +139,7 @@ async fn simple_session_message() {
     let sender_config = ConfigBuilder::new(sender_listen_config)
         .enable_packet_filter()
         .build();
-    let (_exit_send, sender_send, _sender_recv) = Handler::spawn::<DefaultProtocolId>(
+    let (_exit_send, _exited, sender_send, _sender_recv) = Handler::spawn::<DefaultProtocolId>(
         arc_rw!(sender_enr.clone()),
         arc_rw!(key1),
         sender_config,
@@ -143,7 +154,7 @@ async fn simple_session_message() {
     let receiver_config = ConfigBuilder::new(receiver_listen_config)
         .enable_packet_filter()
         .build();
-    let (_exit_recv, recv_send, mut receiver_recv) = Handler::spawn::<DefaultProtocolId>(
+    let (_exit_recv, _exited, recv_send, mut receiver_recv) = Handler::spawn::<DefaultProtocolId>(
         arc_rw!(receiver_enr.clone()),
         arc_rw!(key2),
         receiver_config,
@@ -285,12 +296,12 @@ async fn multiple_messages() {
                         ));
                     }
                 }
-                Some(HandlerOut::Response(_, _)) => {
+                Some(HandlerOut::Response(..)) => {
                     response_count += 1;
                     if response_count == messages_to_send {
                         // Notify the handlers that the message exchange has been completed.
-                        sender_exit.send(()).unwrap();
-                        receiver_exit.send(()).unwrap();
+                        sender_exit.send(false).unwrap();
+                        receiver_exit.send(false).unwrap();
                         return;
                     }
                 }
@@ -520,7 +531,7 @@ async fn test_self_request_ipv4() {
         .enable_packet_filter()
         .build();
 
-    let (_exit_send, send, mut recv) =
+    let (_exit_send, _exited, send, mut recv) =
         Handler::spawn::<DefaultProtocolId>(arc_rw!(enr.clone()), arc_rw!(key), config)
             .await
             .unwrap();
@@ -560,7 +571,7 @@ async fn test_self_request_ipv6() {
         .enable_packet_filter()
         .build();
 
-    let (_exit_send, send, mut recv) =
+    let (_exit_send, _exited, send, mut recv) =
         Handler::spawn::<DefaultProtocolId>(arc_rw!(enr.clone()), arc_rw!(key), config)
             .await
             .unwrap();
@@ -781,14 +792,14 @@ async fn test_replay_active_requests() {
 
         loop {
             match sender_recv.recv().await {
-                Some(HandlerOut::Response(_, response)) => {
+                Some(HandlerOut::Response(_, response, _)) => {
                     assert!(expected_request_ids.remove(&response.id));
                     response_count += 1;
                     if response_count == messages_to_send {
                         // Notify the handlers that the message exchange has been completed.
                         assert!(expected_request_ids.is_empty());
-                        sender_exit.send(()).unwrap();
-                        receiver_exit.send(()).unwrap();
+                        sender_exit.send(false).unwrap();
+                        receiver_exit.send(false).unwrap();
                         return;
                     }
                 }
@@ -975,14 +986,14 @@ async fn test_send_pending_request() {
 
         loop {
             match sender_recv.recv().await {
-                Some(HandlerOut::Response(_, response)) => {
+                Some(HandlerOut::Response(_, response, _)) => {
                     assert!(expected_request_ids.remove(&response.id));
                     response_count += 1;
                     if response_count == messages_to_send {
                         // Notify the handlers that the message exchange has been completed.
                         assert!(expected_request_ids.is_empty());
-                        sender_exit.send(()).unwrap();
-                        receiver_exit.send(()).unwrap();
+                        sender_exit.send(false).unwrap();
+                        receiver_exit.send(false).unwrap();
                         return;
                     }
                 }
@@ -1037,3 +1048,308 @@ async fn test_send_pending_request() {
         }
     }
 }
+
+#[tokio::test]
+// Tests that a session follows a node whose port changes, once the new address answers a PING
+async fn session_roams_to_validated_address() {
+    init();
+    let sender_port = 5010;
+    let receiver_port = 5011;
+    let roamed_port = 5012;
+    let ip = "127.0.0.1".parse().unwrap();
+    let key1 = CombinedKey::generate_secp256k1();
+    let key2 = CombinedKey::generate_secp256k1();
+
+    let sender_enr = Enr::builder()
+        .ip4(ip)
+        .udp4(sender_port)
+        .build(&key1)
+        .unwrap();
+    let receiver_enr = Enr::builder()
+        .ip4(ip)
+        .udp4(receiver_port)
+        .build(&key2)
+        .unwrap();
+
+    let sender_config = ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip,
+        port: sender_port,
+    })
+    .build();
+    let (_sender_exit, _exited, sender_send, mut sender_recv) =
+        Handler::spawn::<DefaultProtocolId>(
+            arc_rw!(sender_enr.clone()),
+            arc_rw!(key1),
+            sender_config,
+        )
+        .await
+        .unwrap();
+    let receiver_config = ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip,
+        port: receiver_port,
+    })
+    .build();
+    let (_receiver_exit, _exited, receiver_send, mut receiver_recv) =
+        Handler::spawn::<DefaultProtocolId>(
+            arc_rw!(receiver_enr.clone()),
+            arc_rw!(key2),
+            receiver_config,
+        )
+        .await
+        .unwrap();
+
+    let ping = |id: u8| {
+        Box::new(Request {
+            id: RequestId(vec![id]),
+            body: RequestBody::Ping { enr_seq: 1 },
+        })
+    };
+    let pong = |id: RequestId| {
+        Box::new(Response {
+            id,
+            body: ResponseBody::Pong {
+                enr_seq: 1,
+                ip: ip.into(),
+                port: NonZeroU16::new(receiver_port).unwrap(),
+            },
+        })
+    };
+
+    let _ = sender_send.send(HandlerIn::Request(receiver_enr.clone().into(), ping(1)));
+
+    let sender_ops = async {
+        while let Some(message) = sender_recv.recv().await {
+            match message {
+                // The session is established, the sender's port changes.
+                HandlerOut::Response(..) => {
+                    let _ = sender_send.send(HandlerIn::Rebind(ListenConfig::Ipv4 {
+                        ip,
+                        port: roamed_port,
+                    }));
+                }
+                // This message is dropped until the new address is validated.
+                HandlerOut::Rebound(_) => {
+                    let _ =
+                        sender_send.send(HandlerIn::Request(receiver_enr.clone().into(), ping(2)));
+                }
+                // The path validation PING.
+                HandlerOut::Request(addr, request) => {
+                    let _ = sender_send.send(HandlerIn::Response(addr, pong(request.id)));
+                }
+                _ => {}
+            }
+        }
+    };
+
+    let receiver_ops = async {
+        while let Some(message) = receiver_recv.recv().await {
+            match message {
+                HandlerOut::WhoAreYou(wru_ref) => {
+                    let _ =
+                        receiver_send.send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())));
+                }
+                HandlerOut::Request(addr, request) => {
+                    if request.id == RequestId(vec![1]) {
+                        let _ = receiver_send.send(HandlerIn::Response(addr, pong(request.id)));
+                    } else {
+                        // Only requests sent after the session moved get through.
+                        assert_eq!(request, ping(3));
+                        assert_eq!(addr.socket_addr.port(), roamed_port);
+                        return;
+                    }
+                }
+                HandlerOut::SessionRoamed(node_address, from) => {
+                    assert_eq!(node_address.node_id, sender_enr.node_id());
+                    assert_eq!(node_address.socket_addr.port(), roamed_port);
+                    assert_eq!(from.port(), sender_port);
+                    let _ =
+                        sender_send.send(HandlerIn::Request(receiver_enr.clone().into(), ping(3)));
+                }
+                _ => {}
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = sender_ops => panic!("Sender handler stopped"),
+        _ = receiver_ops => {}
+        _ = sleep(Duration::from_secs(2)) => panic!("Test timed out"),
+    }
+}
+
+#[tokio::test]
+async fn path_validations_are_limited() {
+    let validation = |node_id: NodeId| roaming::PathValidation {
+        old_address: NodeAddress::new("127.0.0.1:9000".parse().unwrap(), node_id),
+        new_address: NodeAddress::new("127.0.0.1:9001".parse().unwrap(), node_id),
+        request_id: RequestId::random(),
+    };
+    let mut validations = PathValidations::new(Duration::from_secs(1));
+    let node_id = NodeId::random();
+    assert!(validations.may_start(&node_id));
+    validations.start(validation(node_id));
+    assert!(!validations.may_start(&node_id));
+
+    // A node is not validated again within the cooldown, even once its validation is over.
+    assert!(validations.remove(&node_id).is_some());
+    assert!(validations.is_empty());
+    assert!(!validations.may_start(&node_id));
+
+    for _ in 0..roaming::MAX_PENDING_VALIDATIONS {
+        let node_id = NodeId::random();
+        assert!(validations.may_start(&node_id));
+        validations.start(validation(node_id));
+    }
+    assert!(!validations.may_start(&NodeId::random()));
+}
+
+/// Sends five PINGs, one at a time, from a sender to a receiver handler with the given session
+/// rekey configurations. Returns the number of sessions either handler reported as established
+/// and the number of handshakes the receiver was asked to challenge. Panics if a request fails.
+async fn exchange_pings(
+    sender_port: u16,
+    sender_rekey: RekeyConfig,
+    receiver_port: u16,
+    receiver_rekey: RekeyConfig,
+) -> (usize, usize) {
+    let ip = "127.0.0.1".parse().unwrap();
+    let key1 = CombinedKey::generate_secp256k1();
+    let key2 = CombinedKey::generate_secp256k1();
+
+    let sender_enr = Enr::builder()
+        .ip4(ip)
+        .udp4(sender_port)
+        .build(&key1)
+        .unwrap();
+    let receiver_enr = Enr::builder()
+        .ip4(ip)
+        .udp4(receiver_port)
+        .build(&key2)
+        .unwrap();
+
+    let sender_config = ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip,
+        port: sender_port,
+    })
+    .session_rekey(sender_rekey)
+    .build();
+    let (_sender_exit, _exited, sender_send, mut sender_recv) =
+        Handler::spawn::<DefaultProtocolId>(
+            arc_rw!(sender_enr.clone()),
+            arc_rw!(key1),
+            sender_config,
+        )
+        .await
+        .unwrap();
+    let receiver_config = ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip,
+        port: receiver_port,
+    })
+    .session_rekey(receiver_rekey)
+    .build();
+    let (_receiver_exit, _exited, receiver_send, mut receiver_recv) =
+        Handler::spawn::<DefaultProtocolId>(
+            arc_rw!(receiver_enr.clone()),
+            arc_rw!(key2),
+            receiver_config,
+        )
+        .await
+        .unwrap();
+
+    let ping = |id: u8| {
+        Box::new(Request {
+            id: RequestId(vec![id]),
+            body: RequestBody::Ping { enr_seq: 1 },
+        })
+    };
+    let messages_to_send = 5u8;
+    let _ = sender_send.send(HandlerIn::Request(receiver_enr.clone().into(), ping(1)));
+
+    let mut sender_established = 0;
+    let mut receiver_established = 0;
+    let mut challenges = 0;
+    let sender_ops = async {
+        let mut responses = 0;
+        while let Some(message) = sender_recv.recv().await {
+            match message {
+                HandlerOut::Established(..) => sender_established += 1,
+                HandlerOut::RequestFailed(id, error) => panic!("Request {} failed: {}", id, error),
+                HandlerOut::Response(_, response, _) => {
+                    responses += 1;
+                    assert_eq!(response.id, RequestId(vec![responses]));
+                    if responses == messages_to_send {
+                        return;
+                    }
+                    // Requests are sent one at a time, so that each is answered in turn.
+                    let _ = sender_send.send(HandlerIn::Request(
+                        receiver_enr.clone().into(),
+                        ping(responses + 1),
+                    ));
+                }
+                _ => {}
+            }
+        }
+    };
+
+    let receiver_ops = async {
+        while let Some(message) = receiver_recv.recv().await {
+            match message {
+                HandlerOut::WhoAreYou(wru_ref) => {
+                    challenges += 1;
+                    let _ =
+                        receiver_send.send(HandlerIn::WhoAreYou(wru_ref, Some(sender_enr.clone())));
+                }
+                HandlerOut::Established(..) => receiver_established += 1,
+                HandlerOut::Request(addr, request) => {
+                    let response = Response {
+                        id: request.id,
+                        body: ResponseBody::Pong {
+                            enr_seq: 1,
+                            ip: ip.into(),
+                            port: NonZeroU16::new(sender_port).unwrap(),
+                        },
+                    };
+                    let _ = receiver_send.send(HandlerIn::Response(addr, Box::new(response)));
+                }
+                _ => {}
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = sender_ops => {}
+        _ = receiver_ops => panic!("Receiver handler stopped"),
+        _ = sleep(Duration::from_secs(2)) => panic!("Test timed out"),
+    }
+    (sender_established + receiver_established, challenges)
+}
+
+#[tokio::test]
+// Tests that a session is re-keyed through a new handshake once it has carried enough messages
+async fn session_keys_are_renewed() {
+    init();
+    let rekey = RekeyConfig {
+        max_messages: 2,
+        ..Default::default()
+    };
+    let (established, challenges) = exchange_pings(5013, Default::default(), 5014, rekey).await;
+    // The receiver challenges requests itself once the session is due. The renewed sessions are
+    // not reported.
+    assert_eq!(established, 2);
+    assert_eq!(challenges, 1);
+}
+
+#[tokio::test]
+// Tests that a node renews the keys of a session it sends requests on
+async fn session_keys_are_renewed_by_the_sender() {
+    init();
+    let rekey = RekeyConfig {
+        max_messages: 2,
+        ..Default::default()
+    };
+    let (established, challenges) = exchange_pings(5015, rekey, 5016, Default::default()).await;
+    // The sender starts a new handshake before its fourth request. Neither handler reports the
+    // renewed session.
+    assert_eq!(challenges, 2);
+    assert_eq!(established, 2);
+}
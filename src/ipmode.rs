@@ -29,6 +29,11 @@ impl IpMode {
             ListenConfig::Ipv4 { .. } => Ip4,
             ListenConfig::Ipv6 { .. } => Ip6,
             ListenConfig::DualStack { .. } => DualStack,
+            ListenConfig::Multi { .. } => match (listen_config.ipv4(), listen_config.ipv6()) {
+                (Some(_), Some(_)) => DualStack,
+                (None, Some(_)) => Ip6,
+                _ => Ip4,
+            },
         }
     }
 
@@ -67,6 +72,15 @@ impl IpMode {
             }
         }
     }
+
+    /// Whether a socket address can be contacted from the sockets of this mode.
+    pub(crate) fn is_reachable(&self, socket_addr: &SocketAddr) -> bool {
+        match (self, socket_addr) {
+            (DualStack, _) | (Ip4, SocketAddr::V4(_)) => true,
+            (Ip6, SocketAddr::V6(addr)) => to_ipv4_mapped(addr.ip()).is_none(),
+            _ => false,
+        }
+    }
 }
 
 /// Copied from the standard library. See <https://github.com/rust-lang/rust/issues/27709>
@@ -35,9 +35,18 @@
 //! an [`AppliedPending`] result which must be consumed by calling [`take_applied_pending`]
 //! regularly and / or after performing lookup operations like [`entry`] and [`closest_keys`].
 //!
+//! Nodes that are rejected because their bucket is full are kept in a bounded
+//! replacement cache per bucket, which can be inspected with [`iter_replacements`].
+//! When a bucket has room or its least-recently connected node is disconnected, the
+//! best candidate becomes the pending entry, but only once it is marked as connected.
+//! The keys of such candidates are obtained with [`take_revalidation`] so that they
+//! can be contacted.
+//!
 //! [`entry`]: KBucketsTable::entry
 //! [`closest_keys`]: KBucketsTable::closest_keys
 //! [`take_applied_pending`]: KBucketsTable::take_applied_pending
+//! [`iter_replacements`]: KBucketsTable::iter_replacements
+//! [`take_revalidation`]: KBucketsTable::take_revalidation
 
 // [Implementation Notes]
 //
@@ -54,7 +63,9 @@
 // 2. Replacement Cache
 //
 // In this implementation, the "replacement cache" for unresponsive peers
-// consists of a single entry per bucket. Furthermore, this implementation is
+// consists of a single pending entry per bucket, backed by a bounded list of
+// further candidates that are promoted to the pending slot one at a time,
+// once they respond. Furthermore, this implementation is
 // currently tailored to connection-oriented transports, meaning that the
 // "LRU"-based ordering of entries in a bucket is actually based on the last reported
 // connection status of the corresponding peers, from least-recently (dis)connected to
@@ -62,8 +73,9 @@
 // the nodes in the buckets are not reordered as a result of RPC activity, but only as a
 // result of nodes being marked as connected or disconnected. In particular,
 // if a bucket is full and contains only entries for peers that are considered
-// connected, no pending entry is accepted. See the `bucket` submodule for
-// further details.
+// connected, no pending entry is accepted. A pending entry replaces the disconnected
+// node with the lowest `NodeStats` score, the least-recently connected one among
+// equal scores. See the `bucket` submodule for further details.
 //
 // [0]: https://pdos.csail.mit.edu/~petar/papers/maymounkov-kademlia-lncs.pdf
 
@@ -71,17 +83,20 @@ mod bucket;
 mod entry;
 mod filter;
 mod key;
+mod snapshot;
+mod stats;
 
 pub use entry::*;
 
 pub use crate::handler::ConnectionDirection;
-use arrayvec::{self, ArrayVec};
 use bucket::KBucket;
 pub use bucket::{
     ConnectionState, FailureReason, InsertResult as BucketInsertResult, UpdateResult,
-    MAX_NODES_PER_BUCKET,
+    MAX_NODES_PER_BUCKET, MAX_REPLACEMENTS_PER_BUCKET,
 };
-pub use filter::{Filter, IpBucketFilter, IpTableFilter};
+pub use filter::{AllFilter, Filter, IpBucketFilter, IpLimits, IpTableFilter, SubnetLimit};
+pub use snapshot::{BucketSnapshot, EntrySnapshot, TableDiff, TableSnapshot};
+pub use stats::{NodeStats, MAX_RTT_SAMPLES};
 use std::{
     collections::VecDeque,
     time::{Duration, Instant},
@@ -153,6 +168,9 @@ pub struct KBucketsTable<TNodeId, TVal: Eq> {
     /// The list of evicted entries that have been replaced with pending
     /// entries since the last call to [`KBucketsTable::take_applied_pending`].
     applied_pending: VecDeque<AppliedPending<TNodeId, TVal>>,
+    /// The keys of replacement candidates that were promoted to the pending slot of their bucket
+    /// since the last call to [`KBucketsTable::take_revalidation`].
+    revalidations: VecDeque<Key<TNodeId>>,
     /// Filter to be applied at the table level when adding/updating a node.
     table_filter: Option<Box<dyn Filter<TVal>>>,
 }
@@ -165,7 +183,7 @@ pub enum InsertResult<TNodeId> {
     Inserted,
     /// The node was inserted into a pending state.
     Pending {
-        /// The key of the least-recently connected entry that is currently considered
+        /// The key of the lowest-scoring entry that is currently considered
         /// disconnected and whose corresponding peer should be checked for connectivity
         /// in order to prevent it from being evicted. If connectivity to the peer is
         /// re-established, the corresponding entry should be updated with
@@ -191,6 +209,20 @@ pub enum InsertResult<TNodeId> {
     Failed(FailureReason),
 }
 
+/// Applies the pending entry of `bucket` if its timeout has expired and promotes a replacement
+/// candidate into a free pending slot. Returns whether a pending entry was applied.
+fn maintain_bucket<TNodeId: Clone, TVal: Eq>(
+    bucket: &mut KBucket<TNodeId, TVal>,
+    applied_pending: &mut VecDeque<AppliedPending<TNodeId, TVal>>,
+    revalidations: &mut VecDeque<Key<TNodeId>>,
+) -> bool {
+    let applied = bucket.apply_pending();
+    let was_applied = applied.is_some();
+    applied_pending.extend(applied);
+    revalidations.extend(bucket.promote_replacement());
+    was_applied
+}
+
 /// A (type-safe) index into a `KBucketsTable`, i.e. a non-negative integer in the
 /// interval `[0, NUM_BUCKETS)`.
 #[derive(Copy, Clone)]
@@ -226,13 +258,16 @@ where
     ///
     /// The given `pending_timeout` specifies the duration after creation of
     /// a [`PendingEntry`] after which it becomes eligible for insertion into
-    /// a full bucket, replacing the least-recently (dis)connected node.
+    /// a full bucket, replacing the lowest-scoring disconnected node.
+    ///
+    /// Each bucket holds up to `bucket_size` entries, the `k` parameter of Kademlia.
     ///
     /// A filter can be applied that limits entries into a bucket based on the buckets contents.
     /// Entries that fail the filter, will not be inserted.
     pub fn new(
         local_key: Key<TNodeId>,
         pending_timeout: Duration,
+        bucket_size: usize,
         max_incoming_per_bucket: usize,
         table_filter: Option<Box<dyn Filter<TVal>>>,
         bucket_filter: Option<Box<dyn Filter<TVal>>>,
@@ -243,16 +278,32 @@ where
                 .map(|_| {
                     KBucket::new(
                         pending_timeout,
+                        bucket_size,
                         max_incoming_per_bucket,
                         bucket_filter.clone(),
                     )
                 })
                 .collect(),
             applied_pending: VecDeque::new(),
+            revalidations: VecDeque::new(),
             table_filter,
         }
     }
 
+    /// Replaces the filter applied at the table level. Nodes already in the table are not
+    /// re-checked against the new filter.
+    pub fn set_table_filter(&mut self, table_filter: Option<Box<dyn Filter<TVal>>>) {
+        self.table_filter = table_filter;
+    }
+
+    /// Replaces the filter applied to each bucket. Nodes already in the buckets are not
+    /// re-checked against the new filter.
+    pub fn set_bucket_filter(&mut self, bucket_filter: Option<Box<dyn Filter<TVal>>>) {
+        for bucket in self.buckets.iter_mut() {
+            bucket.set_filter(bucket_filter.clone());
+        }
+    }
+
     // Updates a node's status if it exists in the table.
     // This checks all table and bucket filters before performing the update.
     pub fn update_node_status(
@@ -264,11 +315,12 @@ where
         let index = BucketIndex::new(&self.local_key.distance(key));
         if let Some(i) = index {
             let bucket = &mut self.buckets[i.get()];
-            if let Some(applied) = bucket.apply_pending() {
-                self.applied_pending.push_back(applied)
-            }
+            maintain_bucket(bucket, &mut self.applied_pending, &mut self.revalidations);
 
-            bucket.update_status(key, state, direction)
+            let result = bucket.update_status(key, state, direction);
+            // A revalidated replacement may now be inserted, or a failed one replaced.
+            maintain_bucket(bucket, &mut self.applied_pending, &mut self.revalidations);
+            result
         } else {
             UpdateResult::NotModified // The key refers to our current node.
         }
@@ -310,9 +362,7 @@ where
         let index = BucketIndex::new(&self.local_key.distance(key));
         if let Some(i) = index {
             let bucket = &mut self.buckets[i.get()];
-            if let Some(applied) = bucket.apply_pending() {
-                self.applied_pending.push_back(applied)
-            }
+            maintain_bucket(bucket, &mut self.applied_pending, &mut self.revalidations);
 
             if !passed_table_filter {
                 bucket.remove(key);
@@ -327,7 +377,9 @@ where
 
             // If we need to update the connection state, update it here.
             let status_result = if let Some(state) = state {
-                bucket.update_status(key, state, None)
+                let result = bucket.update_status(key, state, None);
+                maintain_bucket(bucket, &mut self.applied_pending, &mut self.revalidations);
+                result
             } else {
                 UpdateResult::NotModified
             };
@@ -380,9 +432,7 @@ where
         let index = BucketIndex::new(&self.local_key.distance(key));
         if let Some(i) = index {
             let bucket = &mut self.buckets[i.get()];
-            if let Some(applied) = bucket.apply_pending() {
-                self.applied_pending.push_back(applied)
-            }
+            maintain_bucket(bucket, &mut self.applied_pending, &mut self.revalidations);
 
             if !passed_table_filter {
                 bucket.remove(key);
@@ -395,6 +445,7 @@ where
                     key: key.clone(),
                     value,
                     status,
+                    stats: Default::default(),
                 };
                 match bucket.insert(node) {
                     bucket::InsertResult::NodeExists => unreachable!("Node must exist"),
@@ -473,10 +524,11 @@ where
         let index = BucketIndex::new(&self.local_key.distance(key));
         if let Some(i) = index {
             let bucket = &mut self.buckets[i.get()];
-            if let Some(applied) = bucket.apply_pending() {
-                self.applied_pending.push_back(applied)
-            }
-            bucket.remove(key)
+            maintain_bucket(bucket, &mut self.applied_pending, &mut self.revalidations);
+            let removed = bucket.remove(key);
+            // The removal may have made room for a replacement.
+            maintain_bucket(bucket, &mut self.applied_pending, &mut self.revalidations);
+            removed
         } else {
             false
         }
@@ -490,9 +542,7 @@ where
         let index = BucketIndex::new(&self.local_key.distance(key));
         if let Some(i) = index {
             let bucket = &mut self.buckets[i.get()];
-            if let Some(applied) = bucket.apply_pending() {
-                self.applied_pending.push_back(applied)
-            }
+            maintain_bucket(bucket, &mut self.applied_pending, &mut self.revalidations);
             Entry::new(bucket, key)
         } else {
             Entry::SelfEntry
@@ -502,14 +552,29 @@ where
     /// Returns an iterator over all the entries in the routing table.
     pub fn iter(&mut self) -> impl Iterator<Item = EntryRefView<'_, TNodeId, TVal>> {
         let applied_pending = &mut self.applied_pending;
+        let revalidations = &mut self.revalidations;
         self.buckets.iter_mut().flat_map(move |table| {
-            if let Some(applied) = table.apply_pending() {
-                applied_pending.push_back(applied)
-            }
+            maintain_bucket(table, applied_pending, revalidations);
             table.iter().map(move |n| EntryRefView {
                 node: NodeRefView {
                     key: &n.key,
                     value: &n.value,
+                    stats: &n.stats,
+                },
+                status: n.status,
+            })
+        })
+    }
+
+    /// Returns an iterator over the replacement candidates of all buckets. The candidates of each
+    /// bucket are ordered from the best to the worst.
+    pub fn iter_replacements(&self) -> impl Iterator<Item = EntryRefView<'_, TNodeId, TVal>> {
+        self.buckets.iter().flat_map(move |table| {
+            table.replacements().map(move |n| EntryRefView {
+                node: NodeRefView {
+                    key: &n.key,
+                    value: &n.value,
+                    stats: &n.stats,
                 },
                 status: n.status,
             })
@@ -540,6 +605,7 @@ where
                 node: NodeRefView {
                     key: &n.key,
                     value: &n.value,
+                    stats: &n.stats,
                 },
                 status: n.status,
             })
@@ -562,6 +628,18 @@ where
         self.applied_pending.pop_front()
     }
 
+    /// Consumes the key of the next replacement candidate that was promoted to a pending slot, if
+    /// any.
+    ///
+    /// When a bucket has room, or its least-recently connected node is disconnected, and there is
+    /// no pending entry, the best candidate of its replacement cache becomes the pending entry.
+    /// Such an entry is only inserted once it has been updated as connected, so the corresponding
+    /// peer should be contacted. If it is updated as disconnected instead, the next candidate is
+    /// promoted.
+    pub fn take_revalidation(&mut self) -> Option<Key<TNodeId>> {
+        self.revalidations.pop_front()
+    }
+
     /// Returns an iterator over the keys that are contained in a kbucket, specified by a log2 distance.
     pub fn nodes_by_distances(
         &mut self,
@@ -586,8 +664,7 @@ where
             // The log2 distance ranges from 1-256 and is always 1 more than the bucket index. For this
             // reason we subtract 1 from log2 distance to get the correct bucket index.
             let bucket = &mut self.buckets[(distance - 1) as usize];
-            if let Some(applied) = bucket.apply_pending() {
-                self.applied_pending.push_back(applied);
+            if maintain_bucket(bucket, &mut self.applied_pending, &mut self.revalidations) {
                 // Break if we've reached the maximum number of nodes we will provide in the
                 // response. There's no need to apply pending buckets past this point, the nodes
                 // in those buckets won't be part of the response.
@@ -608,6 +685,7 @@ where
                 let node = NodeRefView {
                     key: &n.key,
                     value: &n.value,
+                    stats: &n.stats,
                 };
                 EntryRefView {
                     node,
@@ -639,7 +717,7 @@ where
             iter: None,
             table: self,
             buckets_iter: ClosestBucketsIter::new(distance),
-            fmap: |b: &KBucket<TNodeId, TVal>| -> ArrayVec<_, MAX_NODES_PER_BUCKET> {
+            fmap: |b: &KBucket<TNodeId, TVal>| -> Vec<_> {
                 b.iter().map(|n| n.key.clone()).collect()
             },
         }
@@ -661,7 +739,7 @@ where
             iter: None,
             table: self,
             buckets_iter: ClosestBucketsIter::new(distance),
-            fmap: |b: &KBucket<TNodeId, TVal>| -> ArrayVec<_, MAX_NODES_PER_BUCKET> {
+            fmap: |b: &KBucket<TNodeId, TVal>| -> Vec<_> {
                 b.iter()
                     .map(|n| ClosestValue {
                         key: n.key.clone(),
@@ -690,7 +768,7 @@ where
             iter: None,
             table: self,
             buckets_iter: ClosestBucketsIter::new(distance),
-            fmap: move |b: &KBucket<TNodeId, TVal>| -> ArrayVec<_, MAX_NODES_PER_BUCKET> {
+            fmap: move |b: &KBucket<TNodeId, TVal>| -> Vec<_> {
                 b.iter()
                     .map(|n| PredicateValue {
                         key: n.key.clone(),
@@ -713,6 +791,22 @@ where
         }
     }
 
+    /// Returns the request statistics of a node in the routing table or pending insertion.
+    pub fn node_stats(&self, key: &Key<TNodeId>) -> Option<&NodeStats> {
+        let bucket = self.get_bucket(key)?;
+        match bucket.get(key) {
+            Some(node) => Some(&node.stats),
+            None => bucket.as_pending(key).map(|pending| pending.stats()),
+        }
+    }
+
+    /// Returns the mutable request statistics of a node in the routing table or pending
+    /// insertion.
+    pub fn node_stats_mut(&mut self, key: &Key<TNodeId>) -> Option<&mut NodeStats> {
+        let index = BucketIndex::new(&self.local_key.distance(key))?;
+        self.buckets[index.get()].stats_mut(key)
+    }
+
     /// Returns a bucket index given the key. Returns None if bucket index does not exist.
     pub fn get_index(&self, key: &Key<TNodeId>) -> Option<usize> {
         let index = BucketIndex::new(&self.local_key.distance(key));
@@ -734,7 +828,7 @@ struct ClosestIter<'a, TTarget, TNodeId, TVal: Eq, TMap, TOut> {
     /// distance of the local key to the target.
     buckets_iter: ClosestBucketsIter,
     /// The iterator over the entries in the currently traversed bucket.
-    iter: Option<arrayvec::IntoIter<TOut, MAX_NODES_PER_BUCKET>>,
+    iter: Option<std::vec::IntoIter<TOut>>,
     /// The projection function / mapping applied on each bucket as
     /// it is encountered, producing the next `iter`ator.
     fmap: TMap,
@@ -839,7 +933,7 @@ impl<TTarget, TNodeId, TVal, TMap, TOut> Iterator
 where
     TNodeId: Clone,
     TVal: Eq,
-    TMap: Fn(&KBucket<TNodeId, TVal>) -> ArrayVec<TOut, MAX_NODES_PER_BUCKET>,
+    TMap: Fn(&KBucket<TNodeId, TVal>) -> Vec<TOut>,
     TOut: AsRef<Key<TNodeId>>,
 {
     type Item = TOut;
@@ -854,9 +948,11 @@ where
                 None => {
                     if let Some(i) = self.buckets_iter.next() {
                         let bucket = &mut self.table.buckets[i.get()];
-                        if let Some(applied) = bucket.apply_pending() {
-                            self.table.applied_pending.push_back(applied)
-                        }
+                        maintain_bucket(
+                            bucket,
+                            &mut self.table.applied_pending,
+                            &mut self.table.revalidations,
+                        );
                         let mut v = (self.fmap)(bucket);
                         v.sort_by(|a, b| {
                             self.target
@@ -901,6 +997,7 @@ mod tests {
             local_key,
             Duration::from_secs(5),
             MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
             None,
             None,
         );
@@ -925,6 +1022,7 @@ mod tests {
             local_key.clone(),
             Duration::from_secs(5),
             MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
             None,
             None,
         );
@@ -934,6 +1032,53 @@ mod tests {
         }
     }
 
+    #[test]
+    fn buckets_hold_bucket_size_entries() {
+        let local_key = Key::from(NodeId::random());
+        let bucket_size = 4;
+        let mut table = KBucketsTable::<_, ()>::new(
+            local_key,
+            Duration::from_secs(5),
+            bucket_size,
+            2,
+            None,
+            None,
+        );
+        // Half of all keys fall into the farthest bucket.
+        let mut inserted = 0;
+        while inserted < bucket_size {
+            let key = Key::from(NodeId::random());
+            if key.log2_distance(&table.local_key) != Some(256) {
+                continue;
+            }
+            let status = if inserted < 2 {
+                connected_state()
+            } else {
+                disconnected_state()
+            };
+            if let Entry::Absent(e) = table.entry(&key) {
+                assert_eq!(e.insert((), status), BucketInsertResult::Inserted);
+                inserted += 1;
+            }
+        }
+        assert_eq!(table.buckets[255].num_entries(), bucket_size);
+
+        let key = loop {
+            let key = Key::from(NodeId::random());
+            if key.log2_distance(&table.local_key) == Some(256) {
+                break key;
+            }
+        };
+        match table.entry(&key) {
+            Entry::Absent(e) => assert!(matches!(
+                e.insert((), connected_state()),
+                BucketInsertResult::Pending { .. }
+            )),
+            _ => panic!("entry exists"),
+        }
+        assert_eq!(table.buckets[255].num_entries(), bucket_size);
+    }
+
     #[test]
     fn closest() {
         let local_key = Key::from(NodeId::random());
@@ -941,6 +1086,7 @@ mod tests {
             local_key,
             Duration::from_secs(5),
             MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
             None,
             None,
         );
@@ -982,6 +1128,7 @@ mod tests {
             local_key,
             Duration::from_secs(5),
             MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
             None,
             None,
         );
@@ -1011,6 +1158,7 @@ mod tests {
             local_key.clone(),
             Duration::from_millis(1),
             MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
             None,
             None,
         );
@@ -1021,15 +1169,26 @@ mod tests {
             if let Entry::Absent(e) = table.entry(&key) {
                 match e.insert((), disconnected_state()) {
                     BucketInsertResult::Full => {
+                        // The rejected node is kept as a replacement candidate, so use another
+                        // key of the same bucket for the connected node.
+                        let index = key.log2_distance(&local_key);
+                        let key = loop {
+                            let key = Key::from(NodeId::random());
+                            if key.log2_distance(&local_key) == index {
+                                break key;
+                            }
+                        };
                         if let Entry::Absent(e) = table.entry(&key) {
                             match e.insert((), connected_state()) {
                                 BucketInsertResult::Pending { disconnected } => {
+                                    let stats = table.node_stats(&disconnected).cloned().unwrap();
                                     expected_applied = AppliedPending {
                                         inserted: key.clone(),
                                         evicted: Some(Node {
                                             key: disconnected,
                                             value: (),
                                             status: disconnected_state(),
+                                            stats,
                                         }),
                                     };
                                     full_bucket_index = BucketIndex::new(&key.distance(&local_key));
@@ -1072,4 +1231,50 @@ mod tests {
         assert_eq!(Some(expected_applied), table.take_applied_pending());
         assert_eq!(None, table.take_applied_pending());
     }
+
+    #[test]
+    fn replacement_revalidation() {
+        let local_key = Key::from(NodeId::random());
+        let mut table = KBucketsTable::<_, ()>::new(
+            local_key.clone(),
+            Duration::from_secs(5),
+            MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
+            None,
+            None,
+        );
+        // Fill a bucket with connected nodes until a node is rejected.
+        let candidate = loop {
+            let key = Key::from(NodeId::random());
+            if let Entry::Absent(e) = table.entry(&key) {
+                if let BucketInsertResult::Full = e.insert((), connected_state()) {
+                    break key;
+                }
+            }
+        };
+        assert_eq!(
+            vec![candidate.clone()],
+            table
+                .iter_replacements()
+                .map(|e| e.node.key.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(None, table.take_revalidation());
+
+        // Removing a node of the bucket promotes the candidate.
+        let removed = table
+            .get_bucket(&candidate)
+            .and_then(|bucket| bucket.iter().next())
+            .map(|node| node.key.clone())
+            .unwrap();
+        assert!(table.remove(&removed));
+        assert_eq!(Some(candidate.clone()), table.take_revalidation());
+        assert_eq!(0, table.iter_replacements().count());
+
+        // The candidate is inserted once it responds.
+        let _ = table.update_node_status(&candidate, ConnectionState::Connected, None);
+        let applied = table.take_applied_pending().unwrap();
+        assert_eq!(candidate, applied.inserted);
+        assert!(matches!(table.entry(&candidate), Entry::Present(..)));
+    }
 }
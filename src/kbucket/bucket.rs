@@ -33,9 +33,13 @@
 use super::*;
 use tracing::{debug, error};
 
-/// Maximum number of nodes in a bucket, i.e. the (fixed) `k` parameter.
+/// The default maximum number of nodes in a bucket, i.e. the `k` parameter. See
+/// [`crate::ConfigBuilder::bucket_size`].
 pub const MAX_NODES_PER_BUCKET: usize = 16;
 
+/// Maximum number of candidates in the replacement cache of a bucket.
+pub const MAX_REPLACEMENTS_PER_BUCKET: usize = 8;
+
 /// A `PendingNode` is a `Node` that is pending insertion into a `KBucket`.
 #[derive(Debug, Clone)]
 pub struct PendingNode<TNodeId, TVal: Eq> {
@@ -44,6 +48,14 @@ pub struct PendingNode<TNodeId, TVal: Eq> {
 
     /// The instant at which the pending node is eligible for insertion into a bucket.
     replace: Instant,
+
+    /// Whether the node was promoted from the replacement cache and must be marked as connected
+    /// before it is inserted.
+    revalidate: bool,
+
+    /// The disconnected node that is checked for connectivity and replaced by the pending node,
+    /// if the bucket was full when the pending node was added.
+    evict: Option<Key<TNodeId>>,
 }
 
 /// The status of a node in a bucket.
@@ -62,6 +74,11 @@ pub struct NodeStatus {
 
 /// The connection state of a node.
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum ConnectionState {
     /// The node is connected.
     Connected,
@@ -86,10 +103,27 @@ impl NodeStatus {
 }
 
 impl<TNodeId, TVal: Eq> PendingNode<TNodeId, TVal> {
+    pub fn key(&self) -> &Key<TNodeId> {
+        &self.node.key
+    }
+
     pub fn status(&self) -> NodeStatus {
         self.node.status
     }
 
+    pub fn value(&self) -> &TVal {
+        &self.node.value
+    }
+
+    pub fn stats(&self) -> &NodeStats {
+        &self.node.stats
+    }
+
+    /// Whether the node was promoted from the replacement cache and awaits revalidation.
+    pub fn requires_revalidation(&self) -> bool {
+        self.revalidate
+    }
+
     pub fn value_mut(&mut self) -> &mut TVal {
         &mut self.node.value
     }
@@ -110,19 +144,24 @@ pub struct Node<TNodeId, TVal: Eq> {
     pub value: TVal,
     /// The status of the node.
     pub status: NodeStatus,
+    /// The request statistics of the node.
+    pub stats: NodeStats,
 }
 
 /// The position of a node in a `KBucket`, i.e. a non-negative integer
-/// in the range `[0, MAX_NODES_PER_BUCKET)`.
+/// in the range `[0, max_nodes)`.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Position(usize);
 
-/// A `KBucket` is a list of up to `MAX_NODES_PER_BUCKET` `Key`s and associated values,
+/// A `KBucket` is a list of up to `max_nodes` `Key`s and associated values,
 /// ordered from least-recently connected to most-recently connected.
 #[derive(Clone)]
 pub struct KBucket<TNodeId, TVal: Eq> {
     /// The nodes contained in the bucket.
-    nodes: ArrayVec<Node<TNodeId, TVal>, MAX_NODES_PER_BUCKET>,
+    nodes: Vec<Node<TNodeId, TVal>>,
+
+    /// The maximum number of nodes in the bucket, i.e. the `k` parameter.
+    max_nodes: usize,
 
     /// The position (index) in `nodes` that marks the first connected node.
     ///
@@ -130,7 +169,7 @@ pub struct KBucket<TNodeId, TVal: Eq> {
     /// most-recently connected, all entries above this index are also considered
     /// connected, i.e. the range `[0, first_connected_pos)` marks the sub-list of entries
     /// that are considered disconnected and the range
-    /// `[first_connected_pos, MAX_NODES_PER_BUCKET)` marks sub-list of entries that are
+    /// `[first_connected_pos, max_nodes)` marks sub-list of entries that are
     /// considered connected.
     ///
     /// `None` indicates that there are no connected entries in the bucket, i.e.
@@ -139,10 +178,16 @@ pub struct KBucket<TNodeId, TVal: Eq> {
     first_connected_pos: Option<usize>,
 
     /// A node that is pending to be inserted into a full bucket, should the
-    /// least-recently connected (and currently disconnected) node not be
-    /// marked as connected within `unresponsive_timeout`.
+    /// lowest-scoring disconnected node not be marked as connected within
+    /// `unresponsive_timeout`.
     pending: Option<PendingNode<TNodeId, TVal>>,
 
+    /// Candidates that could not be inserted into the full bucket, ordered from the best to the
+    /// worst candidate. Connected candidates precede disconnected ones and within each group,
+    /// the most recently seen candidate comes first. When the pending slot is free, the best
+    /// candidate is promoted to it, see [`KBucket::promote_replacement`].
+    replacements: VecDeque<Node<TNodeId, TVal>>,
+
     /// The timeout window before a new pending node is eligible for insertion,
     /// if the least-recently connected node is not updated as being connected
     /// in the meantime.
@@ -153,7 +198,7 @@ pub struct KBucket<TNodeId, TVal: Eq> {
     filter: Option<Box<dyn Filter<TVal>>>,
 
     /// The maximum number of incoming connections allowed per bucket. Setting this to
-    /// MAX_NODES_PER_BUCKET means there is no restriction on incoming nodes.
+    /// `max_nodes` means there is no restriction on incoming nodes.
     max_incoming: usize,
 }
 
@@ -164,11 +209,10 @@ pub enum InsertResult<TNodeId> {
     /// The entry has been successfully inserted.
     Inserted,
     /// The entry is pending insertion because the relevant bucket is currently full.
-    /// The entry is inserted after a timeout elapsed, if the status of the
-    /// least-recently connected (and currently disconnected) node in the bucket
-    /// is not updated before the timeout expires.
+    /// The entry is inserted after a timeout elapsed, replacing the `disconnected` node,
+    /// if the status of that node is not updated before the timeout expires.
     Pending {
-        /// The key of the least-recently connected entry that is currently considered
+        /// The key of the lowest-scoring entry that is currently considered
         /// disconnected and whose corresponding peer should be checked for connectivity
         /// in order to prevent it from being evicted. If connectivity to the peer is
         /// re-established, the corresponding entry should be updated with a connected status.
@@ -240,22 +284,31 @@ where
     TNodeId: Clone,
     TVal: Eq,
 {
-    /// Creates a new `KBucket` with the given timeout for pending entries.
+    /// Creates a new `KBucket` holding up to `max_nodes` nodes, with the given timeout for
+    /// pending entries.
     pub fn new(
         pending_timeout: Duration,
+        max_nodes: usize,
         max_incoming: usize,
         filter: Option<Box<dyn Filter<TVal>>>,
     ) -> Self {
         KBucket {
-            nodes: ArrayVec::new(),
+            nodes: Vec::with_capacity(max_nodes),
+            max_nodes,
             first_connected_pos: None,
             pending: None,
+            replacements: VecDeque::new(),
             pending_timeout,
             filter,
             max_incoming,
         }
     }
 
+    /// Replaces the filter applied to new entries of the bucket.
+    pub fn set_filter(&mut self, filter: Option<Box<dyn Filter<TVal>>>) {
+        self.filter = filter;
+    }
+
     /// Returns a reference to the pending node of the bucket, if there is any.
     pub fn pending(&self) -> Option<&PendingNode<TNodeId, TVal>> {
         self.pending.as_ref()
@@ -277,23 +330,97 @@ where
         self.nodes.iter()
     }
 
+    /// Returns an iterator over the replacement cache of the bucket, from the best to the worst
+    /// candidate.
+    pub fn replacements(&self) -> impl Iterator<Item = &Node<TNodeId, TVal>> {
+        self.replacements.iter()
+    }
+
+    /// Adds a candidate to the replacement cache, replacing an older record of the same node. If
+    /// the cache is full, the worst candidate is dropped.
+    fn add_replacement(&mut self, node: Node<TNodeId, TVal>) {
+        self.replacements
+            .retain(|candidate| candidate.key != node.key);
+        let pos = if node.status.is_connected() {
+            0
+        } else {
+            self.replacements
+                .iter()
+                .take_while(|candidate| candidate.status.is_connected())
+                .count()
+        };
+        self.replacements.insert(pos, node);
+        self.replacements.truncate(MAX_REPLACEMENTS_PER_BUCKET);
+    }
+
+    /// Promotes the best candidate of the replacement cache to the pending slot, if the slot is
+    /// free and the candidate could be inserted, either because the bucket has room or because its
+    /// least-recently connected node is disconnected.
+    ///
+    /// The promoted node must be revalidated by being marked as connected, before it is inserted
+    /// into the bucket. Its key is returned, so that it can be contacted.
+    pub fn promote_replacement(&mut self) -> Option<Key<TNodeId>> {
+        if self.pending.is_some() || (self.is_full() && self.nodes[0].status.is_connected()) {
+            return None;
+        }
+        let node = self.replacements.pop_front()?;
+        let key = node.key.clone();
+        let evict = self
+            .is_full()
+            .then(|| self.nodes[self.eviction_position()].key.clone());
+        self.pending = Some(PendingNode {
+            node,
+            replace: Instant::now() + self.pending_timeout,
+            revalidate: true,
+            evict,
+        });
+        Some(key)
+    }
+
+    /// The position of the node to be replaced by a pending node: the disconnected node with the
+    /// lowest score, the least-recently connected one among equal scores.
+    fn eviction_position(&self) -> usize {
+        let disconnected = self.first_connected_pos.unwrap_or(self.nodes.len());
+        self.nodes[..disconnected]
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.stats.score().total_cmp(&b.stats.score()))
+            .map_or(0, |(position, _)| position)
+    }
+
     /// Inserts the pending node into the bucket, if its timeout has elapsed,
-    /// replacing the least-recently connected node.
+    /// replacing the disconnected node chosen when the pending node was added.
     ///
     /// If a pending node has been inserted, its key is returned together with
     /// the node that was replaced. `None` indicates that the nodes in the
     /// bucket remained unchanged.
     pub fn apply_pending(&mut self) -> Option<AppliedPending<TNodeId, TVal>> {
-        if let Some(pending) = self.pending.take() {
+        if let Some(mut pending) = self.pending.take() {
             if pending.replace <= Instant::now() {
+                // A promoted replacement that did not respond is dropped.
+                if pending.revalidate {
+                    return None;
+                }
                 // Check if the bucket is full
-                if self.nodes.is_full() {
-                    // Apply bucket filters
+                if self.is_full() {
+                    // Only the node that was checked for connectivity is replaced.
+                    let disconnected = self.first_connected_pos.unwrap_or(self.nodes.len());
+                    let evict_position = pending.evict.as_ref().and_then(|evict| {
+                        self.nodes[..disconnected]
+                            .iter()
+                            .position(|node| &node.key == evict)
+                    });
+                    let evict_position = match evict_position {
+                        Some(position) => position,
+                        None => {
+                            // The node reconnected or was replaced. Keep the pending node as a
+                            // replacement.
+                            self.add_replacement(pending.node);
+                            return None;
+                        }
+                    };
 
-                    if self.nodes[0].status.is_connected() {
-                        // The bucket is full with connected nodes. Drop the pending node.
-                        return None;
-                    }
+                    // Apply bucket filters
                     // Check the custom filter
                     if let Some(filter) = self.filter.as_ref() {
                         if !filter.filter(
@@ -317,10 +444,11 @@ where
 
                     // The pending node will be inserted.
                     let inserted = pending.node.key.clone();
+                    pending.node.stats.record_insertion();
                     // A connected pending node goes at the end of the list for
-                    // the connected peers, removing the least-recently connected.
+                    // the connected peers, removing the chosen disconnected node.
                     if pending.status().is_connected() {
-                        let evicted = Some(self.nodes.remove(0));
+                        let evicted = Some(self.nodes.remove(evict_position));
                         self.first_connected_pos = self
                             .first_connected_pos
                             .map_or_else(|| Some(self.nodes.len()), |p| p.checked_sub(1));
@@ -331,14 +459,14 @@ where
                     // for the disconnected peers.
                     else if let Some(p) = self.first_connected_pos {
                         if let Some(insert_pos) = p.checked_sub(1) {
-                            let evicted = Some(self.nodes.remove(0));
+                            let evicted = Some(self.nodes.remove(evict_position));
                             self.nodes.insert(insert_pos, pending.node);
                             return Some(AppliedPending { inserted, evicted });
                         }
                     } else {
                         // All nodes are disconnected. Insert the new node as the most
-                        // recently disconnected, removing the least-recently disconnected.
-                        let evicted = Some(self.nodes.remove(0));
+                        // recently disconnected, removing the chosen one.
+                        let evicted = Some(self.nodes.remove(evict_position));
                         self.nodes.push(pending.node);
                         return Some(AppliedPending { inserted, evicted });
                     }
@@ -394,6 +522,12 @@ where
         // nodes (i.e. most-recently disconnected or most-recently connected,
         // respectively).
         if let Some(pos) = self.position(key) {
+            // Whether a pending node would replace this node.
+            let is_eviction_candidate = self
+                .pending
+                .as_ref()
+                .and_then(|pending| pending.evict.as_ref())
+                == Some(key);
             // Remove the node from its current position.
             let mut node = self.nodes.remove(pos.0);
             let old_status = node.status;
@@ -422,10 +556,12 @@ where
                         self.first_connected_pos.and_then(|p| p.checked_sub(1))
                 }
             }
-            // If the least-recently connected node re-establishes its
-            // connected status, drop the pending node.
-            if pos == Position(0) && is_connected {
-                self.pending = None
+            // If the node that a pending node would replace re-establishes its
+            // connected status, the pending node returns to the replacement cache.
+            if is_eviction_candidate && is_connected {
+                if let Some(pending) = self.pending.take() {
+                    self.add_replacement(pending.node);
+                }
             }
             // Reinsert the node with the desired status.
             match self.insert(node) {
@@ -459,16 +595,34 @@ where
                     unreachable!("The node was removed so can't be added as pending")
                 }
             }
-        } else if let Some(pending) = &mut self.pending {
-            if &pending.node.key == key {
-                pending.node.status.state = state;
-                if let Some(direction) = direction {
-                    pending.node.status.direction = direction;
+        } else if let Some(pending) = self.pending.as_mut().filter(|p| &p.node.key == key) {
+            let is_full = self.nodes.len() >= self.max_nodes;
+            pending.node.status.state = state;
+            if let Some(direction) = direction {
+                pending.node.status.direction = direction;
+            }
+            if pending.revalidate {
+                if pending.node.status.is_connected() {
+                    // The promoted replacement responded. If there is room, it is inserted
+                    // immediately.
+                    pending.revalidate = false;
+                    if !is_full {
+                        pending.replace = Instant::now();
+                    }
+                } else {
+                    // The promoted replacement is unresponsive, make room for the next one.
+                    self.pending = None;
                 }
-                UpdateResult::UpdatedPending
-            } else {
-                UpdateResult::Failed(FailureReason::KeyNonExistent)
             }
+            UpdateResult::UpdatedPending
+        } else if let Some(pos) = self.replacements.iter().position(|n| &n.key == key) {
+            let mut node = self.replacements.remove(pos).expect("Position is valid");
+            node.status.state = state;
+            if let Some(direction) = direction {
+                node.status.direction = direction;
+            }
+            self.add_replacement(node);
+            UpdateResult::UpdatedPending
         } else {
             UpdateResult::Failed(FailureReason::KeyNonExistent)
         }
@@ -501,13 +655,12 @@ where
                 self.nodes.insert(pos, node);
                 UpdateResult::Updated
             }
-        } else if let Some(pending) = &mut self.pending {
-            if &pending.node.key == key {
-                pending.node.value = value;
-                UpdateResult::UpdatedPending
-            } else {
-                UpdateResult::Failed(FailureReason::KeyNonExistent)
-            }
+        } else if let Some(pending) = self.pending.as_mut().filter(|p| &p.node.key == key) {
+            pending.node.value = value;
+            UpdateResult::UpdatedPending
+        } else if let Some(node) = self.replacements.iter_mut().find(|n| &n.key == key) {
+            node.value = value;
+            UpdateResult::UpdatedPending
         } else {
             UpdateResult::Failed(FailureReason::KeyNonExistent)
         }
@@ -534,11 +687,12 @@ where
     /// to be inserted that doesn't pass the bucket filter, [`InsertResult::FailedFilter`] will be
     /// returned. Similarly, if the inserted node would violate the `max_incoming` value, the
     /// result will return [`InsertResult::TooManyIncoming`].
-    pub fn insert(&mut self, node: Node<TNodeId, TVal>) -> InsertResult<TNodeId> {
+    pub fn insert(&mut self, mut node: Node<TNodeId, TVal>) -> InsertResult<TNodeId> {
         // Prevent inserting duplicate nodes.
         if self.position(&node.key).is_some() {
             return InsertResult::NodeExists;
         }
+        let key = node.key.clone();
 
         // check bucket filter
         if let Some(filter) = self.filter.as_ref() {
@@ -561,30 +715,50 @@ where
                         return InsertResult::TooManyIncoming;
                     }
                 }
-                if self.nodes.is_full() {
-                    if self.first_connected_pos == Some(0) || self.pending.is_some() {
+                if self.is_full() {
+                    // A connected node takes precedence over a replacement that has yet to be
+                    // revalidated.
+                    let pending_blocks = self
+                        .pending
+                        .as_ref()
+                        .is_some_and(|pending| inserting_pending || !pending.revalidate);
+                    if self.first_connected_pos == Some(0) || pending_blocks {
+                        if !inserting_pending {
+                            self.add_replacement(node);
+                        }
                         return InsertResult::Full;
                     } else {
+                        if let Some(pending) = self.pending.take() {
+                            self.add_replacement(pending.node);
+                        }
+                        self.replacements
+                            .retain(|candidate| candidate.key != node.key);
+                        let disconnected = self.nodes[self.eviction_position()].key.clone();
                         self.pending = Some(PendingNode {
                             node,
                             replace: Instant::now() + self.pending_timeout,
+                            revalidate: false,
+                            evict: Some(disconnected.clone()),
                         });
-                        return InsertResult::Pending {
-                            disconnected: self.nodes[0].key.clone(),
-                        };
+                        return InsertResult::Pending { disconnected };
                     }
                 }
 
                 let pos = self.nodes.len();
                 self.first_connected_pos = self.first_connected_pos.or(Some(pos));
+                node.stats.record_insertion();
                 self.nodes.push(node);
                 InsertResult::Inserted
             }
             ConnectionState::Disconnected => {
-                if self.nodes.is_full() {
+                if self.is_full() {
+                    if !inserting_pending {
+                        self.add_replacement(node);
+                    }
                     return InsertResult::Full;
                 }
 
+                node.stats.record_insertion();
                 if let Some(ref mut first_connected_pos) = self.first_connected_pos {
                     self.nodes.insert(*first_connected_pos, node);
                     *first_connected_pos += 1;
@@ -598,8 +772,11 @@ where
         // If we inserted the node, make sure there is no pending node of the same key. This can
         // happen when a pending node is inserted, a node gets removed from the bucket, freeing up
         // space and then re-inserted here.
-        if matches!(insert_result, InsertResult::Inserted) && inserting_pending {
-            self.pending = None
+        if matches!(insert_result, InsertResult::Inserted) {
+            if inserting_pending {
+                self.pending = None
+            }
+            self.replacements.retain(|candidate| candidate.key != key);
         }
         insert_result
     }
@@ -657,6 +834,19 @@ where
         self.nodes.iter_mut().find(move |p| &p.key == key)
     }
 
+    /// Gets the mutable request statistics of the node or the pending node identified by the
+    /// given key.
+    pub fn stats_mut(&mut self, key: &Key<TNodeId>) -> Option<&mut NodeStats> {
+        match self.position(key) {
+            Some(Position(position)) => Some(&mut self.nodes[position].stats),
+            None => self
+                .pending
+                .as_mut()
+                .filter(|pending| &pending.node.key == key)
+                .map(|pending| &mut pending.node.stats),
+        }
+    }
+
     /// Gets a reference to the node identified by the given key.
     ///
     /// Returns `None` if the given key does not refer to an node in the
@@ -665,6 +855,11 @@ where
         self.nodes.iter().find(move |p| &p.key == key)
     }
 
+    /// Returns whether the bucket holds `max_nodes` nodes.
+    fn is_full(&self) -> bool {
+        self.nodes.len() >= self.max_nodes
+    }
+
     /// Returns whether the bucket has reached its maximum capacity of incoming nodes. This is used
     /// to determine if new nodes can be added to the bucket or not.
     fn is_max_incoming(&self) -> bool {
@@ -698,8 +893,10 @@ impl<TNodeId: std::fmt::Debug, TVal: Eq + std::fmt::Debug> std::fmt::Debug
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("KBucket")
             .field("nodes", &self.nodes)
+            .field("max_nodes", &self.max_nodes)
             .field("first_connected_pos", &self.first_connected_pos)
             .field("pending", &self.pending)
+            .field("replacements", &self.replacements)
             .field("pending_timeout", &self.pending_timeout)
             .field("filter", &self.filter.is_some())
             .field("max_incoming", &self.max_incoming)
@@ -795,7 +992,12 @@ pub mod tests {
     {
         fn arbitrary<G: Gen>(g: &mut G) -> KBucket<NodeId, V> {
             let timeout = Duration::from_secs(g.gen_range(1, g.size() as u64));
-            let mut bucket = KBucket::<NodeId, V>::new(timeout, MAX_NODES_PER_BUCKET, None);
+            let mut bucket = KBucket::<NodeId, V>::new(
+                timeout,
+                MAX_NODES_PER_BUCKET,
+                MAX_NODES_PER_BUCKET,
+                None,
+            );
             let num_nodes = g.gen_range(1, MAX_NODES_PER_BUCKET + 1);
             for _ in 0..num_nodes {
                 loop {
@@ -821,6 +1023,7 @@ pub mod tests {
                 key,
                 value: V::arbitrary(g),
                 status: NodeStatus::arbitrary(g),
+                stats: Default::default(),
             }
         }
     }
@@ -864,6 +1067,7 @@ pub mod tests {
                 key,
                 value: (),
                 status,
+                stats: Default::default(),
             };
             assert_eq!(InsertResult::Inserted, bucket.insert(node));
             assert_eq!(bucket.num_entries(), num_entries_start + i + 1);
@@ -980,8 +1184,12 @@ pub mod tests {
     #[test]
     fn ordering() {
         fn prop(status: Vec<NodeStatus>) -> bool {
-            let mut bucket =
-                KBucket::<NodeId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET, None);
+            let mut bucket = KBucket::<NodeId, ()>::new(
+                Duration::from_secs(1),
+                MAX_NODES_PER_BUCKET,
+                MAX_NODES_PER_BUCKET,
+                None,
+            );
 
             // The expected lists of connected and disconnected nodes.
             let mut connected = VecDeque::new();
@@ -994,6 +1202,7 @@ pub mod tests {
                     key: key.clone(),
                     value: (),
                     status,
+                    stats: Default::default(),
                 };
                 let full = bucket.num_entries() == MAX_NODES_PER_BUCKET;
                 if let InsertResult::Inserted = bucket.insert(node) {
@@ -1031,8 +1240,12 @@ pub mod tests {
 
     #[test]
     fn full_bucket() {
-        let mut bucket =
-            KBucket::<NodeId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET, None);
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
+            None,
+        );
 
         let disconnected_status = NodeStatus {
             state: ConnectionState::Disconnected,
@@ -1047,6 +1260,7 @@ pub mod tests {
             key,
             value: (),
             status: disconnected_status,
+            stats: Default::default(),
         };
         match bucket.insert(node) {
             InsertResult::Full => {}
@@ -1066,6 +1280,7 @@ pub mod tests {
                 key: key.clone(),
                 value: (),
                 status: connected_state(),
+                stats: Default::default(),
             };
             match bucket.insert(node.clone()) {
                 InsertResult::Pending { disconnected } => {
@@ -1113,6 +1328,7 @@ pub mod tests {
             key,
             value: (),
             status: connected_state(),
+            stats: Default::default(),
         };
         match bucket.insert(node) {
             InsertResult::Full => {}
@@ -1122,8 +1338,12 @@ pub mod tests {
 
     #[test]
     fn full_bucket_discard_pending() {
-        let mut bucket =
-            KBucket::<NodeId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET, None);
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
+            None,
+        );
         fill_bucket(&mut bucket, disconnected_state());
         let first = bucket.iter().next().unwrap();
         let first_disconnected = first.clone();
@@ -1134,6 +1354,7 @@ pub mod tests {
             key: key.clone(),
             value: (),
             status: connected_state(),
+            stats: Default::default(),
         };
         if let InsertResult::Pending { disconnected } = bucket.insert(node) {
             assert_eq!(&disconnected, &first_disconnected.key);
@@ -1166,8 +1387,12 @@ pub mod tests {
     #[test]
     fn full_bucket_applied_no_duplicates() {
         // First fill the bucket with connected nodes.
-        let mut bucket =
-            KBucket::<NodeId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET, None);
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
+            None,
+        );
         fill_bucket(&mut bucket, connected_state());
 
         let first = bucket.iter().next().unwrap().clone();
@@ -1187,6 +1412,7 @@ pub mod tests {
             key,
             value: (),
             status: connected_state(),
+            stats: Default::default(),
         };
 
         // Add a pending node
@@ -1319,8 +1545,12 @@ pub mod tests {
         ) -> bool {
             let filter = SetFilter { set: filter_set };
             let pending_timeout = Duration::from_millis(pending_timeout_millis);
-            let mut kbucket =
-                KBucket::<NodeId, u8>::new(pending_timeout, max_incoming, Some(Box::new(filter)));
+            let mut kbucket = KBucket::<NodeId, u8>::new(
+                pending_timeout,
+                MAX_NODES_PER_BUCKET,
+                max_incoming,
+                Some(Box::new(filter)),
+            );
 
             for node in initial_nodes {
                 let _ = kbucket.insert(node);
@@ -1342,7 +1572,12 @@ pub mod tests {
     #[test]
     fn table_update_status_connection() {
         let max_incoming = 7;
-        let mut bucket = KBucket::<NodeId, ()>::new(Duration::from_secs(1), max_incoming, None);
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            max_incoming,
+            None,
+        );
 
         let mut incoming_connected = 0;
         let mut keys = Vec::new();
@@ -1363,6 +1598,7 @@ pub mod tests {
                 key: key.clone(),
                 value: (),
                 status,
+                stats: Default::default(),
             };
             assert_eq!(InsertResult::Inserted, bucket.insert(node));
         }
@@ -1399,8 +1635,12 @@ pub mod tests {
     fn bucket_max_incoming_nodes() {
         fn prop(status: Vec<NodeStatus>) -> bool {
             let max_incoming_nodes = 5;
-            let mut bucket =
-                KBucket::<NodeId, ()>::new(Duration::from_secs(1), max_incoming_nodes, None);
+            let mut bucket = KBucket::<NodeId, ()>::new(
+                Duration::from_secs(1),
+                MAX_NODES_PER_BUCKET,
+                max_incoming_nodes,
+                None,
+            );
 
             // The expected lists of connected and disconnected nodes.
             let mut connected = VecDeque::new();
@@ -1413,6 +1653,7 @@ pub mod tests {
                     key: key.clone(),
                     value: (),
                     status,
+                    stats: Default::default(),
                 };
                 let full = bucket.num_entries() == MAX_NODES_PER_BUCKET;
                 match bucket.insert(node) {
@@ -1455,4 +1696,273 @@ pub mod tests {
 
         quickcheck(prop as fn(_) -> _);
     }
+
+    #[test]
+    fn replacement_cache_ordering() {
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
+            None,
+        );
+        fill_bucket(&mut bucket, connected_state());
+
+        let insert = |bucket: &mut KBucket<NodeId, ()>, status| {
+            let key = Key::from(NodeId::random());
+            let node = Node {
+                key: key.clone(),
+                value: (),
+                status,
+                stats: Default::default(),
+            };
+            assert_eq!(InsertResult::Full, bucket.insert(node));
+            key
+        };
+        let disconnected = (0..MAX_REPLACEMENTS_PER_BUCKET + 2)
+            .map(|_| insert(&mut bucket, disconnected_state()))
+            .collect::<Vec<_>>();
+        let connected = (0..2)
+            .map(|_| insert(&mut bucket, connected_state()))
+            .collect::<Vec<_>>();
+
+        // Connected candidates come first, and the most recent candidates of each kind first.
+        let expected = connected
+            .into_iter()
+            .rev()
+            .chain(disconnected.into_iter().rev())
+            .take(MAX_REPLACEMENTS_PER_BUCKET)
+            .collect::<Vec<_>>();
+        let actual = bucket
+            .replacements()
+            .map(|n| n.key.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(expected, actual);
+
+        // Re-inserting a candidate moves it to the front.
+        let key = expected[MAX_REPLACEMENTS_PER_BUCKET - 1].clone();
+        let node = Node {
+            key: key.clone(),
+            value: (),
+            status: connected_state(),
+            stats: Default::default(),
+        };
+        assert_eq!(InsertResult::Full, bucket.insert(node));
+        assert_eq!(Some(&key), bucket.replacements().next().map(|n| &n.key));
+        assert_eq!(MAX_REPLACEMENTS_PER_BUCKET, bucket.replacements().count());
+    }
+
+    #[test]
+    fn replacement_promoted_after_removal() {
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
+            None,
+        );
+        fill_bucket(&mut bucket, connected_state());
+        let candidates = (0..2)
+            .map(|_| {
+                let key = Key::from(NodeId::random());
+                let node = Node {
+                    key: key.clone(),
+                    value: (),
+                    status: disconnected_state(),
+                    stats: Default::default(),
+                };
+                assert_eq!(InsertResult::Full, bucket.insert(node));
+                key
+            })
+            .collect::<Vec<_>>();
+
+        // No candidate is promoted while the bucket is full of connected nodes.
+        assert_eq!(None, bucket.promote_replacement());
+
+        let removed = bucket.iter().next().unwrap().key.clone();
+        assert!(bucket.remove(&removed));
+        assert_eq!(Some(candidates[1].clone()), bucket.promote_replacement());
+        assert!(bucket.pending().unwrap().requires_revalidation());
+        assert_eq!(None, bucket.promote_replacement());
+
+        // A candidate that does not respond is dropped when its timeout expires.
+        let elapsed = Instant::now().checked_sub(Duration::from_secs(1)).unwrap();
+        bucket.pending_mut().unwrap().set_ready_at(elapsed);
+        assert_eq!(None, bucket.apply_pending());
+        assert!(bucket.pending().is_none());
+        assert!(bucket.position(&candidates[1]).is_none());
+
+        // The next candidate is inserted as soon as it responds.
+        assert_eq!(Some(candidates[0].clone()), bucket.promote_replacement());
+        let _ = bucket.update_status(&candidates[0], ConnectionState::Connected, None);
+        assert!(!bucket.pending().unwrap().requires_revalidation());
+        assert_eq!(
+            Some(AppliedPending {
+                inserted: candidates[0].clone(),
+                evicted: None
+            }),
+            bucket.apply_pending()
+        );
+        assert!(bucket.replacements().next().is_none());
+        bucket.check_invariants();
+    }
+
+    #[test]
+    fn replacement_evicts_disconnected() {
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
+            None,
+        );
+        fill_bucket(&mut bucket, disconnected_state());
+        let candidates = (0..2)
+            .map(|_| {
+                let key = Key::from(NodeId::random());
+                let node = Node {
+                    key: key.clone(),
+                    value: (),
+                    status: disconnected_state(),
+                    stats: Default::default(),
+                };
+                assert_eq!(InsertResult::Full, bucket.insert(node));
+                key
+            })
+            .collect::<Vec<_>>();
+
+        // A candidate that fails revalidation is dropped in favour of the next one.
+        assert_eq!(Some(candidates[1].clone()), bucket.promote_replacement());
+        let _ = bucket.update_status(&candidates[1], ConnectionState::Disconnected, None);
+        assert!(bucket.pending().is_none());
+        assert_eq!(Some(candidates[0].clone()), bucket.promote_replacement());
+
+        // A connected node takes the place of a candidate awaiting revalidation.
+        let key = Key::from(NodeId::random());
+        let node = Node {
+            key: key.clone(),
+            value: (),
+            status: connected_state(),
+            stats: Default::default(),
+        };
+        let first = bucket.iter().next().unwrap().key.clone();
+        assert_eq!(
+            InsertResult::Pending {
+                disconnected: first.clone()
+            },
+            bucket.insert(node)
+        );
+        assert_eq!(
+            Some(&candidates[0]),
+            bucket.replacements().next().map(|n| &n.key)
+        );
+
+        // Once applied, the candidate replaces the next disconnected node after revalidation.
+        let elapsed = Instant::now().checked_sub(Duration::from_secs(1)).unwrap();
+        bucket.pending_mut().unwrap().set_ready_at(elapsed);
+        let applied = bucket.apply_pending().unwrap();
+        assert_eq!(key, applied.inserted);
+        assert_eq!(first, applied.evicted.unwrap().key);
+
+        assert_eq!(Some(candidates[0].clone()), bucket.promote_replacement());
+        let _ = bucket.update_status(&candidates[0], ConnectionState::Connected, None);
+        bucket.pending_mut().unwrap().set_ready_at(elapsed);
+        let applied = bucket.apply_pending().unwrap();
+        assert_eq!(candidates[0], applied.inserted);
+        assert!(applied.evicted.is_some());
+        bucket.check_invariants();
+    }
+
+    #[test]
+    fn full_bucket_evicts_lowest_score() {
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
+            None,
+        );
+        fill_bucket(&mut bucket, disconnected_state());
+
+        // Among disconnected nodes of equal score, the least-recently connected is evicted.
+        let first = bucket.iter().next().unwrap().key.clone();
+        assert_eq!(first, bucket.nodes[bucket.eviction_position()].key);
+
+        let unreliable = bucket.iter().nth(3).unwrap().key.clone();
+        let stats = bucket.stats_mut(&unreliable).unwrap();
+        stats.record_failure();
+        stats.record_failure();
+
+        let key = Key::from(NodeId::random());
+        let node = Node {
+            key: key.clone(),
+            value: (),
+            status: connected_state(),
+            stats: Default::default(),
+        };
+        assert_eq!(
+            InsertResult::Pending {
+                disconnected: unreliable.clone()
+            },
+            bucket.insert(node)
+        );
+
+        let elapsed = Instant::now().checked_sub(Duration::from_secs(1)).unwrap();
+        bucket.pending_mut().unwrap().set_ready_at(elapsed);
+        let applied = bucket.apply_pending().unwrap();
+        assert_eq!(key, applied.inserted);
+        assert_eq!(unreliable, applied.evicted.unwrap().key);
+        assert!(bucket.position(&first).is_some());
+        bucket.check_invariants();
+    }
+
+    #[test]
+    fn pending_node_evicts_the_checked_node() {
+        let mut bucket = KBucket::<NodeId, ()>::new(
+            Duration::from_secs(1),
+            MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
+            None,
+        );
+        fill_bucket(&mut bucket, disconnected_state());
+        let first = bucket.iter().next().unwrap().key.clone();
+
+        let key = Key::from(NodeId::random());
+        let node = Node {
+            key: key.clone(),
+            value: (),
+            status: connected_state(),
+            stats: Default::default(),
+        };
+        assert_eq!(
+            InsertResult::Pending {
+                disconnected: first.clone()
+            },
+            bucket.insert(node)
+        );
+
+        // Another node becomes the lowest-scoring one while the first is being checked.
+        let unreliable = bucket.iter().nth(3).unwrap().key.clone();
+        bucket.stats_mut(&unreliable).unwrap().record_failure();
+        assert_eq!(unreliable, bucket.nodes[bucket.eviction_position()].key);
+
+        let elapsed = Instant::now().checked_sub(Duration::from_secs(1)).unwrap();
+        bucket.pending_mut().unwrap().set_ready_at(elapsed);
+        let applied = bucket.apply_pending().unwrap();
+        assert_eq!(key, applied.inserted);
+        assert_eq!(first, applied.evicted.unwrap().key);
+        assert!(bucket.position(&unreliable).is_some());
+
+        // A node that responds to the check is not replaced by the pending node.
+        let node = Node {
+            key: Key::from(NodeId::random()),
+            value: (),
+            status: connected_state(),
+            stats: Default::default(),
+        };
+        let checked = match bucket.insert(node) {
+            InsertResult::Pending { disconnected } => disconnected,
+            x => panic!("{:?}", x),
+        };
+        let _ = bucket.update_status(&checked, ConnectionState::Connected, None);
+        assert!(bucket.pending().is_none());
+        assert_eq!(bucket.replacements().count(), 1);
+        bucket.check_invariants();
+    }
 }
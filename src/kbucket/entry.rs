@@ -44,6 +44,7 @@ pub struct EntryRefView<'a, TPeerId, TVal: Eq> {
 pub struct NodeRefView<'a, TPeerId, TVal: Eq> {
     pub key: &'a Key<TPeerId>,
     pub value: &'a TVal,
+    pub stats: &'a NodeStats,
 }
 
 /// A cloned, immutable view of an entry that is either present in a bucket
@@ -214,6 +215,7 @@ where
             key: self.0.key.clone(),
             value,
             status,
+            stats: Default::default(),
         })
     }
 }
@@ -1,6 +1,10 @@
 //! Provides a trait that can be implemented to apply a filter to a table or bucket.
 
-use crate::Enr;
+use crate::{metrics::METRICS, Enr};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::atomic::Ordering,
+};
 
 pub trait Filter<TVal: Eq>: FilterClone<TVal> + Send + Sync {
     fn filter(
@@ -30,15 +34,94 @@ impl<TVal: Eq> Clone for Box<dyn Filter<TVal>> {
     }
 }
 
+/// A filter that only passes values which pass all of its inner filters.
+///
+/// This is used to combine user-supplied filters with the built-in IP filters.
+pub struct AllFilter<TVal: Eq>(pub Vec<Box<dyn Filter<TVal>>>);
+
+impl<TVal: Eq> Clone for AllFilter<TVal> {
+    fn clone(&self) -> Self {
+        AllFilter(self.0.clone())
+    }
+}
+
+impl<TVal: Eq + 'static> AllFilter<TVal> {
+    /// Joins two optional filters into a single optional filter.
+    pub fn join(
+        first: Option<Box<dyn Filter<TVal>>>,
+        second: Option<Box<dyn Filter<TVal>>>,
+    ) -> Option<Box<dyn Filter<TVal>>> {
+        match (first, second) {
+            (Some(first), Some(second)) => Some(Box::new(AllFilter(vec![first, second]))),
+            (first, second) => first.or(second),
+        }
+    }
+}
+
+impl<TVal: Eq + 'static> Filter<TVal> for AllFilter<TVal> {
+    fn filter(
+        &self,
+        value_to_be_inserted: &TVal,
+        other_vals: &mut dyn Iterator<Item = &TVal>,
+    ) -> bool {
+        // The iterator can only be consumed once, so collect it for each of the inner filters.
+        let other_vals = other_vals.collect::<Vec<_>>();
+        self.0
+            .iter()
+            .all(|filter| filter.filter(value_to_be_inserted, &mut other_vals.iter().copied()))
+    }
+}
+
 // Implementation of an IP filter for buckets and for tables
 
-/// Number of permitted nodes in the same /24 subnet per table.
-const MAX_NODES_PER_SUBNET_TABLE: usize = 10;
-/// The number of nodes permitted in the same /24 subnet per bucket.
-const MAX_NODES_PER_SUBNET_BUCKET: usize = 2;
+/// The number of nodes permitted in subnets of a given prefix length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubnetLimit {
+    /// The length of the prefix that defines a subnet.
+    pub prefix_len: u8,
+    /// The number of nodes permitted in the same subnet per table.
+    pub max_per_table: usize,
+    /// The number of nodes permitted in the same subnet per bucket.
+    pub max_per_bucket: usize,
+}
+
+/// The subnet limits of the IP filters, per IP version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpLimits {
+    /// The limits of IPv4 subnets. Default: 10 per table and 2 per bucket in each /24.
+    pub ipv4: SubnetLimit,
+    /// The limits of IPv6 subnets. Default: 10 per table and 2 per bucket in each /56.
+    pub ipv6: SubnetLimit,
+}
+
+impl Default for IpLimits {
+    fn default() -> Self {
+        IpLimits {
+            ipv4: SubnetLimit {
+                prefix_len: 24,
+                max_per_table: 10,
+                max_per_bucket: 2,
+            },
+            ipv6: SubnetLimit {
+                prefix_len: 56,
+                max_per_table: 10,
+                max_per_bucket: 2,
+            },
+        }
+    }
+}
+
+/// Limits the number of nodes per subnet in the routing table.
+#[derive(Clone, Default)]
+pub struct IpTableFilter {
+    limits: IpLimits,
+}
 
-#[derive(Clone)]
-pub struct IpTableFilter;
+impl IpTableFilter {
+    pub fn new(limits: IpLimits) -> Self {
+        IpTableFilter { limits }
+    }
+}
 
 impl Filter<Enr> for IpTableFilter {
     fn filter(
@@ -46,12 +129,23 @@ impl Filter<Enr> for IpTableFilter {
         value_to_be_inserted: &Enr,
         other_vals: &mut dyn Iterator<Item = &Enr>,
     ) -> bool {
-        ip_filter(value_to_be_inserted, other_vals, MAX_NODES_PER_SUBNET_TABLE)
+        ip_filter(value_to_be_inserted, other_vals, &self.limits, |limit| {
+            limit.max_per_table
+        })
     }
 }
 
-#[derive(Clone)]
-pub struct IpBucketFilter;
+/// Limits the number of nodes per subnet in each bucket.
+#[derive(Clone, Default)]
+pub struct IpBucketFilter {
+    limits: IpLimits,
+}
+
+impl IpBucketFilter {
+    pub fn new(limits: IpLimits) -> Self {
+        IpBucketFilter { limits }
+    }
+}
 
 impl Filter<Enr> for IpBucketFilter {
     fn filter(
@@ -59,38 +153,140 @@ impl Filter<Enr> for IpBucketFilter {
         value_to_be_inserted: &Enr,
         other_vals: &mut dyn Iterator<Item = &Enr>,
     ) -> bool {
-        ip_filter(
-            value_to_be_inserted,
-            other_vals,
-            MAX_NODES_PER_SUBNET_BUCKET,
-        )
+        ip_filter(value_to_be_inserted, other_vals, &self.limits, |limit| {
+            limit.max_per_bucket
+        })
     }
 }
 
 fn ip_filter(
     value_to_be_inserted: &Enr,
     other_vals: &mut dyn Iterator<Item = &Enr>,
-    limit: usize,
+    limits: &IpLimits,
+    max: impl Fn(&SubnetLimit) -> usize,
 ) -> bool {
-    if let Some(ip) = value_to_be_inserted.ip4() {
-        let mut count = 0;
-        for enr in other_vals {
-            // Ignore duplicates
-            if enr == value_to_be_inserted {
-                continue;
-            }
+    let ip4 = value_to_be_inserted.ip4();
+    let ip6 = value_to_be_inserted.ip6();
+    // No IP, so no restrictions
+    if ip4.is_none() && ip6.is_none() {
+        return true;
+    }
+
+    let (mut count4, mut count6) = (0, 0);
+    for enr in other_vals {
+        // Ignore duplicates
+        if enr == value_to_be_inserted {
+            continue;
+        }
 
-            // Count the same /24 subnet
-            if let Some(other_ip) = enr.ip4() {
-                if other_ip.octets()[0..3] == ip.octets()[0..3] {
-                    count += 1;
-                }
+        // Count the same subnets
+        if let (Some(ip), Some(other_ip)) = (ip4, enr.ip4()) {
+            if same_subnet4(ip, other_ip, limits.ipv4.prefix_len) {
+                count4 += 1;
             }
-            if count >= limit {
-                return false;
+        }
+        if let (Some(ip), Some(other_ip)) = (ip6, enr.ip6()) {
+            if same_subnet6(ip, other_ip, limits.ipv6.prefix_len) {
+                count6 += 1;
             }
         }
+        // Only the IP versions the node has an address of are limited.
+        if ip4.is_some() && count4 >= max(&limits.ipv4) {
+            METRICS
+                .ipv4_subnet_rejections
+                .fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        if ip6.is_some() && count6 >= max(&limits.ipv6) {
+            METRICS
+                .ipv6_subnet_rejections
+                .fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
     }
-    // No IP, so no restrictions
     true
 }
+
+fn same_subnet4(ip: Ipv4Addr, other: Ipv4Addr, prefix_len: u8) -> bool {
+    let mask = u32::MAX
+        .checked_shl(32u32.saturating_sub(prefix_len.into()))
+        .unwrap_or(0);
+    u32::from(ip) & mask == u32::from(other) & mask
+}
+
+fn same_subnet6(ip: Ipv6Addr, other: Ipv6Addr, prefix_len: u8) -> bool {
+    let mask = u128::MAX
+        .checked_shl(128u32.saturating_sub(prefix_len.into()))
+        .unwrap_or(0);
+    u128::from(ip) & mask == u128::from(other) & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::CombinedKey;
+    use std::net::IpAddr;
+
+    fn enr(ip: &str) -> Enr {
+        let key = CombinedKey::generate_secp256k1();
+        match ip.parse().unwrap() {
+            IpAddr::V4(ip) => Enr::builder().ip4(ip).udp4(9000).build(&key),
+            IpAddr::V6(ip) => Enr::builder().ip6(ip).udp6(9000).build(&key),
+        }
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ipv4_subnet_limit() {
+        let filter = IpBucketFilter::default();
+        let table = [enr("10.0.0.1"), enr("10.0.0.2"), enr("10.0.1.1")];
+
+        assert!(!filter.filter(&enr("10.0.0.3"), &mut table.iter()));
+        assert!(filter.filter(&enr("10.0.1.2"), &mut table.iter()));
+        // A node already in the bucket is not counted against itself.
+        assert!(filter.filter(&table[0], &mut table.iter()));
+    }
+
+    #[test]
+    fn test_ipv6_subnet_limit() {
+        let table = [enr("2001:db8:0:1::1"), enr("2001:db8:0:2::1")];
+
+        // Both nodes are in the same /56, but different /64s.
+        let filter = IpTableFilter::new(IpLimits {
+            ipv6: SubnetLimit {
+                prefix_len: 56,
+                max_per_table: 2,
+                max_per_bucket: 2,
+            },
+            ..Default::default()
+        });
+        assert!(!filter.filter(&enr("2001:db8:0:3::1"), &mut table.iter()));
+        assert!(filter.filter(&enr("2001:db8:0:100::1"), &mut table.iter()));
+
+        let filter = IpTableFilter::new(IpLimits {
+            ipv6: SubnetLimit {
+                prefix_len: 64,
+                max_per_table: 1,
+                max_per_bucket: 1,
+            },
+            ..Default::default()
+        });
+        assert!(filter.filter(&enr("2001:db8:0:3::1"), &mut table.iter()));
+        assert!(!filter.filter(&enr("2001:db8:0:1::2"), &mut table.iter()));
+    }
+
+    #[test]
+    fn test_limits_only_apply_to_advertised_ip_versions() {
+        let filter = IpTableFilter::new(IpLimits {
+            ipv4: SubnetLimit {
+                prefix_len: 24,
+                max_per_table: 0,
+                max_per_bucket: 0,
+            },
+            ..Default::default()
+        });
+        let table = [enr("10.0.0.1")];
+        assert!(filter.filter(&enr("2001:db8::1"), &mut table.iter()));
+        assert!(!filter.filter(&enr("10.1.0.1"), &mut table.iter()));
+    }
+}
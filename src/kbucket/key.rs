@@ -90,8 +90,7 @@ impl<T> Key<T> {
     /// Computes the integer log-2 distance between two keys, assuming a 256-bit
     /// key. The output returns None if the key's are identical. The range is 1-256.
     pub fn log2_distance<U>(&self, other: &Key<U>) -> Option<u64> {
-        let xor_dist = self.distance(other);
-        let log_dist = u64::from(256 - xor_dist.0.leading_zeros());
+        let log_dist = self.distance(other).log2();
         if log_dist == 0 {
             None
         } else {
@@ -113,6 +112,14 @@ impl From<NodeId> for Key<NodeId> {
 #[derive(Copy, Clone, PartialEq, Eq, Default, PartialOrd, Ord, Debug)]
 pub struct Distance(pub(super) U256);
 
+impl Distance {
+    /// The integer log-2 of the distance, in the range 0-256. Keys at the same log-2 distance
+    /// from a key fall into the same bucket of its routing table.
+    pub fn log2(&self) -> u64 {
+        u64::from(256 - self.0.leading_zeros())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
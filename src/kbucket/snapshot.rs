@@ -0,0 +1,314 @@
+//! Structured views of the routing table and the changes between them.
+//!
+//! A [`TableSnapshot`] lists the entries of each bucket with their connection status, ENR and
+//! insertion time. Comparing two snapshots with [`TableSnapshot::diff`] gives the [`TableDiff`]s
+//! which [`crate::Discv5::table_diffs`] streams as the table changes.
+use super::{bucket::PendingNode, ConnectionDirection, ConnectionState, KBucketsTable, Node};
+use crate::Enr;
+use enr::NodeId;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The routing table at one instant. Serializable with the `serde` feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TableSnapshot {
+    /// The id of the local node, from which the distances of the buckets are measured.
+    pub local_node_id: NodeId,
+    /// The buckets that hold entries or a pending entry, closest first.
+    pub buckets: Vec<BucketSnapshot>,
+}
+
+/// A bucket of the routing table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BucketSnapshot {
+    /// The log2 distance of the nodes of the bucket from the local node.
+    pub distance: u64,
+    /// The entries of the bucket, from the least to the most recently connected.
+    pub entries: Vec<EntrySnapshot>,
+    /// The node waiting to take the place of a disconnected entry.
+    pub pending: Option<EntrySnapshot>,
+}
+
+/// A node of the routing table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EntrySnapshot {
+    /// The id of the node.
+    pub node_id: NodeId,
+    /// The sequence number of the ENR.
+    pub enr_seq: u64,
+    /// The ENR of the node as stored in the table.
+    pub enr: Enr,
+    /// Whether the node is connected. A pending node that was promoted from the replacement cache
+    /// is disconnected until it responds.
+    pub state: ConnectionState,
+    /// The direction of the current connection, or of the last one if disconnected.
+    pub direction: ConnectionDirection,
+    /// When the node entered the table, in milliseconds since the Unix epoch. `None` for pending
+    /// nodes.
+    pub inserted_at_ms: Option<u64>,
+}
+
+/// A change of the routing table between two snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TableDiff {
+    /// A node entered the table.
+    Added(EntrySnapshot),
+    /// A node left the table.
+    Removed(NodeId),
+    /// The ENR, connection state or direction of a node changed.
+    Updated(EntrySnapshot),
+    /// The pending node of a bucket changed, or its ENR, connection state or direction did.
+    PendingUpdated {
+        /// The log2 distance of the bucket.
+        distance: u64,
+        /// The new pending node, `None` if the bucket no longer has one.
+        pending: Option<EntrySnapshot>,
+    },
+}
+
+impl TableSnapshot {
+    /// All entries of the table, excluding pending nodes.
+    pub fn entries(&self) -> impl Iterator<Item = &EntrySnapshot> {
+        self.buckets.iter().flat_map(|bucket| bucket.entries.iter())
+    }
+
+    /// The pending nodes of the table with the distances of their buckets.
+    pub fn pending(&self) -> impl Iterator<Item = (u64, &EntrySnapshot)> {
+        self.buckets.iter().filter_map(|bucket| {
+            bucket
+                .pending
+                .as_ref()
+                .map(|pending| (bucket.distance, pending))
+        })
+    }
+
+    /// The changes that turn this snapshot into `newer`. Removals come first, then the additions
+    /// and updates in the order of `newer`, then the changes of pending nodes.
+    pub fn diff(&self, newer: &TableSnapshot) -> Vec<TableDiff> {
+        let mut old: HashMap<NodeId, &EntrySnapshot> =
+            self.entries().map(|entry| (entry.node_id, entry)).collect();
+        let mut additions = Vec::new();
+        for entry in newer.entries() {
+            match old.remove(&entry.node_id) {
+                None => additions.push(TableDiff::Added(entry.clone())),
+                Some(previous) if previous.differs(entry) => {
+                    additions.push(TableDiff::Updated(entry.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        // Keep the order of the older snapshot, so that diffs are deterministic.
+        let mut diffs: Vec<TableDiff> = self
+            .entries()
+            .filter(|entry| old.contains_key(&entry.node_id))
+            .map(|entry| TableDiff::Removed(entry.node_id))
+            .collect();
+        diffs.extend(additions);
+
+        let mut old_pending: HashMap<u64, &EntrySnapshot> = self.pending().collect();
+        let mut pending_changes: Vec<(u64, Option<EntrySnapshot>)> = Vec::new();
+        for (distance, pending) in newer.pending() {
+            let changed = old_pending.remove(&distance).is_none_or(|previous| {
+                previous.node_id != pending.node_id || previous.differs(pending)
+            });
+            if changed {
+                pending_changes.push((distance, Some(pending.clone())));
+            }
+        }
+        pending_changes.extend(old_pending.into_keys().map(|distance| (distance, None)));
+        pending_changes.sort_by_key(|(distance, _)| *distance);
+        diffs.extend(
+            pending_changes
+                .into_iter()
+                .map(|(distance, pending)| TableDiff::PendingUpdated { distance, pending }),
+        );
+        diffs
+    }
+}
+
+impl EntrySnapshot {
+    /// Whether the ENR, connection state or direction differ from those of `other`.
+    fn differs(&self, other: &EntrySnapshot) -> bool {
+        self.enr != other.enr || self.state != other.state || self.direction != other.direction
+    }
+}
+
+impl KBucketsTable<NodeId, Enr> {
+    /// A structured view of the table. Pending nodes that are due are not applied.
+    pub fn snapshot(&self) -> TableSnapshot {
+        let now = (Instant::now(), SystemTime::now());
+        let buckets = self
+            .buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| bucket.num_entries() > 0 || bucket.pending().is_some())
+            .map(|(index, bucket)| BucketSnapshot {
+                distance: index as u64 + 1,
+                entries: bucket
+                    .iter()
+                    .map(|node| entry_snapshot(node, now))
+                    .collect(),
+                pending: bucket.pending().map(pending_snapshot),
+            })
+            .collect();
+        TableSnapshot {
+            local_node_id: *self.local_key.preimage(),
+            buckets,
+        }
+    }
+}
+
+fn entry_snapshot(node: &Node<NodeId, Enr>, now: (Instant, SystemTime)) -> EntrySnapshot {
+    let inserted_at_ms = node.stats.inserted().and_then(|inserted| {
+        let (instant, system_time) = now;
+        let inserted = system_time.checked_sub(instant.saturating_duration_since(inserted))?;
+        let since_epoch = inserted.duration_since(UNIX_EPOCH).ok()?;
+        u64::try_from(since_epoch.as_millis()).ok()
+    });
+    EntrySnapshot {
+        node_id: *node.key.preimage(),
+        enr_seq: node.value.seq(),
+        enr: node.value.clone(),
+        state: node.status.state,
+        direction: node.status.direction,
+        inserted_at_ms,
+    }
+}
+
+fn pending_snapshot(pending: &PendingNode<NodeId, Enr>) -> EntrySnapshot {
+    EntrySnapshot {
+        node_id: *pending.key().preimage(),
+        enr_seq: pending.value().seq(),
+        enr: pending.value().clone(),
+        state: pending.status().state,
+        direction: pending.status().direction,
+        inserted_at_ms: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kbucket::{InsertResult, Key, NodeStatus, UpdateResult, MAX_NODES_PER_BUCKET};
+    use enr::CombinedKey;
+    use std::time::Duration;
+
+    fn enr(key: &CombinedKey, seq: u64) -> Enr {
+        Enr::builder().seq(seq).build(key).unwrap()
+    }
+
+    #[test]
+    fn snapshots_are_diffed() {
+        let local_key = Key::from(NodeId::random());
+        let mut table = KBucketsTable::<NodeId, Enr>::new(
+            local_key,
+            Duration::from_secs(5),
+            MAX_NODES_PER_BUCKET,
+            MAX_NODES_PER_BUCKET,
+            None,
+            None,
+        );
+        let connected = NodeStatus {
+            state: ConnectionState::Connected,
+            direction: ConnectionDirection::Outgoing,
+        };
+        let (first_key, second_key) = (
+            CombinedKey::generate_secp256k1(),
+            CombinedKey::generate_secp256k1(),
+        );
+        let (first, second) = (enr(&first_key, 1), enr(&second_key, 1));
+        for enr in [&first, &second] {
+            let key = Key::from(enr.node_id());
+            assert!(matches!(
+                table.insert_or_update(&key, enr.clone(), connected),
+                InsertResult::Inserted
+            ));
+        }
+
+        let before = table.snapshot();
+        assert_eq!(before.entries().count(), 2);
+        assert!(before.entries().all(|entry| entry.inserted_at_ms.is_some()));
+        assert!(before.buckets.iter().all(|bucket| bucket.pending.is_none()));
+        assert!(before.diff(&before).is_empty());
+
+        let updated = enr(&first_key, 2);
+        assert!(matches!(
+            table.update_node(&Key::from(first.node_id()), updated, None),
+            UpdateResult::Updated
+        ));
+        assert!(table.remove(&Key::from(second.node_id())));
+        let after = table.snapshot();
+        let diffs = before.diff(&after);
+        assert_eq!(diffs.len(), 2);
+        assert_eq!(diffs[0], TableDiff::Removed(second.node_id()));
+        match &diffs[1] {
+            TableDiff::Updated(entry) => {
+                assert_eq!(entry.node_id, first.node_id());
+                assert_eq!(entry.enr_seq, 2);
+            }
+            diff => panic!("Unexpected diff {:?}", diff),
+        }
+    }
+
+    #[test]
+    fn pending_nodes_are_diffed() {
+        let key = CombinedKey::generate_secp256k1();
+        let pending = EntrySnapshot {
+            node_id: enr(&key, 1).node_id(),
+            enr_seq: 1,
+            enr: enr(&key, 1),
+            state: ConnectionState::Connected,
+            direction: ConnectionDirection::Incoming,
+            inserted_at_ms: None,
+        };
+        let empty = TableSnapshot {
+            local_node_id: NodeId::random(),
+            buckets: Vec::new(),
+        };
+        let with_pending = TableSnapshot {
+            buckets: vec![BucketSnapshot {
+                distance: 256,
+                entries: Vec::new(),
+                pending: Some(pending.clone()),
+            }],
+            ..empty.clone()
+        };
+        assert_eq!(
+            empty.diff(&with_pending),
+            vec![TableDiff::PendingUpdated {
+                distance: 256,
+                pending: Some(pending.clone()),
+            }]
+        );
+        assert!(with_pending.diff(&with_pending).is_empty());
+
+        let mut disconnected = with_pending.clone();
+        let updated = disconnected.buckets[0].pending.as_mut().unwrap();
+        updated.state = ConnectionState::Disconnected;
+        let updated = updated.clone();
+        assert_eq!(
+            with_pending.diff(&disconnected),
+            vec![TableDiff::PendingUpdated {
+                distance: 256,
+                pending: Some(updated),
+            }]
+        );
+
+        assert_eq!(
+            with_pending.diff(&empty),
+            vec![TableDiff::PendingUpdated {
+                distance: 256,
+                pending: None,
+            }]
+        );
+    }
+}
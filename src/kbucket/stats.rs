@@ -0,0 +1,164 @@
+//! Reputation and liveness statistics of the nodes in the routing table.
+//!
+//! Every [`Node`](super::Node) keeps a [`NodeStats`] which records how the peer answered our
+//! requests. The derived [`NodeStats::score`] lets lookups prefer reliable peers and lets a full
+//! bucket evict the worst of its disconnected nodes first.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// The number of round-trip time samples kept per node.
+pub const MAX_RTT_SAMPLES: usize = 8;
+
+/// The round-trip time at which the latency factor of a score is one half.
+const REFERENCE_RTT: Duration = Duration::from_millis(500);
+
+/// The request statistics of a node.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeStats {
+    /// The most recent round-trip times, the oldest first.
+    rtt_samples: VecDeque<Duration>,
+    /// The number of requests that were answered.
+    successes: u32,
+    /// The number of requests that failed.
+    failures: u32,
+    /// The number of responses that violated the protocol.
+    invalid_responses: u32,
+    /// When the node last answered a request.
+    last_response: Option<Instant>,
+    /// When the node entered the routing table.
+    inserted: Option<Instant>,
+}
+
+impl NodeStats {
+    /// Records an answered request, along with its round-trip time if known.
+    pub fn record_success(&mut self, rtt: Option<Duration>) {
+        self.successes = self.successes.saturating_add(1);
+        self.last_response = Some(Instant::now());
+        if let Some(rtt) = rtt {
+            if self.rtt_samples.len() == MAX_RTT_SAMPLES {
+                self.rtt_samples.pop_front();
+            }
+            self.rtt_samples.push_back(rtt);
+        }
+    }
+
+    /// Records a request that failed, e.g. by timing out.
+    pub fn record_failure(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    /// Records that the node entered the routing table, unless it already has.
+    pub(crate) fn record_insertion(&mut self) {
+        self.inserted.get_or_insert_with(Instant::now);
+    }
+
+    /// Records a response that violated the protocol.
+    pub fn record_invalid_response(&mut self) {
+        self.invalid_responses = self.invalid_responses.saturating_add(1);
+    }
+
+    /// The most recent round-trip times, the oldest first.
+    pub fn rtt_samples(&self) -> impl Iterator<Item = &Duration> {
+        self.rtt_samples.iter()
+    }
+
+    /// The mean of the recent round-trip times.
+    pub fn average_rtt(&self) -> Option<Duration> {
+        if self.rtt_samples.is_empty() {
+            return None;
+        }
+        let total: Duration = self.rtt_samples.iter().sum();
+        Some(total / self.rtt_samples.len() as u32)
+    }
+
+    /// The number of requests that were answered.
+    pub fn successes(&self) -> u32 {
+        self.successes
+    }
+
+    /// The number of requests that failed.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// The number of responses that violated the protocol.
+    pub fn invalid_responses(&self) -> u32 {
+        self.invalid_responses
+    }
+
+    /// When the node last answered a request.
+    pub fn last_response(&self) -> Option<Instant> {
+        self.last_response
+    }
+
+    /// When the node entered the routing table. Nodes that are pending or replacement candidates
+    /// have not.
+    pub fn inserted(&self) -> Option<Instant> {
+        self.inserted
+    }
+
+    /// A score in `(0, 1]`, higher for more reliable peers. An unknown node scores `0.5`.
+    ///
+    /// The score is the product of the estimated success rate, a latency factor that halves at
+    /// a round-trip time of 500ms, and a penalty that halves with every invalid response.
+    pub fn score(&self) -> f64 {
+        let successes = f64::from(self.successes);
+        let failures = f64::from(self.failures);
+        // Laplace's rule of succession, so that a few samples don't give extreme values.
+        let reliability = (successes + 1.0) / (successes + failures + 2.0);
+        let latency = self.average_rtt().map_or(1.0, |rtt| {
+            let reference = REFERENCE_RTT.as_secs_f64();
+            reference / (reference + rtt.as_secs_f64())
+        });
+        let validity = 0.5f64.powi(self.invalid_responses.min(64) as i32);
+        reliability * latency * validity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn score_orders_peers() {
+        let unknown = NodeStats::default();
+        assert_eq!(unknown.score(), 0.5);
+        assert_eq!(unknown.average_rtt(), None);
+
+        let mut fast = NodeStats::default();
+        let mut slow = NodeStats::default();
+        let mut flaky = NodeStats::default();
+        for _ in 0..10 {
+            fast.record_success(Some(Duration::from_millis(20)));
+            slow.record_success(Some(Duration::from_millis(800)));
+            flaky.record_success(Some(Duration::from_millis(20)));
+            flaky.record_failure();
+        }
+        assert!(fast.score() > slow.score());
+        assert!(fast.score() > flaky.score());
+        assert!(fast.score() > unknown.score());
+
+        let mut invalid = fast.clone();
+        invalid.record_invalid_response();
+        assert!((invalid.score() - fast.score() / 2.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn rtt_samples_are_bounded() {
+        let mut stats = NodeStats::default();
+        for millis in 0..MAX_RTT_SAMPLES as u64 * 2 {
+            stats.record_success(Some(Duration::from_millis(millis)));
+        }
+        stats.record_success(None);
+        assert_eq!(stats.rtt_samples().count(), MAX_RTT_SAMPLES);
+        assert_eq!(
+            stats.rtt_samples().next(),
+            Some(&Duration::from_millis(MAX_RTT_SAMPLES as u64))
+        );
+        assert_eq!(stats.successes(), MAX_RTT_SAMPLES as u32 * 2 + 1);
+        assert!(stats.last_response().is_some());
+    }
+}
@@ -94,6 +94,9 @@
 //!    });
 //! ```
 
+#[cfg(feature = "admin")]
+#[cfg_attr(docsrs, doc(cfg(feature = "admin")))]
+pub mod admin;
 mod config;
 mod discv5;
 mod error;
@@ -106,6 +109,7 @@ pub mod metrics;
 mod node_info;
 pub mod packet;
 pub mod permit_ban;
+mod port_mapping;
 mod query_pool;
 pub mod rpc;
 pub mod service;
@@ -117,14 +121,30 @@ extern crate lazy_static;
 pub type Enr = enr::Enr<enr::CombinedKey>;
 
 pub use crate::discv5::{Discv5, Event};
-pub use config::{Config, ConfigBuilder};
+#[cfg(feature = "serde")]
+pub use config::{
+    AdaptiveTimeoutFile, ConfigFile, OutboundRateLimitFile, QuotaFile, RateLimitFile,
+    RevalidationFile, SessionRekeyFile, SubnetLimitFile,
+};
+pub use config::{Config, ConfigBuilder, ConfigUpdate, TableFilter};
 pub use error::{Error, QueryError, RequestError, ResponseError};
 pub use executor::{Executor, TokioExecutor};
+pub use handler::{AdaptiveTimeoutConfig, RekeyConfig, SessionInfo};
 pub use ipmode::IpMode;
-pub use kbucket::{ConnectionDirection, ConnectionState, Key};
+pub use kbucket::{
+    BucketSnapshot, ConnectionDirection, ConnectionState, EntrySnapshot, IpLimits, Key, NodeStats,
+    SubnetLimit, TableDiff, TableSnapshot,
+};
 pub use packet::{DefaultProtocolId, ProtocolIdentity};
-pub use permit_ban::PermitBanList;
-pub use service::TalkRequest;
-pub use socket::{ListenConfig, RateLimiter, RateLimiterBuilder};
+pub use permit_ban::{Ban, BanOrigin, BanTarget, IpNetwork, PermitBanList, PermitBanStore};
+pub use port_mapping::{MappingProtocol, PortMappingConfig};
+pub use service::{
+    ActiveQuery, InboundReachability, NatStatus, NatType, Reachability, RevalidationConfig,
+    TalkRequest,
+};
+pub use socket::{
+    ListenConfig, OutboundRateLimiter, OutboundRateLimiterBuilder, RateLimiter, RateLimiterBuilder,
+    SendPriority,
+};
 // re-export the ENR crate
 pub use enr;
@@ -80,12 +80,23 @@ impl<K: Clone + Eq + Hash, V> LruTimeCache<K, V> {
         self.map.len()
     }
 
+    /// Iterates over the non-expired key-value pairs, from least to most recently used.
+    pub fn iter(&mut self) -> impl Iterator<Item = (&K, &V)> {
+        self.remove_expired_values(Instant::now());
+        self.map.iter().map(|(key, (value, _))| (key, value))
+    }
+
     /// Removes a key-value pair from the cache, returning the value at the key if the key
     /// was previously in the map.
     pub fn remove(&mut self, key: &K) -> Option<V> {
         self.map.remove(key).map(|v| v.0)
     }
 
+    /// Retains only the key-value pairs for which `f` returns true.
+    pub fn retain<F: FnMut(&K, &V) -> bool>(&mut self, mut f: F) {
+        self.map.retain(|key, (value, _)| f(key, value));
+    }
+
     /// Removes expired items from the cache.
     fn remove_expired_values(&mut self, now: Instant) {
         let mut expired_keys = vec![];
@@ -121,6 +132,20 @@ mod tests {
         assert_eq!(Some(&30), cache.get(&3));
     }
 
+    #[test]
+    fn retain() {
+        let mut cache = LruTimeCache::new(Duration::from_secs(10), None);
+
+        cache.insert(1, 10);
+        cache.insert(2, 20);
+        cache.insert(3, 30);
+        cache.retain(|key, _| key % 2 == 1);
+
+        assert_eq!(Some(&10), cache.get(&1));
+        assert_eq!(None, cache.get(&2));
+        assert_eq!(Some(&30), cache.get(&3));
+    }
+
     #[test]
     fn capacity() {
         let mut cache = LruTimeCache::new(Duration::from_secs(10), Some(2));
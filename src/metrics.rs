@@ -16,6 +16,16 @@ pub struct InternalMetrics {
     pub bytes_sent: AtomicUsize,
     /// The number of bytes received.
     pub bytes_recv: AtomicUsize,
+    /// The number of nodes rejected from the routing table by the IPv4 subnet limits.
+    pub ipv4_subnet_rejections: AtomicUsize,
+    /// The number of nodes rejected from the routing table by the IPv6 subnet limits.
+    pub ipv6_subnet_rejections: AtomicUsize,
+    /// The number of outbound packets held back by the outbound rate limiter.
+    pub outbound_packets_delayed: AtomicUsize,
+    /// The number of outbound packets dropped by the outbound rate limiter.
+    pub outbound_packets_dropped: AtomicUsize,
+    /// The number of outbound packets currently queued by the outbound rate limiter.
+    pub outbound_queued_packets: AtomicUsize,
 }
 
 impl Default for InternalMetrics {
@@ -26,6 +36,11 @@ impl Default for InternalMetrics {
             unsolicited_requests_per_window: AtomicUsize::new(0),
             bytes_sent: AtomicUsize::new(0),
             bytes_recv: AtomicUsize::new(0),
+            ipv4_subnet_rejections: AtomicUsize::new(0),
+            ipv6_subnet_rejections: AtomicUsize::new(0),
+            outbound_packets_delayed: AtomicUsize::new(0),
+            outbound_packets_dropped: AtomicUsize::new(0),
+            outbound_queued_packets: AtomicUsize::new(0),
         }
     }
 }
@@ -55,6 +70,16 @@ pub struct Metrics {
     pub bytes_sent: usize,
     /// The number of bytes received.
     pub bytes_recv: usize,
+    /// The number of nodes rejected from the routing table by the IPv4 subnet limits.
+    pub ipv4_subnet_rejections: usize,
+    /// The number of nodes rejected from the routing table by the IPv6 subnet limits.
+    pub ipv6_subnet_rejections: usize,
+    /// The number of outbound packets held back by the outbound rate limiter.
+    pub outbound_packets_delayed: usize,
+    /// The number of outbound packets dropped by the outbound rate limiter.
+    pub outbound_packets_dropped: usize,
+    /// The number of outbound packets currently queued by the outbound rate limiter.
+    pub outbound_queued_packets: usize,
 }
 
 impl From<&METRICS> for Metrics {
@@ -67,6 +92,21 @@ impl From<&METRICS> for Metrics {
                 / internal_metrics.moving_window as f64,
             bytes_sent: internal_metrics.bytes_sent.load(Ordering::Relaxed),
             bytes_recv: internal_metrics.bytes_recv.load(Ordering::Relaxed),
+            ipv4_subnet_rejections: internal_metrics
+                .ipv4_subnet_rejections
+                .load(Ordering::Relaxed),
+            ipv6_subnet_rejections: internal_metrics
+                .ipv6_subnet_rejections
+                .load(Ordering::Relaxed),
+            outbound_packets_delayed: internal_metrics
+                .outbound_packets_delayed
+                .load(Ordering::Relaxed),
+            outbound_packets_dropped: internal_metrics
+                .outbound_packets_dropped
+                .load(Ordering::Relaxed),
+            outbound_queued_packets: internal_metrics
+                .outbound_queued_packets
+                .load(Ordering::Relaxed),
         }
     }
 }
@@ -48,11 +48,14 @@ impl NodeContact {
         self.socket_addr
     }
 
+    /// The contact with requests sent to the given address instead.
+    pub(crate) fn with_socket_addr(mut self, socket_addr: SocketAddr) -> Self {
+        self.socket_addr = socket_addr;
+        self
+    }
+
     pub fn node_address(&self) -> NodeAddress {
-        NodeAddress {
-            socket_addr: self.socket_addr,
-            node_id: self.node_id(),
-        }
+        NodeAddress::new(self.socket_addr, self.node_id())
     }
 
     pub fn to_address_and_enr(self) -> (NodeAddress, Option<Enr>) {
@@ -61,13 +64,7 @@ impl NodeContact {
             socket_addr,
             enr,
         } = self;
-        (
-            NodeAddress {
-                node_id: public_key.into(),
-                socket_addr,
-            },
-            enr,
-        )
+        (NodeAddress::new(socket_addr, public_key.into()), enr)
     }
 
     pub fn try_from_enr(enr: Enr, ip_mode: IpMode) -> Result<Self, NonContactable> {
@@ -150,12 +147,33 @@ impl std::fmt::Display for NodeContact {
 }
 
 /// A representation of an unsigned contactable node.
-#[derive(PartialEq, Hash, Eq, Clone, Debug)]
+///
+/// Two node addresses are equal if their socket address and node id are equal, regardless of the
+/// local socket.
+#[derive(Clone, Debug)]
 pub struct NodeAddress {
     /// The destination socket address.
     pub socket_addr: SocketAddr,
     /// The destination Node Id.
     pub node_id: NodeId,
+    /// The local socket on which packets from this node were received, if known. Packets sent to
+    /// this address are sent from this socket.
+    pub local_socket: Option<SocketAddr>,
+}
+
+impl PartialEq for NodeAddress {
+    fn eq(&self, other: &Self) -> bool {
+        self.socket_addr == other.socket_addr && self.node_id == other.node_id
+    }
+}
+
+impl Eq for NodeAddress {}
+
+impl std::hash::Hash for NodeAddress {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.socket_addr.hash(state);
+        self.node_id.hash(state);
+    }
 }
 
 impl Ord for NodeAddress {
@@ -183,6 +201,7 @@ impl NodeAddress {
         Self {
             socket_addr,
             node_id,
+            local_socket: None,
         }
     }
 }
@@ -1,27 +1,476 @@
+//! Lists of nodes and IP ranges which bypass the packet filter or whose packets are dropped.
+//!
+//! IP entries are [`IpNetwork`]s, i.e. CIDR ranges. A single address is a network with a full
+//! length prefix. Lookups of an address check each distinct prefix length once, so their cost
+//! does not grow with the number of entries.
 use crate::node_info::NodeAddress;
 use enr::NodeId;
 use std::{
-    collections::{HashMap, HashSet},
-    net::IpAddr,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
     time::Instant,
 };
 
-#[derive(Debug, Clone, Default)]
+mod store;
+pub(crate) use store::Autosave;
+pub use store::{FileStore, ImportError, PermitBanStore};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct PermitBanList {
-    /// A set of IPs which pass all filters.
-    pub permit_ips: HashSet<IpAddr>,
-    /// A set of IPs whose packets get dropped instantly.
-    pub ban_ips: HashMap<IpAddr, Option<Instant>>,
+    /// A set of IP ranges which pass all filters.
+    pub permit_ips: IpNetworkMap<()>,
+    /// A set of IP ranges whose packets get dropped instantly.
+    pub ban_ips: IpNetworkMap<Ban>,
     /// A set of NodeIds which pass all filters.
     pub permit_nodes: HashSet<NodeId>,
     /// A set of NodeIds whose packets get dropped instantly.
-    pub ban_nodes: HashMap<NodeId, Option<Instant>>,
+    pub ban_nodes: HashMap<NodeId, Ban>,
 }
 
 impl PermitBanList {
-    pub fn ban(&mut self, node_address: NodeAddress, time_to_unban: Option<Instant>) {
+    /// Bans both the node id and the IP of a node.
+    pub fn ban(&mut self, node_address: NodeAddress, ban: Ban) {
+        self.ban_ips
+            .insert(node_address.socket_addr.ip().into(), ban.clone());
+        self.ban_nodes.insert(node_address.node_id, ban);
+    }
+
+    /// Whether the IP falls in a permitted range.
+    pub fn is_ip_permitted(&self, ip: &IpAddr) -> bool {
+        self.permit_ips.contains(ip)
+    }
+
+    /// Whether the IP falls in a banned range.
+    pub fn is_ip_banned(&self, ip: &IpAddr) -> bool {
+        self.ban_ips.contains(ip)
+    }
+
+    /// Removes the bans that have expired by `now`.
+    pub fn remove_expired(&mut self, now: Instant) {
+        self.ban_ips.retain(|_, ban| !ban.has_expired(now));
+        self.ban_nodes.retain(|_, ban| !ban.has_expired(now));
+    }
+
+    /// The bans in effect, IP ranges first.
+    pub fn bans(&self) -> impl Iterator<Item = (BanTarget, &Ban)> {
+        let now = Instant::now();
         self.ban_ips
-            .insert(node_address.socket_addr.ip(), time_to_unban);
-        self.ban_nodes.insert(node_address.node_id, time_to_unban);
+            .iter()
+            .map(|(network, ban)| (BanTarget::Ip(network), ban))
+            .chain(
+                self.ban_nodes
+                    .iter()
+                    .map(|(node_id, ban)| (BanTarget::Node(*node_id), ban)),
+            )
+            .filter(move |(_, ban)| !ban.has_expired(now))
+    }
+}
+
+/// Until when and why a node or IP range is banned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    /// When the ban is lifted. `None` bans permanently.
+    pub expires: Option<Instant>,
+    /// Who enacted the ban.
+    pub origin: BanOrigin,
+    /// Why the ban was enacted, if known.
+    pub reason: Option<String>,
+}
+
+impl Ban {
+    /// A ban enacted by the user.
+    pub fn by_user(expires: Option<Instant>, reason: Option<String>) -> Self {
+        Ban {
+            expires,
+            origin: BanOrigin::User,
+            reason,
+        }
+    }
+
+    /// A ban enacted by the packet filter.
+    pub(crate) fn by_filter(expires: Option<Instant>, reason: &str) -> Self {
+        Ban {
+            expires,
+            origin: BanOrigin::Filter,
+            reason: Some(reason.to_string()),
+        }
+    }
+
+    /// A ban enacted by the service for a protocol violation.
+    pub(crate) fn by_service(expires: Option<Instant>, reason: &str) -> Self {
+        Ban {
+            expires,
+            origin: BanOrigin::Service,
+            reason: Some(reason.to_string()),
+        }
+    }
+
+    /// Whether the ban has been lifted by `now`.
+    pub fn has_expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Who enacted a ban.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BanOrigin {
+    /// The user, via the [`crate::Discv5`] API or the configuration.
+    User,
+    /// The packet filter, for exceeding its rate or node limits.
+    Filter,
+    /// The service, for responses that violate the protocol.
+    Service,
+}
+
+/// The subject of a ban.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BanTarget {
+    /// A node id.
+    Node(NodeId),
+    /// A range of IPs.
+    Ip(IpNetwork),
+}
+
+/// A range of IP addresses sharing a prefix, written as `address/prefix_len` in CIDR notation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct IpNetwork {
+    /// The first address of the range.
+    addr: IpAddr,
+    /// The length of the shared prefix.
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// The network of the addresses that share the first `prefix_len` bits with `addr`.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, &'static str> {
+        let addr = match addr {
+            IpAddr::V4(ip) if prefix_len <= 32 => IpAddr::V4(mask4(ip, prefix_len).into()),
+            IpAddr::V6(ip) if prefix_len <= 128 => IpAddr::V6(mask6(ip, prefix_len).into()),
+            _ => return Err("The prefix length cannot exceed the length of the address"),
+        };
+        Ok(IpNetwork { addr, prefix_len })
+    }
+
+    /// The first address of the range.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// The length of the shared prefix.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether the address falls in the range.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => u32::from(addr) == mask4(*ip, self.prefix_len),
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => u128::from(addr) == mask6(*ip, self.prefix_len),
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpNetwork {
+    fn from(addr: IpAddr) -> Self {
+        let prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        IpNetwork { addr, prefix_len }
+    }
+}
+
+impl From<&IpAddr> for IpNetwork {
+    fn from(addr: &IpAddr) -> Self {
+        (*addr).into()
+    }
+}
+
+impl From<Ipv4Addr> for IpNetwork {
+    fn from(addr: Ipv4Addr) -> Self {
+        IpAddr::V4(addr).into()
+    }
+}
+
+impl From<Ipv6Addr> for IpNetwork {
+    fn from(addr: Ipv6Addr) -> Self {
+        IpAddr::V6(addr).into()
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = &'static str;
+
+    /// Parses `address/prefix_len`, or a single address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| "Invalid IP address")?;
+        match prefix_len {
+            Some(prefix_len) => {
+                let prefix_len = prefix_len.parse().map_err(|_| "Invalid prefix length")?;
+                IpNetwork::new(addr, prefix_len)
+            }
+            None => Ok(addr.into()),
+        }
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+fn mask4(ip: Ipv4Addr, prefix_len: u8) -> u32 {
+    let mask = u32::MAX
+        .checked_shl(32u32.saturating_sub(prefix_len.into()))
+        .unwrap_or(0);
+    u32::from(ip) & mask
+}
+
+fn mask6(ip: Ipv6Addr, prefix_len: u8) -> u128 {
+    let mask = u128::MAX
+        .checked_shl(128u32.saturating_sub(prefix_len.into()))
+        .unwrap_or(0);
+    u128::from(ip) & mask
+}
+
+/// A map from IP ranges to values, looked up by the most specific range containing an address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpNetworkMap<V> {
+    /// The IPv4 ranges by prefix length, keyed by their first address.
+    ipv4: BTreeMap<u8, HashMap<u32, V>>,
+    /// The IPv6 ranges by prefix length, keyed by their first address.
+    ipv6: BTreeMap<u8, HashMap<u128, V>>,
+}
+
+impl<V> Default for IpNetworkMap<V> {
+    fn default() -> Self {
+        IpNetworkMap {
+            ipv4: BTreeMap::new(),
+            ipv6: BTreeMap::new(),
+        }
+    }
+}
+
+impl<V> IpNetworkMap<V> {
+    /// Inserts a range, returning the previous value of the same range.
+    pub fn insert(&mut self, network: IpNetwork, value: V) -> Option<V> {
+        match network.addr {
+            IpAddr::V4(addr) => self
+                .ipv4
+                .entry(network.prefix_len)
+                .or_default()
+                .insert(addr.into(), value),
+            IpAddr::V6(addr) => self
+                .ipv6
+                .entry(network.prefix_len)
+                .or_default()
+                .insert(addr.into(), value),
+        }
+    }
+
+    /// Removes exactly the given range. Ranges it contains or overlaps are kept.
+    pub fn remove(&mut self, network: &IpNetwork) -> Option<V> {
+        let prefix_len = network.prefix_len;
+        match network.addr {
+            IpAddr::V4(addr) => remove_entry(&mut self.ipv4, prefix_len, &addr.into()),
+            IpAddr::V6(addr) => remove_entry(&mut self.ipv6, prefix_len, &addr.into()),
+        }
+    }
+
+    /// The value of the most specific range containing the address.
+    pub fn get(&self, ip: &IpAddr) -> Option<&V> {
+        match ip {
+            IpAddr::V4(ip) => self
+                .ipv4
+                .iter()
+                .rev()
+                .find_map(|(prefix_len, networks)| networks.get(&mask4(*ip, *prefix_len))),
+            IpAddr::V6(ip) => self
+                .ipv6
+                .iter()
+                .rev()
+                .find_map(|(prefix_len, networks)| networks.get(&mask6(*ip, *prefix_len))),
+        }
+    }
+
+    /// Whether any range contains the address.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.get(ip).is_some()
+    }
+
+    /// The value of exactly the given range.
+    pub fn get_network(&self, network: &IpNetwork) -> Option<&V> {
+        match network.addr {
+            IpAddr::V4(addr) => self.ipv4.get(&network.prefix_len)?.get(&addr.into()),
+            IpAddr::V6(addr) => self.ipv6.get(&network.prefix_len)?.get(&addr.into()),
+        }
+    }
+
+    /// Iterates over the ranges and their values.
+    pub fn iter(&self) -> impl Iterator<Item = (IpNetwork, &V)> {
+        let ipv4 = self.ipv4.iter().flat_map(|(prefix_len, networks)| {
+            networks.iter().map(move |(addr, value)| {
+                let addr = IpAddr::V4(Ipv4Addr::from(*addr));
+                let network = IpNetwork {
+                    addr,
+                    prefix_len: *prefix_len,
+                };
+                (network, value)
+            })
+        });
+        let ipv6 = self.ipv6.iter().flat_map(|(prefix_len, networks)| {
+            networks.iter().map(move |(addr, value)| {
+                let addr = IpAddr::V6(Ipv6Addr::from(*addr));
+                let network = IpNetwork {
+                    addr,
+                    prefix_len: *prefix_len,
+                };
+                (network, value)
+            })
+        });
+        ipv4.chain(ipv6)
+    }
+
+    /// Keeps only the ranges for which `f` returns true.
+    pub fn retain(&mut self, mut f: impl FnMut(&IpNetwork, &mut V) -> bool) {
+        for (prefix_len, networks) in self.ipv4.iter_mut() {
+            networks.retain(|addr, value| {
+                let addr = IpAddr::V4(Ipv4Addr::from(*addr));
+                let prefix_len = *prefix_len;
+                f(&IpNetwork { addr, prefix_len }, value)
+            });
+        }
+        for (prefix_len, networks) in self.ipv6.iter_mut() {
+            networks.retain(|addr, value| {
+                let addr = IpAddr::V6(Ipv6Addr::from(*addr));
+                let prefix_len = *prefix_len;
+                f(&IpNetwork { addr, prefix_len }, value)
+            });
+        }
+        self.ipv4.retain(|_, networks| !networks.is_empty());
+        self.ipv6.retain(|_, networks| !networks.is_empty());
+    }
+
+    /// The number of ranges.
+    pub fn len(&self) -> usize {
+        let ipv4: usize = self.ipv4.values().map(HashMap::len).sum();
+        let ipv6: usize = self.ipv6.values().map(HashMap::len).sum();
+        ipv4 + ipv6
+    }
+
+    /// Whether there are no ranges.
+    pub fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty()
+    }
+}
+
+impl IpNetworkMap<()> {
+    /// Adds a range to the set, returning whether it was not yet present.
+    pub fn add(&mut self, network: IpNetwork) -> bool {
+        self.insert(network, ()).is_none()
+    }
+}
+
+/// Removes an entry, dropping the prefix length once it has no ranges left.
+fn remove_entry<A: std::hash::Hash + Eq, V>(
+    by_prefix: &mut BTreeMap<u8, HashMap<A, V>>,
+    prefix_len: u8,
+    addr: &A,
+) -> Option<V> {
+    let networks = by_prefix.get_mut(&prefix_len)?;
+    let value = networks.remove(addr);
+    if networks.is_empty() {
+        by_prefix.remove(&prefix_len);
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn network_parsing() {
+        let network: IpNetwork = "10.1.2.3/16".parse().unwrap();
+        assert_eq!(network.addr(), ip("10.1.0.0"));
+        assert_eq!(network.to_string(), "10.1.0.0/16");
+        assert!(network.contains(&ip("10.1.255.1")));
+        assert!(!network.contains(&ip("10.2.0.1")));
+        assert!(!network.contains(&ip("::1")));
+
+        let single: IpNetwork = "2001:db8::1".parse().unwrap();
+        assert_eq!(single.prefix_len(), 128);
+        assert!(single.contains(&ip("2001:db8::1")));
+
+        let everything: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(&ip("192.168.0.1")));
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("10.0.0/8".parse::<IpNetwork>().is_err());
+        assert!("2001:db8::/x".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn most_specific_range_matches() {
+        let mut map = IpNetworkMap::default();
+        map.insert("10.0.0.0/8".parse().unwrap(), 8);
+        map.insert("10.1.0.0/16".parse().unwrap(), 16);
+        map.insert(ip("10.1.0.1").into(), 32);
+        map.insert("2001:db8::/32".parse().unwrap(), 32);
+
+        assert_eq!(map.get(&ip("10.1.0.1")), Some(&32));
+        assert_eq!(map.get(&ip("10.1.0.2")), Some(&16));
+        assert_eq!(map.get(&ip("10.2.0.1")), Some(&8));
+        assert_eq!(map.get(&ip("11.0.0.1")), None);
+        assert_eq!(map.get(&ip("2001:db8:1::1")), Some(&32));
+        assert_eq!(map.len(), 4);
+
+        // Removing a range keeps the ranges it overlaps.
+        assert_eq!(map.remove(&"10.1.0.0/16".parse().unwrap()), Some(16));
+        assert_eq!(map.get(&ip("10.1.0.2")), Some(&8));
+
+        map.retain(|network, _| network.addr().is_ipv6());
+        assert_eq!(map.len(), 1);
+        assert_eq!(
+            map.iter()
+                .map(|(network, _)| network.to_string())
+                .collect::<Vec<_>>(),
+            vec!["2001:db8::/32"]
+        );
+    }
+
+    #[test]
+    fn expired_bans_are_removed() {
+        let mut list = PermitBanList::default();
+        let now = Instant::now();
+        let node_address = NodeAddress::new("10.0.0.1:9000".parse().unwrap(), NodeId::random());
+        list.ban(
+            node_address,
+            Ban::by_service(Some(now + Duration::from_secs(1)), "invalid response"),
+        );
+        list.ban_ips.insert(
+            "192.168.0.0/16".parse().unwrap(),
+            Ban::by_user(None, Some("hosting provider".into())),
+        );
+
+        assert!(list.is_ip_banned(&ip("192.168.3.4")));
+        assert!(list.is_ip_banned(&ip("10.0.0.1")));
+        assert_eq!(list.bans().count(), 3);
+
+        list.remove_expired(now + Duration::from_secs(2));
+        assert!(!list.is_ip_banned(&ip("10.0.0.1")));
+        let bans: Vec<_> = list.bans().collect();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].1.origin, BanOrigin::User);
     }
 }
@@ -0,0 +1,401 @@
+//! Exports and imports the [`PermitBanList`], and autosaves it through a [`PermitBanStore`].
+//!
+//! The list is stored as text, one entry per line:
+//!
+//! ```text
+//! discv5-permit-ban-list 1
+//! permit-node <node id in hex>
+//! permit-ip <ip range>
+//! ban-node <node id in hex> <expiry> <origin> [reason]
+//! ban-ip <ip range> <expiry> <origin> [reason]
+//! ```
+//!
+//! IP ranges are in CIDR notation. The expiry is a unix timestamp in seconds, or `never` for a
+//! permanent ban. The origin is one of `user`, `filter` or `service`. The reason is the rest of
+//! the line. Blank lines and lines starting with `#` are ignored.
+use super::{Ban, BanOrigin, IpNetwork, PermitBanList};
+use crate::Executor;
+use enr::NodeId;
+use parking_lot::Mutex;
+use std::{
+    fmt, fs, io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+
+/// The first line of an exported list, which identifies the format and its version.
+const HEADER: &str = "discv5-permit-ban-list 1";
+
+/// A failure to import a [`PermitBanList`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportError {
+    /// The line the failure occurred on, starting from 1.
+    pub line: usize,
+    /// What is wrong with the line.
+    pub message: &'static str,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl PermitBanList {
+    /// Exports the list in its text format. Expired bans are left out and the expiry of the others
+    /// is converted to wall-clock time.
+    pub fn export(&self) -> String {
+        let now = Instant::now();
+        let system_now = SystemTime::now();
+        let expiry = |ban: &Ban| match ban.expires {
+            Some(expires) => {
+                let expires = system_now + expires.saturating_duration_since(now);
+                let secs = expires
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_secs())
+                    .unwrap_or_default();
+                secs.to_string()
+            }
+            None => "never".to_string(),
+        };
+        let ban_line = |kind: &str, target: String, ban: &Ban| {
+            let mut line = format!("{} {} {} {}", kind, target, expiry(ban), ban.origin);
+            if let Some(reason) = &ban.reason {
+                // Keep the reason on a single line.
+                let reason: String = reason
+                    .chars()
+                    .map(|c| if c.is_control() { ' ' } else { c })
+                    .collect();
+                line.push(' ');
+                line.push_str(reason.trim());
+            }
+            line
+        };
+
+        let mut lines = Vec::new();
+        lines.extend(
+            self.permit_nodes
+                .iter()
+                .map(|node_id| format!("permit-node {}", hex::encode(node_id.raw()))),
+        );
+        lines.extend(
+            self.permit_ips
+                .iter()
+                .map(|(network, _)| format!("permit-ip {}", network)),
+        );
+        lines.extend(
+            self.ban_nodes
+                .iter()
+                .filter(|(_, ban)| !ban.has_expired(now))
+                .map(|(node_id, ban)| ban_line("ban-node", hex::encode(node_id.raw()), ban)),
+        );
+        lines.extend(
+            self.ban_ips
+                .iter()
+                .filter(|(_, ban)| !ban.has_expired(now))
+                .map(|(network, ban)| ban_line("ban-ip", network.to_string(), ban)),
+        );
+        // Sort the entries so that exports of the same list are identical.
+        lines.sort();
+
+        let mut exported = String::from(HEADER);
+        exported.push('\n');
+        for line in lines {
+            exported.push_str(&line);
+            exported.push('\n');
+        }
+        exported
+    }
+
+    /// Imports a list exported by [`PermitBanList::export`]. Bans that have expired in the
+    /// meantime are left out.
+    pub fn import(exported: &str) -> Result<Self, ImportError> {
+        let now = Instant::now();
+        let system_now = SystemTime::now();
+        let mut list = PermitBanList::default();
+        let mut lines = exported
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+        match lines.next() {
+            Some((_, HEADER)) => {}
+            Some((line, _)) => {
+                return Err(ImportError {
+                    line,
+                    message: "Unknown format or version",
+                })
+            }
+            None => return Ok(list),
+        }
+
+        for (line, entry) in lines {
+            let error = |message| ImportError { line, message };
+            let mut fields = entry.splitn(5, ' ');
+            let kind = fields.next().unwrap_or_default();
+            let target = fields.next().ok_or_else(|| error("Missing target"))?;
+            match kind {
+                "permit-node" => {
+                    list.permit_nodes
+                        .insert(parse_node_id(target).ok_or_else(|| error("Invalid node id"))?);
+                }
+                "permit-ip" => {
+                    list.permit_ips.add(target.parse().map_err(error)?);
+                }
+                "ban-node" | "ban-ip" => {
+                    let expires = match fields.next() {
+                        Some("never") => None,
+                        Some(secs) => {
+                            let secs = secs.parse().map_err(|_| error("Invalid expiry"))?;
+                            let expires = UNIX_EPOCH + std::time::Duration::from_secs(secs);
+                            match expires.duration_since(system_now) {
+                                Ok(remaining) => Some(now + remaining),
+                                // The ban has expired.
+                                Err(_) => continue,
+                            }
+                        }
+                        None => return Err(error("Missing expiry")),
+                    };
+                    let origin = match fields.next() {
+                        Some("user") => BanOrigin::User,
+                        Some("filter") => BanOrigin::Filter,
+                        Some("service") => BanOrigin::Service,
+                        _ => return Err(error("Invalid origin")),
+                    };
+                    let ban = Ban {
+                        expires,
+                        origin,
+                        reason: fields.next().map(|reason| reason.trim().to_string()),
+                    };
+                    if kind == "ban-node" {
+                        let node_id =
+                            parse_node_id(target).ok_or_else(|| error("Invalid node id"))?;
+                        list.ban_nodes.insert(node_id, ban);
+                    } else {
+                        let network: IpNetwork = target.parse().map_err(error)?;
+                        list.ban_ips.insert(network, ban);
+                    }
+                }
+                _ => return Err(error("Unknown entry")),
+            }
+        }
+        Ok(list)
+    }
+
+    /// Loads a list exported to a file. A file that does not exist gives an empty list.
+    pub fn load(path: impl Into<PathBuf>) -> io::Result<Self> {
+        match fs::read_to_string(path.into()) {
+            Ok(exported) => PermitBanList::import(&exported)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(PermitBanList::default()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn parse_node_id(hex_id: &str) -> Option<NodeId> {
+    let mut raw = [0u8; 32];
+    hex::decode_to_slice(hex_id.trim_start_matches("0x"), &mut raw).ok()?;
+    Some(NodeId::new(&raw))
+}
+
+impl fmt::Display for BanOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanOrigin::User => write!(f, "user"),
+            BanOrigin::Filter => write!(f, "filter"),
+            BanOrigin::Service => write!(f, "service"),
+        }
+    }
+}
+
+/// Storage to which the [`PermitBanList`] is autosaved, see
+/// [`crate::ConfigBuilder::permit_ban_store`].
+///
+/// The list is saved when it has changed, at most every few minutes and when discv5 shuts down.
+/// Saving runs on a blocking thread of the [`crate::Executor`].
+pub trait PermitBanStore: Send + Sync {
+    /// Stores the list, as exported by [`PermitBanList::export`].
+    fn save(&self, exported: &str) -> io::Result<()>;
+}
+
+/// Stores the [`PermitBanList`] in a file, which [`PermitBanList::load`] reads back.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileStore { path: path.into() }
+    }
+}
+
+impl PermitBanStore for FileStore {
+    fn save(&self, exported: &str) -> io::Result<()> {
+        // Write to a temporary file first, so that a crash never leaves a partial list behind.
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, exported)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+/// Saves the [`PermitBanList`] to a store whenever it has changed since the last save.
+pub(crate) struct Autosave {
+    store: Arc<dyn PermitBanStore>,
+    /// The list as it was last saved. It is locked for the duration of a save, so that saves
+    /// run one at a time.
+    saved: Arc<Mutex<Option<PermitBanList>>>,
+}
+
+impl Autosave {
+    pub(crate) fn new(store: Arc<dyn PermitBanStore>) -> Self {
+        Autosave {
+            store,
+            saved: Default::default(),
+        }
+    }
+
+    /// Saves the list on a blocking thread if it has changed since the last save. Returns a
+    /// receiver that completes once the save has finished, if one was started.
+    pub(crate) fn save(
+        &self,
+        list: PermitBanList,
+        executor: &dyn Executor,
+    ) -> Option<oneshot::Receiver<()>> {
+        // A save in progress holds the lock, in which case the list is compared once it is done.
+        if self
+            .saved
+            .try_lock()
+            .is_some_and(|saved| saved.as_ref() == Some(&list))
+        {
+            return None;
+        }
+        let store = self.store.clone();
+        let saved = self.saved.clone();
+        let (done, done_recv) = oneshot::channel();
+        executor.spawn_blocking(Box::new(move || {
+            let mut saved = saved.lock();
+            if saved.as_ref() != Some(&list) {
+                match store.save(&list.export()) {
+                    Ok(()) => {
+                        debug!("Saved the permit/ban list");
+                        *saved = Some(list);
+                    }
+                    Err(e) => warn!("Failed to save the permit/ban list: {}", e),
+                }
+            }
+            drop(saved);
+            let _ = done.send(());
+        }));
+        Some(done_recv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn export_and_import() {
+        let now = Instant::now();
+        let node_id = NodeId::random();
+        let mut list = PermitBanList::default();
+        list.permit_nodes.insert(NodeId::random());
+        list.permit_ips.add("10.0.0.0/16".parse().unwrap());
+        list.ban_nodes.insert(
+            node_id,
+            Ban::by_service(Some(now + Duration::from_secs(3600)), "invalid\nresponse"),
+        );
+        list.ban_ips
+            .insert("2001:db8::/32".parse().unwrap(), Ban::by_user(None, None));
+        list.ban_ips.insert(
+            "192.168.0.1".parse().unwrap(),
+            Ban::by_filter(Some(now), "expired"),
+        );
+
+        let exported = list.export();
+        assert_eq!(exported, list.export());
+        assert!(exported.starts_with(HEADER));
+        assert!(!exported.contains("expired"));
+
+        let imported = PermitBanList::import(&exported).unwrap();
+        assert_eq!(imported.permit_nodes, list.permit_nodes);
+        assert_eq!(imported.permit_ips.len(), 1);
+        assert_eq!(imported.ban_ips.len(), 1);
+        let ban = &imported.ban_nodes[&node_id];
+        assert_eq!(ban.origin, BanOrigin::Service);
+        assert_eq!(ban.reason.as_deref(), Some("invalid response"));
+        // Expiry is stored with a precision of a second.
+        let expires = ban.expires.unwrap();
+        let expected = now + Duration::from_secs(3600);
+        assert!(expires.max(expected) - expires.min(expected) < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn invalid_lists_are_rejected() {
+        assert!(PermitBanList::import("").unwrap().ban_nodes.is_empty());
+        assert_eq!(
+            PermitBanList::import("discv5-permit-ban-list 2\n")
+                .unwrap_err()
+                .line,
+            1
+        );
+        let error = PermitBanList::import(&format!(
+            "{}\n\n# comment\nban-ip 10.0.0.0/8 never\n",
+            HEADER
+        ))
+        .unwrap_err();
+        assert_eq!(error.line, 4);
+        assert!(PermitBanList::import(&format!("{}\nban-node 1234 never user\n", HEADER)).is_err());
+        // A ban that has expired since the export is left out.
+        let list = PermitBanList::import(&format!("{}\nban-ip 10.0.0.1 1 filter\n", HEADER));
+        assert!(list.unwrap().ban_ips.is_empty());
+    }
+
+    #[derive(Default)]
+    struct CountingStore {
+        saves: Mutex<Vec<String>>,
+    }
+
+    impl PermitBanStore for CountingStore {
+        fn save(&self, exported: &str) -> io::Result<()> {
+            self.saves.lock().push(exported.to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn autosave_saves_changes_off_the_task() {
+        let store = Arc::new(CountingStore::default());
+        let autosave = Autosave::new(store.clone());
+        let executor = crate::executor::TokioExecutor;
+        let mut list = PermitBanList::default();
+
+        autosave
+            .save(list.clone(), &executor)
+            .unwrap()
+            .await
+            .unwrap();
+        // An unchanged list is not saved again.
+        assert!(autosave.save(list.clone(), &executor).is_none());
+
+        list.permit_nodes.insert(NodeId::random());
+        autosave
+            .save(list.clone(), &executor)
+            .unwrap()
+            .await
+            .unwrap();
+        let saves = store.saves.lock();
+        assert_eq!(saves.len(), 2);
+        assert_eq!(saves[1], list.export());
+    }
+}
@@ -0,0 +1,457 @@
+//! Requests a port mapping for the IPv4 listening socket from the gateway of the local network.
+//!
+//! Nodes behind a NAT can only be reached by peers if the NAT forwards inbound packets to them.
+//! The [`PortMapper`] asks the gateway to forward a UDP port to the local listening socket, using
+//! PCP, its predecessor NAT-PMP or UPnP-IGD, and renews the mapping before its lease expires. The
+//! mapped external socket is reported to the service, which advertises it in the local ENR. The
+//! mapping is removed when the mapper is shut down.
+use self::natpmp::GrantedMapping as Granted;
+use crate::Executor;
+use std::{
+    io::{Error, ErrorKind},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+#[cfg(test)]
+pub(crate) mod mock;
+mod natpmp;
+mod upnp;
+
+/// A protocol to request port mappings from the gateway with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingProtocol {
+    /// The Port Control Protocol (RFC 6887).
+    Pcp,
+    /// NAT Port Mapping Protocol (RFC 6886).
+    NatPmp,
+    /// The WAN connection service of a UPnP Internet Gateway Device.
+    Upnp,
+}
+
+/// Configuration of the port mapping of the IPv4 listening socket.
+#[derive(Debug, Clone)]
+pub struct PortMappingConfig {
+    /// The PCP/NAT-PMP server of the gateway. If `None`, the default gateway of the host is used
+    /// where it can be determined (Linux). Default: `None`.
+    pub gateway: Option<SocketAddr>,
+
+    /// The address SSDP searches for UPnP gateways are sent to. Default: 239.255.255.250:1900.
+    pub upnp_search_address: SocketAddr,
+
+    /// The protocols to try, in order of preference. Default: PCP, NAT-PMP, UPnP.
+    pub protocols: Vec<MappingProtocol>,
+
+    /// The requested lifetime of a mapping. Mappings are renewed after half of the lifetime
+    /// granted by the gateway. Default: 1 hour.
+    pub lease_duration: Duration,
+
+    /// The time to wait for the gateway to respond to a request. Default: 3 seconds.
+    pub request_timeout: Duration,
+
+    /// The time to wait before trying again if no gateway granted a mapping. Default: 5 minutes.
+    pub retry_interval: Duration,
+
+    /// The external port to request. If `None`, the listening port is requested. The gateway may
+    /// grant a different port. Default: `None`.
+    pub external_port: Option<u16>,
+}
+
+impl Default for PortMappingConfig {
+    fn default() -> Self {
+        PortMappingConfig {
+            gateway: None,
+            upnp_search_address: upnp::SSDP_ADDRESS,
+            protocols: vec![
+                MappingProtocol::Pcp,
+                MappingProtocol::NatPmp,
+                MappingProtocol::Upnp,
+            ],
+            lease_duration: Duration::from_secs(60 * 60),
+            request_timeout: Duration::from_secs(3),
+            retry_interval: Duration::from_secs(5 * 60),
+            external_port: None,
+        }
+    }
+}
+
+/// A mapping granted by a gateway, along with the client needed to renew and delete it.
+struct Mapping {
+    gateway: Gateway,
+    granted: Granted,
+}
+
+enum Gateway {
+    Pcp {
+        client: natpmp::Client,
+        internal: SocketAddrV4,
+    },
+    NatPmp(natpmp::Client),
+    Upnp {
+        gateway: upnp::Gateway,
+        internal: SocketAddrV4,
+    },
+}
+
+/// Maintains a port mapping in a background task.
+pub(crate) struct PortMapper {
+    /// The external sockets granted by the gateway. A socket is only reported when it changes.
+    updates: mpsc::Receiver<SocketAddr>,
+    /// Stops the task, which deletes the mapping.
+    exit: Option<oneshot::Sender<()>>,
+    /// Resolves once the task has ended.
+    exited: Option<oneshot::Receiver<()>>,
+}
+
+impl PortMapper {
+    /// Spawns a task which maps an external port to the local `internal_port`.
+    pub fn spawn(
+        config: PortMappingConfig,
+        internal_port: u16,
+        executor: Box<dyn Executor + Send + Sync>,
+    ) -> Self {
+        let (updates_send, updates) = mpsc::channel(5);
+        let (exit, exit_recv) = oneshot::channel();
+        let (exited_send, exited) = oneshot::channel();
+
+        executor.spawn(Box::pin(async move {
+            let task = MapperTask {
+                config,
+                internal_port,
+                nonce: rand::random(),
+                updates: updates_send,
+            };
+            task.run(exit_recv).await;
+            let _ = exited_send.send(());
+        }));
+
+        PortMapper {
+            updates,
+            exit: Some(exit),
+            exited: Some(exited),
+        }
+    }
+
+    /// Waits for the next external socket. Never resolves if there is no mapper.
+    pub async fn next_update(mapper: &mut Option<Self>) -> SocketAddr {
+        if let Some(mapper) = mapper {
+            if let Some(socket) = mapper.updates.recv().await {
+                return socket;
+            }
+        }
+        futures::future::pending().await
+    }
+
+    /// Stops the mapper without waiting for the mapping to be deleted.
+    pub fn stop(&mut self) {
+        if let Some(exit) = self.exit.take() {
+            let _ = exit.send(());
+        }
+    }
+
+    /// Stops the mapper and waits until the mapping is deleted.
+    pub async fn shutdown(&mut self) {
+        self.stop();
+        if let Some(exited) = self.exited.take() {
+            let _ = exited.await;
+        }
+    }
+}
+
+struct MapperTask {
+    config: PortMappingConfig,
+    internal_port: u16,
+    /// Identifies the PCP mapping when it is renewed or deleted.
+    nonce: [u8; 12],
+    updates: mpsc::Sender<SocketAddr>,
+}
+
+impl MapperTask {
+    async fn run(self, mut exit: oneshot::Receiver<()>) {
+        let mut mapping: Option<Mapping> = None;
+        let mut reported: Option<SocketAddrV4> = None;
+        let mut wait = Duration::ZERO;
+        loop {
+            tokio::select! {
+                _ = &mut exit => break,
+                _ = tokio::time::sleep(wait) => {}
+            }
+
+            // A mapping that is being renewed when the mapper stops is still deleted.
+            let result = tokio::select! {
+                _ = &mut exit => break,
+                result = self.request(&mut mapping) => result,
+            };
+            match result {
+                Ok(granted) => {
+                    debug!(external = %granted.external, lifetime = ?granted.lifetime, "Port mapping granted");
+                    wait = granted.lifetime / 2;
+                    if reported != Some(granted.external) {
+                        reported = Some(granted.external);
+                        let _ = self.updates.send(SocketAddr::V4(granted.external)).await;
+                    }
+                }
+                Err(e) => {
+                    debug!(error = %e, "Failed to map the listening port");
+                    wait = self.config.retry_interval;
+                }
+            }
+        }
+
+        if let Some(mapping) = mapping {
+            match self.delete(mapping).await {
+                Ok(()) => info!("Port mapping removed"),
+                Err(e) => warn!(error = %e, "Failed to remove the port mapping"),
+            }
+        }
+    }
+
+    /// Renews the current mapping with the same gateway, or requests a new mapping from the
+    /// configured protocols in order.
+    async fn request(&self, mapping: &mut Option<Mapping>) -> Result<Granted, Error> {
+        if let Some(current) = mapping {
+            let external_port = current.granted.external.port();
+            match self.map(&current.gateway, external_port).await {
+                Ok(granted) => {
+                    current.granted = granted;
+                    return Ok(granted);
+                }
+                Err(e) => {
+                    debug!(error = %e, "Failed to renew the port mapping");
+                    *mapping = None;
+                }
+            }
+        }
+
+        let suggested_port = self.config.external_port.unwrap_or(self.internal_port);
+        let mut last_error = Error::new(ErrorKind::NotFound, "No mapping protocol configured");
+        for protocol in self.config.protocols.iter().copied() {
+            let result = match self.gateway(protocol).await {
+                Ok(gateway) => self
+                    .map(&gateway, suggested_port)
+                    .await
+                    .map(|granted| (gateway, granted)),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok((gateway, granted)) => {
+                    info!(external = %granted.external, ?protocol, "Mapped the listening port");
+                    *mapping = Some(Mapping { gateway, granted });
+                    return Ok(granted);
+                }
+                Err(e) => {
+                    debug!(?protocol, error = %e, "Port mapping protocol failed");
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Locates the gateway serving `protocol`.
+    async fn gateway(&self, protocol: MappingProtocol) -> Result<Gateway, Error> {
+        let timeout = self.config.request_timeout;
+        match protocol {
+            MappingProtocol::Pcp | MappingProtocol::NatPmp => {
+                let server = self
+                    .config
+                    .gateway
+                    .or_else(|| {
+                        default_gateway().map(|ip| SocketAddr::from((ip, natpmp::SERVER_PORT)))
+                    })
+                    .ok_or_else(|| Error::new(ErrorKind::NotFound, "No default gateway found"))?;
+                let client = natpmp::Client::new(server, timeout).await?;
+                if protocol == MappingProtocol::Pcp {
+                    let internal = SocketAddrV4::new(client.local_ip()?, self.internal_port);
+                    Ok(Gateway::Pcp { client, internal })
+                } else {
+                    Ok(Gateway::NatPmp(client))
+                }
+            }
+            MappingProtocol::Upnp => {
+                let gateway =
+                    upnp::Gateway::search(self.config.upnp_search_address, timeout).await?;
+                let internal = SocketAddrV4::new(gateway.local_ip().await?, self.internal_port);
+                Ok(Gateway::Upnp { gateway, internal })
+            }
+        }
+    }
+
+    /// Requests a mapping of `external_port` from `gateway` for the configured lease duration.
+    async fn map(&self, gateway: &Gateway, external_port: u16) -> Result<Granted, Error> {
+        let lease = self.config.lease_duration;
+        let granted = match gateway {
+            Gateway::Pcp { client, internal } => {
+                client
+                    .pcp_map(&self.nonce, *internal, external_port, lease)
+                    .await?
+            }
+            Gateway::NatPmp(client) => {
+                client
+                    .natpmp_map(self.internal_port, external_port, lease)
+                    .await?
+            }
+            Gateway::Upnp { gateway, internal } => {
+                gateway
+                    .add_port_mapping(external_port, *internal, lease)
+                    .await?;
+                Granted {
+                    external: SocketAddrV4::new(gateway.external_ip().await?, external_port),
+                    lifetime: lease,
+                }
+            }
+        };
+        if granted.external.ip().is_unspecified()
+            || granted.external.port() == 0
+            || granted.lifetime.is_zero()
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "The gateway granted an invalid mapping",
+            ));
+        }
+        Ok(granted)
+    }
+
+    /// Removes a mapping from its gateway.
+    async fn delete(&self, mapping: Mapping) -> Result<(), Error> {
+        match mapping.gateway {
+            Gateway::Pcp { client, internal } => client
+                .pcp_map(&self.nonce, internal, 0, Duration::ZERO)
+                .await
+                .map(|_| ()),
+            Gateway::NatPmp(client) => client
+                .natpmp_map(self.internal_port, 0, Duration::ZERO)
+                .await
+                .map(|_| ()),
+            Gateway::Upnp { gateway, .. } => {
+                gateway
+                    .delete_port_mapping(mapping.granted.external.port())
+                    .await
+            }
+        }
+    }
+}
+
+/// The IPv4 default gateway of the host, read from the kernel routing table.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_default_gateway(&routes)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+/// Finds the gateway of the default route in the format of `/proc/net/route`. Addresses are
+/// hexadecimal in host byte order.
+#[cfg_attr(not(any(target_os = "linux", test)), allow(dead_code))]
+fn parse_default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        let gateway = Ipv4Addr::from(gateway.to_ne_bytes());
+        Some(gateway).filter(|ip| !ip.is_unspecified())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TokioExecutor;
+
+    fn config(gateway: SocketAddr, protocols: Vec<MappingProtocol>) -> PortMappingConfig {
+        PortMappingConfig {
+            gateway: Some(gateway),
+            protocols,
+            request_timeout: Duration::from_millis(500),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_default_gateway() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                      eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                      eth0\t00000000\t0100A8C0\t0003\t0\t0\t0\t00000000\n";
+        assert_eq!(
+            parse_default_gateway(routes),
+            Some(Ipv4Addr::new(192, 168, 0, 1))
+        );
+        assert_eq!(parse_default_gateway("Iface\tDestination\tGateway\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_natpmp_and_deletes_on_shutdown() {
+        let mut gateway = mock::NatPmpGateway::spawn(false).await;
+        let mut mapper = Some(PortMapper::spawn(
+            config(
+                gateway.addr,
+                vec![MappingProtocol::Pcp, MappingProtocol::NatPmp],
+            ),
+            9000,
+            Box::<TokioExecutor>::default(),
+        ));
+
+        assert_eq!(
+            PortMapper::next_update(&mut mapper).await,
+            SocketAddr::from((mock::EXTERNAL_IP, 9000))
+        );
+        assert_eq!(
+            gateway.requests.recv().await,
+            Some(mock::Request::NatPmp {
+                internal_port: 9000,
+                lifetime: 3600
+            })
+        );
+
+        mapper.as_mut().unwrap().shutdown().await;
+        assert_eq!(
+            gateway.requests.recv().await,
+            Some(mock::Request::NatPmp {
+                internal_port: 9000,
+                lifetime: 0
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upnp_mapping() {
+        let mut gateway = mock::UpnpGateway::spawn().await;
+        let mut mapper = Some(PortMapper::spawn(
+            PortMappingConfig {
+                upnp_search_address: gateway.ssdp_addr,
+                external_port: Some(9500),
+                ..config(gateway.ssdp_addr, vec![MappingProtocol::Upnp])
+            },
+            9000,
+            Box::<TokioExecutor>::default(),
+        ));
+
+        assert_eq!(
+            PortMapper::next_update(&mut mapper).await,
+            SocketAddr::from((mock::EXTERNAL_IP, 9500))
+        );
+        mapper.as_mut().unwrap().shutdown().await;
+
+        let mut actions = Vec::new();
+        while let Ok(action) = gateway.actions.try_recv() {
+            actions.push(action);
+        }
+        assert_eq!(
+            actions,
+            vec![
+                "AddPortMapping",
+                "GetExternalIPAddress",
+                "DeletePortMapping"
+            ]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Local gateways for testing the port mapping clients.
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    sync::mpsc,
+};
+
+/// The external IP address reported by the mock gateways.
+pub const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+
+/// A mapping request received by a [`NatPmpGateway`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    Pcp { internal_port: u16, lifetime: u32 },
+    NatPmp { internal_port: u16, lifetime: u32 },
+}
+
+/// A PCP/NAT-PMP server which grants every mapping with the suggested external port.
+pub struct NatPmpGateway {
+    pub addr: SocketAddr,
+    pub requests: mpsc::UnboundedReceiver<Request>,
+}
+
+impl NatPmpGateway {
+    /// Spawns a gateway which answers PCP requests if `supports_pcp` is set, and NAT-PMP
+    /// requests.
+    pub async fn spawn(supports_pcp: bool) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (requests_send, requests) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1100];
+            while let Ok((length, src)) = socket.recv_from(&mut buffer).await {
+                let request = &buffer[..length];
+                let response = match (request[0], request[1]) {
+                    (2, 1) if supports_pcp => {
+                        let lifetime =
+                            u32::from_be_bytes([request[4], request[5], request[6], request[7]]);
+                        let internal_port = u16::from_be_bytes([request[40], request[41]]);
+                        let _ = requests_send.send(Request::Pcp {
+                            internal_port,
+                            lifetime,
+                        });
+                        let mut response = request.to_vec();
+                        response[1] = 0x81;
+                        response[3] = 0;
+                        response[8..24].fill(0);
+                        response[44..60].copy_from_slice(&EXTERNAL_IP.to_ipv6_mapped().octets());
+                        response
+                    }
+                    (2, opcode) => vec![0, 0x80 | opcode, 0, 1],
+                    (0, 0) => {
+                        let mut response = vec![0, 0x80, 0, 0, 0, 0, 0, 1];
+                        response.extend_from_slice(&EXTERNAL_IP.octets());
+                        response
+                    }
+                    (0, 1) => {
+                        let internal_port = u16::from_be_bytes([request[4], request[5]]);
+                        let lifetime =
+                            u32::from_be_bytes([request[8], request[9], request[10], request[11]]);
+                        let _ = requests_send.send(Request::NatPmp {
+                            internal_port,
+                            lifetime,
+                        });
+                        let mut response = vec![0, 0x81, 0, 0, 0, 0, 0, 1];
+                        response.extend_from_slice(&request[4..12]);
+                        response
+                    }
+                    _ => continue,
+                };
+                let _ = socket.send_to(&response, src).await;
+            }
+        });
+
+        NatPmpGateway { addr, requests }
+    }
+}
+
+/// A UPnP gateway answering SSDP searches, the device description and SOAP actions.
+pub struct UpnpGateway {
+    pub ssdp_addr: SocketAddr,
+    /// The names of the SOAP actions invoked.
+    pub actions: mpsc::UnboundedReceiver<String>,
+}
+
+impl UpnpGateway {
+    pub async fn spawn() -> Self {
+        let ssdp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ssdp_addr = ssdp.local_addr().unwrap();
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_addr = http.local_addr().unwrap();
+        let (actions_send, actions) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 2048];
+            while let Ok((_, src)) = ssdp.recv_from(&mut buffer).await {
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                     LOCATION: http://{http_addr}/rootDesc.xml\r\n\r\n"
+                );
+                let _ = ssdp.send_to(response.as_bytes(), src).await;
+            }
+        });
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = http.accept().await {
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 2048];
+                // Read the head and the body announced by the content length.
+                let request = loop {
+                    let length = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..length]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let content_length = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("Content-Length: "))
+                            .map_or(0, |length| length.parse().unwrap());
+                        if body.len() >= content_length || length == 0 {
+                            break text;
+                        }
+                    }
+                };
+
+                let body = if request.starts_with("GET /rootDesc.xml") {
+                    "<?xml version=\"1.0\"?><root><device><serviceList><service>\
+                     <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                     <controlURL>/ctl/IPConn</controlURL></service></serviceList></device></root>"
+                        .to_string()
+                } else {
+                    let action = request
+                        .lines()
+                        .find_map(|line| line.strip_prefix("SOAPAction: "))
+                        .and_then(|action| action.trim_matches('"').split('#').nth(1))
+                        .unwrap()
+                        .to_string();
+                    let _ = actions_send.send(action.clone());
+                    format!(
+                        "<s:Envelope><s:Body><u:{action}Response>\
+                         <NewExternalIPAddress>{EXTERNAL_IP}</NewExternalIPAddress>\
+                         </u:{action}Response></s:Body></s:Envelope>"
+                    )
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     {:x}\r\n{body}\r\n0\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        UpnpGateway { ssdp_addr, actions }
+    }
+}
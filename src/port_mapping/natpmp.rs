@@ -0,0 +1,311 @@
+//! A client for PCP (RFC 6887) and its predecessor NAT-PMP (RFC 6886).
+//!
+//! Both protocols are served on the same UDP port of the gateway. A NAT-PMP server answers a PCP
+//! request with an unsupported version error, after which the NAT-PMP requests can be used.
+use std::{
+    convert::TryInto,
+    io::{Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, time::Instant};
+
+/// The port PCP and NAT-PMP servers listen on.
+pub const SERVER_PORT: u16 = 5351;
+
+/// The initial retransmission interval, doubled after every retransmission.
+const INITIAL_RETRANSMIT: Duration = Duration::from_millis(250);
+
+const PCP_VERSION: u8 = 2;
+const PCP_OPCODE_MAP: u8 = 1;
+const PCP_RESPONSE_BIT: u8 = 0x80;
+const PCP_MAP_PACKET_SIZE: usize = 60;
+const NATPMP_VERSION: u8 = 0;
+const NATPMP_OPCODE_EXTERNAL_ADDRESS: u8 = 0;
+const NATPMP_OPCODE_MAP_UDP: u8 = 1;
+const NATPMP_RESPONSE_BIT: u8 = 0x80;
+const NATPMP_UNSUPPORTED_VERSION: u16 = 1;
+const PROTOCOL_UDP: u8 = 17;
+
+/// A UDP port mapping granted by the gateway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrantedMapping {
+    /// The external socket that is forwarded to the internal port.
+    pub external: SocketAddrV4,
+    /// The time until the mapping expires.
+    pub lifetime: Duration,
+}
+
+/// A client for the PCP/NAT-PMP server of a gateway.
+pub struct Client {
+    socket: UdpSocket,
+    timeout: Duration,
+}
+
+impl Client {
+    /// Creates a client for the server at `gateway`. Each request is retransmitted until a
+    /// response arrives or `timeout` expires.
+    pub async fn new(gateway: SocketAddr, timeout: Duration) -> Result<Self, Error> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(gateway).await?;
+        Ok(Client { socket, timeout })
+    }
+
+    /// The local IP address used to reach the gateway.
+    pub fn local_ip(&self) -> Result<Ipv4Addr, Error> {
+        match self.socket.local_addr()?.ip() {
+            IpAddr::V4(ip) => Ok(ip),
+            IpAddr::V6(_) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "Only IPv4 gateways are supported",
+            )),
+        }
+    }
+
+    /// Requests a PCP mapping of the `internal` UDP socket. A lifetime of zero deletes the
+    /// mapping. The `nonce` identifies the mapping and must be reused when renewing or deleting
+    /// it.
+    pub async fn pcp_map(
+        &self,
+        nonce: &[u8; 12],
+        internal: SocketAddrV4,
+        suggested_external_port: u16,
+        lifetime: Duration,
+    ) -> Result<GrantedMapping, Error> {
+        let mut request = [0u8; PCP_MAP_PACKET_SIZE];
+        request[0] = PCP_VERSION;
+        request[1] = PCP_OPCODE_MAP;
+        request[4..8].copy_from_slice(&lifetime_secs(lifetime).to_be_bytes());
+        request[8..24].copy_from_slice(&internal.ip().to_ipv6_mapped().octets());
+        request[24..36].copy_from_slice(nonce);
+        request[36] = PROTOCOL_UDP;
+        request[40..42].copy_from_slice(&internal.port().to_be_bytes());
+        request[42..44].copy_from_slice(&suggested_external_port.to_be_bytes());
+        request[44..60].copy_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+        self.exchange(&request, |response| {
+            if response.len() >= 4 && response[0] == NATPMP_VERSION {
+                return Some(Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "The gateway does not support PCP",
+                )));
+            }
+            if response.len() < 24
+                || response[0] != PCP_VERSION
+                || response[1] != PCP_RESPONSE_BIT | PCP_OPCODE_MAP
+            {
+                return None;
+            }
+            if response[3] != 0 {
+                return Some(Err(Error::other(format!(
+                    "PCP request failed with result code {}",
+                    response[3]
+                ))));
+            }
+            if response.len() < PCP_MAP_PACKET_SIZE || &response[24..36] != nonce {
+                return None;
+            }
+            let lifetime = u32::from_be_bytes(response[4..8].try_into().expect("Correct size"));
+            let port = u16::from_be_bytes([response[42], response[43]]);
+            let ip: [u8; 16] = response[44..60].try_into().expect("Correct size");
+            let ip = match Ipv6Addr::from(ip).to_ipv4_mapped() {
+                Some(ip) => ip,
+                None => {
+                    return Some(Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "PCP response contains an IPv6 external address",
+                    )))
+                }
+            };
+            Some(Ok(GrantedMapping {
+                external: SocketAddrV4::new(ip, port),
+                lifetime: Duration::from_secs(lifetime.into()),
+            }))
+        })
+        .await
+    }
+
+    /// Requests a NAT-PMP mapping of the internal UDP port. A lifetime of zero deletes the
+    /// mapping.
+    pub async fn natpmp_map(
+        &self,
+        internal_port: u16,
+        suggested_external_port: u16,
+        lifetime: Duration,
+    ) -> Result<GrantedMapping, Error> {
+        let external_ip = self.natpmp_external_ip().await?;
+
+        let mut request = [0u8; 12];
+        request[0] = NATPMP_VERSION;
+        request[1] = NATPMP_OPCODE_MAP_UDP;
+        request[4..6].copy_from_slice(&internal_port.to_be_bytes());
+        request[6..8].copy_from_slice(&suggested_external_port.to_be_bytes());
+        request[8..12].copy_from_slice(&lifetime_secs(lifetime).to_be_bytes());
+
+        self.exchange(&request, |response| {
+            if response.len() < 16
+                || response[0] != NATPMP_VERSION
+                || response[1] != NATPMP_RESPONSE_BIT | NATPMP_OPCODE_MAP_UDP
+                || u16::from_be_bytes([response[8], response[9]]) != internal_port
+            {
+                return None;
+            }
+            if let Err(e) = natpmp_result(response) {
+                return Some(Err(e));
+            }
+            let port = u16::from_be_bytes([response[10], response[11]]);
+            let lifetime = u32::from_be_bytes(response[12..16].try_into().expect("Correct size"));
+            Some(Ok(GrantedMapping {
+                external: SocketAddrV4::new(external_ip, port),
+                lifetime: Duration::from_secs(lifetime.into()),
+            }))
+        })
+        .await
+    }
+
+    /// Requests the external IP address of the gateway over NAT-PMP.
+    async fn natpmp_external_ip(&self) -> Result<Ipv4Addr, Error> {
+        let request = [NATPMP_VERSION, NATPMP_OPCODE_EXTERNAL_ADDRESS];
+        self.exchange(&request, |response| {
+            if response.len() < 12
+                || response[0] != NATPMP_VERSION
+                || response[1] != NATPMP_RESPONSE_BIT | NATPMP_OPCODE_EXTERNAL_ADDRESS
+            {
+                return None;
+            }
+            Some(
+                natpmp_result(response)
+                    .map(|_| Ipv4Addr::new(response[8], response[9], response[10], response[11])),
+            )
+        })
+        .await
+    }
+
+    /// Sends `request` to the gateway, retransmitting it with an exponential backoff, until
+    /// `parse` accepts a response or the timeout expires. Responses for which `parse` returns
+    /// `None` are ignored.
+    async fn exchange<T>(
+        &self,
+        request: &[u8],
+        mut parse: impl FnMut(&[u8]) -> Option<Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let deadline = Instant::now() + self.timeout;
+        let mut retransmit = INITIAL_RETRANSMIT;
+        let mut buffer = [0u8; 1100];
+        loop {
+            self.socket.send(request).await?;
+            let wait_until = std::cmp::min(Instant::now() + retransmit, deadline);
+            while let Ok(result) =
+                tokio::time::timeout_at(wait_until, self.socket.recv(&mut buffer)).await
+            {
+                if let Some(result) = parse(&buffer[..result?]) {
+                    return result;
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "No response from the gateway",
+                ));
+            }
+            retransmit *= 2;
+        }
+    }
+}
+
+/// Checks the result code of a NAT-PMP response.
+fn natpmp_result(response: &[u8]) -> Result<(), Error> {
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 => Ok(()),
+        NATPMP_UNSUPPORTED_VERSION => Err(Error::new(
+            ErrorKind::Unsupported,
+            "The gateway does not support NAT-PMP",
+        )),
+        code => Err(Error::other(format!(
+            "NAT-PMP request failed with result code {code}"
+        ))),
+    }
+}
+
+fn lifetime_secs(lifetime: Duration) -> u32 {
+    lifetime.as_secs().try_into().unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::mock, *};
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    #[tokio::test]
+    async fn test_pcp_map_and_delete() {
+        let mut gateway = mock::NatPmpGateway::spawn(true).await;
+        let client = Client::new(gateway.addr, TIMEOUT).await.unwrap();
+        let internal = SocketAddrV4::new(client.local_ip().unwrap(), 9000);
+        let nonce = [7u8; 12];
+
+        let mapping = client
+            .pcp_map(&nonce, internal, 9000, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(mapping.external, SocketAddrV4::new(mock::EXTERNAL_IP, 9000));
+        assert_eq!(mapping.lifetime, Duration::from_secs(3600));
+        assert_eq!(
+            gateway.requests.recv().await,
+            Some(mock::Request::Pcp {
+                internal_port: 9000,
+                lifetime: 3600
+            })
+        );
+
+        client
+            .pcp_map(&nonce, internal, 9000, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(
+            gateway.requests.recv().await,
+            Some(mock::Request::Pcp {
+                internal_port: 9000,
+                lifetime: 0
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_natpmp_only_gateway() {
+        let mut gateway = mock::NatPmpGateway::spawn(false).await;
+        let client = Client::new(gateway.addr, TIMEOUT).await.unwrap();
+        let internal = SocketAddrV4::new(client.local_ip().unwrap(), 9000);
+
+        let error = client
+            .pcp_map(&[1u8; 12], internal, 9000, Duration::from_secs(3600))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+
+        let mapping = client
+            .natpmp_map(9000, 9000, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(mapping.external, SocketAddrV4::new(mock::EXTERNAL_IP, 9000));
+        assert_eq!(
+            gateway.requests.recv().await,
+            Some(mock::Request::NatPmp {
+                internal_port: 9000,
+                lifetime: 3600
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_gateway() {
+        // Nothing listens on the discard port.
+        let client = Client::new("127.0.0.1:9".parse().unwrap(), Duration::from_millis(300))
+            .await
+            .unwrap();
+        assert!(client
+            .natpmp_map(9000, 9000, Duration::from_secs(3600))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,358 @@
+//! A minimal UPnP Internet Gateway Device client.
+//!
+//! The gateway is discovered with an SSDP search. Its device description names the control URL
+//! of the WAN connection service, which accepts the SOAP actions to add and delete port mappings.
+use std::{
+    io::{Error, ErrorKind},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::Instant,
+};
+
+/// The multicast address SSDP searches are sent to.
+pub const SSDP_ADDRESS: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900));
+
+/// The device searched for.
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+/// The WAN connection services that manage port mappings.
+const CONNECTION_SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:",
+    "urn:schemas-upnp-org:service:WANPPPConnection:",
+];
+
+/// The description of the mappings we create.
+const MAPPING_DESCRIPTION: &str = "discv5";
+
+/// A discovered gateway.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gateway {
+    /// The HTTP server of the gateway.
+    addr: SocketAddr,
+    /// The path of the control URL of the WAN connection service.
+    control_path: String,
+    /// The type of the WAN connection service.
+    service_type: String,
+    /// The timeout of each request.
+    timeout: Duration,
+}
+
+impl Gateway {
+    /// Searches for a gateway by sending an SSDP search to `ssdp_address`.
+    pub async fn search(ssdp_address: SocketAddr, timeout: Duration) -> Result<Self, Error> {
+        let deadline = Instant::now() + timeout;
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {ssdp_address}\r\nST: {SEARCH_TARGET}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n"
+        );
+        socket.send_to(search.as_bytes(), ssdp_address).await?;
+
+        let mut buffer = [0u8; 2048];
+        loop {
+            let (length, _) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer))
+                .await
+                .map_err(|_| Error::new(ErrorKind::TimedOut, "No UPnP gateway found"))??;
+            let response = String::from_utf8_lossy(&buffer[..length]);
+            let location = match header(&response, "location") {
+                Some(location) => location.to_string(),
+                None => continue,
+            };
+            match Gateway::from_description(&location, timeout).await {
+                Ok(gateway) => return Ok(gateway),
+                Err(e) => tracing::debug!("Ignoring UPnP device at {}: {}", location, e),
+            }
+        }
+    }
+
+    /// Reads the device description at `location` and finds the WAN connection service.
+    async fn from_description(location: &str, timeout: Duration) -> Result<Self, Error> {
+        let (addr, path) = parse_url(location)?;
+        let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+        let (status, description) = http_request(addr, &request, timeout).await?;
+        if status != 200 {
+            return Err(Error::other(format!(
+                "Device description request failed with status {status}"
+            )));
+        }
+
+        let (service_type, control_url) = elements(&description, "service")
+            .find_map(|service| {
+                let service_type = element(service, "serviceType")?;
+                if !CONNECTION_SERVICES
+                    .iter()
+                    .any(|prefix| service_type.starts_with(prefix))
+                {
+                    return None;
+                }
+                Some((service_type, element(service, "controlURL")?))
+            })
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No WAN connection service"))?;
+
+        // The control URL is usually a path on the same server as the description.
+        let (addr, control_path) = if control_url.starts_with("http://") {
+            parse_url(control_url)?
+        } else if control_url.starts_with('/') {
+            (addr, control_url.to_string())
+        } else {
+            (addr, format!("/{control_url}"))
+        };
+
+        Ok(Gateway {
+            addr,
+            control_path,
+            service_type: service_type.to_string(),
+            timeout,
+        })
+    }
+
+    /// The local IP address used to reach the gateway.
+    pub async fn local_ip(&self) -> Result<Ipv4Addr, Error> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(self.addr).await?;
+        match socket.local_addr()? {
+            SocketAddr::V4(local) => Ok(*local.ip()),
+            SocketAddr::V6(_) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "Only IPv4 gateways are supported",
+            )),
+        }
+    }
+
+    /// Requests the external IP address of the gateway.
+    pub async fn external_ip(&self) -> Result<Ipv4Addr, Error> {
+        let response = self.soap_action("GetExternalIPAddress", "").await?;
+        element(&response, "NewExternalIPAddress")
+            .and_then(|ip| ip.trim().parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid external IP address"))
+    }
+
+    /// Forwards the UDP `external_port` to the `internal` socket for `lease`.
+    pub async fn add_port_mapping(
+        &self,
+        external_port: u16,
+        internal: SocketAddrV4,
+        lease: Duration,
+    ) -> Result<(), Error> {
+        let arguments = format!(
+            "<NewRemoteHost></NewRemoteHost>\
+             <NewExternalPort>{external_port}</NewExternalPort>\
+             <NewProtocol>UDP</NewProtocol>\
+             <NewInternalPort>{}</NewInternalPort>\
+             <NewInternalClient>{}</NewInternalClient>\
+             <NewEnabled>1</NewEnabled>\
+             <NewPortMappingDescription>{MAPPING_DESCRIPTION}</NewPortMappingDescription>\
+             <NewLeaseDuration>{}</NewLeaseDuration>",
+            internal.port(),
+            internal.ip(),
+            lease.as_secs()
+        );
+        self.soap_action("AddPortMapping", &arguments)
+            .await
+            .map(|_| ())
+    }
+
+    /// Removes the mapping of the UDP `external_port`.
+    pub async fn delete_port_mapping(&self, external_port: u16) -> Result<(), Error> {
+        let arguments = format!(
+            "<NewRemoteHost></NewRemoteHost>\
+             <NewExternalPort>{external_port}</NewExternalPort>\
+             <NewProtocol>UDP</NewProtocol>"
+        );
+        self.soap_action("DeletePortMapping", &arguments)
+            .await
+            .map(|_| ())
+    }
+
+    /// Invokes an action of the WAN connection service and returns the response body.
+    async fn soap_action(&self, action: &str, arguments: &str) -> Result<String, Error> {
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{}\">{arguments}</u:{action}></s:Body>\
+             </s:Envelope>",
+            self.service_type
+        );
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+             SOAPAction: \"{}#{action}\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.control_path,
+            self.addr,
+            self.service_type,
+            body.len()
+        );
+        let (status, response) = http_request(self.addr, &request, self.timeout).await?;
+        if status != 200 {
+            let code = element(&response, "errorCode").unwrap_or("unknown");
+            return Err(Error::other(format!(
+                "UPnP action {action} failed with status {status}, error code {code}"
+            )));
+        }
+        Ok(response)
+    }
+}
+
+/// Sends an HTTP request and returns the status code and body of the response.
+async fn http_request(
+    addr: SocketAddr,
+    request: &str,
+    timeout: Duration,
+) -> Result<(u16, String), Error> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, Error>(response)
+    };
+    let response = tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "HTTP request timed out"))??;
+    let response = String::from_utf8_lossy(&response);
+
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid HTTP response");
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(invalid)?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+    let body = if header(head, "transfer-encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        decode_chunked(body).ok_or_else(invalid)?
+    } else {
+        body.to_string()
+    };
+    Ok((status, body))
+}
+
+/// Decodes a body with chunked transfer encoding.
+fn decode_chunked(mut body: &str) -> Option<String> {
+    let mut decoded = String::new();
+    loop {
+        let (size, rest) = body.split_once("\r\n")?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some(decoded);
+        }
+        decoded.push_str(rest.get(..size)?);
+        body = rest.get(size..)?.strip_prefix("\r\n")?;
+    }
+}
+
+/// Returns the value of the first header with the case-insensitive `name`.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Splits an `http://` URL into the address of the server and the path.
+fn parse_url(url: &str) -> Result<(SocketAddr, String), Error> {
+    let invalid = || Error::new(ErrorKind::InvalidData, format!("Unsupported URL {url}"));
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let addr = match authority.parse() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddr::new(authority.parse().map_err(|_| invalid())?, 80),
+    };
+    Ok((addr, path.to_string()))
+}
+
+/// Returns the content of the first element with the given tag, ignoring namespace prefixes.
+fn element<'a>(xml: &'a str, tag: &'a str) -> Option<&'a str> {
+    elements(xml, tag).next()
+}
+
+/// Returns the contents of the elements with the given tag, ignoring namespace prefixes.
+fn elements<'a>(mut xml: &'a str, tag: &'a str) -> impl Iterator<Item = &'a str> {
+    std::iter::from_fn(move || loop {
+        let start = xml.find('<')?;
+        let rest = &xml[start + 1..];
+        let end = rest.find('>')?;
+        let name = rest[..end].split_whitespace().next().unwrap_or_default();
+        xml = &rest[end + 1..];
+        if name.rsplit(':').next() != Some(tag) {
+            continue;
+        }
+        let closing = format!("</{name}>");
+        let content_end = xml.find(&closing)?;
+        let content = &xml[..content_end];
+        xml = &xml[content_end + closing.len()..];
+        return Some(content);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::mock, *};
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    #[tokio::test]
+    async fn test_upnp_gateway() {
+        let mut gateway = mock::UpnpGateway::spawn().await;
+        let client = Gateway::search(gateway.ssdp_addr, TIMEOUT).await.unwrap();
+        assert_eq!(client.control_path, "/ctl/IPConn");
+
+        assert_eq!(client.external_ip().await.unwrap(), mock::EXTERNAL_IP);
+        let internal = SocketAddrV4::new(client.local_ip().await.unwrap(), 9000);
+        client
+            .add_port_mapping(9000, internal, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        client.delete_port_mapping(9000).await.unwrap();
+
+        assert_eq!(
+            gateway.actions.recv().await.as_deref(),
+            Some("GetExternalIPAddress")
+        );
+        assert_eq!(
+            gateway.actions.recv().await.as_deref(),
+            Some("AddPortMapping")
+        );
+        assert_eq!(
+            gateway.actions.recv().await.as_deref(),
+            Some("DeletePortMapping")
+        );
+    }
+
+    #[test]
+    fn test_parse_description() {
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/ctl/L3F</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:2</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>\
+            </serviceList></device></root>";
+        let services: Vec<_> = elements(description, "service")
+            .filter_map(|service| element(service, "controlURL"))
+            .collect();
+        assert_eq!(services, vec!["/ctl/L3F", "/ctl/IPConn"]);
+        assert_eq!(
+            element("<s:Body><u:Resp><NewExternalIPAddress>1.2.3.4</NewExternalIPAddress></u:Resp></s:Body>", "NewExternalIPAddress"),
+            Some("1.2.3.4")
+        );
+        assert_eq!(
+            decode_chunked("4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n").as_deref(),
+            Some("Wikipedia")
+        );
+        assert_eq!(
+            parse_url("http://192.168.1.1:5000/rootDesc.xml").unwrap(),
+            (
+                "192.168.1.1:5000".parse().unwrap(),
+                "/rootDesc.xml".to_string()
+            )
+        );
+    }
+}
@@ -77,11 +77,21 @@ where
         }
     }
 
+    /// Sets the timeout applied to all queries in the pool.
+    pub fn set_query_timeout(&mut self, query_timeout: Duration) {
+        self.query_timeout = query_timeout;
+    }
+
     /// Returns an iterator over the queries in the pool.
     pub fn iter(&self) -> impl Iterator<Item = &Query<TTarget, TNodeId, TResult>> {
         self.queries.values()
     }
 
+    /// Removes all queries from the pool.
+    pub fn drain(&mut self) -> impl Iterator<Item = Query<TTarget, TNodeId, TResult>> + '_ {
+        self.queries.drain().map(|(_, query)| query)
+    }
+
     /// Adds a query to the pool that iterates towards the closest peers to the target.
     pub fn add_findnode_query<I>(
         &mut self,
@@ -238,6 +248,14 @@ where
         }
     }
 
+    /// Informs the query of the known round-trip time of `peer`.
+    pub fn set_rtt(&mut self, peer: &TNodeId, rtt: Duration) {
+        match &mut self.peer_iter {
+            QueryPeerIter::FindNode(iter) => iter.set_rtt(peer, rtt),
+            QueryPeerIter::Predicate(iter) => iter.set_rtt(peer, rtt),
+        }
+    }
+
     /// Informs the query that the attempt to contact `peer` succeeded,
     /// possibly resulting in new peers that should be incorporated into
     /// the query, if applicable.
@@ -253,6 +271,14 @@ where
         }
     }
 
+    /// Finishes the query at its next poll, with the peers that have responded so far.
+    pub fn finish(&mut self) {
+        match &mut self.peer_iter {
+            QueryPeerIter::FindNode(iter) => iter.finish(),
+            QueryPeerIter::Predicate(iter) => iter.finish(),
+        }
+    }
+
     /// Advances the state of the underlying peer iterator.
     fn next(&mut self, now: Instant) -> QueryState<TNodeId> {
         match &mut self.peer_iter {
@@ -273,6 +299,11 @@ where
         }
     }
 
+    /// The instant the query started waiting for its first result, if it has.
+    pub fn started(&self) -> Option<Instant> {
+        self.started
+    }
+
     /// Returns a reference to the query `target`.
     pub fn target(&self) -> &TTarget {
         &self.target
@@ -24,7 +24,7 @@
 use super::*;
 use crate::{
     config::Config,
-    kbucket::{Distance, Key, MAX_NODES_PER_BUCKET},
+    kbucket::{Distance, Key},
 };
 use std::{
     collections::btree_map::{BTreeMap, Entry},
@@ -73,14 +73,21 @@ pub struct FindNodeQueryConfig {
     /// the peer when evaluating the termination conditions, until and unless a
     /// result is delivered. Defaults to `10` seconds.
     pub peer_timeout: Duration,
+
+    /// Whether to prefer peers with a lower round-trip time.
+    ///
+    /// Among the uncontacted peers in the same bucket distance of the target, the query contacts
+    /// the one with the lowest known round-trip time first. Defaults to `false`.
+    pub prefer_low_latency: bool,
 }
 
 impl FindNodeQueryConfig {
     pub fn new_from_config(config: &Config) -> Self {
         Self {
             parallelism: config.query_parallelism,
-            num_results: MAX_NODES_PER_BUCKET,
+            num_results: config.bucket_size,
             peer_timeout: config.query_peer_timeout,
+            prefer_low_latency: config.latency_aware_queries,
         }
     }
 }
@@ -207,6 +214,18 @@ where
         }
     }
 
+    /// Informs the query of the known round-trip time of `peer`.
+    ///
+    /// If the query prefers low latency peers, this is used to order the uncontacted peers
+    /// within the same bucket distance of the target. Unknown peers are ignored.
+    pub fn set_rtt(&mut self, peer: &TNodeId, rtt: Duration) {
+        let key: Key<TNodeId> = peer.clone().into();
+        let distance = key.distance(&self.target_key);
+        if let Some(peer) = self.closest_peers.get_mut(&distance) {
+            peer.rtt = Some(rtt);
+        }
+    }
+
     /// Callback for informing the query about a failed request to a peer
     /// that the query is waiting on.
     ///
@@ -238,6 +257,11 @@ where
         }
     }
 
+    /// Finishes the query early, keeping the results gathered so far.
+    pub fn finish(&mut self) {
+        self.progress = QueryProgress::Finished;
+    }
+
     /// Advances the state of the query, potentially getting a new peer to contact.
     ///
     /// See [`QueryState`].
@@ -256,16 +280,15 @@ where
         // Check if the query is at capacity w.r.t. the allowed parallelism.
         let at_capacity = self.at_capacity();
 
-        for peer in self.closest_peers.values_mut() {
+        let mut next_peer = None;
+
+        for (distance, peer) in self.closest_peers.iter_mut() {
             match peer.state {
                 QueryPeerState::NotContacted => {
                     // This peer is waiting to be reiterated.
                     if !at_capacity {
-                        let timeout = now + self.config.peer_timeout;
-                        peer.state = QueryPeerState::Waiting(timeout);
-                        self.num_waiting += 1;
-                        let peer = peer.key.preimage().clone();
-                        return QueryState::Waiting(Some(peer));
+                        next_peer = Some(*distance);
+                        break;
                     } else {
                         return QueryState::WaitingAtCapacity;
                     }
@@ -308,6 +331,15 @@ where
             }
         }
 
+        if let Some(distance) = next_peer {
+            let distance = self.select_peer(distance);
+            let timeout = now + self.config.peer_timeout;
+            let peer = self.closest_peers.get_mut(&distance).expect("s.a.");
+            peer.state = QueryPeerState::Waiting(timeout);
+            self.num_waiting += 1;
+            return QueryState::Waiting(Some(peer.key.preimage().clone()));
+        }
+
         if self.num_waiting > 0 {
             // The query is still waiting for results and not at capacity w.r.t.
             // the allowed parallelism, but there are no new peers to contact
@@ -336,6 +368,25 @@ where
             .collect()
     }
 
+    /// Selects the next peer to contact, given the distance of the closest uncontacted peer.
+    ///
+    /// If the query prefers low latency peers, the uncontacted peer with the lowest known
+    /// round-trip time in the same bucket distance is selected instead. Peers with an unknown
+    /// round-trip time are tried last and ties go to the closest peer.
+    fn select_peer(&self, closest: Distance) -> Distance {
+        if !self.config.prefer_low_latency {
+            return closest;
+        }
+        let log2 = closest.log2();
+        self.closest_peers
+            .range(closest..)
+            .take_while(|(distance, _)| distance.log2() == log2)
+            .filter(|(_, peer)| matches!(peer.state, QueryPeerState::NotContacted))
+            .min_by_key(|(_, peer)| peer.rtt.unwrap_or(Duration::MAX))
+            .map(|(distance, _)| *distance)
+            .unwrap_or(closest)
+    }
+
     /// Checks if the query is at capacity w.r.t. the permitted parallelism.
     ///
     /// While the query is stalled, up to `num_results` parallel requests
@@ -399,6 +450,9 @@ struct QueryPeer<TNodeId> {
 
     /// The current query state of this peer.
     state: QueryPeerState,
+
+    /// The known round-trip time of this peer.
+    rtt: Option<Duration>,
 }
 
 impl<TNodeId> QueryPeer<TNodeId> {
@@ -407,6 +461,7 @@ impl<TNodeId> QueryPeer<TNodeId> {
             key,
             peers_returned: 0,
             state,
+            rtt: None,
         }
     }
 }
@@ -460,6 +515,7 @@ mod tests {
             parallelism: g.gen_range(1, 10),
             num_results: g.gen_range(1, 25),
             peer_timeout: Duration::from_secs(g.gen_range(10, 30)),
+            prefer_low_latency: false,
         };
         FindNodeQuery::with_config(config, target.into(), known_closest_peers)
     }
@@ -506,6 +562,54 @@ mod tests {
         );
     }
 
+    #[test]
+    fn prefers_low_latency_peers() {
+        let config = FindNodeQueryConfig {
+            parallelism: 1,
+            num_results: 20,
+            peer_timeout: Duration::from_secs(10),
+            prefer_low_latency: true,
+        };
+        // Find a query where the closest peer shares its bucket distance with other peers.
+        let (mut query, same_bucket) = loop {
+            let peers = random_nodes(20).map(Key::from);
+            let query = FindNodeQuery::with_config(config.clone(), NodeId::random().into(), peers);
+            let mut distances = query.closest_peers.keys();
+            let log2 = distances.next().expect("peers exist").log2();
+            let same_bucket = distances.take_while(|d| d.log2() == log2).count();
+            if same_bucket >= 2 {
+                break (query, same_bucket);
+            }
+        };
+        let peers: Vec<NodeId> = query
+            .closest_peers
+            .values()
+            .take(same_bucket + 1)
+            .map(|peer| *peer.key.preimage())
+            .collect();
+
+        // The fastest peer of the bucket is contacted first, regardless of its distance.
+        query.set_rtt(&peers[0], Duration::from_millis(100));
+        query.set_rtt(&peers[2], Duration::from_millis(10));
+        let now = Instant::now();
+        match query.next(now) {
+            QueryState::Waiting(Some(peer)) => assert_eq!(peer, peers[2]),
+            state => panic!("Unexpected query state: {:?}", state),
+        }
+        query.on_success(&peers[2], vec![]);
+
+        // Then the next fastest, followed by peers of unknown latency by distance.
+        match query.next(now) {
+            QueryState::Waiting(Some(peer)) => assert_eq!(peer, peers[0]),
+            state => panic!("Unexpected query state: {:?}", state),
+        }
+        query.on_success(&peers[0], vec![]);
+        match query.next(now) {
+            QueryState::Waiting(Some(peer)) => assert_eq!(peer, peers[1]),
+            state => panic!("Unexpected query state: {:?}", state),
+        }
+    }
+
     #[test]
     fn termination_and_parallelism() {
         fn prop(mut query: TestQuery) {
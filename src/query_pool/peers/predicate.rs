@@ -1,7 +1,7 @@
 use super::*;
 use crate::{
     config::Config,
-    kbucket::{Distance, Key, PredicateKey, MAX_NODES_PER_BUCKET},
+    kbucket::{Distance, Key, PredicateKey},
 };
 use std::{
     collections::btree_map::{BTreeMap, Entry},
@@ -52,14 +52,21 @@ pub(crate) struct PredicateQueryConfig {
     /// the peer when evaluating the termination conditions, until and unless a
     /// result is delivered. Defaults to `10` seconds.
     pub(crate) peer_timeout: Duration,
+
+    /// Whether to prefer peers with a lower round-trip time.
+    ///
+    /// Among the uncontacted peers in the same bucket distance of the target, the query contacts
+    /// the one with the lowest known round-trip time first. Defaults to `false`.
+    pub(crate) prefer_low_latency: bool,
 }
 
 impl PredicateQueryConfig {
     pub(crate) fn new_from_config(config: &Config) -> Self {
         Self {
             parallelism: config.query_parallelism,
-            num_results: MAX_NODES_PER_BUCKET,
+            num_results: config.bucket_size,
             peer_timeout: config.query_peer_timeout,
+            prefer_low_latency: config.latency_aware_queries,
         }
     }
 }
@@ -197,6 +204,18 @@ where
         }
     }
 
+    /// Informs the query of the known round-trip time of `peer`.
+    ///
+    /// If the query prefers low latency peers, this is used to order the uncontacted peers
+    /// within the same bucket distance of the target. Unknown peers are ignored.
+    pub fn set_rtt(&mut self, peer: &TNodeId, rtt: Duration) {
+        let key: Key<TNodeId> = peer.clone().into();
+        let distance = key.distance(&self.target_key);
+        if let Some(peer) = self.closest_peers.get_mut(&distance) {
+            peer.rtt = Some(rtt);
+        }
+    }
+
     /// Callback for informing the query about a failed request to a peer
     /// that the query is waiting on.
     ///
@@ -223,6 +242,11 @@ where
         }
     }
 
+    /// Finishes the query early, keeping the results gathered so far.
+    pub fn finish(&mut self) {
+        self.progress = QueryProgress::Finished;
+    }
+
     /// Advances the state of the query, potentially getting a new peer to contact.
     ///
     /// See [`QueryState`].
@@ -241,16 +265,15 @@ where
         // Check if the query is at capacity w.r.t. the allowed parallelism.
         let at_capacity = self.at_capacity();
 
-        for peer in self.closest_peers.values_mut() {
+        let mut next_peer = None;
+
+        for (distance, peer) in self.closest_peers.iter_mut() {
             match peer.state {
                 QueryPeerState::NotContacted => {
                     // This peer is waiting to be reiterated.
                     if !at_capacity {
-                        let timeout = now + self.config.peer_timeout;
-                        peer.state = QueryPeerState::Waiting(timeout);
-                        self.num_waiting += 1;
-                        let return_peer = peer.key.preimage().clone();
-                        return QueryState::Waiting(Some(return_peer));
+                        next_peer = Some(*distance);
+                        break;
                     } else {
                         return QueryState::WaitingAtCapacity;
                     }
@@ -298,6 +321,15 @@ where
             }
         }
 
+        if let Some(distance) = next_peer {
+            let distance = self.select_peer(distance);
+            let timeout = now + self.config.peer_timeout;
+            let peer = self.closest_peers.get_mut(&distance).expect("s.a.");
+            peer.state = QueryPeerState::Waiting(timeout);
+            self.num_waiting += 1;
+            return QueryState::Waiting(Some(peer.key.preimage().clone()));
+        }
+
         if self.num_waiting > 0 {
             // The query is still waiting for results and not at capacity w.r.t.
             // the allowed parallelism, but there are no new peers to contact
@@ -330,6 +362,25 @@ where
             .collect()
     }
 
+    /// Selects the next peer to contact, given the distance of the closest uncontacted peer.
+    ///
+    /// If the query prefers low latency peers, the uncontacted peer with the lowest known
+    /// round-trip time in the same bucket distance is selected instead. Peers with an unknown
+    /// round-trip time are tried last and ties go to the closest peer.
+    fn select_peer(&self, closest: Distance) -> Distance {
+        if !self.config.prefer_low_latency {
+            return closest;
+        }
+        let log2 = closest.log2();
+        self.closest_peers
+            .range(closest..)
+            .take_while(|(distance, _)| distance.log2() == log2)
+            .filter(|(_, peer)| matches!(peer.state, QueryPeerState::NotContacted))
+            .min_by_key(|(_, peer)| peer.rtt.unwrap_or(Duration::MAX))
+            .map(|(distance, _)| *distance)
+            .unwrap_or(closest)
+    }
+
     /// Checks if the query is at capacity w.r.t. the permitted parallelism.
     ///
     /// While the query is stalled, up to `num_results` parallel requests
@@ -396,6 +447,9 @@ struct QueryPeer<TNodeId> {
 
     /// The current query state of this peer.
     state: QueryPeerState,
+
+    /// The known round-trip time of this peer.
+    rtt: Option<Duration>,
 }
 
 impl<TNodeId> QueryPeer<TNodeId> {
@@ -405,6 +459,7 @@ impl<TNodeId> QueryPeer<TNodeId> {
             peers_returned: 0,
             predicate_match,
             state,
+            rtt: None,
         }
     }
 }
@@ -14,24 +14,30 @@
 //! secp256k1 keys are supported currently.
 
 use self::{
+    enr_propagation::EnrPropagation,
     ip_vote::IpVote,
     query_info::{QueryInfo, QueryType},
+    reachability::ReachabilityTracker,
+    revalidation::{Revalidation, RevalidationEvent},
+    table_watch::TableWatch,
 };
 use crate::{
-    error::{RequestError, ResponseError},
-    handler::{Handler, HandlerIn, HandlerOut},
+    discv5::update_enr_socket,
+    error::{QueryError, RequestError, ResponseError},
+    handler::{Handler, HandlerIn, HandlerOut, SessionInfo},
     kbucket::{
         self, ConnectionDirection, ConnectionState, FailureReason, InsertResult, KBucketsTable,
-        NodeStatus, UpdateResult, MAX_NODES_PER_BUCKET,
+        NodeStats, NodeStatus, TableDiff, UpdateResult,
     },
+    lru_time_cache::LruTimeCache,
     node_info::{NodeAddress, NodeContact, NonContactable},
     packet::{ProtocolIdentity, MAX_PACKET_SIZE},
+    port_mapping::PortMapper,
     query_pool::{
         FindNodeQueryConfig, PredicateQueryConfig, QueryId, QueryPool, QueryPoolState, TargetKey,
     },
-    rpc, Config, Enr, Event, IpMode,
+    rpc, Config, ConfigUpdate, Enr, Event, IpMode, ListenConfig, TableFilter,
 };
-use delay_map::HashSetDelay;
 use enr::{CombinedKey, NodeId};
 use fnv::FnvHashMap;
 use futures::prelude::*;
@@ -39,30 +45,56 @@ use more_asserts::debug_unreachable;
 use parking_lot::RwLock;
 use rpc::*;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     convert::TryInto,
     net::{IpAddr, SocketAddr},
     sync::Arc,
     task::Poll,
-    time::Instant,
+    time::{Duration, Instant},
 };
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info, trace, warn};
 
+mod enr_propagation;
 mod ip_vote;
 mod query_info;
+mod reachability;
+mod revalidation;
+mod table_watch;
 mod test;
 
+pub use reachability::{InboundReachability, NatStatus, NatType, Reachability};
+pub use revalidation::RevalidationConfig;
+
+/// Peers scoring below this are left out of new lookups, unless no other peer is known.
+const MIN_LOOKUP_SCORE: f64 = 0.2;
+
 /// The number of distances (buckets) we simultaneously request from each peer.
 /// NOTE: This must not be larger than 127.
 pub(crate) const DISTANCES_TO_REQUEST_PER_PEER: usize = 3;
 
-/// Currently, a maximum of `DISTANCES_TO_REQUEST_PER_PEER * BUCKET_SIZE` peers
-/// can be returned. Datagrams have a max size of 1280 and ENR's have a max size
-/// of 300 bytes. Bucket sizes should be 16. Therefore, to return all required peers
-/// there should be no more than `5 * DISTANCES_TO_REQUEST_PER_PEER` responses.
-pub(crate) const MAX_NODES_RESPONSES: usize =
-    (MAX_NODES_PER_BUCKET / 4 + 1) * DISTANCES_TO_REQUEST_PER_PEER;
+/// The maximum size of an ENR in bytes.
+const MAX_ENR_SIZE: usize = 300;
+
+/// The space for ENRs in a NODES response, once the overhead of a regular message is taken from
+/// the maximum packet size. See [`Service::send_nodes_response`].
+const NODES_RESPONSE_PAYLOAD: usize = MAX_PACKET_SIZE - 104;
+
+/// The number of ENRs of the maximum size that fit in one NODES response.
+const ENRS_PER_NODES_RESPONSE: usize = NODES_RESPONSE_PAYLOAD / MAX_ENR_SIZE;
+
+/// The number of NODES responses accepted for a single request. A peer returns up to
+/// `bucket_size` nodes for each of the `DISTANCES_TO_REQUEST_PER_PEER` distances we request,
+/// which fit in this many responses even if every ENR has the maximum size.
+pub(crate) const fn max_nodes_responses(bucket_size: usize) -> usize {
+    (bucket_size * DISTANCES_TO_REQUEST_PER_PEER).div_ceil(ENRS_PER_NODES_RESPONSE)
+}
+
+/// The number of nodes a peer with the given bucket size accepts in response to a request, if
+/// every ENR has the maximum size.
+pub(crate) const fn max_nodes_response_limit(bucket_size: usize) -> usize {
+    max_nodes_responses(bucket_size) * ENRS_PER_NODES_RESPONSE
+}
 
 /// Request type for Protocols using `TalkReq` message.
 ///
@@ -143,7 +175,7 @@ pub enum ServiceRequest {
     /// - A FindNode Query - Searches for peers using a random target.
     /// - A Predicate Query - Searches for peers closest to a random target that match a specified
     /// predicate.
-    StartQuery(QueryKind, oneshot::Sender<Vec<Enr>>),
+    StartQuery(QueryKind, oneshot::Sender<Result<Vec<Enr>, QueryError>>),
     /// Send a FINDNODE request for nodes that fall within the given set of distances,
     /// to the designated peer and wait for a response.
     FindNodeDesignated(
@@ -163,9 +195,22 @@ pub enum ServiceRequest {
     /// Sets up an event stream where the discv5 server will return various events such as
     /// discovered nodes as it traverses the DHT.
     RequestEventStream(oneshot::Sender<mpsc::Receiver<Event>>),
+    /// Applies changes to the configuration of the running service.
+    Reconfigure(ConfigUpdate),
+    /// Replaces the listening sockets without restarting the service.
+    Rebind(ListenConfig, oneshot::Sender<Result<(), std::io::Error>>),
+    /// Lists the sessions held with peers. The list is sent once on the channel.
+    Sessions(mpsc::Sender<Vec<SessionInfo>>),
+    /// Lists the queries in progress.
+    ActiveQueries(oneshot::Sender<Vec<ActiveQuery>>),
+    /// The local ENR has been changed outside of the service and connected peers should learn of
+    /// it.
+    LocalEnrUpdated,
+    /// Subscribes to the changes of the routing table.
+    TableDiffs(oneshot::Sender<mpsc::Receiver<Vec<TableDiff>>>),
 }
 
-use crate::discv5::PERMIT_BAN_LIST;
+use crate::{discv5::PERMIT_BAN_LIST, permit_ban::Ban};
 
 pub struct Service {
     /// Configuration parameters.
@@ -180,6 +225,9 @@ pub struct Service {
     /// Storage of the ENR record for each node.
     kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
 
+    /// The filter deciding which discovered ENRs may enter the routing table.
+    table_filter: Arc<RwLock<TableFilter>>,
+
     /// All the iterative queries we are currently performing.
     queries: QueryPool<QueryInfo, NodeId, Enr>,
 
@@ -193,6 +241,12 @@ pub struct Service {
     /// A map of votes nodes have made about our external IP address. We accept the majority.
     ip_votes: Option<IpVote>,
 
+    /// Classifies our NAT and estimates whether we can be reached from the outside.
+    reachability: ReachabilityTracker,
+
+    /// The latest [`NatStatus`], shared with the application.
+    nat_status: Arc<RwLock<NatStatus>>,
+
     /// The channel to send messages to the handler.
     handler_send: mpsc::UnboundedSender<HandlerIn>,
 
@@ -200,20 +254,41 @@ pub struct Service {
     handler_recv: mpsc::Receiver<HandlerOut>,
 
     /// The exit channel to shutdown the handler.
-    handler_exit: Option<oneshot::Sender<()>>,
+    handler_exit: Option<oneshot::Sender<bool>>,
+
+    /// Resolves once the handler has exited.
+    handler_exited: Option<oneshot::Receiver<()>>,
 
     /// The channel of messages sent by the controlling discv5 wrapper.
     discv5_recv: mpsc::Receiver<ServiceRequest>,
 
-    /// The exit channel for the service.
-    exit: oneshot::Receiver<()>,
+    /// The exit channel for the service. If `true` is sent, queued responses are sent before the
+    /// service exits.
+    exit: oneshot::Receiver<bool>,
+
+    /// Schedules the pings that check the liveness of the routing table entries.
+    revalidation: Revalidation,
+
+    /// Paces the pings that inform connected peers of changes to the local ENR.
+    enr_propagation: EnrPropagation,
 
-    /// A queue of peers that require regular ping to check connectivity.
-    peers_to_ping: HashSetDelay<NodeId>,
+    /// The nodes whose session moved away from the socket in their ENR, with that socket and the
+    /// address the session moved to. Requests are sent to the new address for as long as the ENR
+    /// advertises the old one.
+    roamed_addresses: LruTimeCache<NodeId, (SocketAddr, SocketAddr)>,
+
+    /// Streams the changes of the routing table to subscribers.
+    table_watch: TableWatch,
 
     /// A channel that the service emits events on.
     event_stream: Option<mpsc::Sender<Event>>,
 
+    /// Callbacks of rebind requests awaiting the result from the handler, in request order.
+    pending_rebinds: VecDeque<oneshot::Sender<Result<(), std::io::Error>>>,
+
+    /// Maintains the port mapping of the IPv4 listening socket, if configured.
+    port_mapper: Option<PortMapper>,
+
     // Type of socket we are using
     ip_mode: IpMode,
 }
@@ -240,6 +315,17 @@ pub struct Pong {
     pub port: u16,
 }
 
+/// A query in progress, see [`crate::Discv5::active_queries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveQuery {
+    /// The id of the query, unique among the queries of a service.
+    pub id: usize,
+    /// The node id the query searches the closest nodes to.
+    pub target: NodeId,
+    /// How long the query has waited for results, if it has started.
+    pub elapsed: Option<Duration>,
+}
+
 /// The kinds of responses we can send back to the discv5 layer.
 pub enum CallbackResponse {
     /// A response to a requested Nodes.
@@ -268,6 +354,14 @@ impl Default for NodesResponse {
     }
 }
 
+/// The exit channel of a spawned service, a channel that resolves once the service has exited
+/// and the channel to make requests to the service.
+type ServiceReturn = (
+    oneshot::Sender<bool>,
+    oneshot::Receiver<()>,
+    mpsc::Sender<ServiceRequest>,
+);
+
 impl Service {
     /// Builds the `Service` main struct.
     ///
@@ -278,10 +372,12 @@ impl Service {
         local_enr: Arc<RwLock<Enr>>,
         enr_key: Arc<RwLock<CombinedKey>>,
         kbuckets: Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+        table_filter: Arc<RwLock<TableFilter>>,
+        nat_status: Arc<RwLock<NatStatus>>,
         config: Config,
-    ) -> Result<(oneshot::Sender<()>, mpsc::Sender<ServiceRequest>), std::io::Error> {
+    ) -> Result<ServiceReturn, std::io::Error> {
         // process behaviour-level configuration parameters
-        let ip_votes = if config.enr_update {
+        let ip_votes = if config.enr_update && !config.client_only {
             Some(IpVote::new(
                 config.enr_peer_update_min,
                 config.vote_duration,
@@ -291,34 +387,57 @@ impl Service {
         };
 
         let ip_mode = IpMode::new_from_listen_config(&config.listen_config);
+        let reachability = ReachabilityTracker::new(
+            config.enr_peer_update_min,
+            config.vote_duration,
+            config.inbound_observation_window,
+            &config.listen_config,
+        );
+        *nat_status.write() = reachability.status();
 
         // build the session service
-        let (handler_exit, handler_send, handler_recv) =
+        let (handler_exit, handler_exited, handler_send, handler_recv) =
             Handler::spawn::<P>(local_enr.clone(), enr_key.clone(), config.clone()).await?;
+        let port_mapper = Service::spawn_port_mapper(&config);
 
         // create the required channels
         let (discv5_send, discv5_recv) = mpsc::channel(30);
         let (exit_send, exit) = oneshot::channel();
+        let (exited_send, exited) = oneshot::channel();
 
         config
             .executor
             .clone()
             .expect("Executor must be present")
             .spawn(Box::pin(async move {
+                let enr_propagation =
+                    EnrPropagation::new(local_enr.read().seq(), config.enr_propagation_rate);
                 let mut service = Service {
                     local_enr,
                     enr_key,
                     kbuckets,
+                    table_filter,
                     queries: QueryPool::new(config.query_timeout),
                     active_requests: Default::default(),
                     active_nodes_responses: HashMap::new(),
                     ip_votes,
+                    reachability,
+                    nat_status,
                     handler_send,
                     handler_recv,
                     handler_exit: Some(handler_exit),
-                    peers_to_ping: HashSetDelay::new(config.ping_interval),
+                    handler_exited: Some(handler_exited),
+                    revalidation: Revalidation::new(config.revalidation, config.ping_interval),
+                    enr_propagation,
+                    roamed_addresses: LruTimeCache::new(
+                        config.session_timeout,
+                        Some(config.session_cache_capacity),
+                    ),
+                    table_watch: TableWatch::new(),
                     discv5_recv,
                     event_stream: None,
+                    pending_rebinds: VecDeque::new(),
+                    port_mapper,
                     exit,
                     config: config.clone(),
                     ip_mode,
@@ -326,20 +445,19 @@ impl Service {
 
                 info!(mode = ?service.ip_mode, "Discv5 Service started");
                 service.start().await;
+                drop(service);
+                let _ = exited_send.send(());
             }));
 
-        Ok((exit_send, discv5_send))
+        Ok((exit_send, exited, discv5_send))
     }
 
     /// The main execution loop of the discv5 serviced.
     async fn start(&mut self) {
         loop {
             tokio::select! {
-                _ = &mut self.exit => {
-                    if let Some(exit) = self.handler_exit.take() {
-                        let _ = exit.send(());
-                        info!("Discv5 Service shutdown");
-                    }
+                send_final_responses = &mut self.exit => {
+                    self.shutdown(send_final_responses.unwrap_or(false)).await;
                     return;
                 }
                 Some(service_request) = self.discv5_recv.recv() => {
@@ -347,7 +465,10 @@ impl Service {
                         ServiceRequest::StartQuery(query, callback) => {
                             match query {
                                 QueryKind::FindNode { target_node } => {
-                                    self.start_findnode_query(target_node, callback);
+                                    self.start_findnode_query(QueryType::FindNode(target_node), callback);
+                                }
+                                QueryKind::Resolve { target_node } => {
+                                    self.start_findnode_query(QueryType::Resolve(target_node), callback);
                                 }
                                 QueryKind::Predicate { target_node, target_peer_no, predicate } => {
                                     self.start_predicate_query(target_node, target_peer_no, predicate, callback);
@@ -373,19 +494,66 @@ impl Service {
                                 error!("Failed to return the event stream channel");
                             }
                         }
+                        ServiceRequest::Reconfigure(update) => {
+                            self.reconfigure(update);
+                        }
+                        ServiceRequest::Rebind(listen_config, callback) => {
+                            if let Err(e) = self.handler_send.send(HandlerIn::Rebind(listen_config)) {
+                                warn!("Failed to send the rebind request to the handler {}", e);
+                                let _ = callback.send(Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Handler channel closed")));
+                            } else {
+                                self.pending_rebinds.push_back(callback);
+                            }
+                        }
+                        ServiceRequest::Sessions(callback) => {
+                            if let Err(e) = self.handler_send.send(HandlerIn::Sessions(callback)) {
+                                warn!("Failed to request the sessions from the handler {}", e);
+                            }
+                        }
+                        ServiceRequest::ActiveQueries(callback) => {
+                            let now = Instant::now();
+                            let queries = self
+                                .queries
+                                .iter()
+                                .map(|query| {
+                                    let (QueryType::FindNode(target) | QueryType::Resolve(target)) =
+                                        query.target().query_type;
+                                    ActiveQuery {
+                                        id: *query.id(),
+                                        target,
+                                        elapsed: query.started().map(|started| now - started),
+                                    }
+                                })
+                                .collect();
+                            let _ = callback.send(queries);
+                        }
+                        ServiceRequest::LocalEnrUpdated => {}
+                        ServiceRequest::TableDiffs(callback) => {
+                            let snapshot = self.kbuckets.read().snapshot();
+                            if callback.send(self.table_watch.subscribe(snapshot)).is_err() {
+                                error!("Failed to return the routing table diff channel");
+                            }
+                        }
                     }
+                    // The sequence number of the local ENR tells whether it has changed, so a
+                    // change is noticed even if its notification was dropped from a full channel.
+                    self.propagate_local_enr();
                 }
                 Some(event) = self.handler_recv.recv() => {
                     match event {
                         HandlerOut::Established(enr, socket_addr, direction) => {
                             self.send_event(Event::SessionEstablished(enr.clone(), socket_addr));
+                            if direction == ConnectionDirection::Incoming {
+                                self.reachability.inbound_session(socket_addr);
+                                self.update_nat_status();
+                            }
                             self.inject_session_established(enr, direction);
                         }
                         HandlerOut::Request(node_address, request) => {
                                 self.handle_rpc_request(node_address, *request);
                             }
-                        HandlerOut::Response(node_address, response) => {
-                                self.handle_rpc_response(node_address, *response);
+                        HandlerOut::Response(node_address, response, rtt) => {
+                                self.handle_rpc_response(node_address, *response, rtt);
                             }
                         HandlerOut::WhoAreYou(whoareyou_ref) => {
                             // check what our latest known ENR is for this node.
@@ -409,10 +577,34 @@ impl Service {
                             }
                             self.rpc_failure(request_id, error);
                         }
+                        HandlerOut::Rebound(listen_config) => {
+                            self.rebound(listen_config);
+                            if let Some(callback) = self.pending_rebinds.pop_front() {
+                                let _ = callback.send(Ok(()));
+                            }
+                        }
+                        HandlerOut::RebindFailed(listen_config, kind, error) => {
+                            warn!(?listen_config, %error, "Failed to rebind the listening sockets");
+                            if let Some(callback) = self.pending_rebinds.pop_front() {
+                                let _ = callback.send(Err(std::io::Error::new(kind, error)));
+                            }
+                        }
+                        HandlerOut::SessionRoamed(node_address, from) => {
+                            self.session_roamed(node_address.node_id, from, node_address.socket_addr);
+                            self.send_event(Event::SessionRoamed {
+                                node_id: node_address.node_id,
+                                from,
+                                to: node_address.socket_addr,
+                            });
+                        }
                     }
                 }
-                event = Service::bucket_maintenance_poll(&self.kbuckets) => {
-                    self.send_event(event);
+                maintenance = Service::bucket_maintenance_poll(&self.kbuckets) => {
+                    match maintenance {
+                        BucketMaintenance::Applied(event) => self.send_event(event),
+                        // A replacement candidate is only inserted once it responds.
+                        BucketMaintenance::Revalidate(enr) => self.send_ping(enr, None),
+                    }
                 }
                 query_event = Service::query_event_poll(&mut self.queries) => {
                     match query_event {
@@ -426,47 +618,369 @@ impl Service {
                             let mut result = query.into_result();
                             // obtain the ENR's for the resulting nodes
                             let mut found_enrs = Vec::new();
-                            for node_id in result.closest_peers {
-                                if let Some(position) = result.target.untrusted_enrs.iter().position(|enr| enr.node_id() == node_id) {
-                                    let enr = result.target.untrusted_enrs.swap_remove(position);
-                                    found_enrs.push(enr);
-                                } else if let Some(enr) = self.find_enr(&node_id) {
-                                    // look up from the routing table
-                                    found_enrs.push(enr);
+                            if let QueryType::Resolve(target) = result.target.query_type {
+                                // Only the node itself can confirm its latest ENR, which the
+                                // untrusted ENRs hold if it responded.
+                                if result.closest_peers.any(|node_id| node_id == target) {
+                                    found_enrs.extend(
+                                        result.target.untrusted_enrs.iter()
+                                            .filter(|enr| enr.node_id() == target)
+                                            .max_by_key(|enr| enr.seq())
+                                            .cloned(),
+                                    );
                                 }
-                                else {
-                                    warn!("ENR not present in queries results");
+                            } else {
+                                for node_id in result.closest_peers {
+                                    if let Some(position) = result.target.untrusted_enrs.iter().position(|enr| enr.node_id() == node_id) {
+                                        let enr = result.target.untrusted_enrs.swap_remove(position);
+                                        found_enrs.push(enr);
+                                    } else if let Some(enr) = self.find_enr(&node_id) {
+                                        // look up from the routing table
+                                        found_enrs.push(enr);
+                                    }
+                                    else {
+                                        warn!("ENR not present in queries results");
+                                    }
                                 }
                             }
-                            if result.target.callback.send(found_enrs).is_err() {
+                            if result.target.callback.send(Ok(found_enrs)).is_err() {
                                 warn!("Callback dropped for query {}. Results dropped", *id);
                             }
                         }
                     }
                 }
-                Some(Ok(node_id)) = self.peers_to_ping.next() => {
-                    // If the node is in the routing table, Ping it and re-queue the node.
-                    let key = kbucket::Key::from(node_id);
-                    let enr =  {
-                        if let kbucket::Entry::Present(entry, _) = self.kbuckets.write().entry(&key) {
-                        // The peer is in the routing table, ping it and re-queue the ping
-                        self.peers_to_ping.insert(node_id);
-                        Some(entry.value().clone())
-                        } else { None }
-                    };
-
-                    if let Some(enr) = enr {
-                        self.send_ping(enr, None);
+                mapped_socket = PortMapper::next_update(&mut self.port_mapper) => {
+                    if update_enr_socket(&self.local_enr, &self.enr_key, mapped_socket, false) {
+                        info!("Local UDP socket updated to the mapped socket: {}", mapped_socket);
+                        self.send_event(Event::SocketUpdated(mapped_socket));
+                        self.propagate_local_enr();
+                    }
+                }
+                event = self.revalidation.next() => {
+                    match event {
+                        RevalidationEvent::Ping(node_id) => {
+                            // The response or failure of the ping schedules the next check.
+                            let key = kbucket::Key::from(node_id);
+                            let enr = match self.kbuckets.write().entry(&key) {
+                                kbucket::Entry::Present(entry, _) => Some(entry.value().clone()),
+                                _ => None,
+                            };
+                            match enr {
+                                Some(enr) => self.send_ping(enr, None),
+                                None => self.revalidation.remove(&node_id),
+                            }
+                        }
+                        RevalidationEvent::Sync => {
+                            let entries = self
+                                .kbuckets
+                                .write()
+                                .iter()
+                                .map(|entry| (*entry.node.key.preimage(), entry.status.is_connected()))
+                                .collect::<Vec<_>>();
+                            self.revalidation.sync(entries);
+                        }
+                    }
+                }
+                batch = self.enr_propagation.next_batch() => {
+                    for node_id in batch {
+                        // Peers may have been removed or disconnected since being queued.
+                        let key = kbucket::Key::from(node_id);
+                        let enr = match self.kbuckets.write().entry(&key) {
+                            kbucket::Entry::Present(entry, status) if status.is_connected() => {
+                                Some(entry.value().clone())
+                            }
+                            _ => None,
+                        };
+                        if let Some(enr) = enr {
+                            self.send_ping(enr, None);
+                        }
                     }
                 }
+                _ = self.table_watch.tick() => {
+                    let snapshot = self.kbuckets.read().snapshot();
+                    self.table_watch.publish(snapshot);
+                }
+            }
+        }
+    }
+
+    /// Applies changes to the configuration of the running service and forwards the relevant
+    /// changes to the handler.
+    fn reconfigure(&mut self, update: ConfigUpdate) {
+        update.apply(&mut self.config);
+        if let Some(query_timeout) = update.query_timeout {
+            self.queries.set_query_timeout(query_timeout);
+        }
+        if let Some(ping_interval) = update.ping_interval {
+            self.revalidation.set_stable_interval(ping_interval);
+        }
+        if let Err(e) = self.handler_send.send(HandlerIn::Reconfigure(update)) {
+            warn!(
+                "Failed to send the configuration update to the handler {}",
+                e
+            );
+        }
+    }
+
+    /// Spawns the port mapper for the IPv4 listening socket if port mapping is configured.
+    fn spawn_port_mapper(config: &Config) -> Option<PortMapper> {
+        if config.client_only {
+            return None;
+        }
+        let port_mapping = config.port_mapping.clone()?;
+        let listen_socket = config.listen_config.ipv4()?;
+        Some(PortMapper::spawn(
+            port_mapping,
+            listen_socket.port(),
+            config.executor.clone().expect("Executor must be present"),
+        ))
+    }
+
+    /// Applies a change of the listening sockets to the service state and the local ENR.
+    fn rebound(&mut self, listen_config: ListenConfig) {
+        info!(?listen_config, "Listening sockets updated");
+        let previous_ipv4 = self.config.listen_config.ipv4();
+        self.ip_mode = IpMode::new_from_listen_config(&listen_config);
+        self.config.listen_config = listen_config.clone();
+
+        // The mapping forwards to the previous port.
+        if listen_config.ipv4().map(|socket| socket.port())
+            != previous_ipv4.map(|socket| socket.port())
+        {
+            if let Some(mut port_mapper) = self.port_mapper.take() {
+                port_mapper.stop();
+            }
+            self.port_mapper = Service::spawn_port_mapper(&self.config);
+        }
+
+        // Votes were cast for the previous sockets.
+        if self.ip_votes.is_some() {
+            self.ip_votes = Some(IpVote::new(
+                self.config.enr_peer_update_min,
+                self.config.vote_duration,
+            ));
+        }
+        self.reachability.set_listen_config(&listen_config);
+        self.update_nat_status();
+
+        let mut updated = false;
+        let (local_ip4, local_ip6) = {
+            let local_enr = self.local_enr.read();
+            (local_enr.ip4(), local_enr.ip6())
+        };
+
+        // Unspecified listening addresses keep the advertised IP and only update the port.
+        let new_ip4 = listen_config.ipv4().and_then(|socket| {
+            let ip = Some(*socket.ip())
+                .filter(|ip| !ip.is_unspecified())
+                .or(local_ip4)?;
+            Some(SocketAddr::from((ip, socket.port())))
+        });
+        let new_ip6 = listen_config.ipv6().and_then(|socket| {
+            let ip = Some(*socket.ip())
+                .filter(|ip| !ip.is_unspecified())
+                .or(local_ip6)?;
+            Some(SocketAddr::from((ip, socket.port())))
+        });
+
+        // Client-only nodes advertise no UDP socket.
+        let new_sockets = if self.config.client_only {
+            [None, None]
+        } else {
+            [new_ip4, new_ip6]
+        };
+        for new_socket in new_sockets.iter().flatten().copied() {
+            let current = match new_socket {
+                SocketAddr::V4(_) => self.local_enr.read().udp4_socket().map(SocketAddr::V4),
+                SocketAddr::V6(_) => self.local_enr.read().udp6_socket().map(SocketAddr::V6),
+            };
+            if current == Some(new_socket) {
+                continue;
+            }
+            let result = self
+                .local_enr
+                .write()
+                .set_udp_socket(new_socket, &self.enr_key.read());
+            match result {
+                Ok(_) => {
+                    updated = true;
+                    info!("Local UDP socket updated to: {}", new_socket);
+                    self.send_event(Event::SocketUpdated(new_socket));
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to update local UDP socket. socket: {}, error: {:?}",
+                        new_socket, e
+                    );
+                }
+            }
+        }
+
+        // Stop advertising IP versions we no longer listen on.
+        if self.remove_enr_sockets(
+            listen_config.ipv4().is_none(),
+            listen_config.ipv6().is_none(),
+        ) {
+            updated = true;
+        }
+
+        self.send_event(Event::ListenSocketsUpdated(listen_config));
+        if updated {
+            self.propagate_local_enr();
+        }
+    }
+
+    /// Removes the UDP sockets of the selected IP versions from the local ENR. Returns whether the
+    /// ENR changed.
+    fn remove_enr_sockets(&mut self, ipv4: bool, ipv6: bool) -> bool {
+        let mut removed_keys: Vec<&[u8]> = Vec::new();
+        {
+            let local_enr = self.local_enr.read();
+            if ipv4 && local_enr.udp4().is_some() {
+                removed_keys.push(b"udp");
+            }
+            if ipv6 && local_enr.udp6().is_some() {
+                removed_keys.push(b"udp6");
+            }
+        }
+        if removed_keys.is_empty() {
+            return false;
+        }
+        let result = self.local_enr.write().remove_insert(
+            removed_keys.into_iter(),
+            std::iter::empty::<(&[u8], &[u8])>(),
+            &self.enr_key.read(),
+        );
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Failed to remove local UDP sockets. error: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Derives the [`NatStatus`] from the latest observations and reports it if it changed. If
+    /// the ENR is updated from PONG votes, sockets that peers provably cannot reach are no longer
+    /// advertised.
+    fn update_nat_status(&mut self) {
+        let status = match self.reachability.update() {
+            Some(status) => status,
+            None => return,
+        };
+        debug!(?status, "NAT status updated");
+        *self.nat_status.write() = status;
+        self.send_event(Event::NatStatusUpdated(status));
+
+        // Peers may disagree on our socket on purpose, so it is only removed once no unsolicited
+        // session has reached us either.
+        let unreachable = |reachability: Reachability| {
+            reachability.nat_type == NatType::Symmetric
+                && reachability.inbound == InboundReachability::Unreachable
+        };
+        if self.ip_votes.is_some()
+            && self.remove_enr_sockets(unreachable(status.ipv4), unreachable(status.ipv6))
+        {
+            info!("Local UDP socket removed from the ENR as peers cannot reach it");
+            self.propagate_local_enr();
+        }
+    }
+
+    /// Fails all outstanding requests and queries, marks the nodes of the routing table as
+    /// disconnected as their sessions are lost, removes the port mapping and shuts down the
+    /// handler. If `send_final_responses` is set, the handler sends the responses that were queued
+    /// before the shutdown.
+    async fn shutdown(&mut self, send_final_responses: bool) {
+        // Requests that have not been processed yet.
+        self.discv5_recv.close();
+        while let Ok(service_request) = self.discv5_recv.try_recv() {
+            match service_request {
+                ServiceRequest::StartQuery(_, callback) => {
+                    let _ = callback.send(Err(QueryError::ServiceShutdown));
+                }
+                ServiceRequest::FindNodeDesignated(_, _, callback) => {
+                    let _ = callback.send(Err(RequestError::ServiceShutdown));
+                }
+                ServiceRequest::Talk(_, _, _, callback) => {
+                    let _ = callback.send(Err(RequestError::ServiceShutdown));
+                }
+                ServiceRequest::Ping(_, Some(callback)) => {
+                    let _ = callback.send(Err(RequestError::ServiceShutdown));
+                }
+                ServiceRequest::Rebind(_, callback) => self.pending_rebinds.push_back(callback),
+                ServiceRequest::Ping(_, None)
+                | ServiceRequest::RequestEventStream(_)
+                | ServiceRequest::Reconfigure(_)
+                | ServiceRequest::Sessions(_)
+                | ServiceRequest::ActiveQueries(_)
+                | ServiceRequest::LocalEnrUpdated
+                | ServiceRequest::TableDiffs(_) => {}
+            }
+        }
+        for callback in self.pending_rebinds.drain(..) {
+            let _ = callback.send(Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "Service shutdown",
+            )));
+        }
+
+        // Requests that are awaiting a response.
+        for (_, active_request) in self.active_requests.drain() {
+            match active_request.callback {
+                Some(CallbackResponse::Nodes(callback)) => {
+                    let _ = callback.send(Err(RequestError::ServiceShutdown));
+                }
+                Some(CallbackResponse::Talk(callback)) => {
+                    let _ = callback.send(Err(RequestError::ServiceShutdown));
+                }
+                Some(CallbackResponse::Pong(callback)) => {
+                    let _ = callback.send(Err(RequestError::ServiceShutdown));
+                }
+                None => {}
             }
         }
+        self.active_nodes_responses.clear();
+        for query in self.queries.drain() {
+            let _ = query
+                .into_result()
+                .target
+                .callback
+                .send(Err(QueryError::ServiceShutdown));
+        }
+
+        {
+            let mut kbuckets = self.kbuckets.write();
+            let connected: Vec<_> = kbuckets
+                .iter()
+                .filter(|entry| entry.status.is_connected())
+                .map(|entry| entry.node.key.clone())
+                .collect();
+            for key in connected {
+                let _ = kbuckets.update_node_status(&key, ConnectionState::Disconnected, None);
+            }
+        }
+
+        if let Some(port_mapper) = self.port_mapper.as_mut() {
+            port_mapper.shutdown().await;
+        }
+
+        if let Some(exit) = self.handler_exit.take() {
+            let _ = exit.send(send_final_responses);
+        }
+        if let Some(exited) = self.handler_exited.take() {
+            let _ = exited.await;
+        }
+        info!("Discv5 Service shutdown");
     }
 
     /// Internal function that starts a query.
-    fn start_findnode_query(&mut self, target_node: NodeId, callback: oneshot::Sender<Vec<Enr>>) {
+    fn start_findnode_query(
+        &mut self,
+        query_type: QueryType,
+        callback: oneshot::Sender<Result<Vec<Enr>, QueryError>>,
+    ) {
         let mut target = QueryInfo {
-            query_type: QueryType::FindNode(target_node),
+            query_type,
             untrusted_enrs: Default::default(),
             distances_to_request: DISTANCES_TO_REQUEST_PER_PEER,
             callback,
@@ -476,7 +990,8 @@ impl Service {
         let mut known_closest_peers = Vec::new();
         {
             let mut kbuckets = self.kbuckets.write();
-            for closest in kbuckets.closest_values(&target_key) {
+            let closest = kbuckets.closest_values(&target_key).collect();
+            for closest in prefer_reliable(&kbuckets, closest) {
                 // Add the known ENR's to the untrusted list
                 target.untrusted_enrs.push(closest.value);
                 // Add the key to the list for the query
@@ -486,13 +1001,16 @@ impl Service {
 
         if known_closest_peers.is_empty() {
             warn!("No known_closest_peers found. Return empty result without sending query.");
-            if target.callback.send(vec![]).is_err() {
+            if target.callback.send(Ok(vec![])).is_err() {
                 warn!("Failed to callback");
             }
         } else {
             let query_config = FindNodeQueryConfig::new_from_config(&self.config);
-            self.queries
-                .add_findnode_query(query_config, target, known_closest_peers);
+            let peers: Vec<NodeId> = known_closest_peers.iter().map(|k| *k.preimage()).collect();
+            let query_id =
+                self.queries
+                    .add_findnode_query(query_config, target, known_closest_peers);
+            self.set_query_latencies(query_id, &peers);
         }
     }
 
@@ -502,7 +1020,7 @@ impl Service {
         target_node: NodeId,
         num_nodes: usize,
         predicate: Box<dyn Fn(&Enr) -> bool + Send>,
-        callback: oneshot::Sender<Vec<Enr>>,
+        callback: oneshot::Sender<Result<Vec<Enr>, QueryError>>,
     ) {
         let mut target = QueryInfo {
             query_type: QueryType::FindNode(target_node),
@@ -519,7 +1037,10 @@ impl Service {
         let mut known_closest_peers = Vec::<kbucket::PredicateKey<_>>::new();
         {
             let mut kbuckets = self.kbuckets.write();
-            for closest in kbuckets.closest_values_predicate(&target_key, &kbucket_predicate) {
+            let closest = kbuckets
+                .closest_values_predicate(&target_key, &kbucket_predicate)
+                .collect();
+            for closest in prefer_reliable(&kbuckets, closest) {
                 let (node_id_predicate, enr) = closest.to_key_value();
                 // Add the known ENR's to the untrusted list
                 target.untrusted_enrs.push(enr);
@@ -530,14 +1051,23 @@ impl Service {
 
         if known_closest_peers.is_empty() {
             warn!("No known_closest_peers found. Return empty result without sending query.");
-            if target.callback.send(vec![]).is_err() {
+            if target.callback.send(Ok(vec![])).is_err() {
                 warn!("Failed to callback");
             }
         } else {
             let mut query_config = PredicateQueryConfig::new_from_config(&self.config);
             query_config.num_results = num_nodes;
-            self.queries
-                .add_predicate_query(query_config, target, known_closest_peers, predicate);
+            let peers: Vec<NodeId> = known_closest_peers
+                .iter()
+                .map(|k| *k.key.preimage())
+                .collect();
+            let query_id = self.queries.add_predicate_query(
+                query_config,
+                target,
+                known_closest_peers,
+                predicate,
+            );
+            self.set_query_latencies(query_id, &peers);
         }
     }
 
@@ -568,7 +1098,16 @@ impl Service {
         let id = req.id;
         match req.body {
             RequestBody::FindNode { distances } => {
-                self.send_nodes_response(node_address, id, distances);
+                if self.config.client_only {
+                    debug!(
+                        "Refusing FINDNODE request from {} in client-only mode",
+                        node_address
+                    );
+                    // No distances are served, which sends an empty response.
+                    self.send_nodes_response(node_address, id, Vec::new());
+                } else {
+                    self.send_nodes_response(node_address, id, distances);
+                }
             }
             RequestBody::Ping { enr_seq } => {
                 // check if we need to update the known ENR
@@ -635,13 +1174,28 @@ impl Service {
                     sender: Some(self.handler_send.clone()),
                 };
 
+                if self.config.client_only {
+                    debug!(
+                        "Refusing TALK request from {} in client-only mode",
+                        req.node_address
+                    );
+                    if let Err(e) = req.respond(Vec::new()) {
+                        warn!("Failed to send the TALK response {:?}", e);
+                    }
+                    return;
+                }
                 self.send_event(Event::TalkRequest(req));
             }
         }
     }
 
     /// Processes an RPC response from a peer.
-    fn handle_rpc_response(&mut self, node_address: NodeAddress, response: Response) {
+    fn handle_rpc_response(
+        &mut self,
+        node_address: NodeAddress,
+        response: Response,
+        rtt: Option<Duration>,
+    ) {
         // verify we know of the rpc_id
         let id = response.id.clone();
 
@@ -659,22 +1213,30 @@ impl Service {
                 return error!("Received a response from an unexpected address. Expected {}, received {}, request_id {}", expected_node_address, node_address, id);
             }
 
+            let node_id = node_address.node_id;
+
             if !response.match_request(&active_request.request_body) {
                 warn!(
                     "Node gave an incorrect response type. Ignoring response from: {}",
                     node_address
                 );
+                self.update_node_stats(&node_id, NodeStats::record_invalid_response);
                 return;
             }
 
-            let node_id = node_address.node_id;
+            // Only the first of multiple NODES responses counts as a success.
+            if !self.active_nodes_responses.contains_key(&id) {
+                self.update_node_stats(&node_id, |stats| stats.record_success(rtt));
+                self.revalidation.on_success(&node_id);
+            }
 
             match response.body {
                 ResponseBody::Nodes { total, mut nodes } => {
-                    if total > MAX_NODES_RESPONSES as u64 {
+                    let max_responses = max_nodes_responses(self.config.bucket_size);
+                    if total > max_responses as u64 {
                         warn!(
                             "NodesResponse has a total larger than {}, nodes will be truncated",
-                            MAX_NODES_RESPONSES
+                            max_responses
                         );
                     }
 
@@ -705,7 +1267,10 @@ impl Service {
                                 node_address
                             );
                             let ban_timeout = self.config.ban_duration.map(|v| Instant::now() + v);
-                            PERMIT_BAN_LIST.write().ban(node_address, ban_timeout);
+                            let ban =
+                                Ban::by_service(ban_timeout, "returned multiple ENRs for itself");
+                            PERMIT_BAN_LIST.write().ban(node_address, ban);
+                            self.update_node_stats(&node_id, NodeStats::record_invalid_response);
                             nodes.retain(|enr| {
                                 peer_key.log2_distance(&enr.node_id().into()).is_none()
                             });
@@ -725,7 +1290,12 @@ impl Service {
                             let addr = active_request.contact.socket_addr();
                             warn!(%node_id, %addr, "ENRs received of unsolicited distances. Blacklisting");
                             let ban_timeout = self.config.ban_duration.map(|v| Instant::now() + v);
-                            PERMIT_BAN_LIST.write().ban(node_address, ban_timeout);
+                            let ban = Ban::by_service(
+                                ban_timeout,
+                                "returned ENRs at unsolicited distances",
+                            );
+                            PERMIT_BAN_LIST.write().ban(node_address, ban);
+                            self.update_node_stats(&node_id, NodeStats::record_invalid_response);
                         }
                     }
 
@@ -744,7 +1314,7 @@ impl Service {
                         // rpc messages.
                         if current_response.received_nodes.len() < self.config.max_nodes_response
                             && (current_response.count as u64) < total
-                            && current_response.count < MAX_NODES_RESPONSES
+                            && current_response.count < max_responses
                         {
                             current_response.count += 1;
 
@@ -799,6 +1369,14 @@ impl Service {
                             if status.is_connected() && !status.is_incoming());
 
                         if should_count {
+                            self.reachability.insert_vote(
+                                node_id,
+                                node_address.local_socket,
+                                socket,
+                            );
+                            self.update_nat_status();
+                            let nat_status = self.reachability.status();
+
                             // get the advertised local addresses
                             let (local_ip4_socket, local_ip6_socket) = {
                                 let local_enr = self.local_enr.read();
@@ -809,6 +1387,11 @@ impl Service {
                                 ip_votes.insert(node_id, socket);
                                 let (maybe_ip4_majority, maybe_ip6_majority) = ip_votes.majority();
 
+                                // A socket is not advertised if peers cannot agree on it.
+                                let maybe_ip4_majority = maybe_ip4_majority
+                                    .filter(|_| nat_status.ipv4.nat_type != NatType::Symmetric);
+                                let maybe_ip6_majority = maybe_ip6_majority
+                                    .filter(|_| nat_status.ipv6.nat_type != NatType::Symmetric);
                                 let new_ip4 = maybe_ip4_majority.and_then(|majority| {
                                     if Some(majority) != local_ip4_socket {
                                         Some(majority)
@@ -866,7 +1449,7 @@ impl Service {
                                         }
                                     }
                                     if updated {
-                                        self.ping_connected_peers();
+                                        self.propagate_local_enr();
                                     }
                                 }
                             }
@@ -935,25 +1518,26 @@ impl Service {
         }
     }
 
-    /// Ping all peers that are connected in the routing table.
-    fn ping_connected_peers(&mut self) {
-        // maintain the ping interval
-        let connected_peers = {
-            let mut kbuckets = self.kbuckets.write();
-            kbuckets
-                .iter()
-                .filter_map(|entry| {
-                    if entry.status.is_connected() {
-                        Some(entry.node.value.clone())
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>()
-        };
-
-        for enr in connected_peers {
-            self.send_ping(enr.clone(), None);
+    /// Queues the peers that are connected in the routing table to be pinged, so they learn of
+    /// the current local ENR. Peers in the closest buckets are pinged first.
+    fn propagate_local_enr(&mut self) {
+        let seq = self.local_enr.read().seq();
+        if self.enr_propagation.is_queued(seq) {
+            return;
+        }
+        let connected_peers = self
+            .kbuckets
+            .write()
+            .iter()
+            .filter(|entry| entry.status.is_connected())
+            .map(|entry| *entry.node.key.preimage())
+            .collect::<Vec<_>>();
+        if self.enr_propagation.start(seq, connected_peers) {
+            debug!(
+                seq,
+                peers = self.enr_propagation.len(),
+                "Propagating the local ENR to connected peers"
+            );
         }
     }
 
@@ -1069,7 +1653,7 @@ impl Service {
                 // packed response.
                 //
                 // The estimated total overhead for a regular message is therefore 104 bytes.
-                if entry_size + total_size < MAX_PACKET_SIZE - 104 {
+                if entry_size + total_size < NODES_RESPONSE_PAYLOAD {
                     total_size += entry_size;
                     trace!(
                         "Adding ENR {}, size {}, total size {}",
@@ -1152,16 +1736,25 @@ impl Service {
     }
 
     /// Sends generic RPC requests. Each request gets added to known outputs, awaiting a response.
-    fn send_rpc_request(&mut self, active_request: ActiveRequest) {
+    fn send_rpc_request(&mut self, mut active_request: ActiveRequest) {
         // Generate a random rpc_id which is matched per node id
         let id = RequestId::random();
         let request: Request = Request {
             id: id.clone(),
             body: active_request.request_body.clone(),
         };
+        if let Some((enr_socket, roamed_to)) = self
+            .roamed_addresses
+            .peek(&active_request.contact.node_id())
+        {
+            if active_request.contact.socket_addr() == *enr_socket {
+                active_request.contact = active_request.contact.with_socket_addr(*roamed_to);
+            }
+        }
         let contact = active_request.contact.clone();
 
         debug!("Sending RPC {} to node: {}", request, contact);
+        self.reachability.contacted(contact.socket_addr().ip());
         if self
             .handler_send
             .send(HandlerIn::Request(contact, Box::new(request)))
@@ -1171,6 +1764,30 @@ impl Service {
         }
     }
 
+    /// Records the address the session of a node moved to. The node answered there, so failures
+    /// of requests sent before the move do not count towards its eviction.
+    fn session_roamed(&mut self, node_id: NodeId, from: SocketAddr, to: SocketAddr) {
+        // A node that moves again is still advertising the socket it first moved from.
+        let enr_socket = match self.roamed_addresses.peek(&node_id) {
+            Some((enr_socket, roamed_to)) if *roamed_to == from => *enr_socket,
+            _ => from,
+        };
+        if enr_socket == to {
+            self.roamed_addresses.remove(&node_id);
+        } else {
+            self.roamed_addresses.insert(node_id, (enr_socket, to));
+        }
+        self.revalidation.on_success(&node_id);
+    }
+
+    /// Updates the request statistics of a node, if it is in the routing table.
+    fn update_node_stats(&self, node_id: &NodeId, update: impl FnOnce(&mut NodeStats)) {
+        let key = kbucket::Key::from(*node_id);
+        if let Some(stats) = self.kbuckets.write().node_stats_mut(&key) {
+            update(stats);
+        }
+    }
+
     fn send_event(&mut self, event: Event) {
         if let Some(stream) = self.event_stream.as_mut() {
             if let Err(mpsc::error::TrySendError::Closed(_)) = stream.try_send(event) {
@@ -1183,6 +1800,8 @@ impl Service {
     /// Processes discovered peers from a query.
     fn discovered(&mut self, source: &NodeId, mut enrs: Vec<Enr>, query_id: Option<QueryId>) {
         let local_id = self.local_enr.read().node_id();
+        // The ENR a node returns for itself is not a new peer for a query, but it is the latest.
+        let source_enr = enrs.iter().find(|enr| enr.node_id() == *source).cloned();
         enrs.retain(|enr| {
             if enr.node_id() == local_id {
                 return false;
@@ -1195,7 +1814,8 @@ impl Service {
             }
 
             // ignore peers that don't pass the table filter
-            if (self.config.table_filter)(enr) {
+            let passes_table_filter = (self.table_filter.read())(enr);
+            if passes_table_filter {
                 let key = kbucket::Key::from(enr.node_id());
 
                 // If the ENR exists in the routing table and the discovered ENR has a greater
@@ -1211,7 +1831,7 @@ impl Service {
                     if let UpdateResult::Failed(reason) =
                         self.kbuckets.write().update_node(&key, enr.clone(), None)
                     {
-                        self.peers_to_ping.remove(&enr.node_id());
+                        self.revalidation.remove(&enr.node_id());
                         debug!(
                             "Failed to update discovered ENR. Node: {}, Reason: {:?}",
                             source, reason
@@ -1234,29 +1854,52 @@ impl Service {
         // if this is part of a query, update the query
         if let Some(query_id) = query_id {
             if let Some(query) = self.queries.get_mut(query_id) {
-                let mut peer_count = 0;
-                for enr_ref in enrs.iter() {
-                    if !query
-                        .target_mut()
-                        .untrusted_enrs
-                        .iter()
-                        .any(|e| e.node_id() == enr_ref.node_id())
+                let untrusted_enrs = &mut query.target_mut().untrusted_enrs;
+                for enr_ref in enrs.iter().chain(&source_enr) {
+                    match untrusted_enrs
+                        .iter_mut()
+                        .find(|e| e.node_id() == enr_ref.node_id())
                     {
-                        query.target_mut().untrusted_enrs.push(enr_ref.clone());
+                        Some(known) if known.seq() < enr_ref.seq() => *known = enr_ref.clone(),
+                        Some(_) => {}
+                        None => untrusted_enrs.push(enr_ref.clone()),
                     }
-                    peer_count += 1;
                 }
-                debug!("{} peers found for query id {:?}", peer_count, query_id);
-                query.on_success(source, &enrs)
+                debug!("{} peers found for query id {:?}", enrs.len(), query_id);
+                query.on_success(source, &enrs);
+                if query.target().query_type == QueryType::Resolve(*source) {
+                    query.finish();
+                }
             } else {
                 debug!("Response returned for ended query {:?}", query_id)
             }
+            let peers: Vec<NodeId> = enrs.iter().map(Enr::node_id).collect();
+            self.set_query_latencies(query_id, &peers);
+        }
+    }
+
+    /// Informs a latency-aware query of the known round-trip times of the given peers.
+    fn set_query_latencies(&mut self, query_id: QueryId, peers: &[NodeId]) {
+        if !self.config.latency_aware_queries {
+            return;
+        }
+        let query = match self.queries.get_mut(query_id) {
+            Some(query) => query,
+            None => return,
+        };
+        let kbuckets = self.kbuckets.read();
+        for node_id in peers {
+            let rtt = kbuckets
+                .node_stats(&kbucket::Key::from(*node_id))
+                .and_then(NodeStats::average_rtt);
+            if let Some(rtt) = rtt {
+                query.set_rtt(node_id, rtt);
+            }
         }
     }
 
     /// Update the connection status of a node in the routing table.
-    /// This tracks whether or not we should be pinging peers. Disconnected peers are removed from
-    /// the queue and newly added peers to the routing table are added to the queue.
+    /// Disconnections count as failures towards the eviction of the node, see [`Revalidation`].
     fn connection_updated(&mut self, node_id: NodeId, new_status: ConnectionStatus) {
         // Variables to that may require post-processing
         let mut ping_peer = None;
@@ -1279,7 +1922,6 @@ impl Service {
                     InsertResult::Inserted => {
                         // We added this peer to the table
                         debug!("New connected node added to routing table: {}", node_id);
-                        self.peers_to_ping.insert(node_id);
 
                         // PING immediately if the direction is outgoing. This allows us to receive
                         // a PONG without waiting for the ping_interval, making ENR updates faster.
@@ -1305,12 +1947,12 @@ impl Service {
                         // The node was updated
                         if promoted_to_connected {
                             debug!("Node promoted to connected: {}", node_id);
-                            self.peers_to_ping.insert(node_id);
+                            self.revalidation.on_success(&node_id);
                         }
                     }
                     InsertResult::ValueUpdated | InsertResult::UpdatedPending => {}
                     InsertResult::Failed(reason) => {
-                        self.peers_to_ping.remove(&node_id);
+                        self.revalidation.remove(&node_id);
                         trace!("Could not insert node: {}, reason: {:?}", node_id, reason);
                     }
                 }
@@ -1322,7 +1964,7 @@ impl Service {
                     .update_node(&key, enr, Some(ConnectionState::Connected))
                 {
                     UpdateResult::Failed(reason) => {
-                        self.peers_to_ping.remove(&node_id);
+                        self.revalidation.remove(&node_id);
                         debug!(
                             "Could not update ENR from pong. Node: {}, reason: {:?}",
                             node_id, reason
@@ -1334,7 +1976,6 @@ impl Service {
                 }
             }
             ConnectionStatus::Disconnected => {
-                // If the node has disconnected, remove any ping timer for the node.
                 match self.kbuckets.write().update_node_status(
                     &key,
                     ConnectionState::Disconnected,
@@ -1353,7 +1994,11 @@ impl Service {
                         debug!("Node set to disconnected: {}", node_id)
                     }
                 }
-                self.peers_to_ping.remove(&node_id);
+                // The node is checked sooner, and evicted if it keeps failing.
+                if self.revalidation.on_failure(&node_id) {
+                    debug!(%node_id, "Evicting unresponsive node from the routing table");
+                    self.kbuckets.write().remove(&key);
+                }
             }
         };
 
@@ -1403,6 +2048,11 @@ impl Service {
             _ => connection_direction,
         };
 
+        // Client-only nodes only add the peers they contacted themselves.
+        if self.config.client_only && direction == ConnectionDirection::Incoming {
+            return;
+        }
+
         debug!(
             "Session established with Node: {}, direction: {}",
             node_id, direction
@@ -1415,6 +2065,8 @@ impl Service {
     fn rpc_failure(&mut self, id: RequestId, error: RequestError) {
         trace!("RPC Error removing request. Reason: {:?}, id {}", error, id);
         if let Some(active_request) = self.active_requests.remove(&id) {
+            self.update_node_stats(&active_request.contact.node_id(), NodeStats::record_failure);
+
             // If this is initiated by the user, return an error on the callback. All callbacks
             // support a request error.
             match active_request.callback {
@@ -1502,15 +2154,28 @@ impl Service {
 
     /// A future that maintains the routing table and inserts nodes when required. This returns the
     /// [`Event::NodeInserted`] variant if a new node has been inserted into the routing table.
-    async fn bucket_maintenance_poll(kbuckets: &Arc<RwLock<KBucketsTable<NodeId, Enr>>>) -> Event {
+    async fn bucket_maintenance_poll(
+        kbuckets: &Arc<RwLock<KBucketsTable<NodeId, Enr>>>,
+    ) -> BucketMaintenance {
         future::poll_fn(move |_cx| {
+            let mut kbuckets = kbuckets.write();
             // Drain applied pending entries from the routing table.
-            if let Some(entry) = kbuckets.write().take_applied_pending() {
+            if let Some(entry) = kbuckets.take_applied_pending() {
                 let event = Event::NodeInserted {
                     node_id: entry.inserted.into_preimage(),
                     replaced: entry.evicted.map(|n| n.key.into_preimage()),
                 };
-                return Poll::Ready(event);
+                return Poll::Ready(BucketMaintenance::Applied(event));
+            }
+            // Drain replacement candidates that need to be contacted.
+            while let Some(key) = kbuckets.take_revalidation() {
+                let enr = kbuckets
+                    .get_bucket(&key)
+                    .and_then(|bucket| bucket.as_pending(&key))
+                    .map(|pending| pending.value().clone());
+                if let Some(enr) = enr {
+                    return Poll::Ready(BucketMaintenance::Revalidate(enr));
+                }
             }
             Poll::Pending
         })
@@ -1535,6 +2200,32 @@ impl Service {
     }
 }
 
+/// Leaves the peers scoring below [`MIN_LOOKUP_SCORE`] out of the peers to seed a lookup with,
+/// unless none of them scores higher.
+fn prefer_reliable<T: AsRef<kbucket::Key<NodeId>>>(
+    kbuckets: &KBucketsTable<NodeId, Enr>,
+    peers: Vec<T>,
+) -> Vec<T> {
+    let is_reliable = |peer: &T| {
+        kbuckets
+            .node_stats(peer.as_ref())
+            .is_none_or(|stats| stats.score() >= MIN_LOOKUP_SCORE)
+    };
+    if peers.iter().any(is_reliable) {
+        peers.into_iter().filter(is_reliable).collect()
+    } else {
+        peers
+    }
+}
+
+/// The outcome of polling the routing table for maintenance work.
+enum BucketMaintenance {
+    /// A pending entry was inserted into the routing table.
+    Applied(Event),
+    /// A replacement candidate was promoted and must respond before it is inserted.
+    Revalidate(Enr),
+}
+
 /// The result of the `query_event_poll` indicating an action is required to further progress an
 /// active query.
 enum QueryEvent {
@@ -1557,6 +2248,8 @@ pub enum QueryKind {
         target_peer_no: usize,
         predicate: Box<dyn Fn(&Enr) -> bool + Send>,
     },
+    /// A lookup for the latest ENR of a node, which ends as soon as the node itself responds.
+    Resolve { target_node: NodeId },
 }
 
 /// Reporting the connection status of a node.
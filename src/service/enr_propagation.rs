@@ -0,0 +1,89 @@
+//! Spreads changes of the local ENR to the connected peers of the routing table.
+//!
+//! Peers learn of a new ENR sequence number from our PINGs. Rather than pinging every connected
+//! peer at once, peers are pinged in small batches at the configured rate, closest buckets first.
+use enr::NodeId;
+use std::{collections::VecDeque, time::Duration};
+use tokio::time::{Interval, MissedTickBehavior};
+
+/// The time between batches of pings.
+const BATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+pub(crate) struct EnrPropagation {
+    /// The peers still to be pinged, closest first.
+    pending: VecDeque<NodeId>,
+    /// The latest sequence number of the local ENR that has been queued for propagation.
+    seq: u64,
+    /// Paces the batches.
+    interval: Interval,
+    /// The number of peers pinged per batch.
+    batch_size: usize,
+}
+
+impl EnrPropagation {
+    /// Starts with the current sequence number of the local ENR, which peers already know.
+    pub(crate) fn new(seq: u64, peers_per_second: usize) -> Self {
+        let mut interval = tokio::time::interval(BATCH_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let batches_per_second =
+            (Duration::from_secs(1).as_millis() / BATCH_INTERVAL.as_millis()) as usize;
+        EnrPropagation {
+            pending: VecDeque::new(),
+            seq,
+            interval,
+            batch_size: peers_per_second.div_ceil(batches_per_second).max(1),
+        }
+    }
+
+    /// Queues the peers to learn of the local ENR with sequence number `seq`, replacing the
+    /// peers queued for an older one. Peers must be ordered closest first. Returns false if `seq`
+    /// has already been queued.
+    pub(crate) fn start(&mut self, seq: u64, peers: impl IntoIterator<Item = NodeId>) -> bool {
+        if self.is_queued(seq) {
+            return false;
+        }
+        self.seq = seq;
+        self.pending = peers.into_iter().collect();
+        true
+    }
+
+    /// Whether the local ENR with sequence number `seq`, or a newer one, has been queued.
+    pub(crate) fn is_queued(&self, seq: u64) -> bool {
+        seq <= self.seq
+    }
+
+    /// The number of peers still to be pinged.
+    pub(crate) fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Waits for the next batch of peers to ping. Never resolves if no peers are queued.
+    pub(crate) async fn next_batch(&mut self) -> Vec<NodeId> {
+        if self.pending.is_empty() {
+            return futures::future::pending().await;
+        }
+        self.interval.tick().await;
+        let batch_size = self.batch_size.min(self.pending.len());
+        self.pending.drain(..batch_size).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn peers_are_pinged_in_batches() {
+        let mut propagation = EnrPropagation::new(1, 20);
+        let peers: Vec<NodeId> = (0..5).map(|_| NodeId::random()).collect();
+
+        assert!(!propagation.start(1, peers.clone()));
+        assert!(propagation.start(2, peers.clone()));
+        assert_eq!(propagation.next_batch().await, peers[..2]);
+        // A newer ENR replaces the queue.
+        assert!(propagation.start(3, peers[..3].to_vec()));
+        assert_eq!(propagation.next_batch().await, peers[..2]);
+        assert_eq!(propagation.next_batch().await, peers[2..3]);
+        assert_eq!(propagation.len(), 0);
+    }
+}
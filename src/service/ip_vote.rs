@@ -36,11 +36,36 @@ impl IpVote {
 
     /// Returns the majority `SocketAddr` if it exists. If there are not enough votes to meet the threshold this returns None.
     pub fn majority(&mut self) -> (Option<SocketAddrV4>, Option<SocketAddrV6>) {
+        let (ip4_count, ip6_count) = self.count();
+
+        // find the maximum socket addr
+        let ip4_majority = majority(ip4_count.into_iter(), &self.minimum_threshold);
+        let ip6_majority = majority(ip6_count.into_iter(), &self.minimum_threshold);
+        (ip4_majority, ip6_majority)
+    }
+
+    /// Returns the number of votes and the most voted for `SocketAddr` of each IP version,
+    /// regardless of the threshold.
+    pub fn tally(&mut self) -> (Tally, Tally) {
+        let (ip4_count, ip6_count) = self.count();
+        (
+            Tally::new(ip4_count.into_iter().map(|(k, count)| (k.into(), count))),
+            Tally::new(ip6_count.into_iter().map(|(k, count)| (k.into(), count))),
+        )
+    }
+
+    /// Removes expired votes and counts the votes for each socket.
+    fn count(
+        &mut self,
+    ) -> (
+        FnvHashMap<SocketAddrV4, usize>,
+        FnvHashMap<SocketAddrV6, usize>,
+    ) {
         // remove any expired votes
         let instant = Instant::now();
         self.votes.retain(|_, v| v.1 > instant);
 
-        // count votes, take majority
+        // count votes
         let mut ip4_count: FnvHashMap<SocketAddrV4, usize> = FnvHashMap::default();
         let mut ip6_count: FnvHashMap<SocketAddrV6, usize> = FnvHashMap::default();
         for (socket, _) in self.votes.values() {
@@ -51,11 +76,29 @@ impl IpVote {
                 SocketAddr::V6(socket) => *ip6_count.entry(*socket).or_insert_with(|| 0) += 1,
             }
         }
+        (ip4_count, ip6_count)
+    }
+}
 
-        // find the maximum socket addr
-        let ip4_majority = majority(ip4_count.into_iter(), &self.minimum_threshold);
-        let ip6_majority = majority(ip6_count.into_iter(), &self.minimum_threshold);
-        (ip4_majority, ip6_majority)
+/// The votes cast for the sockets of one IP version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct Tally {
+    /// The total number of votes.
+    pub total: usize,
+    /// The number of different sockets voted for.
+    pub distinct: usize,
+    /// The socket with the most votes and its number of votes.
+    pub most_voted: Option<(SocketAddr, usize)>,
+}
+
+impl Tally {
+    fn new(counts: impl Iterator<Item = (SocketAddr, usize)>) -> Self {
+        let counts: Vec<_> = counts.collect();
+        Tally {
+            total: counts.iter().map(|(_, count)| count).sum(),
+            distinct: counts.len(),
+            most_voted: counts.into_iter().max_by_key(|(_, count)| *count),
+        }
     }
 }
 
@@ -1,4 +1,4 @@
-use crate::{kbucket::Key, rpc::RequestBody, Enr};
+use crate::{error::QueryError, kbucket::Key, rpc::RequestBody, Enr};
 use enr::{k256::sha2::digest::generic_array::GenericArray, NodeId};
 use smallvec::SmallVec;
 use tokio::sync::oneshot;
@@ -13,7 +13,7 @@ pub struct QueryInfo {
     pub untrusted_enrs: SmallVec<[Enr; 16]>,
 
     /// A callback channel for the service that requested the query.
-    pub callback: oneshot::Sender<Vec<Enr>>,
+    pub callback: oneshot::Sender<Result<Vec<Enr>, QueryError>>,
 
     /// The number of distances we request for each peer.
     /// NOTE: This must not be larger than 127.
@@ -25,13 +25,16 @@ pub struct QueryInfo {
 pub enum QueryType {
     /// The user requested a `FIND_NODE` query to be performed. It should be reported when finished.
     FindNode(NodeId),
+    /// The user requested the latest ENR of a node. The query ends as soon as the node itself
+    /// responds.
+    Resolve(NodeId),
 }
 
 impl QueryInfo {
     /// Builds an RPC Request, given the QueryInfo
     pub(crate) fn rpc_request(&self, peer: NodeId) -> RequestBody {
         match self.query_type {
-            QueryType::FindNode(node_id) => {
+            QueryType::FindNode(node_id) | QueryType::Resolve(node_id) => {
                 let distances = findnode_log2distance(node_id, peer, self.distances_to_request)
                     .unwrap_or_else(|| vec![0]);
                 RequestBody::FindNode { distances }
@@ -43,7 +46,7 @@ impl QueryInfo {
 impl crate::query_pool::TargetKey<NodeId> for QueryInfo {
     fn key(&self) -> Key<NodeId> {
         match self.query_type {
-            QueryType::FindNode(ref node_id) => {
+            QueryType::FindNode(ref node_id) | QueryType::Resolve(ref node_id) => {
                 Key::new_raw(*node_id, *GenericArray::from_slice(&node_id.raw()))
             }
         }
@@ -0,0 +1,446 @@
+//! Estimates whether the local node can be reached by other nodes.
+//!
+//! The sockets peers report in PONG responses classify the NAT we are behind. If peers observe
+//! the socket we listen on, there is no NAT. If peers agree on a different socket, the NAT maps
+//! our socket consistently. If peers disagree on the socket, the NAT allocates a new mapping for
+//! each destination (a symmetric NAT) and no socket we could advertise is reachable.
+//!
+//! Each of our sockets is mapped separately, so only the reports received on the same local
+//! socket are compared. As peers can report any socket, a symmetric NAT is only assumed once at
+//! least [`MIN_SYMMETRIC_MAPPINGS`] different sockets were reported without a majority.
+//!
+//! Whether a consistent mapping also accepts unsolicited traffic is estimated from the sessions
+//! other nodes establish with us. A session initiated by a node whose IP we have not recently
+//! contacted could only have reached us through an open mapping.
+use super::ip_vote::{IpVote, Tally};
+use crate::{lru_time_cache::LruTimeCache, ListenConfig};
+use enr::NodeId;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+/// The number of recently contacted IP addresses that are remembered.
+const CONTACTED_CAPACITY: usize = 10_000;
+
+/// The minimum number of different sockets peers must report without a majority before the NAT
+/// is considered symmetric.
+const MIN_SYMMETRIC_MAPPINGS: usize = 3;
+
+/// The type of NAT the local node is behind, as observed by its peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NatType {
+    /// Not enough peers have reported our socket yet.
+    #[default]
+    Unknown,
+    /// Peers observe the socket we listen on.
+    Public,
+    /// Peers observe a single translated socket and unsolicited sessions reach us.
+    FullCone,
+    /// Peers observe a single translated socket, but no unsolicited sessions reach us.
+    Restricted,
+    /// Peers observe different sockets. The socket seen by one peer cannot be used by another.
+    /// As peers may report false sockets, this alone does not show that we are unreachable.
+    Symmetric,
+}
+
+/// Whether nodes that we have not contacted can establish sessions with us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InboundReachability {
+    /// There is not enough information yet.
+    #[default]
+    Unknown,
+    /// An unsolicited session has been established.
+    Reachable,
+    /// No unsolicited session has been established while our external socket was known or the
+    /// NAT was symmetric.
+    Unreachable,
+}
+
+/// The reachability of the local node over one IP version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Reachability {
+    /// The type of NAT we are behind.
+    pub nat_type: NatType,
+    /// Whether unsolicited sessions reach us.
+    pub inbound: InboundReachability,
+    /// The socket the majority of peers observe, if there is one.
+    pub external_socket: Option<SocketAddr>,
+}
+
+/// The reachability of the local node. See [`crate::Discv5::nat_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NatStatus {
+    /// The reachability over IPv4.
+    pub ipv4: Reachability,
+    /// The reachability over IPv6.
+    pub ipv6: Reachability,
+}
+
+impl NatStatus {
+    /// The reachability over the IP version of `socket`.
+    pub fn for_socket(&self, socket: &SocketAddr) -> &Reachability {
+        if socket.is_ipv4() {
+            &self.ipv4
+        } else {
+            &self.ipv6
+        }
+    }
+}
+
+/// The observations about one IP version.
+#[derive(Debug, Default)]
+struct Observations {
+    /// The external socket the majority of peers observe and when it was first observed.
+    external_socket: Option<(SocketAddr, Instant)>,
+    /// When peers were first observed to disagree on our socket, if they currently do.
+    symmetric_since: Option<Instant>,
+    /// Whether an unsolicited session was established since the external socket changed.
+    unsolicited_session: bool,
+}
+
+/// Collects the observations of peers and derives the [`NatStatus`] from them.
+pub(crate) struct ReachabilityTracker {
+    /// The sockets peers report in PONG responses, by the local socket the response was received
+    /// on.
+    votes: BTreeMap<Option<SocketAddr>, IpVote>,
+    /// The time votes remain valid.
+    vote_duration: Duration,
+    /// The minimum number of votes required before the NAT is classified.
+    minimum_threshold: usize,
+    /// The sockets we listen on.
+    listen_sockets: Vec<SocketAddr>,
+    /// The IP addresses we have recently sent requests to.
+    contacted: LruTimeCache<IpAddr, ()>,
+    /// How long an external socket must be known without unsolicited sessions before we are
+    /// considered unreachable.
+    observation_window: Duration,
+    ipv4: Observations,
+    ipv6: Observations,
+    /// The last derived status.
+    status: NatStatus,
+}
+
+impl ReachabilityTracker {
+    pub fn new(
+        minimum_threshold: usize,
+        vote_duration: Duration,
+        observation_window: Duration,
+        listen_config: &ListenConfig,
+    ) -> Self {
+        ReachabilityTracker {
+            votes: BTreeMap::new(),
+            vote_duration,
+            minimum_threshold,
+            listen_sockets: listen_config.sockets(),
+            contacted: LruTimeCache::new(observation_window, Some(CONTACTED_CAPACITY)),
+            observation_window,
+            ipv4: Observations::default(),
+            ipv6: Observations::default(),
+            status: NatStatus::default(),
+        }
+    }
+
+    /// The last derived status.
+    pub fn status(&self) -> NatStatus {
+        self.status
+    }
+
+    /// Records the socket a peer observed us at, in a response received on `local_socket`.
+    pub fn insert_vote(
+        &mut self,
+        node_id: NodeId,
+        local_socket: Option<SocketAddr>,
+        socket: SocketAddr,
+    ) {
+        let (minimum_threshold, vote_duration) = (self.minimum_threshold, self.vote_duration);
+        self.votes
+            .entry(local_socket)
+            .or_insert_with(|| IpVote::new(minimum_threshold, vote_duration))
+            .insert(node_id, socket);
+    }
+
+    /// Records that we sent a request to `ip`. Sessions initiated from this IP are not
+    /// unsolicited for the duration of the observation window.
+    pub fn contacted(&mut self, ip: IpAddr) {
+        self.contacted.insert(ip, ());
+    }
+
+    /// Records a session that was initiated by the node at `socket`.
+    pub fn inbound_session(&mut self, socket: SocketAddr) {
+        if self.contacted.peek(&socket.ip()).is_some() {
+            return;
+        }
+        match socket {
+            SocketAddr::V4(_) => self.ipv4.unsolicited_session = true,
+            SocketAddr::V6(_) => self.ipv6.unsolicited_session = true,
+        }
+    }
+
+    /// Discards all observations, as they were made for the previous sockets.
+    pub fn set_listen_config(&mut self, listen_config: &ListenConfig) {
+        self.votes.clear();
+        self.listen_sockets = listen_config.sockets();
+        self.ipv4 = Observations::default();
+        self.ipv6 = Observations::default();
+    }
+
+    /// Derives the status from the current observations. Returns the new status if it changed.
+    pub fn update(&mut self) -> Option<NatStatus> {
+        let now = Instant::now();
+        // The local socket with the most votes of each IP version is classified.
+        let (mut ip4_tally, mut ip6_tally) = (Tally::default(), Tally::default());
+        self.votes.retain(|_, votes| {
+            let (ip4, ip6) = votes.tally();
+            if ip4.total > ip4_tally.total {
+                ip4_tally = ip4;
+            }
+            if ip6.total > ip6_tally.total {
+                ip6_tally = ip6;
+            }
+            ip4.total + ip6.total > 0
+        });
+
+        let status = NatStatus {
+            ipv4: self.classify(ip4_tally, false, now),
+            ipv6: self.classify(ip6_tally, true, now),
+        };
+        if status == self.status {
+            return None;
+        }
+        self.status = status;
+        Some(status)
+    }
+
+    fn classify(&mut self, tally: Tally, ipv6: bool, now: Instant) -> Reachability {
+        let observations = if ipv6 { &mut self.ipv6 } else { &mut self.ipv4 };
+
+        let undecided = Reachability {
+            inbound: if observations.unsolicited_session {
+                InboundReachability::Reachable
+            } else {
+                InboundReachability::Unknown
+            },
+            ..Default::default()
+        };
+        let (external_socket, count) = match tally.most_voted {
+            Some((socket, count)) if tally.total >= self.minimum_threshold => (socket, count),
+            _ => return undecided,
+        };
+
+        // Without a majority, each peer sees a different mapping.
+        if count * 2 <= tally.total {
+            // A few peers reporting different sockets may just be lying.
+            if tally.distinct < self.minimum_threshold.max(MIN_SYMMETRIC_MAPPINGS) {
+                return undecided;
+            }
+            let since = match observations.symmetric_since {
+                Some(since) => since,
+                None => {
+                    if observations.external_socket.take().is_some() {
+                        observations.unsolicited_session = false;
+                    }
+                    observations.symmetric_since = Some(now);
+                    now
+                }
+            };
+            return Reachability {
+                nat_type: NatType::Symmetric,
+                inbound: inbound(observations, since, now, self.observation_window),
+                external_socket: None,
+            };
+        }
+
+        if observations.symmetric_since.take().is_some() {
+            observations.unsolicited_session = false;
+        }
+        let since = match observations.external_socket {
+            Some((socket, since)) if socket == external_socket => since,
+            previous => {
+                // Sessions established through a previous mapping say nothing about this one.
+                if previous.is_some() {
+                    observations.unsolicited_session = false;
+                }
+                observations.external_socket = Some((external_socket, now));
+                now
+            }
+        };
+
+        let inbound = inbound(observations, since, now, self.observation_window);
+
+        let nat_type = if is_listen_socket(&self.listen_sockets, &external_socket) {
+            NatType::Public
+        } else {
+            match inbound {
+                InboundReachability::Reachable => NatType::FullCone,
+                InboundReachability::Unreachable => NatType::Restricted,
+                InboundReachability::Unknown => NatType::Unknown,
+            }
+        };
+
+        Reachability {
+            nat_type,
+            inbound,
+            external_socket: Some(external_socket),
+        }
+    }
+}
+
+/// Whether unsolicited sessions reach us, given the observations made since `since`.
+fn inbound(
+    observations: &Observations,
+    since: Instant,
+    now: Instant,
+    observation_window: Duration,
+) -> InboundReachability {
+    if observations.unsolicited_session {
+        InboundReachability::Reachable
+    } else if now.saturating_duration_since(since) >= observation_window {
+        InboundReachability::Unreachable
+    } else {
+        InboundReachability::Unknown
+    }
+}
+
+/// Whether `socket` is one of the `listen_sockets`. A socket listening on an unspecified IP
+/// matches any IP with the same port.
+fn is_listen_socket(listen_sockets: &[SocketAddr], socket: &SocketAddr) -> bool {
+    listen_sockets.iter().any(|listen| {
+        listen.port() == socket.port()
+            && listen.is_ipv4() == socket.is_ipv4()
+            && (listen.ip().is_unspecified() || listen.ip() == socket.ip())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_tracker(observation_window: Duration) -> ReachabilityTracker {
+        let listen_config = ListenConfig::Multi {
+            sockets: vec![
+                "10.0.0.1:9000".parse().unwrap(),
+                "10.0.0.1:9001".parse().unwrap(),
+            ],
+            enr_ipv4: None,
+            enr_ipv6: None,
+        };
+        ReachabilityTracker::new(
+            2,
+            Duration::from_secs(60),
+            observation_window,
+            &listen_config,
+        )
+    }
+
+    #[test]
+    fn test_public() {
+        let mut tracker = build_tracker(Duration::from_secs(60));
+        let socket: SocketAddr = "10.0.0.1:9000".parse().unwrap();
+        tracker.insert_vote(NodeId::random(), None, socket);
+        assert_eq!(tracker.update(), None);
+
+        tracker.insert_vote(NodeId::random(), None, socket);
+        let status = tracker.update().unwrap();
+        assert_eq!(status.ipv4.nat_type, NatType::Public);
+        assert_eq!(status.ipv4.external_socket, Some(socket));
+        assert_eq!(status.ipv6, Reachability::default());
+    }
+
+    #[test]
+    fn test_symmetric() {
+        let mut tracker = build_tracker(Duration::from_secs(60));
+        // Two different sockets are not enough to tell a symmetric NAT from lying peers.
+        for port in 1..=2 {
+            tracker.insert_vote(
+                NodeId::random(),
+                None,
+                SocketAddr::new("1.1.1.1".parse().unwrap(), port),
+            );
+        }
+        assert_eq!(tracker.update(), None);
+
+        tracker.insert_vote(
+            NodeId::random(),
+            None,
+            SocketAddr::new("1.1.1.1".parse().unwrap(), 3),
+        );
+        let status = tracker.update().unwrap();
+        assert_eq!(status.ipv4.nat_type, NatType::Symmetric);
+        assert_eq!(status.ipv4.inbound, InboundReachability::Unknown);
+        assert_eq!(status.ipv4.external_socket, None);
+
+        // Unsolicited sessions show that we are reachable despite the reports.
+        tracker.inbound_session("2.2.2.2:9000".parse().unwrap());
+        let status = tracker.update().unwrap();
+        assert_eq!(status.ipv4.nat_type, NatType::Symmetric);
+        assert_eq!(status.ipv4.inbound, InboundReachability::Reachable);
+
+        // Without them, we are unreachable once the observation window has passed.
+        let mut tracker = build_tracker(Duration::ZERO);
+        for port in 1..=3 {
+            tracker.insert_vote(
+                NodeId::random(),
+                None,
+                SocketAddr::new("1.1.1.1".parse().unwrap(), port),
+            );
+        }
+        let status = tracker.update().unwrap();
+        assert_eq!(status.ipv4.nat_type, NatType::Symmetric);
+        assert_eq!(status.ipv4.inbound, InboundReachability::Unreachable);
+    }
+
+    #[test]
+    fn test_votes_grouped_by_local_socket() {
+        let mut tracker = build_tracker(Duration::ZERO);
+        // Each of our sockets is mapped to its own external socket.
+        for (local_port, external_port) in [(9000, 3000), (9001, 3001)] {
+            let local_socket = SocketAddr::new("10.0.0.1".parse().unwrap(), local_port);
+            for _ in 0..2 {
+                tracker.insert_vote(
+                    NodeId::random(),
+                    Some(local_socket),
+                    SocketAddr::new("1.1.1.1".parse().unwrap(), external_port),
+                );
+            }
+        }
+        tracker.insert_vote(
+            NodeId::random(),
+            Some("10.0.0.1:9001".parse().unwrap()),
+            "1.1.1.1:3001".parse().unwrap(),
+        );
+        let status = tracker.update().unwrap();
+        assert_eq!(status.ipv4.nat_type, NatType::Restricted);
+        assert_eq!(
+            status.ipv4.external_socket,
+            Some("1.1.1.1:3001".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_full_cone_and_restricted() {
+        let external: SocketAddr = "1.1.1.1:3000".parse().unwrap();
+
+        // An unsolicited session shows that the mapping accepts inbound traffic.
+        let mut tracker = build_tracker(Duration::from_secs(60));
+        tracker.insert_vote(NodeId::random(), None, external);
+        tracker.insert_vote(NodeId::random(), None, external);
+        assert_eq!(tracker.update().unwrap().ipv4.nat_type, NatType::Unknown);
+        tracker.contacted("2.2.2.2".parse().unwrap());
+        tracker.inbound_session("2.2.2.2:9000".parse().unwrap());
+        assert_eq!(tracker.update(), None);
+        tracker.inbound_session("3.3.3.3:9000".parse().unwrap());
+        let status = tracker.update().unwrap();
+        assert_eq!(status.ipv4.nat_type, NatType::FullCone);
+        assert_eq!(status.ipv4.inbound, InboundReachability::Reachable);
+
+        // Without unsolicited sessions over the observation window, we are unreachable.
+        let mut tracker = build_tracker(Duration::ZERO);
+        tracker.insert_vote(NodeId::random(), None, external);
+        tracker.insert_vote(NodeId::random(), None, external);
+        let status = tracker.update().unwrap();
+        assert_eq!(status.ipv4.nat_type, NatType::Restricted);
+        assert_eq!(status.ipv4.inbound, InboundReachability::Unreachable);
+    }
+}
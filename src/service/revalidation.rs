@@ -0,0 +1,280 @@
+//! Schedules the liveness checks of the routing table entries.
+//!
+//! Entries are pinged at an interval which depends on how they have behaved. Disconnected entries
+//! are checked every `disconnected_interval`. Connected entries are checked every
+//! `fresh_interval` until they have been responsive for `stable_after`, and every `ping_interval`
+//! of the [`crate::Config`] from then on. Any response from an entry postpones its next check, and
+//! an entry whose requests fail `max_failures` times in a row is evicted.
+//!
+//! Each interval is randomly shortened or lengthened by up to `jitter_percent`, so that the checks
+//! of entries added at the same time drift apart.
+use delay_map::HashSetDelay;
+use enr::NodeId;
+use futures::StreamExt;
+use rand::Rng;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use tokio::time::{Interval, MissedTickBehavior};
+
+/// How often the schedule is reconciled with the entries of the routing table.
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the routing table entries are checked for liveness. See
+/// [`crate::ConfigBuilder::revalidation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RevalidationConfig {
+    /// The time between pings of a connected entry that has not yet been responsive for
+    /// `stable_after`. Default: 30 seconds.
+    pub fresh_interval: Duration,
+    /// How long an entry must answer every request before it is pinged at the `ping_interval`
+    /// of the [`crate::Config`]. Default: 30 minutes.
+    pub stable_after: Duration,
+    /// The time between pings of a disconnected entry. Default: 60 seconds.
+    pub disconnected_interval: Duration,
+    /// The number of requests in a row that an entry may fail before it is evicted. Default: 3.
+    pub max_failures: u32,
+    /// The percentage by which each interval is randomly shortened or lengthened. Must be less
+    /// than 100. Default: 20.
+    pub jitter_percent: u8,
+}
+
+impl Default for RevalidationConfig {
+    fn default() -> Self {
+        RevalidationConfig {
+            fresh_interval: Duration::from_secs(30),
+            stable_after: Duration::from_secs(30 * 60),
+            disconnected_interval: Duration::from_secs(60),
+            max_failures: 3,
+            jitter_percent: 20,
+        }
+    }
+}
+
+impl RevalidationConfig {
+    /// Checks that the intervals and `max_failures` are non-zero and that `jitter_percent` is
+    /// less than 100.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.fresh_interval.is_zero() || self.disconnected_interval.is_zero() {
+            return Err("Revalidation intervals must be non-zero");
+        }
+        if self.max_failures == 0 {
+            return Err("max_failures must be non-zero");
+        }
+        if self.jitter_percent >= 100 {
+            return Err("jitter_percent must be less than 100");
+        }
+        Ok(())
+    }
+}
+
+/// What the schedule requires of the service.
+pub(crate) enum RevalidationEvent {
+    /// The node is due to be pinged.
+    Ping(NodeId),
+    /// The schedule must be synced with the routing table.
+    Sync,
+}
+
+/// What is known of the liveness of an entry.
+#[derive(Debug, Clone, Copy)]
+struct Liveness {
+    /// Since when the entry has answered all requests, if it does.
+    responsive_since: Option<Instant>,
+    /// The number of requests in a row that failed.
+    failures: u32,
+}
+
+pub(crate) struct Revalidation {
+    config: RevalidationConfig,
+    /// The time between pings of stable entries.
+    stable_interval: Duration,
+    /// The entries of the routing table as of the last sync.
+    entries: HashMap<NodeId, Liveness>,
+    /// The entries whose next check is scheduled.
+    timers: HashSetDelay<NodeId>,
+    /// Paces the syncs with the routing table.
+    sync: Interval,
+}
+
+impl Revalidation {
+    pub(crate) fn new(config: RevalidationConfig, stable_interval: Duration) -> Self {
+        let mut sync = tokio::time::interval(SYNC_INTERVAL);
+        sync.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Revalidation {
+            config,
+            stable_interval,
+            entries: HashMap::new(),
+            timers: HashSetDelay::new(stable_interval),
+            sync,
+        }
+    }
+
+    /// Sets the time between pings of stable entries, applied as their checks are rescheduled.
+    pub(crate) fn set_stable_interval(&mut self, stable_interval: Duration) {
+        self.stable_interval = stable_interval;
+    }
+
+    /// Starts tracking the given entries of the routing table, with whether they are connected,
+    /// and stops tracking any other. Entries without a scheduled check are scheduled.
+    pub(crate) fn sync(&mut self, table: impl IntoIterator<Item = (NodeId, bool)>) {
+        let now = Instant::now();
+        let mut present = HashSet::new();
+        for (node_id, connected) in table {
+            present.insert(node_id);
+            self.entries.entry(node_id).or_insert(Liveness {
+                responsive_since: connected.then_some(now),
+                failures: 0,
+            });
+            if !self.timers.contains_key(&node_id) {
+                self.schedule(node_id, now);
+            }
+        }
+        let timers = &mut self.timers;
+        self.entries.retain(|node_id, _| {
+            let keep = present.contains(node_id);
+            if !keep {
+                timers.remove(node_id);
+            }
+            keep
+        });
+    }
+
+    /// Records a response from a node, postponing its next check.
+    pub(crate) fn on_success(&mut self, node_id: &NodeId) {
+        let now = Instant::now();
+        if let Some(liveness) = self.entries.get_mut(node_id) {
+            liveness.failures = 0;
+            liveness.responsive_since.get_or_insert(now);
+            self.schedule(*node_id, now);
+        }
+    }
+
+    /// Records a failed request to a node. Returns true if the node has failed too many requests
+    /// in a row and must be evicted, in which case it is no longer tracked.
+    pub(crate) fn on_failure(&mut self, node_id: &NodeId) -> bool {
+        let liveness = match self.entries.get_mut(node_id) {
+            Some(liveness) => liveness,
+            None => return false,
+        };
+        liveness.failures += 1;
+        liveness.responsive_since = None;
+        if liveness.failures >= self.config.max_failures {
+            self.remove(node_id);
+            return true;
+        }
+        self.schedule(*node_id, Instant::now());
+        false
+    }
+
+    /// Stops tracking a node.
+    pub(crate) fn remove(&mut self, node_id: &NodeId) {
+        self.entries.remove(node_id);
+        self.timers.remove(node_id);
+    }
+
+    /// Waits for the next node to ping or for the next sync.
+    pub(crate) async fn next(&mut self) -> RevalidationEvent {
+        let timers = &mut self.timers;
+        let due = async move {
+            match timers.next().await {
+                Some(Ok(node_id)) => node_id,
+                _ => futures::future::pending().await,
+            }
+        };
+        tokio::select! {
+            node_id = due => RevalidationEvent::Ping(node_id),
+            _ = self.sync.tick() => RevalidationEvent::Sync,
+        }
+    }
+
+    /// Schedules the next check of a tracked node.
+    fn schedule(&mut self, node_id: NodeId, now: Instant) {
+        if let Some(liveness) = self.entries.get(&node_id) {
+            let interval = self.jittered(self.interval(liveness, now));
+            self.timers.insert_at(node_id, interval);
+        }
+    }
+
+    /// The time between checks of a node, without jitter.
+    fn interval(&self, liveness: &Liveness, now: Instant) -> Duration {
+        match liveness.responsive_since {
+            None => self.config.disconnected_interval,
+            Some(since) if now.saturating_duration_since(since) >= self.config.stable_after => {
+                self.stable_interval
+            }
+            Some(_) => self.config.fresh_interval,
+        }
+    }
+
+    fn jittered(&self, interval: Duration) -> Duration {
+        if self.config.jitter_percent == 0 {
+            return interval;
+        }
+        let jitter = f64::from(self.config.jitter_percent) / 100.0;
+        interval.mul_f64(rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn entries_are_checked_by_tier() {
+        let config = RevalidationConfig {
+            stable_after: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let stable_interval = Duration::from_secs(300);
+        let mut revalidation = Revalidation::new(config, stable_interval);
+        let connected = NodeId::random();
+        let disconnected = NodeId::random();
+        revalidation.sync([(connected, true), (disconnected, false)]);
+
+        let now = Instant::now();
+        let interval = |revalidation: &Revalidation, node_id, now| {
+            revalidation.interval(&revalidation.entries[node_id], now)
+        };
+        assert_eq!(
+            interval(&revalidation, &connected, now),
+            config.fresh_interval
+        );
+        assert_eq!(
+            interval(&revalidation, &disconnected, now),
+            config.disconnected_interval
+        );
+        let later = now + config.stable_after;
+        assert_eq!(interval(&revalidation, &connected, later), stable_interval);
+
+        // A responsive entry is fresh until it has been responsive for `stable_after`.
+        revalidation.on_success(&disconnected);
+        assert_eq!(
+            interval(&revalidation, &disconnected, Instant::now()),
+            config.fresh_interval
+        );
+
+        // Entries that leave the table are no longer tracked.
+        revalidation.sync([(connected, true)]);
+        assert!(!revalidation.entries.contains_key(&disconnected));
+        assert!(!revalidation.timers.contains_key(&disconnected));
+    }
+
+    #[tokio::test]
+    async fn failing_entries_are_evicted() {
+        let mut revalidation = Revalidation::new(Default::default(), Duration::from_secs(300));
+        let node_id = NodeId::random();
+        revalidation.sync([(node_id, true)]);
+
+        assert!(!revalidation.on_failure(&node_id));
+        // A response resets the count.
+        revalidation.on_success(&node_id);
+        assert!(!revalidation.on_failure(&node_id));
+        assert!(!revalidation.on_failure(&node_id));
+        assert!(revalidation.on_failure(&node_id));
+        assert!(!revalidation.entries.contains_key(&node_id));
+        // Nodes that aren't tracked are never evicted.
+        assert!(!revalidation.on_failure(&node_id));
+    }
+}
@@ -0,0 +1,175 @@
+//! Streams the changes of the routing table to subscribers.
+//!
+//! The table is snapshotted periodically while there are subscribers, and each subscriber is sent
+//! the diff from the previous snapshot. Changes that are undone between two snapshots are not
+//! reported.
+use crate::kbucket::{TableDiff, TableSnapshot};
+use std::time::Duration;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::{Interval, MissedTickBehavior},
+};
+
+/// The time between snapshots of the table.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The number of batches of diffs a subscriber may leave unread before it is disconnected.
+const SUBSCRIBER_CHANNEL_SIZE: usize = 64;
+
+pub(crate) struct TableWatch {
+    /// The snapshot that the subscribers are up to date with.
+    last: Option<TableSnapshot>,
+    subscribers: Vec<mpsc::Sender<Vec<TableDiff>>>,
+    /// Paces the snapshots.
+    interval: Interval,
+}
+
+impl TableWatch {
+    pub(crate) fn new() -> Self {
+        let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        TableWatch {
+            last: None,
+            subscribers: Vec::new(),
+            interval,
+        }
+    }
+
+    /// Adds a subscriber, which is first sent every entry and pending node of `current` as added.
+    /// Existing subscribers are sent the changes up to `current`.
+    pub(crate) fn subscribe(&mut self, current: TableSnapshot) -> mpsc::Receiver<Vec<TableDiff>> {
+        self.publish(current.clone());
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_CHANNEL_SIZE);
+        let added: Vec<TableDiff> = current
+            .entries()
+            .cloned()
+            .map(TableDiff::Added)
+            .chain(
+                current
+                    .pending()
+                    .map(|(distance, pending)| TableDiff::PendingUpdated {
+                        distance,
+                        pending: Some(pending.clone()),
+                    }),
+            )
+            .collect();
+        if !added.is_empty() {
+            // The channel is empty, so this cannot fail.
+            let _ = sender.try_send(added);
+        }
+        self.subscribers.push(sender);
+        self.last = Some(current);
+        receiver
+    }
+
+    /// Sends the changes from the last snapshot to `current` to the subscribers. Subscribers
+    /// which have gone or have fallen too far behind are dropped.
+    pub(crate) fn publish(&mut self, current: TableSnapshot) {
+        let diffs = match &self.last {
+            Some(last) => last.diff(&current),
+            None => Vec::new(),
+        };
+        self.last = Some(current);
+        if !diffs.is_empty() {
+            self.subscribers
+                .retain(|subscriber| match subscriber.try_send(diffs.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        tracing::debug!("Disconnecting a lagging routing table subscriber");
+                        false
+                    }
+                    Err(TrySendError::Closed(_)) => false,
+                });
+        }
+        self.subscribers
+            .retain(|subscriber| !subscriber.is_closed());
+        if self.subscribers.is_empty() {
+            self.last = None;
+        }
+    }
+
+    /// Waits for the next snapshot to be due. Never resolves if there are no subscribers.
+    pub(crate) async fn tick(&mut self) {
+        if self.subscribers.is_empty() {
+            return futures::future::pending().await;
+        }
+        self.interval.tick().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kbucket::{BucketSnapshot, EntrySnapshot},
+        ConnectionDirection, ConnectionState, Enr,
+    };
+    use enr::{CombinedKey, NodeId};
+
+    fn entry(key: &CombinedKey, seq: u64) -> EntrySnapshot {
+        let enr = Enr::builder().seq(seq).build(key).unwrap();
+        EntrySnapshot {
+            node_id: enr.node_id(),
+            enr_seq: seq,
+            enr,
+            state: ConnectionState::Connected,
+            direction: ConnectionDirection::Incoming,
+            inserted_at_ms: Some(0),
+        }
+    }
+
+    fn snapshot(entries: Vec<EntrySnapshot>) -> TableSnapshot {
+        TableSnapshot {
+            local_node_id: NodeId::new(&[0; 32]),
+            buckets: vec![BucketSnapshot {
+                distance: 256,
+                entries,
+                pending: None,
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_diffs() {
+        let (first_key, second_key) = (
+            CombinedKey::generate_secp256k1(),
+            CombinedKey::generate_secp256k1(),
+        );
+        let mut watch = TableWatch::new();
+        let first = entry(&first_key, 1);
+        let mut early = watch.subscribe(snapshot(vec![first.clone()]));
+        assert_eq!(early.try_recv(), Ok(vec![TableDiff::Added(first.clone())]));
+
+        let (updated, second) = (entry(&first_key, 2), entry(&second_key, 1));
+        watch.publish(snapshot(vec![updated.clone(), second.clone()]));
+        assert_eq!(
+            early.try_recv(),
+            Ok(vec![
+                TableDiff::Updated(updated),
+                TableDiff::Added(second.clone())
+            ])
+        );
+
+        // Existing subscribers catch up before a new subscriber is added.
+        let mut late = watch.subscribe(snapshot(vec![second.clone()]));
+        assert_eq!(
+            early.try_recv(),
+            Ok(vec![TableDiff::Removed(first.node_id)])
+        );
+        assert_eq!(late.try_recv(), Ok(vec![TableDiff::Added(second.clone())]));
+        assert!(early.try_recv().is_err());
+
+        // A subscriber that stops reading is disconnected.
+        drop(late);
+        for i in 0..=SUBSCRIBER_CHANNEL_SIZE {
+            let entries = if i % 2 == 0 {
+                vec![]
+            } else {
+                vec![second.clone()]
+            };
+            watch.publish(snapshot(entries));
+        }
+        assert!(watch.subscribers.is_empty());
+        assert!(watch.last.is_none());
+    }
+}
@@ -17,13 +17,18 @@ use crate::{
 };
 use enr::CombinedKey;
 use parking_lot::RwLock;
-use std::{collections::HashMap, net::Ipv4Addr, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::sync::{mpsc, oneshot};
 
 /// Default UDP port number to use for tests requiring UDP exposure
 pub const DEFAULT_UDP_PORT: u16 = 0;
 
-fn _connected_state() -> NodeStatus {
+fn connected_state() -> NodeStatus {
     NodeStatus {
         state: ConnectionState::Connected,
         direction: ConnectionDirection::Outgoing,
@@ -56,15 +61,15 @@ async fn build_service<P: ProtocolIdentity>(
         .executor(Box::<crate::executor::TokioExecutor>::default())
         .build();
     // build the session service
-    let (_handler_exit, handler_send, handler_recv) =
+    let (_handler_exit, handler_exited, handler_send, handler_recv) =
         Handler::spawn::<P>(local_enr.clone(), enr_key.clone(), config.clone())
             .await
             .unwrap();
 
     let (table_filter, bucket_filter) = if filters {
         (
-            Some(Box::new(kbucket::IpTableFilter) as Box<dyn kbucket::Filter<Enr>>),
-            Some(Box::new(kbucket::IpBucketFilter) as Box<dyn kbucket::Filter<Enr>>),
+            Some(Box::new(kbucket::IpTableFilter::default()) as Box<dyn kbucket::Filter<Enr>>),
+            Some(Box::new(kbucket::IpBucketFilter::default()) as Box<dyn kbucket::Filter<Enr>>),
         )
     } else {
         (None, None)
@@ -73,6 +78,7 @@ async fn build_service<P: ProtocolIdentity>(
     let kbuckets = Arc::new(RwLock::new(KBucketsTable::new(
         local_enr.read().node_id().into(),
         Duration::from_secs(60),
+        config.bucket_size,
         config.incoming_bucket_limit,
         table_filter,
         bucket_filter,
@@ -82,20 +88,40 @@ async fn build_service<P: ProtocolIdentity>(
     let (_discv5_send, discv5_recv) = mpsc::channel(30);
     let (_exit_send, exit) = oneshot::channel();
 
+    let table_filter = Arc::new(RwLock::new(config.table_filter.clone()));
+    let enr_propagation = EnrPropagation::new(local_enr.read().seq(), config.enr_propagation_rate);
+
     Service {
         local_enr,
         enr_key,
         kbuckets,
+        table_filter,
         queries: QueryPool::new(config.query_timeout),
         active_requests: Default::default(),
         active_nodes_responses: HashMap::new(),
         ip_votes: None,
+        reachability: ReachabilityTracker::new(
+            config.enr_peer_update_min,
+            config.vote_duration,
+            config.inbound_observation_window,
+            &config.listen_config,
+        ),
+        nat_status: Default::default(),
         handler_send,
         handler_recv,
         handler_exit: Some(_handler_exit),
-        peers_to_ping: HashSetDelay::new(config.ping_interval),
+        handler_exited: Some(handler_exited),
+        revalidation: Revalidation::new(config.revalidation, config.ping_interval),
+        enr_propagation,
+        roamed_addresses: LruTimeCache::new(
+            config.session_timeout,
+            Some(config.session_cache_capacity),
+        ),
+        table_watch: TableWatch::new(),
         discv5_recv,
         event_stream: None,
+        pending_rebinds: VecDeque::new(),
+        port_mapper: None,
         exit,
         config,
         ip_mode: Default::default(),
@@ -150,6 +176,7 @@ async fn test_updating_connection_on_ping() {
     };
 
     let node_contact: NodeContact = enr2.into();
+    let node_contact_clone = node_contact.clone();
     let expected_return_addr = node_contact.node_address();
 
     service.active_requests.insert(
@@ -163,10 +190,33 @@ async fn test_updating_connection_on_ping() {
     );
 
     // Handle the ping and expect the disconnected Node to become connected
-    service.handle_rpc_response(expected_return_addr, response);
-    let buckets = service.kbuckets.read();
-    let node = buckets.iter_ref().next().unwrap();
-    assert!(node.status.is_connected())
+    service.handle_rpc_response(
+        expected_return_addr,
+        response,
+        Some(Duration::from_millis(10)),
+    );
+    {
+        let buckets = service.kbuckets.read();
+        let node = buckets.iter_ref().next().unwrap();
+        assert!(node.status.is_connected());
+        assert_eq!(1, node.node.stats.successes());
+        assert_eq!(1, node.node.stats.rtt_samples().count());
+    }
+
+    // A failed request is recorded as well
+    service.active_requests.insert(
+        RequestId(vec![2]),
+        ActiveRequest {
+            contact: node_contact_clone,
+            request_body: RequestBody::Ping { enr_seq: 2 },
+            query_id: None,
+            callback: None,
+        },
+    );
+    service.rpc_failure(RequestId(vec![2]), RequestError::Timeout);
+    let stats = service.kbuckets.read().node_stats(&key).cloned().unwrap();
+    assert_eq!(1, stats.failures());
+    assert_eq!(1, stats.successes());
 }
 
 #[tokio::test]
@@ -304,6 +354,7 @@ async fn test_handling_concurrent_responses() {
                 nodes: vec![enrs_for_response.pop().unwrap()],
             },
         },
+        None,
     );
     // Service has still two active requests since we are waiting for the second NODE response to
     // `Request1`.
@@ -321,6 +372,7 @@ async fn test_handling_concurrent_responses() {
                 nodes: vec![enrs_for_response.pop().unwrap()],
             },
         },
+        None,
     );
     // `Request2` is completed so now the number of active requests should be one.
     assert_eq!(1, service.active_requests.len());
@@ -337,7 +389,255 @@ async fn test_handling_concurrent_responses() {
                 nodes: vec![enrs_for_response.pop().unwrap()],
             },
         },
+        None,
     );
     assert!(service.active_requests.is_empty());
     assert!(service.active_nodes_responses.is_empty());
 }
+
+#[tokio::test]
+async fn test_symmetric_nat_stops_advertising_socket() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let ip: std::net::Ipv4Addr = "127.0.0.1".parse().unwrap();
+    let enr = Enr::builder()
+        .ip4(ip)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+    let mut service = build_service::<DefaultProtocolId>(
+        Arc::new(RwLock::new(enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    )
+    .await;
+    service.ip_votes = Some(IpVote::new(2, Duration::from_secs(60)));
+    service.reachability = ReachabilityTracker::new(
+        2,
+        Duration::from_secs(60),
+        Duration::ZERO,
+        &service.config.listen_config,
+    );
+
+    // Each peer observes us at a different port.
+    for (id, port) in [(1u8, 3000u16), (2, 3001), (3, 3002)] {
+        let peer_key = CombinedKey::generate_secp256k1();
+        let peer_enr = Enr::builder()
+            .ip4(ip)
+            .udp4(9000 + port)
+            .build(&peer_key)
+            .unwrap();
+        let key = kbucket::Key::from(peer_enr.node_id());
+        if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
+            assert!(matches!(
+                entry.insert(peer_enr.clone(), connected_state()),
+                BucketInsertResult::Inserted
+            ));
+        }
+
+        let node_contact: NodeContact = peer_enr.into();
+        let node_address = node_contact.node_address();
+        service.active_requests.insert(
+            RequestId(vec![id]),
+            ActiveRequest {
+                contact: node_contact,
+                request_body: RequestBody::Ping { enr_seq: 1 },
+                query_id: None,
+                callback: None,
+            },
+        );
+        let response = Response {
+            id: RequestId(vec![id]),
+            body: ResponseBody::Pong {
+                enr_seq: 1,
+                ip: "1.1.1.1".parse().unwrap(),
+                port: port.try_into().unwrap(),
+            },
+        };
+        service.handle_rpc_response(node_address, response, None);
+    }
+
+    let status = *service.nat_status.read();
+    assert_eq!(status.ipv4.nat_type, NatType::Symmetric);
+    assert_eq!(status.ipv4.inbound, InboundReachability::Unreachable);
+    assert!(service.local_enr.read().udp4().is_none());
+}
+
+#[tokio::test]
+async fn test_local_enr_changes_reach_connected_peers() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Arc::new(RwLock::new(
+        Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .udp4(DEFAULT_UDP_PORT)
+            .build(&enr_key)
+            .unwrap(),
+    ));
+    let enr_key = Arc::new(RwLock::new(enr_key));
+    let mut service =
+        build_service::<DefaultProtocolId>(local_enr.clone(), enr_key.clone(), false).await;
+
+    let peers: Vec<Enr> = (0..2)
+        .map(|_| {
+            Enr::builder()
+                .ip4(Ipv4Addr::LOCALHOST)
+                .udp4(DEFAULT_UDP_PORT)
+                .build(&CombinedKey::generate_secp256k1())
+                .unwrap()
+        })
+        .collect();
+    for (peer, status) in peers.iter().zip([connected_state(), disconnected_state()]) {
+        let key = kbucket::Key::from(peer.node_id());
+        if let kbucket::Entry::Absent(entry) = service.kbuckets.write().entry(&key) {
+            assert!(matches!(
+                entry.insert(peer.clone(), status),
+                BucketInsertResult::Inserted
+            ));
+        }
+    }
+
+    // Nothing is queued until the local ENR changes.
+    service.propagate_local_enr();
+    assert_eq!(service.enr_propagation.len(), 0);
+
+    local_enr
+        .write()
+        .set_udp4(DEFAULT_UDP_PORT + 1, &enr_key.read())
+        .unwrap();
+    service.propagate_local_enr();
+    assert_eq!(
+        service.enr_propagation.next_batch().await,
+        vec![peers[0].node_id()]
+    );
+}
+
+#[tokio::test]
+async fn test_client_only_does_not_serve() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&enr_key)
+        .unwrap();
+    let mut service = build_service::<DefaultProtocolId>(
+        Arc::new(RwLock::new(local_enr)),
+        Arc::new(RwLock::new(enr_key)),
+        false,
+    )
+    .await;
+    service.config.client_only = true;
+    let (handler_send, mut handler_recv) = mpsc::unbounded_channel();
+    service.handler_send = handler_send;
+
+    let peer = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&CombinedKey::generate_secp256k1())
+        .unwrap();
+    let outgoing_peer = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(DEFAULT_UDP_PORT)
+        .build(&CombinedKey::generate_secp256k1())
+        .unwrap();
+
+    // Only the peers we connect to are added to the table.
+    service.inject_session_established(peer.clone(), ConnectionDirection::Incoming);
+    service.inject_session_established(outgoing_peer.clone(), ConnectionDirection::Outgoing);
+    assert_eq!(
+        service
+            .kbuckets
+            .write()
+            .iter()
+            .map(|entry| *entry.node.key.preimage())
+            .collect::<Vec<_>>(),
+        vec![outgoing_peer.node_id()]
+    );
+
+    let node_address = NodeAddress::new("127.0.0.1:9000".parse().unwrap(), peer.node_id());
+    service.handle_rpc_request(
+        node_address.clone(),
+        Request {
+            id: RequestId::random(),
+            body: RequestBody::FindNode {
+                distances: vec![0, 255, 256],
+            },
+        },
+    );
+    service.handle_rpc_request(
+        node_address,
+        Request {
+            id: RequestId::random(),
+            body: RequestBody::Talk {
+                protocol: b"test".to_vec(),
+                request: b"ping".to_vec(),
+            },
+        },
+    );
+
+    let mut bodies = Vec::new();
+    while let Ok(message) = handler_recv.try_recv() {
+        if let HandlerIn::Response(_, response) = message {
+            bodies.push(response.body);
+        }
+    }
+    assert_eq!(
+        bodies,
+        vec![
+            ResponseBody::Nodes {
+                total: 1,
+                nodes: Vec::new(),
+            },
+            ResponseBody::Talk {
+                response: Vec::new(),
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_requests_follow_a_roamed_session() {
+    init();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let local_enr = Arc::new(RwLock::new(
+        Enr::builder()
+            .ip4(Ipv4Addr::LOCALHOST)
+            .udp4(DEFAULT_UDP_PORT)
+            .build(&enr_key)
+            .unwrap(),
+    ));
+    let enr_key = Arc::new(RwLock::new(enr_key));
+    let mut service =
+        build_service::<DefaultProtocolId>(local_enr.clone(), enr_key.clone(), false).await;
+
+    let peer_key = CombinedKey::generate_secp256k1();
+    let mut peer = Enr::builder()
+        .ip4(Ipv4Addr::LOCALHOST)
+        .udp4(30303)
+        .build(&peer_key)
+        .unwrap();
+    let node_id = peer.node_id();
+    let socket = |port: u16| SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let ping = |service: &mut Service, enr: &Enr| {
+        service.active_requests.clear();
+        service.send_ping(enr.clone(), None);
+        let (_, request) = service.active_requests.iter().next().unwrap();
+        request.contact.socket_addr()
+    };
+
+    service.session_roamed(node_id, socket(30303), socket(30304));
+    assert_eq!(ping(&mut service, &peer), socket(30304));
+
+    // Moving again still replaces the socket the ENR advertises.
+    service.session_roamed(node_id, socket(30304), socket(30305));
+    assert_eq!(ping(&mut service, &peer), socket(30305));
+
+    // Once the ENR advertises another socket, it is used again.
+    peer.set_udp4(30306, &peer_key).unwrap();
+    assert_eq!(ping(&mut service, &peer), socket(30306));
+
+    // Moving back to the socket of the ENR forgets the roamed address.
+    service.session_roamed(node_id, socket(30305), socket(30303));
+    assert!(service.roamed_addresses.peek(&node_id).is_none());
+}
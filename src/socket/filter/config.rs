@@ -1,6 +1,7 @@
 use super::rate_limiter::RateLimiter;
+use std::time::Duration;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FilterConfig {
     /// Whether the packet filter is enabled or not.
     pub enabled: bool,
@@ -16,3 +17,13 @@ pub struct FilterConfig {
     /// The default is 5.
     pub max_bans_per_ip: Option<usize>,
 }
+
+/// Changes to apply to the packet filter of a running socket. Parameters set to `None` are left
+/// unchanged.
+#[derive(Debug, Default)]
+pub struct FilterUpdate {
+    /// The new rate limiter for unsolicited packets.
+    pub rate_limiter: Option<Option<RateLimiter>>,
+    /// The new duration for bans enacted by the filter.
+    pub ban_duration: Option<Option<Duration>>,
+}
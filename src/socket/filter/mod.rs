@@ -1,6 +1,12 @@
 //! A filter which decides whether to accept/reject incoming UDP packets.
 
-use crate::{discv5::PERMIT_BAN_LIST, metrics::METRICS, node_info::NodeAddress, packet::Packet};
+use crate::{
+    discv5::PERMIT_BAN_LIST,
+    metrics::METRICS,
+    node_info::NodeAddress,
+    packet::{Packet, PacketKind},
+    permit_ban::Ban,
+};
 use cache::ReceivedPacketCache;
 use enr::NodeId;
 use lru::LruCache;
@@ -16,7 +22,7 @@ use tracing::{debug, warn};
 mod cache;
 mod config;
 pub mod rate_limiter;
-pub use config::FilterConfig;
+pub use config::{FilterConfig, FilterUpdate};
 use rate_limiter::{LimitKind, RateLimiter};
 
 /// The maximum number of IPs to retain when calculating the number of nodes per IP.
@@ -83,14 +89,24 @@ impl Filter {
         }
     }
 
+    /// Applies changes to the filter parameters. A new rate limiter starts with fresh quotas.
+    pub fn update(&mut self, update: FilterUpdate) {
+        if let Some(rate_limiter) = update.rate_limiter {
+            self.rate_limiter = rate_limiter;
+        }
+        if let Some(ban_duration) = update.ban_duration {
+            self.ban_duration = ban_duration;
+        }
+    }
+
     /// The first check. This determines if a new UDP packet should be decoded or dropped.
     /// Only unsolicited packets arrive here.
     pub fn initial_pass(&mut self, src: &SocketAddr) -> bool {
-        if PERMIT_BAN_LIST.read().permit_ips.get(&src.ip()).is_some() {
+        if PERMIT_BAN_LIST.read().is_ip_permitted(&src.ip()) {
             return true;
         }
 
-        if PERMIT_BAN_LIST.read().ban_ips.get(&src.ip()).is_some() {
+        if PERMIT_BAN_LIST.read().is_ip_banned(&src.ip()) {
             debug!("Dropped unsolicited packet from banned src: {:?}", src);
             return false;
         }
@@ -116,10 +132,18 @@ impl Filter {
                 warn!("Banning IP for excessive requests: {:?}", src.ip());
                 // Ban the IP address
                 let ban_timeout = self.ban_duration.map(|v| Instant::now() + v);
-                PERMIT_BAN_LIST
-                    .write()
-                    .ban_ips
-                    .insert(src.ip(), ban_timeout);
+                PERMIT_BAN_LIST.write().ban_ips.insert(
+                    src.ip().into(),
+                    Ban::by_filter(ban_timeout, "exceeded the request limit per IP"),
+                );
+                return false;
+            }
+
+            if rate_limiter.allows(&LimitKind::Subnet(src.ip())).is_err() {
+                debug!(
+                    "Dropped unsolicited packet from subnet limit: {:?}",
+                    src.ip()
+                );
                 return false;
             }
 
@@ -131,7 +155,7 @@ impl Filter {
         true
     }
 
-    pub fn final_pass(&mut self, node_address: &NodeAddress, _packet: &Packet) -> bool {
+    pub fn final_pass(&mut self, node_address: &NodeAddress, packet: &Packet) -> bool {
         if PERMIT_BAN_LIST
             .read()
             .permit_nodes
@@ -171,10 +195,10 @@ impl Filter {
 
                 // The node is being banned
                 let ban_timeout = self.ban_duration.map(|v| Instant::now() + v);
-                PERMIT_BAN_LIST
-                    .write()
-                    .ban_nodes
-                    .insert(node_address.node_id, ban_timeout);
+                PERMIT_BAN_LIST.write().ban_nodes.insert(
+                    node_address.node_id,
+                    Ban::by_filter(ban_timeout, "exceeded the request limit per node"),
+                );
 
                 // If we are tracking banned nodes per IP, add to the count. If the count is higher
                 // than our tolerance, ban the IP.
@@ -183,7 +207,10 @@ impl Filter {
                     if let Some(banned_count) = self.banned_nodes.get_mut(&ip) {
                         *banned_count += 1;
                         if *banned_count >= max_bans_per_ip {
-                            PERMIT_BAN_LIST.write().ban_ips.insert(ip, ban_timeout);
+                            PERMIT_BAN_LIST.write().ban_ips.insert(
+                                ip.into(),
+                                Ban::by_filter(ban_timeout, "exceeded the banned nodes per IP"),
+                            );
                         }
                     } else {
                         self.banned_nodes.put(ip, 0);
@@ -192,6 +219,16 @@ impl Filter {
 
                 return false;
             }
+
+            // Handshakes are costly to process, so they are limited separately.
+            if matches!(packet.header.kind, PacketKind::Handshake { .. })
+                && rate_limiter
+                    .allows(&LimitKind::Handshake(node_address.socket_addr.ip()))
+                    .is_err()
+            {
+                debug!("Dropped handshake from handshake limit: {}", node_address);
+                return false;
+            }
         }
 
         // Check the nodes per IP filter configuration
@@ -214,7 +251,10 @@ impl Filter {
                 warn!("IP has exceeded its node-id limit and is now banned {}", ip);
                 // The node is being banned
                 let ban_timeout = self.ban_duration.map(|v| Instant::now() + v);
-                PERMIT_BAN_LIST.write().ban_ips.insert(ip, ban_timeout);
+                PERMIT_BAN_LIST.write().ban_ips.insert(
+                    ip.into(),
+                    Ban::by_filter(ban_timeout, "exceeded the node ids per IP"),
+                );
                 self.known_addrs.pop(&ip);
                 return false;
             }
@@ -7,6 +7,11 @@ use std::{
     time::{Duration, Instant},
 };
 
+/// The default prefix length of the IPv4 subnets limited by the subnet quota.
+const DEFAULT_IPV4_SUBNET_PREFIX: u8 = 24;
+/// The default prefix length of the IPv6 subnets limited by the subnet quota.
+const DEFAULT_IPV6_SUBNET_PREFIX: u8 = 48;
+
 /// Nanoseconds since a given time.
 // Maintained as u64 to reduce footprint
 // NOTE: this also implies that the rate limiter will manage checking if a batch is allowed for at
@@ -53,6 +58,21 @@ pub struct Quota {
     max_tokens: u64,
 }
 
+impl Quota {
+    /// Allows `max_tokens` tokens every `replenish_all_every`.
+    pub(crate) fn n_every(max_tokens: u64, replenish_all_every: Duration) -> Self {
+        Quota {
+            replenish_all_every,
+            max_tokens,
+        }
+    }
+
+    /// The token limit.
+    pub(crate) fn max_tokens(&self) -> u64 {
+        self.max_tokens
+    }
+}
+
 /// Manages rate limiting of requests per peer, with differentiated rates per protocol.
 #[derive(Debug, Clone)]
 pub struct RateLimiter {
@@ -67,6 +87,20 @@ pub struct RateLimiter {
     node_rl: Option<Limiter<NodeId>>,
     /// Rate limit for each ip.
     ip_rl: Option<Limiter<IpAddr>>,
+    /// Rate limit for each subnet.
+    subnet_rl: Option<Limiter<IpAddr>>,
+    /// The prefix lengths of the IPv4 and IPv6 subnets.
+    subnet_prefixes: (u8, u8),
+    /// Rate limit of handshakes for each ip.
+    handshake_rl: Option<Limiter<IpAddr>>,
+    /// Rate limit of FINDNODE distances for each node.
+    findnode_rl: Option<Limiter<NodeId>>,
+    /// Rate limit of PINGs for each node.
+    ping_rl: Option<Limiter<NodeId>>,
+    /// Rate limit of TALKREQs of any protocol for each node.
+    talk_rl: Option<Limiter<NodeId>>,
+    /// Rate limits of TALKREQs of a specific protocol for each node.
+    talk_protocol_rl: FnvHashMap<Vec<u8>, Limiter<NodeId>>,
 }
 
 /// Error type for non conformant requests
@@ -84,13 +118,34 @@ pub enum LimitKind {
     NodeId(NodeId),
     /// Request counts toward the ip limit.
     Ip(IpAddr),
+    /// Request counts toward the limit of the subnet of the ip.
+    Subnet(IpAddr),
+    /// A handshake counts toward the handshake limit of the ip.
+    Handshake(IpAddr),
+    /// A FINDNODE request counts toward the FINDNODE limit of the node, one token per requested
+    /// distance.
+    FindNode(NodeId, usize),
+    /// A PING request counts toward the PING limit of the node.
+    Ping(NodeId),
+    /// A TALKREQ counts toward the TALKREQ limit of the node and the limit of its protocol.
+    Talk(NodeId, Vec<u8>),
 }
 
-/// User-friendly builder of a `RateLimiter`. The user can specify three kinds of rate limits but
-/// must at least set the total quota. The three types are:
+/// User-friendly builder of a `RateLimiter`. The user can specify several kinds of rate limits but
+/// must at least set the total quota. The kinds are:
 /// 1. Total Quota - Specifies the total number of inbound requests. This must be set.
 /// 2. Node Quota - Specifies the number of requests per node id.
 /// 3. IP Quota - Specifies the number of requests per IP.
+/// 4. Subnet Quota - Specifies the number of requests per subnet. The prefix lengths of the
+///    subnets default to /24 for IPv4 and /48 for IPv6.
+///
+/// Decrypted requests are additionally charged by their type and cost:
+/// 1. Handshake Quota - Specifies the number of handshakes per IP.
+/// 2. FINDNODE Quota - Specifies the number of distances requested per node id. A request
+///    costs one token per distance, so the burst must allow the largest expected request.
+/// 3. PING Quota - Specifies the number of PINGs per node id.
+/// 4. TALK Quotas - Specify the number of TALKREQs per node id, for all protocols and for
+///    individual protocols.
 ///
 /// Quotas can be set via the X_one_every() functions to set hard limits as described above. Using
 /// the `X_n_every()` functions allow for bursts.
@@ -102,6 +157,22 @@ pub struct RateLimiterBuilder {
     node_quota: Option<Quota>,
     /// Quota for each IP.
     ip_quota: Option<Quota>,
+    /// Quota for each subnet.
+    subnet_quota: Option<Quota>,
+    /// The prefix length of the IPv4 subnets.
+    ipv4_subnet_prefix: Option<u8>,
+    /// The prefix length of the IPv6 subnets.
+    ipv6_subnet_prefix: Option<u8>,
+    /// Quota of handshakes for each IP.
+    handshake_quota: Option<Quota>,
+    /// Quota of FINDNODE distances for each node-id.
+    findnode_quota: Option<Quota>,
+    /// Quota of PINGs for each node-id.
+    ping_quota: Option<Quota>,
+    /// Quota of TALKREQs for each node-id.
+    talk_quota: Option<Quota>,
+    /// Quotas of TALKREQs per protocol for each node-id.
+    talk_protocol_quotas: Vec<(Vec<u8>, Quota)>,
 }
 
 #[allow(dead_code)]
@@ -180,6 +251,63 @@ impl RateLimiterBuilder {
         })
     }
 
+    /// Allow `n` tokens to be used every `time_period` for each subnet.
+    pub fn subnet_n_every(mut self, n: u64, time_period: Duration) -> Self {
+        self.subnet_quota = Some(Quota::n_every(n, time_period));
+        self
+    }
+
+    /// Sets the prefix length of the IPv4 subnets limited by the subnet quota.
+    pub fn ipv4_subnet_prefix(mut self, prefix_len: u8) -> Self {
+        self.ipv4_subnet_prefix = Some(prefix_len);
+        self
+    }
+
+    /// Sets the prefix length of the IPv6 subnets limited by the subnet quota.
+    pub fn ipv6_subnet_prefix(mut self, prefix_len: u8) -> Self {
+        self.ipv6_subnet_prefix = Some(prefix_len);
+        self
+    }
+
+    /// Allow `n` handshakes every `time_period` for each IP.
+    pub fn handshake_n_every(mut self, n: u64, time_period: Duration) -> Self {
+        self.handshake_quota = Some(Quota::n_every(n, time_period));
+        self
+    }
+
+    /// Allow `n` FINDNODE distances to be requested every `time_period` for each node id.
+    pub fn findnode_n_every(mut self, n: u64, time_period: Duration) -> Self {
+        self.findnode_quota = Some(Quota::n_every(n, time_period));
+        self
+    }
+
+    /// Allow `n` PINGs every `time_period` for each node id.
+    pub fn ping_n_every(mut self, n: u64, time_period: Duration) -> Self {
+        self.ping_quota = Some(Quota::n_every(n, time_period));
+        self
+    }
+
+    /// Allow `n` TALKREQs of any protocol every `time_period` for each node id.
+    pub fn talk_n_every(mut self, n: u64, time_period: Duration) -> Self {
+        self.talk_quota = Some(Quota::n_every(n, time_period));
+        self
+    }
+
+    /// Allow `n` TALKREQs of `protocol` every `time_period` for each node id. These count
+    /// towards the quota of all protocols as well.
+    pub fn talk_protocol_n_every(
+        mut self,
+        protocol: impl Into<Vec<u8>>,
+        n: u64,
+        time_period: Duration,
+    ) -> Self {
+        let protocol = protocol.into();
+        self.talk_protocol_quotas.retain(|(p, _)| *p != protocol);
+        self.talk_protocol_quotas
+            .push((protocol, Quota::n_every(n, time_period)));
+        self
+    }
+
     pub fn build(self) -> Result<RateLimiter, &'static str> {
         // get our quotas
         let total_quota = self
@@ -196,6 +324,24 @@ impl RateLimiterBuilder {
             Some(q) => Some(Limiter::from_quota(q)?),
             None => None,
         };
+        let subnet_prefixes = (
+            self.ipv4_subnet_prefix
+                .unwrap_or(DEFAULT_IPV4_SUBNET_PREFIX),
+            self.ipv6_subnet_prefix
+                .unwrap_or(DEFAULT_IPV6_SUBNET_PREFIX),
+        );
+        if subnet_prefixes.0 > 32 || subnet_prefixes.1 > 128 {
+            return Err("Subnet prefix lengths cannot exceed the length of an address");
+        }
+        let subnet_rl = self.subnet_quota.map(Limiter::from_quota).transpose()?;
+        let handshake_rl = self.handshake_quota.map(Limiter::from_quota).transpose()?;
+        let findnode_rl = self.findnode_quota.map(Limiter::from_quota).transpose()?;
+        let ping_rl = self.ping_quota.map(Limiter::from_quota).transpose()?;
+        let talk_rl = self.talk_quota.map(Limiter::from_quota).transpose()?;
+        let mut talk_protocol_rl = FnvHashMap::default();
+        for (protocol, quota) in self.talk_protocol_quotas {
+            talk_protocol_rl.insert(protocol, Limiter::from_quota(quota)?);
+        }
 
         let total_requests_per_second = if total_quota.max_tokens == 1 {
             (1.0 / total_quota.replenish_all_every.as_secs_f32()
@@ -213,6 +359,13 @@ impl RateLimiterBuilder {
             total_rl,
             node_rl,
             ip_rl,
+            subnet_rl,
+            subnet_prefixes,
+            handshake_rl,
+            findnode_rl,
+            ping_rl,
+            talk_rl,
+            talk_protocol_rl,
             init_time: Instant::now(),
         })
     }
@@ -222,28 +375,52 @@ impl RateLimiter {
     /// Indicates whether the request is allowed based on the configured rate limits.
     pub fn allows(&mut self, request: &LimitKind) -> Result<(), RateLimitedErr> {
         let time_since_start = self.init_time.elapsed();
-        let tokens = 1; // Only count each of these as one.
 
         // Check the limits
         match request {
-            LimitKind::Total => self.total_rl.allows(time_since_start, &(), tokens),
-            LimitKind::Ip(ip_addr) => {
-                if let Some(limiter) = self.ip_rl.as_mut() {
-                    limiter.allows(time_since_start, ip_addr, tokens)
-                } else {
-                    Ok(())
-                }
-            }
+            LimitKind::Total => self.total_rl.allows(time_since_start, &(), 1),
+            LimitKind::Ip(ip_addr) => allows(self.ip_rl.as_mut(), time_since_start, ip_addr, 1),
             LimitKind::NodeId(node_id) => {
-                if let Some(limiter) = self.node_rl.as_mut() {
-                    limiter.allows(time_since_start, node_id, tokens)
-                } else {
-                    Ok(())
+                allows(self.node_rl.as_mut(), time_since_start, node_id, 1)
+            }
+            LimitKind::Subnet(ip_addr) => {
+                let subnet = subnet(ip_addr, self.subnet_prefixes);
+                allows(self.subnet_rl.as_mut(), time_since_start, &subnet, 1)
+            }
+            LimitKind::Handshake(ip_addr) => {
+                allows(self.handshake_rl.as_mut(), time_since_start, ip_addr, 1)
+            }
+            LimitKind::FindNode(node_id, distances) => allows(
+                self.findnode_rl.as_mut(),
+                time_since_start,
+                node_id,
+                (*distances).max(1) as u64,
+            ),
+            LimitKind::Ping(node_id) => allows(self.ping_rl.as_mut(), time_since_start, node_id, 1),
+            LimitKind::Talk(node_id, protocol) => {
+                // Only consume tokens if both limits allow the request.
+                if let Some(limiter) = self.talk_rl.as_ref() {
+                    limiter.check(time_since_start, node_id, 1)?;
                 }
+                allows(
+                    self.talk_protocol_rl.get_mut(protocol),
+                    time_since_start,
+                    node_id,
+                    1,
+                )?;
+                allows(self.talk_rl.as_mut(), time_since_start, node_id, 1)
             }
         }
     }
 
+    /// Whether any quota for decrypted requests is set.
+    pub(crate) fn limits_requests(&self) -> bool {
+        self.findnode_rl.is_some()
+            || self.ping_rl.is_some()
+            || self.talk_rl.is_some()
+            || !self.talk_protocol_rl.is_empty()
+    }
+
     /// Returns the expected total requests per second.
     pub fn total_requests_per_second(&self) -> f32 {
         self.total_requests_per_second
@@ -259,6 +436,52 @@ impl RateLimiter {
         if let Some(v) = self.node_rl.as_mut() {
             v.prune(time_since_start)
         };
+        if let Some(v) = self.subnet_rl.as_mut() {
+            v.prune(time_since_start)
+        };
+        if let Some(v) = self.handshake_rl.as_mut() {
+            v.prune(time_since_start)
+        };
+        let request_limiters = self
+            .findnode_rl
+            .iter_mut()
+            .chain(self.ping_rl.iter_mut())
+            .chain(self.talk_rl.iter_mut())
+            .chain(self.talk_protocol_rl.values_mut());
+        for v in request_limiters {
+            v.prune(time_since_start)
+        }
+    }
+}
+
+/// Checks a request against an optional limiter, allowing it if the limit is not set.
+fn allows<Key: Hash + Eq + Clone>(
+    limiter: Option<&mut Limiter<Key>>,
+    time_since_start: Duration,
+    key: &Key,
+    tokens: u64,
+) -> Result<(), RateLimitedErr> {
+    match limiter {
+        Some(limiter) => limiter.allows(time_since_start, key, tokens),
+        None => Ok(()),
+    }
+}
+
+/// The subnet of an IP, given the IPv4 and IPv6 prefix lengths.
+fn subnet(ip: &IpAddr, (ipv4_prefix, ipv6_prefix): (u8, u8)) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX
+                .checked_shl(32u32.saturating_sub(ipv4_prefix.into()))
+                .unwrap_or(0);
+            IpAddr::V4((u32::from(*ip) & mask).into())
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX
+                .checked_shl(128u32.saturating_sub(ipv6_prefix.into()))
+                .unwrap_or(0);
+            IpAddr::V6((u128::from(*ip) & mask).into())
+        }
     }
 }
 
@@ -301,12 +524,32 @@ impl<Key: Hash + Eq + Clone> Limiter<Key> {
         time_since_start: Duration,
         key: &Key,
         tokens: u64,
+    ) -> Result<(), RateLimitedErr> {
+        self.check(time_since_start, key, tokens)?;
+        let time_since_start = time_since_start.as_nanos() as u64;
+        // If the key is new, we consider their bucket full (which means, their request will be
+        // allowed)
+        let tat = self
+            .tat_per_key
+            .entry(key.clone())
+            .or_insert(time_since_start);
+        // calculate the new TAT
+        *tat = time_since_start.max(*tat) + self.t * tokens;
+        Ok(())
+    }
+
+    /// Indicates whether the request would be allowed, without consuming any tokens.
+    pub fn check(
+        &self,
+        time_since_start: Duration,
+        key: &Key,
+        tokens: u64,
     ) -> Result<(), RateLimitedErr> {
         let time_since_start = time_since_start.as_nanos() as u64;
         let tau = self.tau;
         let t = self.t;
         // how long does it take to replenish these tokens
-        let additional_time = t * tokens;
+        let additional_time = t.saturating_mul(tokens);
         if additional_time > tau {
             // the time required to process this amount of tokens is longer than the time that
             // makes the bucket full. So, this batch can _never_ be processed
@@ -316,10 +559,11 @@ impl<Key: Hash + Eq + Clone> Limiter<Key> {
         // allowed)
         let tat = self
             .tat_per_key
-            .entry(key.clone())
-            .or_insert(time_since_start);
+            .get(key)
+            .copied()
+            .unwrap_or(time_since_start);
         // check how soon could the request be made
-        let earliest_time = (*tat + additional_time).saturating_sub(tau);
+        let earliest_time = (tat + additional_time).saturating_sub(tau);
         // earliest_time is in the future
         if time_since_start < earliest_time {
             Err(RateLimitedErr::TooSoon(Duration::from_nanos(
@@ -327,8 +571,6 @@ impl<Key: Hash + Eq + Clone> Limiter<Key> {
                 earliest_time - time_since_start,
             )))
         } else {
-            // calculate the new TAT
-            *tat = time_since_start.max(*tat) + additional_time;
             Ok(())
         }
     }
@@ -343,8 +585,68 @@ impl<Key: Hash + Eq + Clone> Limiter<Key> {
 
 #[cfg(test)]
 mod tests {
-    use super::{Limiter, Quota};
-    use std::time::Duration;
+    use super::{LimitKind, Limiter, Quota, RateLimiterBuilder};
+    use enr::NodeId;
+    use std::{net::IpAddr, time::Duration};
+
+    #[test]
+    fn requests_are_charged_by_type() {
+        let mut limiter = RateLimiterBuilder::new()
+            .total_n_every(100, Duration::from_secs(60))
+            .findnode_n_every(4, Duration::from_secs(60))
+            .ping_n_every(1, Duration::from_secs(60))
+            .talk_n_every(3, Duration::from_secs(60))
+            .talk_protocol_n_every("bulk", 1, Duration::from_secs(60))
+            .build()
+            .unwrap();
+        assert!(limiter.limits_requests());
+        let node_id = NodeId::random();
+
+        // FINDNODE costs one token per distance.
+        assert!(limiter.allows(&LimitKind::FindNode(node_id, 3)).is_ok());
+        assert!(limiter.allows(&LimitKind::FindNode(node_id, 3)).is_err());
+        assert!(limiter.allows(&LimitKind::FindNode(node_id, 1)).is_ok());
+
+        assert!(limiter.allows(&LimitKind::Ping(node_id)).is_ok());
+        assert!(limiter.allows(&LimitKind::Ping(node_id)).is_err());
+        assert!(limiter.allows(&LimitKind::Ping(NodeId::random())).is_ok());
+
+        // A protocol quota applies on top of the quota of all protocols.
+        let bulk = |node_id| LimitKind::Talk(node_id, b"bulk".to_vec());
+        let other = |node_id| LimitKind::Talk(node_id, b"other".to_vec());
+        assert!(limiter.allows(&bulk(node_id)).is_ok());
+        assert!(limiter.allows(&bulk(node_id)).is_err());
+        assert!(limiter.allows(&other(node_id)).is_ok());
+        assert!(limiter.allows(&other(node_id)).is_ok());
+        assert!(limiter.allows(&other(node_id)).is_err());
+    }
+
+    #[test]
+    fn subnets_share_a_quota() {
+        let mut limiter = RateLimiterBuilder::new()
+            .total_n_every(100, Duration::from_secs(60))
+            .subnet_n_every(2, Duration::from_secs(60))
+            .ipv4_subnet_prefix(24)
+            .ipv6_subnet_prefix(48)
+            .build()
+            .unwrap();
+        assert!(!limiter.limits_requests());
+        let ip = |ip: &str| LimitKind::Subnet(ip.parse::<IpAddr>().unwrap());
+
+        assert!(limiter.allows(&ip("10.0.0.1")).is_ok());
+        assert!(limiter.allows(&ip("10.0.0.2")).is_ok());
+        assert!(limiter.allows(&ip("10.0.0.3")).is_err());
+        assert!(limiter.allows(&ip("10.0.1.1")).is_ok());
+        assert!(limiter.allows(&ip("2001:db8::1")).is_ok());
+        assert!(limiter.allows(&ip("2001:db8:0:1::1")).is_ok());
+        assert!(limiter.allows(&ip("2001:db8:0:2::1")).is_err());
+
+        assert!(RateLimiterBuilder::new()
+            .total_n_every(100, Duration::from_secs(60))
+            .ipv4_subnet_prefix(33)
+            .build()
+            .is_err());
+    }
 
     #[test]
     fn it_works_a() {
@@ -2,6 +2,7 @@ use crate::{packet::ProtocolIdentity, Executor};
 use parking_lot::RwLock;
 use recv::*;
 use send::*;
+use smallvec::SmallVec;
 use socket2::{Domain, Protocol, Socket as Socket2, Type};
 use std::{
     collections::HashMap,
@@ -16,20 +17,23 @@ use tokio::{
 };
 
 mod filter;
+mod outbound_limiter;
 mod recv;
 mod send;
 
+pub(crate) use filter::rate_limiter::LimitKind;
 pub use filter::{
     rate_limiter::{RateLimiter, RateLimiterBuilder},
-    FilterConfig,
+    FilterConfig, FilterUpdate,
 };
+pub use outbound_limiter::{OutboundRateLimiter, OutboundRateLimiterBuilder};
 pub use recv::InboundPacket;
-pub use send::OutboundPacket;
+pub use send::{OutboundPacket, SendPriority};
 
 /// Configuration for the sockets to listen on.
 ///
 /// Default implementation is the UNSPECIFIED ipv4 address with port 9000.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ListenConfig {
     Ipv4 {
         ip: Ipv4Addr,
@@ -45,9 +49,21 @@ pub enum ListenConfig {
         ipv6: Ipv6Addr,
         ipv6_port: u16,
     },
+    /// Listens on each of the given sockets, which can be any number of IPv4 and IPv6 sockets.
+    /// Responses are sent from the socket the request was received on.
+    Multi {
+        sockets: Vec<SocketAddr>,
+        /// The IPv4 socket advertised in the local ENR, which must be one of `sockets`. If
+        /// `None`, the first IPv4 socket is advertised.
+        enr_ipv4: Option<SocketAddrV4>,
+        /// The IPv6 socket advertised in the local ENR, which must be one of `sockets`. If
+        /// `None`, the first IPv6 socket is advertised.
+        enr_ipv6: Option<SocketAddrV6>,
+    },
 }
 
 /// Convenience objects for setting up the recv handler.
+#[derive(Clone)]
 pub struct SocketConfig {
     /// The executor to spawn the tasks.
     pub executor: Box<dyn Executor + Send + Sync>,
@@ -61,14 +77,25 @@ pub struct SocketConfig {
     pub expected_responses: Arc<RwLock<HashMap<SocketAddr, usize>>>,
     /// The local node id used to decrypt messages.
     pub local_node_id: enr::NodeId,
+    /// Limits the rate of outbound packets, if set.
+    pub outbound_rate_limiter: Option<OutboundRateLimiter>,
+    /// Packets held back by the outbound rate limiter for longer than the request timeout are
+    /// dropped.
+    pub request_timeout: Duration,
 }
 
 /// Creates the UDP socket and handles the exit futures for the send/recv UDP handlers.
 pub struct Socket {
     pub send: mpsc::Sender<OutboundPacket>,
     pub recv: mpsc::Receiver<InboundPacket>,
-    sender_exit: Option<oneshot::Sender<()>>,
+    /// Applies changes to the packet filter of the recv task.
+    pub filter_update: mpsc::UnboundedSender<FilterUpdate>,
+    /// The bound UDP sockets and their local addresses.
+    sockets: SmallVec<[(SocketAddr, Arc<UdpSocket>); 2]>,
+    sender_exit: Option<oneshot::Sender<bool>>,
     recv_exit: Option<oneshot::Sender<()>>,
+    /// Resolve once the send/recv tasks have exited and released their sockets.
+    exited: SmallVec<[oneshot::Receiver<()>; 2]>,
 }
 
 impl Socket {
@@ -87,10 +114,40 @@ impl Socket {
         }
     }
 
+    /// Binds a new UDP socket, unless one of the `existing` sockets is already bound to the
+    /// requested address.
+    async fn bind_or_reuse(
+        socket_addr: SocketAddr,
+        existing: &[(SocketAddr, Arc<UdpSocket>)],
+    ) -> Result<Arc<UdpSocket>, Error> {
+        if let Some((_, socket)) = existing.iter().find(|(addr, _)| *addr == socket_addr) {
+            return Ok(socket.clone());
+        }
+        Ok(Arc::new(Socket::new_socket(&socket_addr).await?))
+    }
+
     /// Creates a UDP socket, spawns a send/recv task and returns the channels.
     /// If this struct is dropped, the send/recv tasks will shutdown.
     /// This needs to be run inside of a tokio executor.
     pub(crate) async fn new<P: ProtocolIdentity>(config: SocketConfig) -> Result<Self, Error> {
+        Socket::new_with_existing::<P>(config, &[]).await
+    }
+
+    /// Creates new send/recv tasks for the given configuration. Sockets of `self` that are bound to
+    /// a requested address are shared with the new tasks, so that an unchanged address does not
+    /// need to be released before it can be bound again. The current tasks shutdown once `self`
+    /// is dropped.
+    pub(crate) async fn rebind<P: ProtocolIdentity>(
+        &self,
+        config: SocketConfig,
+    ) -> Result<Self, Error> {
+        Socket::new_with_existing::<P>(config, &self.sockets).await
+    }
+
+    async fn new_with_existing<P: ProtocolIdentity>(
+        config: SocketConfig,
+        existing: &[(SocketAddr, Arc<UdpSocket>)],
+    ) -> Result<Self, Error> {
         let SocketConfig {
             executor,
             filter_config,
@@ -98,65 +155,136 @@ impl Socket {
             ban_duration,
             expected_responses,
             local_node_id,
+            outbound_rate_limiter,
+            request_timeout,
         } = config;
 
-        // For recv socket, intentionally forgetting which socket is the ipv4 and which is the ipv6 one.
-        let (first_recv, second_recv, send_ipv4, send_ipv6): (
-            Arc<UdpSocket>,
-            Option<_>,
-            Option<_>,
-            Option<_>,
-        ) = match listen_config {
-            ListenConfig::Ipv4 { ip, port } => {
-                let ipv4_socket = Arc::new(Socket::new_socket(&(ip, port).into()).await?);
-                (ipv4_socket.clone(), None, Some(ipv4_socket), None)
-            }
-            ListenConfig::Ipv6 { ip, port } => {
-                let ipv6_socket = Arc::new(Socket::new_socket(&(ip, port).into()).await?);
-                (ipv6_socket.clone(), None, None, Some(ipv6_socket))
-            }
-            ListenConfig::DualStack {
-                ipv4,
-                ipv4_port,
-                ipv6,
-                ipv6_port,
-            } => {
-                let ipv4_socket = Arc::new(Socket::new_socket(&(ipv4, ipv4_port).into()).await?);
-                let ipv6_socket = Arc::new(Socket::new_socket(&(ipv6, ipv6_port).into()).await?);
-                (
-                    ipv4_socket.clone(),
-                    Some(ipv6_socket.clone()),
-                    Some(ipv4_socket),
-                    Some(ipv6_socket),
-                )
+        let listen_sockets = listen_config.sockets();
+        if listen_sockets.is_empty() {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "At least one listen socket must be given",
+            ));
+        }
+        if let ListenConfig::Multi {
+            enr_ipv4, enr_ipv6, ..
+        } = &listen_config
+        {
+            let advertised = enr_ipv4
+                .map(SocketAddr::V4)
+                .into_iter()
+                .chain(enr_ipv6.map(SocketAddr::V6));
+            if advertised
+                .into_iter()
+                .any(|socket| !listen_sockets.contains(&socket))
+            {
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "The sockets advertised in the ENR must be listened on",
+                ));
             }
-        };
+        }
+
+        let mut sockets = SmallVec::new();
+        for socket_addr in listen_sockets {
+            let socket = Socket::bind_or_reuse(socket_addr, existing).await?;
+            sockets.push((socket.local_addr()?, socket));
+        }
 
         // spawn the recv handler
         let recv_config = RecvHandlerConfig {
             filter_config,
             executor: executor.clone(),
-            recv: first_recv,
-            second_recv,
+            sockets: sockets.to_vec(),
             local_node_id,
             expected_responses,
             ban_duration,
         };
 
-        let (recv, recv_exit) = RecvHandler::spawn::<P>(recv_config);
+        let (recv, filter_update, recv_exit, recv_exited) = RecvHandler::spawn::<P>(recv_config);
         // spawn the sender handler
-        let (send, sender_exit) = SendHandler::spawn::<P>(executor, send_ipv4, send_ipv6);
+        let (send, sender_exit, sender_exited) = SendHandler::spawn::<P>(
+            executor,
+            sockets.to_vec(),
+            outbound_rate_limiter,
+            request_timeout,
+        );
 
         Ok(Socket {
             send,
             recv,
+            filter_update,
+            sockets,
             sender_exit: Some(sender_exit),
             recv_exit: Some(recv_exit),
+            exited: smallvec::smallvec![sender_exited, recv_exited],
         })
     }
+
+    /// Shuts down the send/recv tasks and waits for them to exit. If `flush` is set, packets
+    /// already queued for sending are sent first. Once this returns, the UDP sockets are only
+    /// held by `self`.
+    pub(crate) async fn shutdown(&mut self, flush: bool) {
+        if let Some(exit) = self.sender_exit.take() {
+            let _ = exit.send(flush);
+        }
+        if let Some(exit) = self.recv_exit.take() {
+            let _ = exit.send(());
+        }
+        for exited in self.exited.drain(..) {
+            let _ = exited.await;
+        }
+    }
 }
 
 impl ListenConfig {
+    /// All sockets of this configuration.
+    pub fn sockets(&self) -> Vec<SocketAddr> {
+        match self {
+            ListenConfig::Ipv4 { ip, port } => vec![(*ip, *port).into()],
+            ListenConfig::Ipv6 { ip, port } => vec![(*ip, *port).into()],
+            ListenConfig::DualStack {
+                ipv4,
+                ipv4_port,
+                ipv6,
+                ipv6_port,
+            } => vec![(*ipv4, *ipv4_port).into(), (*ipv6, *ipv6_port).into()],
+            ListenConfig::Multi { sockets, .. } => sockets.clone(),
+        }
+    }
+
+    /// The IPv4 socket of this configuration that is advertised in the local ENR, if any. For a
+    /// [`ListenConfig::Multi`] configuration this is `enr_ipv4`, or else the first IPv4 socket.
+    pub fn ipv4(&self) -> Option<SocketAddrV4> {
+        if let ListenConfig::Multi {
+            enr_ipv4: Some(socket),
+            ..
+        } = self
+        {
+            return Some(*socket);
+        }
+        self.sockets().into_iter().find_map(|socket| match socket {
+            SocketAddr::V4(socket) => Some(socket),
+            SocketAddr::V6(_) => None,
+        })
+    }
+
+    /// The IPv6 socket of this configuration that is advertised in the local ENR, if any. For a
+    /// [`ListenConfig::Multi`] configuration this is `enr_ipv6`, or else the first IPv6 socket.
+    pub fn ipv6(&self) -> Option<SocketAddrV6> {
+        if let ListenConfig::Multi {
+            enr_ipv6: Some(socket),
+            ..
+        } = self
+        {
+            return Some(*socket);
+        }
+        self.sockets().into_iter().find_map(|socket| match socket {
+            SocketAddr::V6(socket) => Some(socket),
+            SocketAddr::V4(_) => None,
+        })
+    }
+
     /// If an [`IpAddr`] is known, a ListenConfig can be created based on the version. This will
     /// not create a dual stack configuration.
     pub fn from_ip(ip: IpAddr, port: u16) -> ListenConfig {
@@ -215,6 +343,22 @@ impl ListenConfig {
                 ipv6,
                 ipv6_port,
             },
+            ListenConfig::Multi {
+                sockets,
+                enr_ipv4,
+                enr_ipv6,
+            } => {
+                let new_socket = SocketAddrV4::new(ip, port);
+                ListenConfig::Multi {
+                    sockets: replace_socket(
+                        sockets,
+                        enr_ipv4.map(SocketAddr::V4),
+                        new_socket.into(),
+                    ),
+                    enr_ipv4: enr_ipv4.map(|_| new_socket),
+                    enr_ipv6,
+                }
+            }
         }
     }
 
@@ -241,10 +385,43 @@ impl ListenConfig {
                 ipv6: ip,
                 ipv6_port: port,
             },
+            ListenConfig::Multi {
+                sockets,
+                enr_ipv4,
+                enr_ipv6,
+            } => {
+                let new_socket = SocketAddrV6::new(ip, port, 0, 0);
+                ListenConfig::Multi {
+                    sockets: replace_socket(
+                        sockets,
+                        enr_ipv6.map(SocketAddr::V6),
+                        new_socket.into(),
+                    ),
+                    enr_ipv4,
+                    enr_ipv6: enr_ipv6.map(|_| new_socket),
+                }
+            }
         }
     }
 }
 
+/// Replaces `advertised`, or else the first socket of the same IP version as `new_socket`, or
+/// appends `new_socket` if there is none.
+fn replace_socket(
+    mut sockets: Vec<SocketAddr>,
+    advertised: Option<SocketAddr>,
+    new_socket: SocketAddr,
+) -> Vec<SocketAddr> {
+    match sockets.iter_mut().find(|socket| match advertised {
+        Some(advertised) => **socket == advertised,
+        None => socket.is_ipv4() == new_socket.is_ipv4(),
+    }) {
+        Some(socket) => *socket = new_socket,
+        None => sockets.push(new_socket),
+    }
+    sockets
+}
+
 impl Default for ListenConfig {
     fn default() -> Self {
         Self::Ipv4 {
@@ -270,13 +447,13 @@ impl From<SocketAddr> for ListenConfig {
 }
 
 impl Drop for Socket {
-    // close the send/recv handlers
+    // close the send/recv handlers, unless they have already been shutdown
     fn drop(&mut self) {
-        let _ = self
-            .sender_exit
-            .take()
-            .expect("Exit always exists")
-            .send(());
-        let _ = self.recv_exit.take().expect("Exit always exists").send(());
+        if let Some(exit) = self.sender_exit.take() {
+            let _ = exit.send(false);
+        }
+        if let Some(exit) = self.recv_exit.take() {
+            let _ = exit.send(());
+        }
     }
 }
@@ -0,0 +1,262 @@
+//! Limits the rate of outbound packets.
+//!
+//! Budgets are given in packets and in bytes, for all outbound packets, per destination node id
+//! and per destination IP. Packets that exceed a budget are queued by the send task until the
+//! budget allows them, see [`SendPriority`](super::SendPriority).
+use super::filter::rate_limiter::{Limiter, Quota, RateLimitedErr};
+use crate::{node_info::NodeAddress, packet::MAX_PACKET_SIZE};
+use enr::NodeId;
+use std::{
+    hash::Hash,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// A budget in packets and bytes for a single kind of key.
+#[derive(Debug, Clone)]
+struct Budget<Key: Hash + Eq + Clone> {
+    /// The packet budget.
+    packets: Option<Limiter<Key>>,
+    /// The byte budget.
+    bytes: Option<Limiter<Key>>,
+}
+
+impl<Key: Hash + Eq + Clone> Budget<Key> {
+    fn new(packets: Option<Quota>, bytes: Option<Quota>) -> Result<Self, &'static str> {
+        if bytes
+            .as_ref()
+            .is_some_and(|quota| quota.max_tokens() < MAX_PACKET_SIZE as u64)
+        {
+            return Err("Byte budgets must allow a packet of the maximum size");
+        }
+        Ok(Budget {
+            packets: packets.map(Limiter::from_quota).transpose()?,
+            bytes: bytes.map(Limiter::from_quota).transpose()?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.packets.is_none() && self.bytes.is_none()
+    }
+
+    /// Checks the packet against both budgets, without consuming any tokens.
+    fn check(&self, time_since_start: Duration, key: &Key, len: u64) -> Result<(), RateLimitedErr> {
+        let packets = self
+            .packets
+            .as_ref()
+            .map_or(Ok(()), |limiter| limiter.check(time_since_start, key, 1));
+        let bytes = self
+            .bytes
+            .as_ref()
+            .map_or(Ok(()), |limiter| limiter.check(time_since_start, key, len));
+        merge(packets, bytes)
+    }
+
+    /// Consumes the tokens of a packet that has been checked.
+    fn consume(&mut self, time_since_start: Duration, key: &Key, len: u64) {
+        if let Some(limiter) = self.packets.as_mut() {
+            let _ = limiter.allows(time_since_start, key, 1);
+        }
+        if let Some(limiter) = self.bytes.as_mut() {
+            let _ = limiter.allows(time_since_start, key, len);
+        }
+    }
+
+    fn prune(&mut self, time_since_start: Duration) {
+        for limiter in self.packets.iter_mut().chain(self.bytes.iter_mut()) {
+            limiter.prune(time_since_start);
+        }
+    }
+}
+
+/// Combines the results of two limiters, keeping the longest wait.
+fn merge(
+    a: Result<(), RateLimitedErr>,
+    b: Result<(), RateLimitedErr>,
+) -> Result<(), RateLimitedErr> {
+    match (a, b) {
+        (Err(RateLimitedErr::TooLarge), _) | (_, Err(RateLimitedErr::TooLarge)) => {
+            Err(RateLimitedErr::TooLarge)
+        }
+        (Err(RateLimitedErr::TooSoon(a)), Err(RateLimitedErr::TooSoon(b))) => {
+            Err(RateLimitedErr::TooSoon(a.max(b)))
+        }
+        (Err(e), Ok(())) | (Ok(()), Err(e)) => Err(e),
+        (Ok(()), Ok(())) => Ok(()),
+    }
+}
+
+/// Limits the rate of outbound packets. Built with an [`OutboundRateLimiterBuilder`].
+#[derive(Debug, Clone)]
+pub struct OutboundRateLimiter {
+    /// Creation time of the rate limiter.
+    init_time: Instant,
+    /// The budget for all outbound packets.
+    total: Budget<()>,
+    /// The budget per destination node id.
+    node: Budget<NodeId>,
+    /// The budget per destination IP.
+    ip: Budget<IpAddr>,
+}
+
+impl OutboundRateLimiter {
+    /// Indicates whether a packet of `len` bytes to `node_address` is within all budgets, in
+    /// which case its tokens are consumed. Otherwise, gives the time after which the packet
+    /// could be sent.
+    pub(crate) fn allows(
+        &mut self,
+        node_address: &NodeAddress,
+        len: usize,
+    ) -> Result<(), RateLimitedErr> {
+        let time_since_start = self.init_time.elapsed();
+        let len = len as u64;
+        let node_id = &node_address.node_id;
+        let ip = &node_address.socket_addr.ip();
+
+        merge(
+            merge(
+                self.total.check(time_since_start, &(), len),
+                self.node.check(time_since_start, node_id, len),
+            ),
+            self.ip.check(time_since_start, ip, len),
+        )?;
+
+        self.total.consume(time_since_start, &(), len);
+        self.node.consume(time_since_start, node_id, len);
+        self.ip.consume(time_since_start, ip, len);
+        Ok(())
+    }
+
+    /// Prunes excess entries. Should be called regularly to remove old entries.
+    pub(crate) fn prune(&mut self) {
+        let time_since_start = self.init_time.elapsed();
+        self.total.prune(time_since_start);
+        self.node.prune(time_since_start);
+        self.ip.prune(time_since_start);
+    }
+}
+
+/// Builder of an [`OutboundRateLimiter`]. Budgets can be set in packets and in bytes for:
+/// 1. Total - All outbound packets.
+/// 2. Node - The packets sent to each node id.
+/// 3. IP - The packets sent to each IP.
+///
+/// At least one budget must be set. Byte budgets must allow bursts of at least one packet of the
+/// maximum size.
+#[derive(Default)]
+pub struct OutboundRateLimiterBuilder {
+    total_packets: Option<Quota>,
+    total_bytes: Option<Quota>,
+    node_packets: Option<Quota>,
+    node_bytes: Option<Quota>,
+    ip_packets: Option<Quota>,
+    ip_bytes: Option<Quota>,
+}
+
+impl OutboundRateLimiterBuilder {
+    /// Get an empty `OutboundRateLimiterBuilder`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Allow `n` packets to be sent every `time_period` in total.
+    pub fn total_packets_n_every(mut self, n: u64, time_period: Duration) -> Self {
+        self.total_packets = Some(Quota::n_every(n, time_period));
+        self
+    }
+
+    /// Allow `n` bytes to be sent every `time_period` in total.
+    pub fn total_bytes_n_every(mut self, n: u64, time_period: Duration) -> Self {
+        self.total_bytes = Some(Quota::n_every(n, time_period));
+        self
+    }
+
+    /// Allow `n` packets to be sent to each node id every `time_period`.
+    pub fn node_packets_n_every(mut self, n: u64, time_period: Duration) -> Self {
+        self.node_packets = Some(Quota::n_every(n, time_period));
+        self
+    }
+
+    /// Allow `n` bytes to be sent to each node id every `time_period`.
+    pub fn node_bytes_n_every(mut self, n: u64, time_period: Duration) -> Self {
+        self.node_bytes = Some(Quota::n_every(n, time_period));
+        self
+    }
+
+    /// Allow `n` packets to be sent to each IP every `time_period`.
+    pub fn ip_packets_n_every(mut self, n: u64, time_period: Duration) -> Self {
+        self.ip_packets = Some(Quota::n_every(n, time_period));
+        self
+    }
+
+    /// Allow `n` bytes to be sent to each IP every `time_period`.
+    pub fn ip_bytes_n_every(mut self, n: u64, time_period: Duration) -> Self {
+        self.ip_bytes = Some(Quota::n_every(n, time_period));
+        self
+    }
+
+    /// Builds the [`OutboundRateLimiter`]. Fails if no budget is set or if a byte budget can't
+    /// fit a packet of the maximum size.
+    pub fn build(self) -> Result<OutboundRateLimiter, &'static str> {
+        let limiter = OutboundRateLimiter {
+            init_time: Instant::now(),
+            total: Budget::new(self.total_packets, self.total_bytes)?,
+            node: Budget::new(self.node_packets, self.node_bytes)?,
+            ip: Budget::new(self.ip_packets, self.ip_bytes)?,
+        };
+        if limiter.total.is_empty() && limiter.node.is_empty() && limiter.ip.is_empty() {
+            return Err("At least one outbound budget must be set");
+        }
+        Ok(limiter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::{CombinedKey, Enr};
+    use std::net::SocketAddr;
+
+    fn node_address(port: u16) -> NodeAddress {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = Enr::empty(&key).unwrap();
+        let socket_addr: SocketAddr = ([127, 0, 0, 1], port).into();
+        NodeAddress::new(socket_addr, enr.node_id())
+    }
+
+    #[test]
+    fn budgets_are_enforced() {
+        let mut limiter = OutboundRateLimiterBuilder::new()
+            .node_packets_n_every(2, Duration::from_secs(60))
+            .total_bytes_n_every(2000, Duration::from_secs(60))
+            .build()
+            .unwrap();
+        let first = node_address(1500);
+        let second = node_address(1501);
+
+        // The per-node packet budget.
+        assert!(limiter.allows(&first, 100).is_ok());
+        assert!(limiter.allows(&first, 100).is_ok());
+        assert!(matches!(
+            limiter.allows(&first, 100),
+            Err(RateLimitedErr::TooSoon(_))
+        ));
+        // A rejected packet consumes no tokens of the other budgets.
+        assert!(limiter.allows(&second, 1280).is_ok());
+        assert!(limiter.allows(&second, 1280).is_err());
+        assert!(limiter.allows(&second, 520).is_ok());
+    }
+
+    #[test]
+    fn invalid_budgets_are_rejected() {
+        assert!(OutboundRateLimiterBuilder::new().build().is_err());
+        assert!(OutboundRateLimiterBuilder::new()
+            .ip_bytes_n_every(1000, Duration::from_secs(1))
+            .build()
+            .is_err());
+        assert!(OutboundRateLimiterBuilder::new()
+            .total_packets_n_every(0, Duration::from_secs(1))
+            .build()
+            .is_err());
+    }
+}
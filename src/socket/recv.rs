@@ -2,11 +2,14 @@
 //!
 //! Every UDP packet passes a filter before being processed.
 
-use super::filter::{Filter, FilterConfig};
+use super::filter::{Filter, FilterConfig, FilterUpdate};
 use crate::{metrics::METRICS, node_info::NodeAddress, packet::*, Executor};
 use parking_lot::RwLock;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap, future::Future, net::SocketAddr, sync::Arc, task::Poll, time::Duration,
+};
 use tokio::{
+    io::ReadBuf,
     net::UdpSocket,
     sync::{mpsc, oneshot},
 };
@@ -17,6 +20,8 @@ use tracing::{debug, trace, warn};
 pub struct InboundPacket {
     /// The originating socket addr.
     pub src_address: SocketAddr,
+    /// The local socket the packet was received on.
+    pub local_socket: SocketAddr,
     /// The packet header.
     pub header: PacketHeader,
     /// The message of the packet.
@@ -31,19 +36,16 @@ pub struct RecvHandlerConfig {
     /// If the filter is enabled this sets the default timeout for bans enacted by the filter.
     pub ban_duration: Option<Duration>,
     pub executor: Box<dyn Executor>,
-    pub recv: Arc<UdpSocket>,
-    pub second_recv: Option<Arc<UdpSocket>>,
+    /// The UDP sockets to receive from, with their local addresses.
+    pub sockets: Vec<(SocketAddr, Arc<UdpSocket>)>,
     pub local_node_id: enr::NodeId,
     pub expected_responses: Arc<RwLock<HashMap<SocketAddr, usize>>>,
 }
 
 /// The main task that handles inbound UDP packets.
 pub(crate) struct RecvHandler {
-    /// The UDP recv socket.
-    recv: Arc<UdpSocket>,
-    /// An option second UDP socket. Used when dialing over both Ipv4 and Ipv6.
-    second_recv: Option<Arc<UdpSocket>>,
-    /// Simple hack to alternate reading from the first or the second socket.
+    /// The UDP recv sockets with their local addresses.
+    sockets: Vec<(SocketAddr, Arc<UdpSocket>)>,
     /// The list of waiting responses. These are used to allow incoming packets from sources
     /// that we are expected a response from bypassing the rate-limit filters.
     expected_responses: Arc<RwLock<HashMap<SocketAddr, usize>>>,
@@ -53,6 +55,8 @@ pub(crate) struct RecvHandler {
     node_id: enr::NodeId,
     /// The channel to send the packet handler.
     handler: mpsc::Sender<InboundPacket>,
+    /// The channel to receive updates to the filter parameters.
+    filter_update: mpsc::UnboundedReceiver<FilterUpdate>,
     /// Exit channel to shutdown the recv handler.
     exit: oneshot::Receiver<()>,
 }
@@ -61,14 +65,20 @@ impl RecvHandler {
     /// Spawns the `RecvHandler` on a provided executor.
     pub(crate) fn spawn<P: ProtocolIdentity>(
         config: RecvHandlerConfig,
-    ) -> (mpsc::Receiver<InboundPacket>, oneshot::Sender<()>) {
+    ) -> (
+        mpsc::Receiver<InboundPacket>,
+        mpsc::UnboundedSender<FilterUpdate>,
+        oneshot::Sender<()>,
+        oneshot::Receiver<()>,
+    ) {
         let (exit_sender, exit) = oneshot::channel();
+        let (exited_sender, exited) = oneshot::channel();
+        let (filter_update_send, filter_update) = mpsc::unbounded_channel();
         let RecvHandlerConfig {
             filter_config,
             ban_duration,
             executor,
-            recv,
-            second_recv,
+            sockets,
             local_node_id,
             expected_responses,
         } = config;
@@ -79,12 +89,12 @@ impl RecvHandler {
         let (handler, handler_recv) = mpsc::channel(30);
 
         let mut recv_handler = RecvHandler {
-            recv,
-            second_recv,
+            sockets,
             expected_responses,
             filter: Filter::new(filter_config, ban_duration),
             node_id: local_node_id,
             handler,
+            filter_update,
             exit,
         };
 
@@ -92,34 +102,38 @@ impl RecvHandler {
         executor.spawn(Box::pin(async move {
             debug!("Recv handler starting");
             recv_handler.start::<P>(filter_enabled).await;
+            // Release the sockets before reporting that the task has exited.
+            drop(recv_handler);
+            let _ = exited_sender.send(());
         }));
-        (handler_recv, exit_sender)
+        (handler_recv, filter_update_send, exit_sender, exited)
     }
 
     /// The main future driving the recv handler. This will shutdown when the exit future is fired.
     async fn start<P: ProtocolIdentity>(&mut self, filter_enabled: bool) {
         // Interval to prune to rate limiter.
         let mut interval = tokio::time::interval(Duration::from_secs(30));
-        let mut first_buffer = [0; MAX_PACKET_SIZE];
-        let mut second_buffer = [0; MAX_PACKET_SIZE];
-        use futures::future::OptionFuture;
-        // We want to completely deactivate this branch of the select when there is no second
-        // socket to receive from.
-        let check_second_recv = self.second_recv.is_some();
+        let mut recv_buffer = [0; MAX_PACKET_SIZE];
+        let sockets = self.sockets.clone();
+        let mut next_socket = 0;
 
         loop {
             tokio::select! {
-                Ok((length, src)) = self.recv.recv_from(&mut first_buffer) => {
-                    METRICS.add_recv_bytes(length);
-                    self.handle_inbound::<P>(src, length, &first_buffer).await;
-                }
-                Some(Ok((length, src))) = Into::<OptionFuture<_>>::into(self.second_recv.as_ref().map(|second_recv|second_recv.recv_from(&mut second_buffer))), if check_second_recv => {
-                    METRICS.add_recv_bytes(length);
-                    self.handle_inbound::<P>(src, length, &second_buffer).await;
+                (local_socket, result) = recv_from_any(&sockets, &mut recv_buffer, &mut next_socket) => {
+                    match result {
+                        Ok((length, src)) => {
+                            METRICS.add_recv_bytes(length);
+                            self.handle_inbound::<P>(src, local_socket, length, &recv_buffer).await;
+                        }
+                        Err(e) => trace!("Failed to receive on {local_socket}: {e}"),
+                    }
                 }
                 _ = interval.tick(), if filter_enabled => {
                     self.filter.prune_limiter();
                 },
+                Some(update) = self.filter_update.recv() => {
+                    self.filter.update(update);
+                }
                 _ = &mut self.exit => {
                     debug!("Recv handler shutdown");
                     return;
@@ -133,6 +147,7 @@ impl RecvHandler {
     async fn handle_inbound<P: ProtocolIdentity>(
         &mut self,
         mut src_address: SocketAddr,
+        local_socket: SocketAddr,
         length: usize,
         recv_buffer: &[u8; MAX_PACKET_SIZE],
     ) {
@@ -197,6 +212,7 @@ impl RecvHandler {
             let node_address = NodeAddress {
                 socket_addr: src_address,
                 node_id,
+                local_socket: Some(local_socket),
             };
 
             // Perform packet-level filtering
@@ -207,6 +223,7 @@ impl RecvHandler {
 
         let inbound = InboundPacket {
             src_address,
+            local_socket,
             header: packet.header,
             message: packet.message,
             authenticated_data,
@@ -219,3 +236,26 @@ impl RecvHandler {
             .unwrap_or_else(|e| warn!("Could not send packet to handler: {}", e));
     }
 }
+
+/// Receives a packet from whichever socket is ready first. Sockets are polled in turn, starting
+/// after the socket that received the previous packet, so that a busy socket cannot starve the
+/// others.
+fn recv_from_any<'a>(
+    sockets: &'a [(SocketAddr, Arc<UdpSocket>)],
+    buffer: &'a mut [u8],
+    next_socket: &'a mut usize,
+) -> impl Future<Output = (SocketAddr, std::io::Result<(usize, SocketAddr)>)> + 'a {
+    futures::future::poll_fn(move |cx| {
+        for offset in 0..sockets.len() {
+            let index = (*next_socket + offset) % sockets.len();
+            let (local_socket, socket) = &sockets[index];
+            let mut read_buf = ReadBuf::new(buffer);
+            if let Poll::Ready(result) = socket.poll_recv_from(cx, &mut read_buf) {
+                let length = read_buf.filled().len();
+                *next_socket = index + 1;
+                return Poll::Ready((*local_socket, result.map(|src| (length, src))));
+            }
+        }
+        Poll::Pending
+    })
+}
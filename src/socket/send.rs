@@ -1,29 +1,84 @@
 //! This is a standalone task that encodes and sends Discv5 UDP packets
+use super::{filter::rate_limiter::RateLimitedErr, OutboundRateLimiter};
 use crate::{metrics::METRICS, node_info::NodeAddress, packet::*, Executor};
-use std::{net::SocketAddr, sync::Arc};
+use lru::LruCache;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 use tokio::{
     net::UdpSocket,
     sync::{mpsc, oneshot},
 };
 use tracing::{debug, error, trace, warn};
 
+/// The number of destinations for which the local socket last used is remembered.
+const REMEMBERED_DESTINATIONS: NonZeroUsize = match NonZeroUsize::new(1000) {
+    Some(non_zero) => non_zero,
+    None => unreachable!(),
+};
+
+/// The maximum number of packets held back by the outbound rate limiter.
+const MAX_QUEUED_PACKETS: usize = 1000;
+
+/// The interval at which expired entries of the outbound rate limiter are pruned.
+const LIMITER_PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The order in which packets held back by the outbound rate limiter are sent. If the queue is
+/// full, packets of the lowest priority are dropped first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SendPriority {
+    /// Responses and handshake packets, which peers are waiting on.
+    High = 0,
+    /// Requests.
+    Normal = 1,
+    /// Application requests that may be sent in bulk, such as TALKREQ.
+    Low = 2,
+}
+
 pub struct OutboundPacket {
-    /// The destination node address
+    /// The destination node address. If the local socket of the address is set, the packet is
+    /// sent from that socket.
     pub node_address: NodeAddress,
     /// The packet to be encoded.
     pub packet: Packet,
+    /// The priority of the packet if the outbound rate limiter holds it back.
+    pub priority: SendPriority,
+}
+
+/// An encoded packet held back by the outbound rate limiter.
+struct QueuedPacket {
+    node_address: NodeAddress,
+    encoded_packet: Vec<u8>,
+    /// When the packet was first held back.
+    queued_at: Instant,
 }
 
 /// The main task that handles outbound UDP packets.
 pub(crate) struct SendHandler {
-    /// The UDP send socket for IPv4.
-    send_ipv4: Option<Arc<UdpSocket>>,
-    /// The UDP send socket for IPv6.
-    send_ipv6: Option<Arc<UdpSocket>>,
+    /// The UDP send sockets with their local addresses.
+    sockets: Vec<(SocketAddr, Arc<UdpSocket>)>,
+    /// The index of the socket last used for each destination. Packets without a local socket
+    /// are sent from the socket the destination was last contacted from, so that the remote
+    /// observes a consistent address.
+    destinations: LruCache<SocketAddr, usize>,
     /// The channel to respond to send requests.
     handler_recv: mpsc::Receiver<OutboundPacket>,
-    /// Exit channel to shutdown the handler.
-    exit: oneshot::Receiver<()>,
+    /// Exit channel to shutdown the handler. If `true` is sent, queued packets are sent before
+    /// the handler exits.
+    exit: oneshot::Receiver<bool>,
+    /// Limits the rate of outbound packets, if set.
+    limiter: Option<OutboundRateLimiter>,
+    /// The packets held back by the rate limiter, one queue per [`SendPriority`].
+    queue: [VecDeque<QueuedPacket>; 3],
+    /// Packets held back for longer are dropped, as their request has been retried or has failed
+    /// by then.
+    max_queue_time: Duration,
+    /// When the rate limiter may allow the next queued packet.
+    retry_at: Option<Instant>,
 }
 
 enum Error {
@@ -33,55 +88,73 @@ enum Error {
 
 impl SendHandler {
     /// Spawns the `SendHandler` on a provided executor.
-    /// This returns the sending channel to process `OutboundPacket`'s and an exit channel to
-    /// shutdown the handler.
+    /// This returns the sending channel to process `OutboundPacket`'s, an exit channel to
+    /// shutdown the handler and a channel that resolves once the handler has exited.
     pub(crate) fn spawn<P: ProtocolIdentity>(
         executor: Box<dyn Executor>,
-        send_ipv4: Option<Arc<UdpSocket>>,
-        send_ipv6: Option<Arc<UdpSocket>>,
-    ) -> (mpsc::Sender<OutboundPacket>, oneshot::Sender<()>) {
+        sockets: Vec<(SocketAddr, Arc<UdpSocket>)>,
+        limiter: Option<OutboundRateLimiter>,
+        max_queue_time: Duration,
+    ) -> (
+        mpsc::Sender<OutboundPacket>,
+        oneshot::Sender<bool>,
+        oneshot::Receiver<()>,
+    ) {
         let (exit_send, exit) = oneshot::channel();
+        let (exited_send, exited) = oneshot::channel();
         let (handler_send, handler_recv) = mpsc::channel(30);
 
         let mut send_handler = SendHandler {
-            send_ipv4,
-            send_ipv6,
+            sockets,
+            destinations: LruCache::new(REMEMBERED_DESTINATIONS),
             handler_recv,
             exit,
+            limiter,
+            queue: Default::default(),
+            max_queue_time,
+            retry_at: None,
         };
 
         // start the handler
         executor.spawn(Box::pin(async move {
             debug!("Send handler starting");
             send_handler.start::<P>().await;
+            // Release the sockets before reporting that the task has exited.
+            drop(send_handler);
+            let _ = exited_send.send(());
         }));
-        (handler_send, exit_send)
+        (handler_send, exit_send, exited)
     }
 
     /// The main future driving the send handler. This will shutdown when the exit future is fired.
     async fn start<P: ProtocolIdentity>(&mut self) {
+        let mut prune_interval = tokio::time::interval(LIMITER_PRUNE_INTERVAL);
         loop {
+            let retry_at = self.retry_at;
             tokio::select! {
                 Some(packet) = self.handler_recv.recv() => {
-                    let encoded_packet = packet.packet.encode::<P>(&packet.node_address.node_id);
-                    if encoded_packet.len() > MAX_PACKET_SIZE {
-                        warn!("Sending packet larger than max size: {} max: {}", encoded_packet.len(), MAX_PACKET_SIZE);
+                    self.send_queued().await;
+                    self.send_packet::<P>(packet).await;
+                }
+                _ = tokio::time::sleep_until(retry_at.unwrap_or_else(Instant::now).into()), if retry_at.is_some() => {
+                    self.send_queued().await;
+                }
+                _ = prune_interval.tick(), if self.limiter.is_some() => {
+                    if let Some(limiter) = self.limiter.as_mut() {
+                        limiter.prune();
                     }
-                    let addr = &packet.node_address.socket_addr;
-                    if let Err(e) = self.send(&encoded_packet, addr).await {
-                        match e {
-                            Error::Io(e) => {
-                                trace!("Could not send packet to {addr} . Error: {e}");
-                            },
-                            Error::SocketMismatch => {
-                                error!("Socket mismatch attempting to send a packet to {addr}.")
-                            }
+                }
+                flush = &mut self.exit => {
+                    if flush.unwrap_or(false) {
+                        self.handler_recv.close();
+                        while let Ok(packet) = self.handler_recv.try_recv() {
+                            self.send_packet::<P>(packet).await;
                         }
-                    } else {
-                        METRICS.add_sent_bytes(encoded_packet.len());
+                        // The rate limits no longer apply once the handler shuts down.
+                        self.limiter = None;
+                        self.send_queued().await;
                     }
-                }
-                _ = &mut self.exit => {
+                    METRICS.outbound_queued_packets.store(0, Ordering::Relaxed);
                     debug!("Send handler shutdown");
                     return;
                 }
@@ -89,27 +162,270 @@ impl SendHandler {
         }
     }
 
-    async fn send(&self, encoded_packet: &[u8], socket_addr: &SocketAddr) -> Result<usize, Error> {
-        let socket = match socket_addr {
-            SocketAddr::V4(_) => {
-                if let Some(socket) = self.send_ipv4.as_ref() {
-                    socket
-                } else {
-                    return Err(Error::SocketMismatch);
+    /// Encodes and sends a single packet, or queues it if the rate limiter holds it back.
+    async fn send_packet<P: ProtocolIdentity>(&mut self, packet: OutboundPacket) {
+        let encoded_packet = packet.packet.encode::<P>(&packet.node_address.node_id);
+        if encoded_packet.len() > MAX_PACKET_SIZE {
+            warn!(
+                "Sending packet larger than max size: {} max: {}",
+                encoded_packet.len(),
+                MAX_PACKET_SIZE
+            );
+        }
+        let queued_packet = QueuedPacket {
+            node_address: packet.node_address,
+            encoded_packet,
+            queued_at: Instant::now(),
+        };
+        match self.check_limits(&queued_packet) {
+            Ok(()) => self.transmit(&queued_packet).await,
+            Err(RateLimitedErr::TooSoon(wait)) => {
+                METRICS
+                    .outbound_packets_delayed
+                    .fetch_add(1, Ordering::Relaxed);
+                self.schedule_retry(wait);
+                self.enqueue(queued_packet, packet.priority);
+            }
+            Err(RateLimitedErr::TooLarge) => self.drop_packet(&queued_packet),
+        }
+    }
+
+    /// Sends the queued packets the rate limiter allows, the highest priority first. Packets to
+    /// other destinations may fit the budgets while a packet before them is held back. Packets
+    /// queued for longer than `max_queue_time` are dropped.
+    async fn send_queued(&mut self) {
+        if self.queue.iter().all(VecDeque::is_empty) {
+            return;
+        }
+        self.retry_at = None;
+        for priority in 0..self.queue.len() {
+            for queued_packet in std::mem::take(&mut self.queue[priority]) {
+                if queued_packet.queued_at.elapsed() > self.max_queue_time {
+                    trace!("Queued packet expired. {}", queued_packet.node_address);
+                    self.drop_packet(&queued_packet);
+                    continue;
+                }
+                match self.check_limits(&queued_packet) {
+                    Ok(()) => self.transmit(&queued_packet).await,
+                    Err(RateLimitedErr::TooSoon(wait)) => {
+                        self.schedule_retry(wait);
+                        self.queue[priority].push_back(queued_packet);
+                    }
+                    Err(RateLimitedErr::TooLarge) => self.drop_packet(&queued_packet),
                 }
             }
-            SocketAddr::V6(_) => {
-                if let Some(socket) = self.send_ipv6.as_ref() {
-                    socket
-                } else {
-                    return Err(Error::SocketMismatch);
+        }
+        self.update_queue_metric();
+    }
+
+    /// Checks the packet against the rate limiter, consuming its tokens if it is allowed.
+    fn check_limits(&mut self, queued_packet: &QueuedPacket) -> Result<(), RateLimitedErr> {
+        match self.limiter.as_mut() {
+            Some(limiter) => limiter.allows(
+                &queued_packet.node_address,
+                queued_packet.encoded_packet.len(),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes sure the queue is processed again once `wait` has elapsed.
+    fn schedule_retry(&mut self, wait: Duration) {
+        let at = Instant::now() + wait;
+        if self.retry_at.is_none_or(|retry_at| at < retry_at) {
+            self.retry_at = Some(at);
+        }
+    }
+
+    /// Queues a packet held back by the rate limiter. If the queue is full, the newest packet of
+    /// the lowest priority is dropped to make room, unless that is the new packet itself.
+    fn enqueue(&mut self, queued_packet: QueuedPacket, priority: SendPriority) {
+        let priority = priority as usize;
+        if self.queue.iter().map(VecDeque::len).sum::<usize>() >= MAX_QUEUED_PACKETS {
+            let lower_priority = self.queue[priority + 1..]
+                .iter_mut()
+                .rev()
+                .find(|queue| !queue.is_empty());
+            match lower_priority.and_then(VecDeque::pop_back) {
+                Some(dropped) => self.drop_packet(&dropped),
+                None => return self.drop_packet(&queued_packet),
+            }
+        }
+        self.queue[priority].push_back(queued_packet);
+        self.update_queue_metric();
+    }
+
+    fn drop_packet(&self, queued_packet: &QueuedPacket) {
+        debug!(
+            "Outbound rate limit exceeded, dropping packet to {}",
+            queued_packet.node_address
+        );
+        METRICS
+            .outbound_packets_dropped
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn update_queue_metric(&self) {
+        let queued = self.queue.iter().map(VecDeque::len).sum();
+        METRICS
+            .outbound_queued_packets
+            .store(queued, Ordering::Relaxed);
+    }
+
+    /// Sends an encoded packet.
+    async fn transmit(&mut self, queued_packet: &QueuedPacket) {
+        let encoded_packet = &queued_packet.encoded_packet;
+        let addr = &queued_packet.node_address.socket_addr;
+        if let Err(e) = self.send(encoded_packet, &queued_packet.node_address).await {
+            match e {
+                Error::Io(e) => {
+                    trace!("Could not send packet to {addr} . Error: {e}");
+                }
+                Error::SocketMismatch => {
+                    error!("Socket mismatch attempting to send a packet to {addr}.")
                 }
             }
-        };
+        } else {
+            METRICS.add_sent_bytes(encoded_packet.len());
+        }
+    }
+
+    async fn send(
+        &mut self,
+        encoded_packet: &[u8],
+        node_address: &NodeAddress,
+    ) -> Result<usize, Error> {
+        let socket_addr = &node_address.socket_addr;
+        let index = self
+            .socket_index(node_address)
+            .ok_or(Error::SocketMismatch)?;
+        let socket = &self.sockets[index].1;
 
         socket
             .send_to(encoded_packet, socket_addr)
             .await
             .map_err(Error::Io)
     }
+
+    /// Chooses the socket to send from. This is the local socket of the node address if it is
+    /// set, otherwise the socket the destination was last contacted from, otherwise the first
+    /// socket of the destination's IP version.
+    fn socket_index(&mut self, node_address: &NodeAddress) -> Option<usize> {
+        let destination = node_address.socket_addr;
+        let same_version = |local: &SocketAddr| local.is_ipv4() == destination.is_ipv4();
+
+        if let Some(local_socket) = node_address.local_socket {
+            if let Some(index) = self
+                .sockets
+                .iter()
+                .position(|(local, _)| *local == local_socket && same_version(local))
+            {
+                self.destinations.put(destination, index);
+                return Some(index);
+            }
+        }
+
+        if let Some(index) = self.destinations.get(&destination) {
+            return Some(*index);
+        }
+
+        self.sockets
+            .iter()
+            .position(|(local, _)| same_version(local))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{socket::OutboundRateLimiterBuilder, TokioExecutor};
+    use enr::NodeId;
+
+    #[tokio::test]
+    async fn queued_packets_are_sent_by_priority() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = socket.local_addr().unwrap();
+        let node_address = NodeAddress::new(receiver.local_addr().unwrap(), NodeId::random());
+
+        let limiter = OutboundRateLimiterBuilder::new()
+            .total_packets_n_every(1, Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let (send, _exit, _exited) = SendHandler::spawn::<DefaultProtocolId>(
+            Box::<TokioExecutor>::default(),
+            vec![(local_addr, socket)],
+            Some(limiter),
+            Duration::from_secs(1),
+        );
+
+        let mut expected = Vec::new();
+        for priority in [SendPriority::Normal, SendPriority::Low, SendPriority::High] {
+            let packet = Packet::new_random(&NodeId::random()).unwrap();
+            expected.push(
+                packet
+                    .clone()
+                    .encode::<DefaultProtocolId>(&node_address.node_id),
+            );
+            let outbound_packet = OutboundPacket {
+                node_address: node_address.clone(),
+                packet,
+                priority,
+            };
+            send.send(outbound_packet).await.unwrap();
+        }
+
+        // The first packet fits the budget, the others are sent by priority once it allows.
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            let length = tokio::time::timeout(Duration::from_secs(2), receiver.recv(&mut buffer))
+                .await
+                .expect("packet is sent")
+                .unwrap();
+            received.push(buffer[..length].to_vec());
+        }
+        assert_eq!(
+            received,
+            vec![
+                expected[0].clone(),
+                expected[2].clone(),
+                expected[1].clone()
+            ]
+        );
+        assert!(METRICS.outbound_packets_delayed.load(Ordering::Relaxed) >= 2);
+    }
+
+    #[tokio::test]
+    async fn expired_packets_are_dropped() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = socket.local_addr().unwrap();
+        let node_address = NodeAddress::new(receiver.local_addr().unwrap(), NodeId::random());
+
+        let limiter = OutboundRateLimiterBuilder::new()
+            .total_packets_n_every(1, Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let (send, _exit, _exited) = SendHandler::spawn::<DefaultProtocolId>(
+            Box::<TokioExecutor>::default(),
+            vec![(local_addr, socket)],
+            Some(limiter),
+            Duration::from_millis(100),
+        );
+        for _ in 0..2 {
+            let outbound_packet = OutboundPacket {
+                node_address: node_address.clone(),
+                packet: Packet::new_random(&NodeId::random()).unwrap(),
+                priority: SendPriority::Normal,
+            };
+            send.send(outbound_packet).await.unwrap();
+        }
+
+        // The second packet is held back for longer than it may be queued.
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        assert!(receiver.recv(&mut buffer).await.is_ok());
+        let second = tokio::time::timeout(Duration::from_secs(1), receiver.recv(&mut buffer));
+        assert!(second.await.is_err());
+    }
 }